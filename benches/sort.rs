@@ -34,7 +34,9 @@ fn bench_ext_sort_1000_sorted(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new();
             let sorted_iter = sorter.sort((0..1000).map(MyStruct)).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -54,7 +56,9 @@ fn bench_ext_sort_1000_rev(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new();
             let sorted_iter = sorter.sort((0..1000).map(MyStruct).rev()).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -77,7 +81,9 @@ fn bench_ext_sort_1000_rand(c: &mut Criterion) {
             let sorted_iter = sorter
                 .sort((0..1000).map(|_| MyStruct(rand::random())))
                 .unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -97,7 +103,9 @@ fn bench_ext_sort_100_000_sorted(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new();
             let sorted_iter = sorter.sort((0..100_000).map(MyStruct)).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -117,7 +125,9 @@ fn bench_ext_sort_100_000_rev(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new();
             let sorted_iter = sorter.sort((0..100_000).map(MyStruct).rev()).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -140,7 +150,9 @@ fn bench_ext_sort_100_000_rand(c: &mut Criterion) {
             let sorted_iter = sorter
                 .sort((0..100_000).map(|_| MyStruct(rand::random())))
                 .unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -150,7 +162,9 @@ fn bench_ext_sort_1million_max10k_sorted(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new().with_segment_size(10_000);
             let sorted_iter = sorter.sort((0..1_000_000).map(MyStruct)).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -160,7 +174,9 @@ fn bench_ext_sort_1million_max10k_rev(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new().with_segment_size(10_000);
             let sorted_iter = sorter.sort((0..1_000_000).map(MyStruct).rev()).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -172,7 +188,9 @@ fn bench_ext_sort_1million_max10k_rand(c: &mut Criterion) {
             let sorted_iter = sorter
                 .sort((0..1_000_000).map(|_| MyStruct(rand::random())))
                 .unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -187,7 +205,9 @@ fn bench_ext_sort_1million_max10k_rand_parallel(c: &mut Criterion) {
             let sorted_iter = sorter
                 .sort((0..1_000_000).map(|_| MyStruct(rand::random())))
                 .unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -197,7 +217,9 @@ fn bench_ext_sort_1million_max100k_sorted(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new().with_segment_size(100_000);
             let sorted_iter = sorter.sort((0..1_000_000).map(MyStruct)).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -207,7 +229,9 @@ fn bench_ext_sort_1million_max100k_rev(c: &mut Criterion) {
         b.iter(|| {
             let sorter = ExternalSorter::new().with_segment_size(100_000);
             let sorted_iter = sorter.sort((0..1_000_000).map(MyStruct).rev()).unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -219,7 +243,9 @@ fn bench_ext_sort_1million_max100k_rand(c: &mut Criterion) {
             let sorted_iter = sorter
                 .sort((0..1_000_000).map(|_| MyStruct(rand::random())))
                 .unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -234,7 +260,9 @@ fn bench_ext_sort_1million_max100k_rand_parallel(c: &mut Criterion) {
             let sorted_iter = sorter
                 .sort((0..1_000_000).map(|_| MyStruct(rand::random())))
                 .unwrap();
-            black_box(sorted_iter.count());
+            sorted_iter.for_each(|item| {
+                black_box(item.unwrap());
+            });
         })
     });
 }
@@ -0,0 +1,342 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    io::{Error, ErrorKind, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+#[cfg(feature = "parallel")]
+use rayon::slice::ParallelSliceMut;
+
+use crate::{
+    cleanup::create_temp_dir,
+    segment::{SegmentFile, SegmentReader, SegmentWriter},
+    DuplicatePolicy, ExternalSorterOptions, SortAlgorithm, SortedIterator,
+};
+
+/// External sorter of byte records that copies pushed records into a single
+/// contiguous arena instead of allocating each of them individually.
+///
+/// Records are stored in their encoded form (see the [`Sortable`]
+/// implementation of `Vec<u8>`) one after the other in the arena, and the
+/// sorter only sorts their positions. Once written to disk, the arena is
+/// cleared while keeping its capacity, so pushing records doesn't allocate once
+/// the first segment has been written. This is much cheaper than buffering
+/// millions of small `String` or `Vec<u8>` items.
+///
+/// Sorted records are returned as `Vec<u8>`.
+///
+/// [`Sortable`]: crate::Sortable
+pub struct ArenaPushSorter<F>
+where
+    F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync + Clone,
+{
+    options: ExternalSorterOptions,
+    tempdir: Option<tempfile::TempDir>,
+    count: u64,
+    segment_files: Vec<SegmentFile>,
+    next_segment_id: usize,
+    memory_segments: usize,
+    arena: Vec<u8>,
+    records: Vec<(usize, usize)>,
+    cmp: F,
+}
+
+impl<F> ArenaPushSorter<F>
+where
+    F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync + Clone,
+{
+    pub(crate) fn new(options: ExternalSorterOptions, cmp: F) -> ArenaPushSorter<F> {
+        ArenaPushSorter {
+            options,
+            tempdir: None,
+            count: 0,
+            segment_files: Vec::new(),
+            next_segment_id: 0,
+            memory_segments: 0,
+            arena: Vec::new(),
+            records: Vec::new(),
+            cmp,
+        }
+    }
+
+    /// Pushes all records from an iterator into the sorter.
+    pub fn push_iter<I, R>(&mut self, iterator: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[u8]>,
+    {
+        for record in iterator {
+            self.push(record)?;
+        }
+        Ok(())
+    }
+
+    /// Pushes a single record into the sorter by copying it into the arena.
+    ///
+    /// Records are limited to `u32::MAX` bytes.
+    pub fn push<R: AsRef<[u8]>>(&mut self, record: R) -> Result<(), Error> {
+        let record = record.as_ref();
+        let len = u32::try_from(record.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "record is too large"))?;
+
+        let offset = self.arena.len();
+        self.arena.extend_from_slice(&len.to_le_bytes());
+        self.arena.extend_from_slice(record);
+        self.records.push((offset, self.arena.len() - offset));
+        self.count += 1;
+
+        let segment_bytes = self.options.segment_bytes;
+        if self.records.len() > self.options.segment_size
+            || (segment_bytes > 0 && self.arena.len() as u64 > segment_bytes)
+        {
+            self.sort_and_write_segment()?;
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn done(
+        mut self,
+    ) -> Result<
+        SortedIterator<Vec<u8>, impl Fn(&Vec<u8>, &Vec<u8>) -> Ordering + Send + Sync + Clone>,
+        Error,
+    > {
+        // Write any records left in the arena, but only if we had at least 1
+        // segment written. Otherwise, we iterate the records from memory.
+        let pass_through_queue = if self.segment_files.is_empty() {
+            self.sort_records();
+            let queue = self
+                .records
+                .iter()
+                .map(|&(offset, len)| self.arena[offset + 4..offset + len].to_vec())
+                .collect::<VecDeque<_>>();
+            Some(queue)
+        } else {
+            if !self.records.is_empty() {
+                self.sort_and_write_segment()?;
+            }
+            None
+        };
+
+        let cmp = self.cmp;
+        SortedIterator::new(
+            self.tempdir,
+            pass_through_queue,
+            self.segment_files,
+            Some(self.count),
+            move |a: &Vec<u8>, b: &Vec<u8>| cmp(a, b),
+            self.options,
+        )
+    }
+
+    /// Sorts the pushed records and writes them in their encoded form to the
+    /// given writer, returning the number of records written.
+    ///
+    /// Unlike consuming the sorted iterator returned by
+    /// [`ArenaPushSorter::done`] and writing its records (see
+    /// [`SortedIterator::write_to`]), records are compared and copied from the
+    /// segments to the writer in their encoded form, without being decoded
+    /// into individually allocated records and encoded again. The writer
+    /// isn't buffered, and records that compare equal are removed according
+    /// to the duplicate policy (see [`ExternalSorter::with_duplicate_policy`]).
+    ///
+    /// [`ExternalSorter::with_duplicate_policy`]: crate::ExternalSorter::with_duplicate_policy
+    pub fn write_to<W: Write>(mut self, writer: &mut W) -> Result<u64, Error> {
+        let policy = self.options.duplicate_policy;
+        let mut output = EncodedOutput {
+            writer,
+            pending: Vec::new(),
+            has_pending: false,
+            written: 0,
+        };
+
+        if self.segment_files.is_empty() {
+            self.sort_records();
+            for &(offset, len) in &self.records {
+                let record = &self.arena[offset..offset + len];
+                output.write(record, policy, &self.cmp)?;
+            }
+            return output.finish();
+        }
+
+        if !self.records.is_empty() {
+            self.sort_and_write_segment()?;
+        }
+
+        let mut segments = Vec::with_capacity(self.segment_files.len());
+        for mut segment_file in std::mem::take(&mut self.segment_files) {
+            segment_file.file.seek(SeekFrom::Start(0))?;
+            segment_file.apply_read_transform();
+            let mut reader = SegmentReader::new(segment_file.file, segment_file.format)?;
+            let mut record = Vec::new();
+            if reader.read_encoded_record(&mut record)? {
+                segments.push((reader, record));
+            }
+        }
+
+        // the smallest record is picked by peeking at the next record of each
+        // segment, the first segment winning ties
+        while !segments.is_empty() {
+            let mut smallest = 0;
+            for (segment_index, (_, record)) in segments.iter().enumerate().skip(1) {
+                if (self.cmp)(&record[4..], &segments[smallest].1[4..]) == Ordering::Less {
+                    smallest = segment_index;
+                }
+            }
+
+            let (reader, record) = &mut segments[smallest];
+            output.write(record, policy, &self.cmp)?;
+            if !reader.read_encoded_record(record)? {
+                segments.remove(smallest);
+            }
+        }
+
+        output.finish()
+    }
+
+    fn sort_records(&mut self) {
+        let arena = &self.arena;
+        let cmp = &self.cmp;
+        let record_cmp = |a: &(usize, usize), b: &(usize, usize)| {
+            cmp(&arena[a.0 + 4..a.0 + a.1], &arena[b.0 + 4..b.0 + b.1])
+        };
+
+        match (self.options.buffer_sort_algorithm(), self.options.parallel) {
+            #[cfg(feature = "parallel")]
+            (SortAlgorithm::Unstable, true) => {
+                let records = &mut self.records;
+                self.options
+                    .install(|| records.par_sort_unstable_by(record_cmp))
+            }
+            #[cfg(feature = "parallel")]
+            (SortAlgorithm::Stable, true) => {
+                let records = &mut self.records;
+                self.options.install(|| records.par_sort_by(record_cmp))
+            }
+            (SortAlgorithm::Unstable, _) => self.records.sort_unstable_by(record_cmp),
+            (SortAlgorithm::Stable, _) => self.records.sort_by(record_cmp),
+        }
+    }
+
+    fn sort_and_write_segment(&mut self) -> Result<(), Error> {
+        self.sort_records();
+
+        let capacity = self.records.len();
+        let mut writer = self.create_segment_writer(capacity)?;
+        let records = std::mem::take(&mut self.records);
+        for &(offset, len) in &records {
+            if writer.is_full(self.options.max_segment_bytes) {
+                let next_writer = self.create_segment_writer(capacity)?;
+                let full_writer = std::mem::replace(&mut writer, next_writer);
+                self.segment_files.push(full_writer.finish()?);
+            }
+            writer.write_encoded(&self.arena[offset..offset + len])?;
+        }
+        self.segment_files.push(writer.finish()?);
+
+        self.records = records;
+        self.records.clear();
+        self.arena.clear();
+
+        Ok(())
+    }
+
+    /// Creates the writer of the next segment, which is kept in memory for the
+    /// first segments or while it is small if enabled.
+    fn create_segment_writer(&mut self, capacity: usize) -> Result<SegmentWriter<Vec<u8>>, Error> {
+        if self.memory_segments < self.options.in_memory_segments {
+            self.memory_segments += 1;
+            return SegmentWriter::create_in_memory(&self.options, capacity);
+        }
+
+        let segment_path = self.next_segment_path()?;
+        match self.options.in_memory_segment_bytes {
+            0 => SegmentWriter::create(segment_path, &self.options, capacity),
+            max_bytes => {
+                SegmentWriter::create_spilling(segment_path, max_bytes, &self.options, capacity)
+            }
+        }
+    }
+
+    /// Returns the path of the next segment, or `None` if segments are written
+    /// in scratch files created by the tempfile builder.
+    fn next_segment_path(&mut self) -> Result<Option<PathBuf>, Error> {
+        if self.options.tempfile_builder.is_some() {
+            return Ok(None);
+        }
+
+        if self.tempdir.is_none() {
+            self.tempdir = Some(create_temp_dir(self.options.sort_dir.as_deref())?);
+        }
+
+        let sort_dir = self.tempdir.as_ref().unwrap().path();
+        let segment_path = sort_dir.join(format!("{}", self.next_segment_id));
+        self.next_segment_id += 1;
+        Ok(Some(segment_path))
+    }
+}
+
+/// Writer of encoded sorted records, holding back the last written record
+/// while records that compare equal to it may be removed.
+struct EncodedOutput<'a, W: Write> {
+    writer: &'a mut W,
+    pending: Vec<u8>,
+    has_pending: bool,
+    written: u64,
+}
+
+impl<W: Write> EncodedOutput<'_, W> {
+    fn write<F>(&mut self, record: &[u8], policy: DuplicatePolicy, cmp: &F) -> Result<(), Error>
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
+        if policy == DuplicatePolicy::KeepAll {
+            self.written += 1;
+            return self.writer.write_all(record);
+        }
+
+        if self.has_pending && cmp(&self.pending[4..], &record[4..]) == Ordering::Equal {
+            if policy == DuplicatePolicy::KeepLast {
+                self.pending.clear();
+                self.pending.extend_from_slice(record);
+            }
+            return Ok(());
+        }
+
+        self.flush_pending()?;
+        self.pending.extend_from_slice(record);
+        self.has_pending = true;
+        Ok(())
+    }
+
+    fn flush_pending(&mut self) -> Result<(), Error> {
+        if self.has_pending {
+            self.writer.write_all(&self.pending)?;
+            self.pending.clear();
+            self.has_pending = false;
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<u64, Error> {
+        self.flush_pending()?;
+        Ok(self.written)
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+/// Checksum of a record that doesn't match the one written along with it,
+/// returned when reading segments written with record checksums (see
+/// [`ExternalSorter::with_record_checksums`]).
+///
+/// Sorted iterators return it wrapped in an `std::io::Error` of kind
+/// `InvalidData`, from which it can be retrieved by downcasting.
+///
+/// [`ExternalSorter::with_record_checksums`]: crate::ExternalSorter::with_record_checksums
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumError {
+    /// Checksum written along with the record.
+    pub expected: u32,
+
+    /// Checksum of the record as read.
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupted record: checksum {:08x} doesn't match expected {:08x}",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+impl From<ChecksumError> for std::io::Error {
+    fn from(err: ChecksumError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE) of a record, computed incrementally.
+#[derive(Clone, Copy)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = CRC32_TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+
+    pub fn checksum(bytes: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(bytes);
+        crc.finish()
+    }
+}
+
+/// Writer computing the checksum of the bytes written through it.
+pub(crate) struct ChecksumWriter<'a, W: Write> {
+    inner: &'a mut W,
+    crc: Crc32,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> ChecksumWriter<'a, W> {
+        ChecksumWriter {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.crc.finish()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader computing the checksum of the bytes read through it.
+pub(crate) struct ChecksumReader<'a, R: Read> {
+    inner: &'a mut R,
+    crc: Crc32,
+}
+
+impl<'a, R: Read> ChecksumReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> ChecksumReader<'a, R> {
+        ChecksumReader {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.crc.finish()
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
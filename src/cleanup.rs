@@ -0,0 +1,106 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{Error, ErrorKind},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Prefix of the temporary directories created by the sorters, which allows
+/// recognizing the ones left behind by crashed processes.
+pub(crate) const TEMP_DIR_PREFIX: &str = ".extsort-";
+
+/// Creates a uniquely named temporary directory for the segments of a sorter,
+/// in the given sort directory or in the system's temporary directory.
+pub(crate) fn create_temp_dir(sort_dir: Option<&Path>) -> Result<tempfile::TempDir, Error> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(TEMP_DIR_PREFIX);
+    match sort_dir {
+        Some(sort_dir) => builder.tempdir_in(sort_dir),
+        None if NO_TEMP_DIR => Err(no_temp_dir_error()),
+        None => builder.tempdir(),
+    }
+}
+
+/// Creates an anonymous temporary file in the system's temporary directory.
+pub(crate) fn create_anonymous_tempfile() -> Result<File, Error> {
+    if NO_TEMP_DIR {
+        return Err(no_temp_dir_error());
+    }
+    tempfile::tempfile()
+}
+
+/// Whether the target has no system temporary directory, in which case
+/// [`std::env::temp_dir`] panics instead of returning an error.
+const NO_TEMP_DIR: bool = cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+
+fn no_temp_dir_error() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "the target has no temporary directory, a sort directory is required",
+    )
+}
+
+/// Removes the temporary directories of sorters found in the given
+/// directory that weren't modified for at least the given duration, returning
+/// the number of removed directories.
+///
+/// Sorters delete their temporary directory when they are dropped, but a
+/// process that crashed or was killed leaves it behind along with its
+/// segments. Temporary directories are recognized by their name, and are
+/// created in the sort directory if one is configured (see
+/// [`ExternalSorter::with_sort_dir`]), or in the system's temporary directory
+/// otherwise (see [`std::env::temp_dir`]).
+///
+/// A directory is only modified when segments are written to it, so the
+/// duration should be longer than the longest expected sort to avoid
+/// removing the directory of a sorter that is still being consumed.
+///
+/// [`ExternalSorter::with_sort_dir`]: crate::ExternalSorter::with_sort_dir
+pub fn cleanup_stale<P: AsRef<Path>>(sort_dir: P, older_than: Duration) -> Result<usize, Error> {
+    // read first so that targets without a filesystem (and clock) fail
+    let entries = std::fs::read_dir(sort_dir)?;
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let is_temp_dir = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(TEMP_DIR_PREFIX));
+        if !is_temp_dir || !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        // directories modified in the future are considered fresh
+        let modified = entry.metadata()?.modified()?;
+        let stale = now
+            .duration_since(modified)
+            .is_ok_and(|age| age >= older_than);
+        if !stale {
+            continue;
+        }
+
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(()) => removed += 1,
+            // another process may be cleaning up concurrently
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,251 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Comparators to use with [`ExternalSorter::sort_by`](crate::ExternalSorter::sort_by).
+//!
+//! The comparators don't allocate, and strings that only differ by their case
+//! are considered equal. They can be chained with [`Ordering::then_with`] to
+//! break ties, or compare by other fields.
+//!
+//! # Examples
+//! ```rust
+//! use extsort::{compare, ExternalSorter};
+//!
+//! let words = ["banana", "Apple", "apple", "Cherry"].map(String::from);
+//! let sorted = ExternalSorter::new()
+//!     .sort_by(words, |a, b| {
+//!         compare::ascii_case_insensitive(a, b).then_with(|| a.cmp(b))
+//!     })
+//!     .unwrap()
+//!     .collect_sorted()
+//!     .unwrap();
+//! assert_eq!(sorted, ["Apple", "apple", "banana", "Cherry"]);
+//! ```
+//!
+//! Comparisons on multiple keys with mixed directions can be built with a
+//! [`KeySpec`] instead of chaining them by hand.
+
+use std::{
+    cmp::Ordering,
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+
+/// Compares byte strings ignoring the case of ASCII letters, by comparing
+/// their bytes as if they were lowercase. Non-ASCII bytes are compared as is.
+pub fn ascii_case_insensitive<S: AsRef<[u8]> + ?Sized>(a: &S, b: &S) -> Ordering {
+    let a = a.as_ref().iter().map(u8::to_ascii_lowercase);
+    let b = b.as_ref().iter().map(u8::to_ascii_lowercase);
+    a.cmp(b)
+}
+
+/// Compares strings ignoring their case, by comparing the characters of their
+/// case folded forms.
+///
+/// Characters are folded by lowercasing their uppercase mapping, which
+/// matches the full Unicode case folding for nearly all characters. For
+/// instance, `ß` is equal to `ss` and the final sigma `ς` is equal to `σ`.
+/// Unlike locale-aware collation, characters are ordered by their code point.
+pub fn unicode_case_insensitive<S: AsRef<str> + ?Sized>(a: &S, b: &S) -> Ordering {
+    let a = a.as_ref().chars().flat_map(fold_char);
+    let b = b.as_ref().chars().flat_map(fold_char);
+    a.cmp(b)
+}
+
+fn fold_char(c: char) -> impl Iterator<Item = char> {
+    c.to_uppercase().flat_map(char::to_lowercase)
+}
+
+/// Number of triples of items on which the comparator is checked by
+/// [`check_total_order`].
+const CHECKED_TRIPLES: usize = 64;
+
+/// Spot-checks that a comparator is a total order on items that were just
+/// sorted, and that they are sorted according to it.
+///
+/// All adjacent items are checked to be in order, which catches buffer sorts
+/// that don't agree with the comparator used to merge segments. Triples of
+/// items are then sampled to check reflexivity, antisymmetry and transitivity.
+/// Items are sampled using a fixed seed so that checks are reproducible.
+pub(crate) fn check_total_order<T, F>(items: &[T], cmp: &F) -> Result<(), Error>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let invalid = |message: String| Err(Error::new(ErrorKind::InvalidInput, message));
+
+    if let Some(i) = (1..items.len()).find(|&i| cmp(&items[i - 1], &items[i]) == Ordering::Greater)
+    {
+        return invalid(format!(
+            "sorted items at {} and {} are out of order according to the comparator",
+            i - 1,
+            i
+        ));
+    }
+
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let mut seed = 0x9E37_79B9_7F4A_7C15u64;
+    let mut sample = || {
+        // xorshift, which is good enough to pick indices
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % items.len() as u64) as usize
+    };
+
+    for _ in 0..CHECKED_TRIPLES {
+        let mut indices = [sample(), sample(), sample()];
+        indices.sort_unstable();
+        let [a, b, c] = indices.map(|i| &items[i]);
+
+        if cmp(a, a) != Ordering::Equal {
+            return invalid(format!(
+                "comparator isn't reflexive: item at {} isn't equal to itself",
+                indices[0]
+            ));
+        }
+
+        for (i, j) in [(0, 1), (1, 2), (0, 2)] {
+            let (x, y) = (&items[indices[i]], &items[indices[j]]);
+            if cmp(x, y) != cmp(y, x).reverse() {
+                return invalid(format!(
+                    "comparator isn't antisymmetric for items at {} and {}",
+                    indices[i], indices[j]
+                ));
+            }
+        }
+
+        let ab = cmp(a, b);
+        let bc = cmp(b, c);
+        let ac = cmp(a, c);
+        // adjacent items being in order, sorted items need to be in order
+        // with all the following ones for the comparator to be transitive
+        let transitive = match (ab, bc) {
+            (Ordering::Greater, _) | (_, Ordering::Greater) => false,
+            (Ordering::Equal, Ordering::Equal) => ac == Ordering::Equal,
+            _ => ac == Ordering::Less,
+        };
+        if !transitive {
+            return invalid(format!(
+                "comparator isn't transitive for items at {}, {} and {}",
+                indices[0], indices[1], indices[2]
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+type KeyComparator<T> = Arc<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
+
+/// Comparator on multiple keys extracted from the items, each sorted either
+/// in ascending or descending order.
+///
+/// Keys are compared in the order they were added, the next key only being
+/// used to break ties on the previous ones.
+///
+/// # Examples
+/// ```rust
+/// use extsort::{compare::KeySpec, ExternalSorter};
+///
+/// let scores = [("ca", 10u32, 2u32), ("us", 20, 1), ("ca", 30, 3), ("ca", 30, 1)];
+/// let cmp = KeySpec::new()
+///     .asc(|r: &(&str, u32, u32)| r.0)
+///     .desc(|r| r.1)
+///     .asc(|r| r.2)
+///     .build();
+///
+/// let mut sorted = scores.to_vec();
+/// sorted.sort_by(&cmp);
+/// assert_eq!(sorted, [("ca", 30, 1), ("ca", 30, 3), ("ca", 10, 2), ("us", 20, 1)]);
+/// ```
+///
+/// The comparator returned by [`KeySpec::build`] can be given to any of the
+/// sorter methods accepting a comparator, such as
+/// [`ExternalSorter::sort_by`].
+///
+/// [`ExternalSorter::sort_by`]: crate::ExternalSorter::sort_by
+pub struct KeySpec<T> {
+    keys: Vec<KeyComparator<T>>,
+}
+
+impl<T> KeySpec<T> {
+    /// Creates a specification without any key, for which all items are
+    /// equal.
+    pub fn new() -> KeySpec<T> {
+        KeySpec { keys: Vec::new() }
+    }
+
+    /// Adds a key sorted in ascending order.
+    pub fn asc<K, E>(self, key: E) -> KeySpec<T>
+    where
+        K: Ord,
+        E: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        self.then(move |a, b| key(a).cmp(&key(b)))
+    }
+
+    /// Adds a key sorted in descending order.
+    pub fn desc<K, E>(self, key: E) -> KeySpec<T>
+    where
+        K: Ord,
+        E: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        self.then(move |a, b| key(b).cmp(&key(a)))
+    }
+
+    /// Adds a comparator used to break ties on the previous keys, for keys
+    /// that can't be compared by their [`Ord`] implementation.
+    pub fn then<C>(mut self, cmp: C) -> KeySpec<T>
+    where
+        C: Fn(&T, &T) -> Ordering + Send + Sync + 'static,
+    {
+        self.keys.push(Arc::new(cmp));
+        self
+    }
+
+    /// Compares two items on each key, in order, until one isn't equal.
+    pub fn compare(&self, a: &T, b: &T) -> Ordering {
+        for key in &self.keys {
+            match key(a, b) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// Returns the comparator of the specification, to be given to the
+    /// sorter.
+    pub fn build(self) -> impl Fn(&T, &T) -> Ordering + Send + Sync + Clone {
+        let spec = Arc::new(self);
+        move |a: &T, b: &T| spec.compare(a, b)
+    }
+}
+
+impl<T> Default for KeySpec<T> {
+    fn default() -> Self {
+        KeySpec::new()
+    }
+}
+
+impl<T> Clone for KeySpec<T> {
+    fn clone(&self) -> Self {
+        KeySpec {
+            keys: self.keys.clone(),
+        }
+    }
+}
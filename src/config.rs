@@ -0,0 +1,269 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, path::PathBuf};
+
+use crate::ExternalSorterOptions;
+
+/// Invalid configuration of an [`ExternalSorter`](crate::ExternalSorter),
+/// detected before sorting (see
+/// [`ExternalSorter::validate`](crate::ExternalSorter::validate)).
+///
+/// Sorting methods return it wrapped in an `std::io::Error` of kind
+/// `InvalidInput`, from which it can be retrieved by downcasting:
+///
+/// ```rust
+/// use extsort::{ConfigError, ExternalSorter};
+///
+/// let err = ExternalSorter::new()
+///     .with_segment_size(0)
+///     .sort(vec![1u32.to_be_bytes().to_vec()])
+///     .err()
+///     .unwrap();
+/// let config_err = err.get_ref().and_then(|err| err.downcast_ref::<ConfigError>());
+/// assert!(matches!(config_err, Some(ConfigError::ZeroSegmentSize)));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The segment size is 0, while at least one item needs to be buffered.
+    ZeroSegmentSize,
+
+    /// The sort directory doesn't exist.
+    SortDirNotFound(PathBuf),
+
+    /// The sort directory isn't a directory.
+    SortDirNotADirectory(PathBuf),
+
+    /// The sort directory is read-only, or its permissions can't be read.
+    SortDirNotWritable(PathBuf),
+
+    /// The compaction fan-in is lower than 2, which wouldn't merge segments.
+    InvalidCompactionFanIn(usize),
+
+    /// The memory fraction isn't within 0 and 1.
+    InvalidMemoryFraction(f64),
+
+    /// The minimum length of natural runs is greater than the segment size,
+    /// so that no run could ever be detected within the buffer.
+    NaturalRunsLongerThanSegment { min_len: usize, segment_size: usize },
+
+    /// Deterministic mode is enabled along with a memory limit or a memory
+    /// pool, which would make segment boundaries depend on the memory
+    /// available on the system or left in the pool.
+    NondeterministicMemoryLimit,
+
+    /// Segments are written in blocks along with a reverse index, which
+    /// requires the offset of each item.
+    ReverseIndexWithBlocks,
+
+    /// Scratch files are created by a custom builder along with a reverse
+    /// index, which requires reopening segments by their path.
+    ReverseIndexWithTempfileBuilder,
+
+    /// Segments may be kept in memory along with a reverse index, which
+    /// requires reopening segments by their path.
+    ReverseIndexWithInMemorySegments,
+
+    /// Segments are written through a segment transform along with a reverse
+    /// index, which requires reading them from arbitrary offsets.
+    ReverseIndexWithSegmentTransform,
+
+    /// Prefix compression is enabled while segments aren't written in blocks,
+    /// at the start of which the compression restarts.
+    PrefixCompressionWithoutBlocks,
+
+    /// Delta encoding is enabled while segments aren't written in blocks, at
+    /// the start of which the encoding restarts.
+    DeltaEncodingWithoutBlocks,
+
+    /// The keys of the delta encoding are wider than 8 bytes.
+    InvalidDeltaKeyBytes(usize),
+
+    /// Both prefix compression and delta encoding are enabled, while items
+    /// can only be written with one of them.
+    PrefixCompressionWithDeltaEncoding,
+
+    /// An environment variable read by
+    /// [`ExternalSorter::from_env`](crate::ExternalSorter::from_env) has an
+    /// invalid value.
+    InvalidEnvVar { name: String, value: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroSegmentSize => write!(f, "segment size must be non-zero"),
+            ConfigError::SortDirNotFound(path) => {
+                write!(f, "sort directory {} doesn't exist", path.display())
+            }
+            ConfigError::SortDirNotADirectory(path) => {
+                write!(f, "sort directory {} isn't a directory", path.display())
+            }
+            ConfigError::SortDirNotWritable(path) => {
+                write!(f, "sort directory {} isn't writable", path.display())
+            }
+            ConfigError::InvalidCompactionFanIn(fan_in) => {
+                write!(f, "compaction fan-in must be at least 2, got {}", fan_in)
+            }
+            ConfigError::InvalidMemoryFraction(fraction) => {
+                write!(
+                    f,
+                    "memory fraction must be within 0 and 1, got {}",
+                    fraction
+                )
+            }
+            ConfigError::NaturalRunsLongerThanSegment {
+                min_len,
+                segment_size,
+            } => write!(
+                f,
+                "natural runs minimum length {} is greater than the segment size {}",
+                min_len, segment_size
+            ),
+            ConfigError::NondeterministicMemoryLimit => {
+                write!(f, "memory limit can't be used in deterministic mode")
+            }
+            ConfigError::ReverseIndexWithBlocks => {
+                write!(
+                    f,
+                    "reverse index can't be used with segments written in blocks"
+                )
+            }
+            ConfigError::ReverseIndexWithTempfileBuilder => {
+                write!(
+                    f,
+                    "reverse index can't be used with a custom tempfile builder"
+                )
+            }
+            ConfigError::ReverseIndexWithInMemorySegments => {
+                write!(f, "reverse index can't be used with in-memory segments")
+            }
+            ConfigError::ReverseIndexWithSegmentTransform => {
+                write!(f, "reverse index can't be used with a segment transform")
+            }
+            ConfigError::PrefixCompressionWithoutBlocks => {
+                write!(f, "prefix compression requires segments written in blocks")
+            }
+            ConfigError::DeltaEncodingWithoutBlocks => {
+                write!(f, "delta encoding requires segments written in blocks")
+            }
+            ConfigError::InvalidDeltaKeyBytes(key_bytes) => {
+                write!(
+                    f,
+                    "delta encoded keys must be at most 8 bytes, got {}",
+                    key_bytes
+                )
+            }
+            ConfigError::PrefixCompressionWithDeltaEncoding => {
+                write!(f, "prefix compression can't be used with delta encoding")
+            }
+            ConfigError::InvalidEnvVar { name, value } => {
+                write!(
+                    f,
+                    "environment variable {} has invalid value {:?}",
+                    name, value
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(err: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
+
+impl ExternalSorterOptions {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.segment_size == 0 {
+            return Err(ConfigError::ZeroSegmentSize);
+        }
+
+        if let Some(sort_dir) = &self.sort_dir {
+            let metadata = match std::fs::metadata(sort_dir) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(ConfigError::SortDirNotFound(sort_dir.clone()))
+                }
+                Err(_) => return Err(ConfigError::SortDirNotWritable(sort_dir.clone())),
+            };
+            if !metadata.is_dir() {
+                return Err(ConfigError::SortDirNotADirectory(sort_dir.clone()));
+            }
+            if metadata.permissions().readonly() {
+                return Err(ConfigError::SortDirNotWritable(sort_dir.clone()));
+            }
+        }
+
+        if let Some(fan_in) = self.compaction_fan_in {
+            if fan_in < 2 {
+                return Err(ConfigError::InvalidCompactionFanIn(fan_in));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.memory_fraction) {
+            return Err(ConfigError::InvalidMemoryFraction(self.memory_fraction));
+        }
+
+        if self.deterministic && (self.memory_fraction > 0.0 || self.memory_pool.is_some()) {
+            return Err(ConfigError::NondeterministicMemoryLimit);
+        }
+
+        if self.natural_run_min_len > self.segment_size {
+            return Err(ConfigError::NaturalRunsLongerThanSegment {
+                min_len: self.natural_run_min_len,
+                segment_size: self.segment_size,
+            });
+        }
+
+        if self.reverse_index && self.block_size > 0 {
+            return Err(ConfigError::ReverseIndexWithBlocks);
+        }
+
+        if self.reverse_index && self.tempfile_builder.is_some() {
+            return Err(ConfigError::ReverseIndexWithTempfileBuilder);
+        }
+
+        let in_memory = self.in_memory_segments > 0 || self.in_memory_segment_bytes > 0;
+        if self.reverse_index && in_memory {
+            return Err(ConfigError::ReverseIndexWithInMemorySegments);
+        }
+
+        if self.reverse_index && self.segment_transform.is_some() {
+            return Err(ConfigError::ReverseIndexWithSegmentTransform);
+        }
+
+        if self.prefix_compression && self.block_size == 0 {
+            return Err(ConfigError::PrefixCompressionWithoutBlocks);
+        }
+
+        if self.delta_key_bytes > 8 {
+            return Err(ConfigError::InvalidDeltaKeyBytes(self.delta_key_bytes));
+        }
+
+        if self.delta_key_bytes > 0 && self.block_size == 0 {
+            return Err(ConfigError::DeltaEncodingWithoutBlocks);
+        }
+
+        if self.prefix_compression && self.delta_key_bytes > 0 {
+            return Err(ConfigError::PrefixCompressionWithDeltaEncoding);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorting of items whose encoding depends on a shared context.
+//!
+//! Items implementing [`SortableWithContext`] receive a context when they are
+//! encoded and decoded, such as a dictionary to encode strings by their index
+//! instead of embedding them in every item. The context is given to the
+//! sorter using [`ExternalSorter::with_codec_context`].
+//!
+//! # Examples
+//! ```rust
+//! use extsort::{context::SortableWithContext, ExternalSorter};
+//! use std::io::{Read, Write};
+//!
+//! struct Dictionary(Vec<&'static str>);
+//!
+//! #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+//! struct Country(&'static str);
+//!
+//! impl SortableWithContext<Dictionary> for Country {
+//!     fn encode<W: Write>(&self, writer: &mut W, dict: &Dictionary) -> std::io::Result<()> {
+//!         let index = dict.0.iter().position(|name| *name == self.0).unwrap();
+//!         writer.write_all(&[index as u8])
+//!     }
+//!
+//!     fn decode<R: Read>(reader: &mut R, dict: &Dictionary) -> std::io::Result<Country> {
+//!         let mut index = [0];
+//!         reader.read_exact(&mut index)?;
+//!         Ok(Country(dict.0[index[0] as usize]))
+//!     }
+//! }
+//!
+//! let dict = Dictionary(vec!["france", "canada", "japan"]);
+//! let countries = ["japan", "canada", "france", "canada"].map(Country);
+//! let sorted = ExternalSorter::new()
+//!     .with_segment_size(2)
+//!     .with_codec_context(dict)
+//!     .sort(countries)
+//!     .unwrap()
+//!     .collect::<std::io::Result<Vec<_>>>()
+//!     .unwrap();
+//! assert_eq!(sorted, ["canada", "canada", "france", "japan"].map(Country));
+//! ```
+//!
+//! [`ExternalSorter::with_codec_context`]: crate::ExternalSorter::with_codec_context
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    cmp::Ordering,
+    io::{Error, Read, Write},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+use crate::{ExternalSorter, Sortable};
+
+/// Item that can be encoded and decoded given a shared context of type `C`.
+///
+/// See [`Sortable`] for the requirements of the encoding.
+pub trait SortableWithContext<C>: Sized + Send {
+    /// Encodes the item to the given writer.
+    fn encode<W: Write>(&self, writer: &mut W, context: &C) -> std::io::Result<()>;
+
+    /// Decodes the item from the given reader.
+    ///
+    /// Important: as with [`Sortable::decode`], the implementation relies on
+    /// the `UnexpectedEof` error from `std::io::Read` to detect the end of the
+    /// stream.
+    fn decode<R: Read>(reader: &mut R, context: &C) -> std::io::Result<Self>;
+}
+
+type AnyContext = Arc<dyn Any + Send + Sync>;
+
+thread_local! {
+    // contexts of the sorts being run on the current thread, the innermost
+    // last, since `Sortable` doesn't allow passing them down the sorter
+    static CONTEXTS: RefCell<Vec<AnyContext>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs the given function with the context available to the items being
+/// encoded or decoded on the current thread.
+fn with_context<C, R>(context: &Arc<C>, f: impl FnOnce() -> R) -> R
+where
+    C: Send + Sync + 'static,
+{
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            CONTEXTS.with(|contexts| contexts.borrow_mut().pop());
+        }
+    }
+
+    CONTEXTS.with(|contexts| contexts.borrow_mut().push(context.clone()));
+    let _guard = Guard;
+    f()
+}
+
+/// Calls the given function with the innermost context of the current thread.
+fn current_context<C, R>(f: impl FnOnce(&C) -> std::io::Result<R>) -> std::io::Result<R>
+where
+    C: 'static,
+{
+    CONTEXTS.with(|contexts| {
+        let contexts = contexts.borrow();
+        let context = contexts
+            .last()
+            .and_then(|context| context.downcast_ref::<C>())
+            .ok_or_else(|| Error::other("codec context is not available"))?;
+        f(context)
+    })
+}
+
+/// Item sorted in place of a [`SortableWithContext`] item, encoded using the
+/// context of the current thread.
+struct InContext<T, C>(T, PhantomData<fn() -> C>);
+
+impl<T, C> Sortable for InContext<T, C>
+where
+    T: SortableWithContext<C>,
+    C: 'static,
+{
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        current_context(|context: &C| self.0.encode(writer, context))
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        current_context(|context: &C| T::decode(reader, context))
+            .map(|item| InContext(item, PhantomData))
+    }
+}
+
+/// Sorter of items encoded with a shared context, as returned by
+/// [`ExternalSorter::with_codec_context`].
+pub struct ContextSorter<C> {
+    sorter: ExternalSorter,
+    context: Arc<C>,
+}
+
+impl<C> ContextSorter<C>
+where
+    C: Send + Sync + 'static,
+{
+    pub(crate) fn new(sorter: ExternalSorter, context: C) -> ContextSorter<C> {
+        ContextSorter {
+            sorter,
+            context: Arc::new(context),
+        }
+    }
+
+    /// Returns the context used to encode and decode the items.
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    /// Sorts a given iterator, returning a new iterator with the sorted items.
+    pub fn sort<T, I>(self, iterator: I) -> Result<ContextSortedIterator<T, C>, Error>
+    where
+        T: SortableWithContext<C> + Ord + 'static,
+        I: IntoIterator<Item = T>,
+    {
+        self.sort_by(iterator, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator with a comparator function, returning a new
+    /// iterator with the sorted items.
+    pub fn sort_by<T, I, F>(self, iterator: I, cmp: F) -> Result<ContextSortedIterator<T, C>, Error>
+    where
+        T: SortableWithContext<C> + 'static,
+        I: IntoIterator<Item = T>,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone + 'static,
+    {
+        let context = self.context;
+        let sorter = self.sorter;
+        let items = iterator
+            .into_iter()
+            .map(|item| InContext(item, PhantomData));
+        let sorted = with_context(&context, || {
+            sorter.sort_by(items, move |a: &InContext<T, C>, b: &InContext<T, C>| {
+                cmp(&a.0, &b.0)
+            })
+        })?;
+
+        Ok(ContextSortedIterator {
+            inner: Box::new(sorted.map(|item| item.map(|item| item.0))),
+            context,
+        })
+    }
+}
+
+/// Iterator over the items sorted by a [`ContextSorter`], decoding them with
+/// its context.
+pub struct ContextSortedIterator<T, C> {
+    inner: Box<dyn Iterator<Item = std::io::Result<T>> + Send>,
+    context: Arc<C>,
+}
+
+impl<T, C> ContextSortedIterator<T, C> {
+    /// Returns the context used to decode the items.
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+}
+
+impl<T, C> Iterator for ContextSortedIterator<T, C>
+where
+    C: Send + Sync + 'static,
+{
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        with_context(&self.context, || self.inner.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorting of CSV rows by one or more columns.
+//!
+//! Rows are framed following RFC 4180: fields may be quoted, in which case
+//! they can contain delimiters, quotes escaped by doubling them and line
+//! breaks. Rows are written back exactly as they were read, including their
+//! line terminator, and only the fields of the sorted columns are parsed.
+//!
+//! # Examples
+//! ```rust
+//! use extsort::formats::csv::{ColumnType, CsvSorter};
+//! use extsort::ExternalSorter;
+//!
+//! let input = "name,age\nbob,32\nalice,27\n";
+//! let mut output = Vec::new();
+//! CsvSorter::new(ExternalSorter::new())
+//!     .with_column(1, ColumnType::Numeric)
+//!     .sort(input.as_bytes(), &mut output)
+//!     .unwrap();
+//! assert_eq!(output, b"name,age\nalice,27\nbob,32\n");
+//! ```
+
+use std::{
+    cmp::Ordering,
+    io::{BufRead, Error, ErrorKind, Read, Write},
+};
+
+use crate::{framing, ExternalSorter, Sortable};
+
+/// Type of the values of a sorted column, which determines their ordering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    /// Values are compared as floating point numbers.
+    Numeric,
+
+    /// Values are compared as bytes, in lexicographic order.
+    String,
+
+    /// Values are compared as ISO 8601 dates or date times (e.g. `2024-01-31`
+    /// or `2024-01-31T12:30:00`), whose lexicographic order is chronological.
+    Date,
+}
+
+/// Sorts CSV rows by one or more columns using an external sorter.
+///
+/// Values that can't be parsed according to the type of their column, as well
+/// as missing values, are sorted before all other values.
+pub struct CsvSorter {
+    sorter: ExternalSorter,
+    columns: Vec<Column>,
+    delimiter: u8,
+    has_headers: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Column {
+    index: usize,
+    column_type: ColumnType,
+    descending: bool,
+}
+
+impl CsvSorter {
+    /// Creates a CSV sorter using the given external sorter, expecting comma
+    /// delimited rows with a header row.
+    pub fn new(sorter: ExternalSorter) -> CsvSorter {
+        CsvSorter {
+            sorter,
+            columns: Vec::new(),
+            delimiter: b',',
+            has_headers: true,
+        }
+    }
+
+    /// Sets the field delimiter.
+    ///
+    /// Default is `,`
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row contains headers, in which case it is
+    /// written first without being sorted.
+    ///
+    /// Default is true
+    pub fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Sorts the rows by the column at the given index in ascending order,
+    /// after the previously added columns.
+    pub fn with_column(mut self, index: usize, column_type: ColumnType) -> Self {
+        self.columns.push(Column {
+            index,
+            column_type,
+            descending: false,
+        });
+        self
+    }
+
+    /// Sorts the rows by the column at the given index in descending order,
+    /// after the previously added columns.
+    pub fn with_column_desc(mut self, index: usize, column_type: ColumnType) -> Self {
+        self.columns.push(Column {
+            index,
+            column_type,
+            descending: true,
+        });
+        self
+    }
+
+    /// Reads the rows from the reader, and writes them sorted to the writer,
+    /// returning the number of sorted rows (excluding headers).
+    pub fn sort<R: Read, W: Write>(self, reader: R, writer: &mut W) -> Result<u64, Error> {
+        let mut reader = std::io::BufReader::new(reader);
+
+        if self.has_headers {
+            if let Some(headers) = read_row(&mut reader)? {
+                writer.write_all(&headers)?;
+                if !headers.ends_with(b"\n") {
+                    writer.write_all(b"\n")?;
+                }
+            }
+        }
+
+        let columns = self.columns;
+        let delimiter = self.delimiter;
+        let rows = std::iter::from_fn(|| read_row(&mut reader).transpose()).map(|raw| {
+            raw.map(|raw| CsvRow {
+                keys: parse_keys(&raw, delimiter, &columns),
+                raw,
+            })
+        });
+
+        // reading errors are kept aside since the sorter consumes items
+        let mut read_error = None;
+        let rows = rows.map_while(|row| match row {
+            Ok(row) => Some(row),
+            Err(err) => {
+                read_error = Some(err);
+                None
+            }
+        });
+
+        let descending = columns
+            .iter()
+            .map(|column| column.descending)
+            .collect::<Vec<_>>();
+        let sorted = self.sorter.sort_by(rows, move |a: &CsvRow, b: &CsvRow| {
+            compare_keys(&a.keys, &b.keys, &descending)
+        })?;
+        if let Some(err) = read_error {
+            return Err(err);
+        }
+
+        // the last row may not be terminated, but could be sorted anywhere
+        let mut count = 0;
+        for row in sorted {
+            let row = row?;
+            writer.write_all(&row.raw)?;
+            if !row.raw.ends_with(b"\n") {
+                writer.write_all(b"\n")?;
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Sorted CSV row along with the parsed values of its sorted columns.
+struct CsvRow {
+    keys: Vec<Key>,
+    raw: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Missing,
+    Number(f64),
+    Text(Vec<u8>),
+}
+
+impl Sortable for CsvRow {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        framing::write_varint(writer, self.keys.len() as u64)?;
+        for key in &self.keys {
+            match key {
+                Key::Missing => writer.write_all(&[0])?,
+                Key::Number(number) => {
+                    writer.write_all(&[1])?;
+                    writer.write_all(&number.to_le_bytes())?;
+                }
+                Key::Text(text) => {
+                    writer.write_all(&[2])?;
+                    text.encode(writer)?;
+                }
+            }
+        }
+        self.raw.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let count = framing::read_varint(reader)?;
+
+        let mut byte = [0; 1];
+        let mut keys = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            reader.read_exact(&mut byte)?;
+            keys.push(match byte[0] {
+                0 => Key::Missing,
+                1 => {
+                    let mut number = [0; 8];
+                    reader.read_exact(&mut number)?;
+                    Key::Number(f64::from_le_bytes(number))
+                }
+                2 => Key::Text(Vec::<u8>::decode(reader)?),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid csv key")),
+            });
+        }
+
+        Ok(CsvRow {
+            keys,
+            raw: Vec::<u8>::decode(reader)?,
+        })
+    }
+}
+
+fn compare_keys(a: &[Key], b: &[Key], descending: &[bool]) -> Ordering {
+    for ((a, b), descending) in a.iter().zip(b).zip(descending) {
+        let ordering = match (a, b) {
+            (Key::Missing, Key::Missing) => Ordering::Equal,
+            (Key::Missing, _) => Ordering::Less,
+            (_, Key::Missing) => Ordering::Greater,
+            (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+            (Key::Text(a), Key::Text(b)) => a.cmp(b),
+            (Key::Number(_), Key::Text(_)) => Ordering::Less,
+            (Key::Text(_), Key::Number(_)) => Ordering::Greater,
+        };
+
+        let ordering = if *descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Reads the next row along with its line terminator, handling line breaks
+/// within quoted fields.
+fn read_row<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut row = Vec::new();
+    let mut in_quotes = false;
+    loop {
+        let start = row.len();
+        if reader.read_until(b'\n', &mut row)? == 0 {
+            break;
+        }
+
+        for byte in &row[start..] {
+            if *byte == b'"' {
+                in_quotes = !in_quotes;
+            }
+        }
+
+        if !in_quotes && row.last() == Some(&b'\n') {
+            break;
+        }
+    }
+
+    Ok((!row.is_empty()).then_some(row))
+}
+
+/// Returns the row without its line terminator.
+fn trim_terminator(row: &[u8]) -> &[u8] {
+    let row = row.strip_suffix(b"\n").unwrap_or(row);
+    row.strip_suffix(b"\r").unwrap_or(row)
+}
+
+/// Splits a row into its unquoted fields.
+fn split_fields(raw: &[u8], delimiter: u8) -> Vec<Vec<u8>> {
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut in_quotes = false;
+    let mut bytes = raw.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'"' if in_quotes && bytes.peek() == Some(&&b'"') => {
+                field.push(b'"');
+                bytes.next();
+            }
+            b'"' => in_quotes = !in_quotes,
+            _ if byte == delimiter && !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(byte),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_keys(raw: &[u8], delimiter: u8, columns: &[Column]) -> Vec<Key> {
+    let mut fields = split_fields(trim_terminator(raw), delimiter);
+    columns
+        .iter()
+        .map(|column| {
+            let Some(field) = fields.get_mut(column.index) else {
+                return Key::Missing;
+            };
+
+            match column.column_type {
+                ColumnType::Numeric => std::str::from_utf8(field)
+                    .ok()
+                    .and_then(|field| field.trim().parse::<f64>().ok())
+                    .map_or(Key::Missing, Key::Number),
+                ColumnType::String => Key::Text(std::mem::take(field)),
+                ColumnType::Date if is_iso_date(field) => Key::Text(std::mem::take(field)),
+                ColumnType::Date => Key::Missing,
+            }
+        })
+        .collect()
+}
+
+/// Checks that the value starts with a `YYYY-MM-DD` date.
+fn is_iso_date(value: &[u8]) -> bool {
+    value.len() >= 10
+        && value[..10].iter().enumerate().all(|(i, byte)| match i {
+            4 | 7 => *byte == b'-',
+            _ => byte.is_ascii_digit(),
+        })
+}
@@ -0,0 +1,269 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorting of newline-delimited JSON by one or more keys.
+//!
+//! Each line is parsed once when it is read to extract its keys, designated by
+//! [JSON pointers](https://www.rfc-editor.org/rfc/rfc6901) such as `/user/id`.
+//! The raw lines are then sorted along with their keys only, so that no JSON
+//! parsing happens while comparing or spilling lines, and are written back
+//! unmodified.
+//!
+//! Keys of different JSON types are ordered as follows: missing, `null`,
+//! booleans, numbers, strings, and then arrays and objects, which are compared
+//! by their serialized form.
+//!
+//! # Examples
+//! ```rust
+//! use extsort::formats::jsonl::JsonlSorter;
+//! use extsort::ExternalSorter;
+//!
+//! let input = "{\"name\":\"bob\",\"age\":32}\n{\"name\":\"alice\",\"age\":27}\n";
+//! let mut output = Vec::new();
+//! JsonlSorter::new(ExternalSorter::new())
+//!     .with_key("/age")
+//!     .sort(input.as_bytes(), &mut output)
+//!     .unwrap();
+//! assert_eq!(output, b"{\"name\":\"alice\",\"age\":27}\n{\"name\":\"bob\",\"age\":32}\n");
+//! ```
+
+use std::{
+    cmp::Ordering,
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+};
+
+use serde_json::Value;
+
+use crate::{framing, ExternalSorter, Sortable};
+
+/// Sorts newline-delimited JSON by one or more keys using an external sorter.
+///
+/// Blank lines are skipped, and a line that isn't valid JSON fails the sort.
+pub struct JsonlSorter {
+    sorter: ExternalSorter,
+    keys: Vec<KeyExpr>,
+}
+
+struct KeyExpr {
+    pointer: String,
+    descending: bool,
+}
+
+impl JsonlSorter {
+    /// Creates a JSONL sorter using the given external sorter.
+    pub fn new(sorter: ExternalSorter) -> JsonlSorter {
+        JsonlSorter {
+            sorter,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Sorts the lines in ascending order of the value designated by the JSON
+    /// pointer, after the previously added keys.
+    ///
+    /// Panics if the pointer is neither empty nor starting with a `/`.
+    pub fn with_key<P: Into<String>>(self, pointer: P) -> Self {
+        self.push_key(pointer.into(), false)
+    }
+
+    /// Sorts the lines in descending order of the value designated by the JSON
+    /// pointer, after the previously added keys.
+    ///
+    /// Panics if the pointer is neither empty nor starting with a `/`.
+    pub fn with_key_desc<P: Into<String>>(self, pointer: P) -> Self {
+        self.push_key(pointer.into(), true)
+    }
+
+    fn push_key(mut self, pointer: String, descending: bool) -> Self {
+        assert!(
+            pointer.is_empty() || pointer.starts_with('/'),
+            "invalid json pointer: {}",
+            pointer
+        );
+        self.keys.push(KeyExpr {
+            pointer,
+            descending,
+        });
+        self
+    }
+
+    /// Reads the lines from the reader, and writes them sorted to the writer,
+    /// returning the number of sorted lines.
+    pub fn sort<R: Read, W: Write>(self, reader: R, writer: &mut W) -> Result<u64, Error> {
+        let keys = self.keys;
+        let mut lines = BufReader::new(reader).split(b'\n').enumerate();
+
+        // reading errors are kept aside since the sorter consumes items
+        let mut read_error = None;
+        let rows = std::iter::from_fn(|| loop {
+            let (number, line) = lines.next()?;
+            let mut raw = match line {
+                Ok(raw) => raw,
+                Err(err) => {
+                    read_error = Some(err);
+                    return None;
+                }
+            };
+            if raw.last() == Some(&b'\r') {
+                raw.pop();
+            }
+            if raw.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            return match parse_keys(&raw, &keys) {
+                Ok(keys) => Some(JsonlRow { keys, raw }),
+                Err(err) => {
+                    read_error = Some(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid json on line {}: {}", number + 1, err),
+                    ));
+                    None
+                }
+            };
+        });
+
+        let descending = keys.iter().map(|key| key.descending).collect::<Vec<_>>();
+        let sorted = self
+            .sorter
+            .sort_by(rows, move |a: &JsonlRow, b: &JsonlRow| {
+                compare_keys(&a.keys, &b.keys, &descending)
+            })?;
+        if let Some(err) = read_error {
+            return Err(err);
+        }
+
+        let mut count = 0;
+        for row in sorted {
+            let row = row?;
+            writer.write_all(&row.raw)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Sorted line along with the extracted values of its keys.
+struct JsonlRow {
+    keys: Vec<Key>,
+    raw: Vec<u8>,
+}
+
+/// Extracted key value, whose variants are declared in their sorting order.
+#[derive(Debug, PartialEq, PartialOrd)]
+enum Key {
+    Missing,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(Vec<u8>),
+    Other(Vec<u8>),
+}
+
+impl Key {
+    fn tag(&self) -> u8 {
+        match self {
+            Key::Missing => 0,
+            Key::Null => 1,
+            Key::Bool(_) => 2,
+            Key::Number(_) => 3,
+            Key::String(_) => 4,
+            Key::Other(_) => 5,
+        }
+    }
+}
+
+impl Sortable for JsonlRow {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        framing::write_varint(writer, self.keys.len() as u64)?;
+        for key in &self.keys {
+            writer.write_all(&[key.tag()])?;
+            match key {
+                Key::Missing | Key::Null => {}
+                Key::Bool(value) => writer.write_all(&[*value as u8])?,
+                Key::Number(value) => writer.write_all(&value.to_le_bytes())?,
+                Key::String(value) | Key::Other(value) => value.encode(writer)?,
+            }
+        }
+        self.raw.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let count = framing::read_varint(reader)?;
+
+        let mut byte = [0; 1];
+        let mut keys = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            reader.read_exact(&mut byte)?;
+            keys.push(match byte[0] {
+                0 => Key::Missing,
+                1 => Key::Null,
+                2 => {
+                    reader.read_exact(&mut byte)?;
+                    Key::Bool(byte[0] != 0)
+                }
+                3 => {
+                    let mut value = [0; 8];
+                    reader.read_exact(&mut value)?;
+                    Key::Number(f64::from_le_bytes(value))
+                }
+                4 => Key::String(Vec::<u8>::decode(reader)?),
+                5 => Key::Other(Vec::<u8>::decode(reader)?),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid jsonl key")),
+            });
+        }
+
+        Ok(JsonlRow {
+            keys,
+            raw: Vec::<u8>::decode(reader)?,
+        })
+    }
+}
+
+fn parse_keys(raw: &[u8], keys: &[KeyExpr]) -> Result<Vec<Key>, serde_json::Error> {
+    let value = serde_json::from_slice::<Value>(raw)?;
+    Ok(keys
+        .iter()
+        .map(|key| match value.pointer(&key.pointer) {
+            None => Key::Missing,
+            Some(Value::Null) => Key::Null,
+            Some(Value::Bool(value)) => Key::Bool(*value),
+            Some(Value::Number(value)) => value.as_f64().map_or(Key::Missing, Key::Number),
+            Some(Value::String(value)) => Key::String(value.as_bytes().to_vec()),
+            Some(value) => Key::Other(value.to_string().into_bytes()),
+        })
+        .collect())
+}
+
+fn compare_keys(a: &[Key], b: &[Key], descending: &[bool]) -> Ordering {
+    for ((a, b), descending) in a.iter().zip(b).zip(descending) {
+        let ordering = match (a, b) {
+            (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+            _ => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        };
+
+        let ordering = if *descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
@@ -0,0 +1,200 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorting of text formats whose records span multiple lines.
+//!
+//! Records either span a fixed number of lines (e.g. the 4 lines of a FASTQ
+//! read), or end with a separator line (e.g. the `//` line of GenBank
+//! entries). Records are written back exactly as they were read, and are
+//! sorted by a key extracted from their lines.
+//!
+//! # Examples
+//! ```rust
+//! use extsort::formats::records::{RecordFraming, RecordSorter};
+//! use extsort::ExternalSorter;
+//!
+//! let input = "@read2\nACGT\n+\nIIII\n@read1\nTTGA\n+\nIIII\n";
+//! let mut output = Vec::new();
+//! RecordSorter::new(ExternalSorter::new(), RecordFraming::Lines(4))
+//!     .sort_by_key(input.as_bytes(), &mut output, |lines| lines[0].to_vec())
+//!     .unwrap();
+//! assert_eq!(output, b"@read1\nTTGA\n+\nIIII\n@read2\nACGT\n+\nIIII\n");
+//! ```
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+
+use crate::{ExternalSorter, Sortable};
+
+/// How the lines of the input are grouped into records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordFraming {
+    /// Each record spans the given number of lines. The input fails to be
+    /// sorted if its last record is incomplete.
+    Lines(usize),
+
+    /// Each record ends with a line equal to the given separator, which is
+    /// kept as the last line of the record. A separator line is added to the
+    /// last record if it is missing, so that records stay delimited once
+    /// sorted.
+    Separator(String),
+}
+
+/// Sorts the multi-line records of a text input using an external sorter.
+pub struct RecordSorter {
+    sorter: ExternalSorter,
+    framing: RecordFraming,
+}
+
+impl RecordSorter {
+    /// Creates a record sorter using the given external sorter and framing.
+    ///
+    /// Panics if records are framed by a number of lines of 0.
+    pub fn new(sorter: ExternalSorter, framing: RecordFraming) -> RecordSorter {
+        assert_ne!(
+            framing,
+            RecordFraming::Lines(0),
+            "records need to span at least one line"
+        );
+        RecordSorter { sorter, framing }
+    }
+
+    /// Reads the records from the reader, and writes them to the writer sorted
+    /// by the key extracted from their lines, returning the number of sorted
+    /// records.
+    ///
+    /// The key function receives the lines of the record without their line
+    /// terminator, including the separator line if records are delimited by
+    /// one.
+    pub fn sort_by_key<R, W, K, F>(self, reader: R, writer: &mut W, key: F) -> Result<u64, Error>
+    where
+        R: Read,
+        W: Write,
+        K: Sortable + Ord,
+        F: Fn(&[&[u8]]) -> K,
+    {
+        let framing = self.framing;
+        let mut reader = BufReader::new(reader);
+
+        // reading errors are kept aside since the sorter consumes items
+        let mut read_error = None;
+        let records = std::iter::from_fn(|| match read_record(&mut reader, &framing) {
+            Ok(Some(raw)) => Some(KeyedRecord {
+                key: key(&split_lines(&raw)),
+                raw,
+            }),
+            Ok(None) => None,
+            Err(err) => {
+                read_error = Some(err);
+                None
+            }
+        });
+
+        let sorted = self
+            .sorter
+            .sort_by(records, |a: &KeyedRecord<K>, b: &KeyedRecord<K>| {
+                a.key.cmp(&b.key)
+            })?;
+        if let Some(err) = read_error {
+            return Err(err);
+        }
+
+        let mut count = 0;
+        for record in sorted {
+            writer.write_all(&record?.raw)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Sorted record along with its extracted key.
+struct KeyedRecord<K> {
+    key: K,
+    raw: Vec<u8>,
+}
+
+impl<K: Sortable> Sortable for KeyedRecord<K> {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.key.encode(writer)?;
+        self.raw.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        Ok(KeyedRecord {
+            key: K::decode(reader)?,
+            raw: Vec::<u8>::decode(reader)?,
+        })
+    }
+}
+
+/// Reads the lines of the next record, making sure that its last line is
+/// terminated.
+fn read_record<R: BufRead>(
+    reader: &mut R,
+    framing: &RecordFraming,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut record = Vec::new();
+    let mut lines = 0;
+    loop {
+        let start = record.len();
+        if reader.read_until(b'\n', &mut record)? == 0 {
+            break;
+        }
+        if record.last() != Some(&b'\n') {
+            record.push(b'\n');
+        }
+        lines += 1;
+
+        let complete = match framing {
+            RecordFraming::Lines(count) => lines == *count,
+            RecordFraming::Separator(separator) => {
+                trim_terminator(&record[start..]) == separator.as_bytes()
+            }
+        };
+        if complete {
+            return Ok(Some(record));
+        }
+    }
+
+    if lines == 0 {
+        return Ok(None);
+    }
+
+    match framing {
+        RecordFraming::Lines(count) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("truncated record of {} lines out of {}", lines, count),
+        )),
+        RecordFraming::Separator(separator) => {
+            record.extend_from_slice(separator.as_bytes());
+            record.push(b'\n');
+            Ok(Some(record))
+        }
+    }
+}
+
+fn split_lines(record: &[u8]) -> Vec<&[u8]> {
+    record
+        .strip_suffix(b"\n")
+        .unwrap_or(record)
+        .split(|byte| *byte == b'\n')
+        .map(trim_terminator)
+        .collect()
+}
+
+fn trim_terminator(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
@@ -0,0 +1,178 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers to write and read length-prefixed byte records when implementing
+//! [`Sortable`].
+//!
+//! Since the end of a segment is detected by an `UnexpectedEof` error, the
+//! readers only return this error if the stream ends before the first byte of
+//! the record. A stream ending in the middle of a record is reported as an
+//! `InvalidData` error, so that a truncated segment isn't mistaken for its
+//! end.
+//!
+//! # Examples
+//! ```rust
+//! use extsort::framing::{read_varint_prefixed, write_varint_prefixed};
+//! use extsort::Sortable;
+//! use std::io::{Read, Write};
+//!
+//! struct Record {
+//!     key: Vec<u8>,
+//!     value: Vec<u8>,
+//! }
+//!
+//! impl Sortable for Record {
+//!     fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+//!         write_varint_prefixed(writer, &self.key)?;
+//!         write_varint_prefixed(writer, &self.value)
+//!     }
+//!
+//!     fn decode<R: Read>(reader: &mut R) -> std::io::Result<Record> {
+//!         let key = read_varint_prefixed(reader)?;
+//!         let value = read_varint_prefixed(reader)?;
+//!         Ok(Record { key, value })
+//!     }
+//! }
+//! ```
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::Sortable;
+
+/// Writes an unsigned integer using the LEB128 variable-length encoding,
+/// taking from 1 byte for values below 128 up to 10 bytes.
+pub fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> std::io::Result<()> {
+    let mut buffer = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer[len] = byte;
+            len += 1;
+            break;
+        }
+        buffer[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buffer[..len])
+}
+
+/// Reads an unsigned integer written by [`write_varint`].
+pub fn read_varint<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let mut byte = [0; 1];
+        if let Err(err) = reader.read_exact(&mut byte) {
+            return Err(if i == 0 { err } else { truncated(err) });
+        }
+
+        let bits = (byte[0] & 0x7f) as u64;
+        if i == 9 && bits > 1 {
+            break;
+        }
+        value |= bits << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::new(ErrorKind::InvalidData, "varint is too large"))
+}
+
+/// Writes a byte record prefixed by its length as a varint.
+pub fn write_varint_prefixed<W: Write>(writer: &mut W, record: &[u8]) -> std::io::Result<()> {
+    write_varint(writer, record.len() as u64)?;
+    writer.write_all(record)
+}
+
+/// Reads a byte record written by [`write_varint_prefixed`].
+pub fn read_varint_prefixed<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let len = read_varint(reader)?;
+    read_record(reader, len)
+}
+
+/// Writes a byte record prefixed by its length as a little-endian `u32`,
+/// failing with an `InvalidInput` error if it is larger than `u32::MAX` bytes.
+pub fn write_u32_prefixed<W: Write>(writer: &mut W, record: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(record.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "record is too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(record)
+}
+
+/// Reads a byte record written by [`write_u32_prefixed`].
+pub fn read_u32_prefixed<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]) {
+            Ok(0) if read == 0 => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "end of stream"))
+            }
+            Ok(0) => return Err(truncated(ErrorKind::UnexpectedEof.into())),
+            Ok(count) => read += count,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    read_record(reader, u32::from_le_bytes(len) as u64)
+}
+
+fn read_record<R: Read>(reader: &mut R, len: u64) -> std::io::Result<Vec<u8>> {
+    // the record is read incrementally so that a corrupted length doesn't
+    // allocate more than what the stream contains
+    let mut record = Vec::new();
+    let read = reader.take(len).read_to_end(&mut record)?;
+    if (read as u64) < len {
+        return Err(truncated(ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(record)
+}
+
+pub(crate) fn truncated(err: Error) -> Error {
+    if err.kind() == ErrorKind::UnexpectedEof {
+        Error::new(ErrorKind::InvalidData, "truncated record")
+    } else {
+        err
+    }
+}
+
+/// Byte record encoded with a varint length prefix, which takes less space
+/// than the `u32` prefix of `Vec<u8>` for small records.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarintBytes(pub Vec<u8>);
+
+impl Sortable for VarintBytes {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write_varint_prefixed(writer, &self.0)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        read_varint_prefixed(reader).map(VarintBytes)
+    }
+}
+
+impl From<Vec<u8>> for VarintBytes {
+    fn from(record: Vec<u8>) -> Self {
+        VarintBytes(record)
+    }
+}
+
+impl AsRef<[u8]> for VarintBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
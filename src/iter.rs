@@ -14,35 +14,97 @@
 
 use std::{
     cmp::Ordering,
-    collections::{BinaryHeap, VecDeque},
+    collections::{binary_heap::PeekMut, BinaryHeap, VecDeque},
     fs::File,
-    io::{BufReader, Error, Seek, SeekFrom},
+    io::{BufWriter, Error, ErrorKind, Seek, SeekFrom, Write},
+    iter::FusedIterator,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::{ExternalSorterOptions, Sortable};
+#[cfg(feature = "parallel")]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    quota::QuotaReservation,
+    segment::{
+        create_tempfile, ReverseCursor, SegmentFile, SegmentReader, SegmentStats, SegmentWriter,
+        SparseIndex,
+    },
+    DuplicatePolicy, ExternalSorterOptions, Sortable, SortedFile,
+};
 
 /// Iterator over sorted items that may have been written to disk during the
 /// sorting process.
 ///
-/// The iterator operates in 3 modes based on the number of items and segments on disk:
+/// The iterator operates in 4 modes based on the number of items and segments on disk:
 /// - If the items fit into a memory buffer, the iterator dequeues directly from
 ///   a sorted VecDeque.
+/// - If the key ranges of the segments on disk don't overlap, the iterator
+///   reads the segments one after the other without any merging.
 /// - If there aren't a lot of segments on disk, the iterator peeks from the
 ///   segments and returns the smallest item. This is faster than using a binary
 ///   heap since the cost of peeking over all segments at each iteration is less
-///   than the cost of maintaining the binary heap.
+///   than the cost of maintaining the binary heap. Unless configured (see
+///   [`ExternalSorter::with_heap_iter_segment_count`]), the threshold depends
+///   on the measured cost of the comparator.
 /// - Otherwise, the iterator uses a binary heap to keep track of the smallest
 ///   item from each segment.
+///
+/// [`ExternalSorter::with_heap_iter_segment_count`]: crate::ExternalSorter::with_heap_iter_segment_count
 pub struct SortedIterator<T, F>
 where
     T: Sortable,
     F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
 {
-    _tempdir: Option<tempfile::TempDir>,
+    _tempdir: Option<Arc<tempfile::TempDir>>,
+    _reservation: Option<Arc<QuotaReservation>>,
     segments: Vec<Segment>,
     mode: Mode<T, F>,
-    count: u64,
+    count: Option<u64>,
+    remaining: Option<u64>,
+    peeked: Option<Option<std::io::Result<T>>>,
+    duplicate: Option<Option<std::io::Result<T>>>,
+    combiner: Option<Combiner<T>>,
+    back_values: Option<Vec<Option<T>>>,
+    exhausted: bool,
+    bytes_spilled: u64,
+    last_emitted: Option<Vec<u8>>,
+    merge_cost: Option<f64>,
     cmp: F,
+    options: ExternalSorterOptions,
+}
+
+/// Underlying parts of a [`SortedIterator`], as returned by
+/// [`SortedIterator::into_parts`].
+///
+/// Can be used to implement custom consumption strategies over the sorted
+/// segments, and turned back into an iterator using
+/// [`SortedIterator::from_parts`]. The options of the sorter that produced the
+/// iterator (e.g. its duplicate policy or combiner) are carried along, so that
+/// the rebuilt iterator behaves like the decomposed one.
+pub struct SortedIteratorParts<T, F> {
+    /// Temporary directory in which the segment files were written, if any.
+    /// Dropping it deletes the directory along with the segment files.
+    pub tempdir: Option<tempfile::TempDir>,
+
+    /// Sorted items, if all of them fit in the memory buffer and no segment
+    /// was written to disk.
+    pub buffer: Option<VecDeque<T>>,
+
+    /// Individually sorted segment files of encoded items.
+    pub segment_files: Vec<File>,
+
+    /// Total number of items, if known.
+    pub count: Option<u64>,
+
+    /// Comparator used to sort the items.
+    pub cmp: F,
+
+    options: ExternalSorterOptions,
+    combiner: Option<Combiner<T>>,
+    reservation: Option<Arc<QuotaReservation>>,
 }
 
 enum Mode<T, F>
@@ -52,13 +114,83 @@ where
 {
     Passthrough(VecDeque<T>),
     Heap(BinaryHeap<HeapItem<T, F>>),
-    Peek(Vec<Option<T>>),
+    Peek(PeekHeads<T>),
+    Concat {
+        order: VecDeque<usize>,
+        pending: Option<T>,
+    },
 }
 
 struct Segment {
-    reader: BufReader<File>,
-    heap_count: usize,
-    done: bool,
+    reader: SegmentReader,
+    path: Option<PathBuf>,
+    scratch: bool,
+    count: Option<u64>,
+    decoded: u64,
+    index: SparseIndex,
+    stats: Option<SegmentStats>,
+    reverse: Option<ReverseCursor>,
+}
+
+impl Segment {
+    /// Decodes the next item of the segment, or returns `None` if the end of
+    /// the segment has been reached.
+    ///
+    /// The end of segments written by the sorter is detected using their item
+    /// count, so that a truncated segment returns an error instead of fewer
+    /// items. Other segments end on the `UnexpectedEof` error of the decoder.
+    fn decode_next<T: Sortable>(&mut self) -> Result<Option<T>, Error> {
+        if self.count == Some(self.decoded) {
+            self.release();
+            return Ok(None);
+        }
+
+        match self.reader.decode_next() {
+            Ok(Some(value)) => {
+                self.decoded += 1;
+                Ok(Some(value))
+            }
+            Ok(None) if self.count.is_some() => Err(self.truncated()),
+            Ok(None) => {
+                self.release();
+                Ok(None)
+            }
+            Err(err) if self.count.is_some() && err.kind() == ErrorKind::UnexpectedEof => {
+                Err(self.truncated())
+            }
+            other => other,
+        }
+    }
+
+    /// Deletes the file of a segment written by the sorter once all of its
+    /// items were decoded, so that its disk space (or memory) is freed while
+    /// the other segments are still being read.
+    ///
+    /// The file is truncated first since it is still open, unless it is also
+    /// read from its end. Failures are ignored, since the file is deleted
+    /// along with the temporary directory anyway.
+    fn release(&mut self) {
+        if !std::mem::take(&mut self.scratch) {
+            return;
+        }
+        if self.reverse.is_none() {
+            let _ = self.reader.truncate();
+        }
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn truncated(&self) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "truncated segment: expected {} items, found {}",
+                self.count.unwrap_or(0),
+                self.decoded
+            ),
+        )
+    }
 }
 
 impl<T, F> SortedIterator<T, F>
@@ -69,93 +201,778 @@ where
     pub(crate) fn new(
         tempdir: Option<tempfile::TempDir>,
         pass_through_queue: Option<VecDeque<T>>,
-        mut segment_files: Vec<File>,
-        count: u64,
+        mut segment_files: Vec<SegmentFile>,
+        count: Option<u64>,
         cmp: F,
         options: ExternalSorterOptions,
     ) -> Result<SortedIterator<T, F>, Error> {
         for segment_file in &mut segment_files {
-            segment_file.seek(SeekFrom::Start(0))?;
+            segment_file.file.seek(SeekFrom::Start(0))?;
         }
 
-        let mut segments: Vec<Segment> = segment_files
-            .into_iter()
-            .map(|file| Segment {
-                reader: BufReader::new(file),
-                heap_count: 0,
-                done: false,
-            })
-            .collect();
+        let bytes_spilled = segment_files
+            .iter()
+            .filter(|segment_file| segment_file.file.is_file())
+            .map(|segment_file| segment_file.size.unwrap_or(0))
+            .sum();
+
+        let mut segments = Vec::with_capacity(segment_files.len());
+        for mut segment_file in segment_files {
+            segment_file.apply_read_transform();
+            let reverse = match (&segment_file.path, segment_file.item_offsets) {
+                (Some(path), Some(offsets)) => {
+                    Some(ReverseCursor::open(path, offsets, segment_file.format)?)
+                }
+                _ => None,
+            };
+
+            segments.push(Segment {
+                reader: SegmentReader::new(segment_file.file, segment_file.format)?,
+                path: segment_file.path,
+                scratch: segment_file.scratch,
+                count: segment_file.count,
+                decoded: 0,
+                index: segment_file.index,
+                stats: segment_file.stats,
+                reverse,
+            });
+        }
 
+        let mut merge_cost = None;
         let mode = if let Some(queue) = pass_through_queue {
             Mode::Passthrough(queue)
-        } else if segments.len() < options.heap_iter_segment_count {
+        } else if let Some(order) = Self::concat_order(&segments, &cmp)? {
+            Mode::Concat {
+                order,
+                pending: None,
+            }
+        } else {
             let mut next_values = Vec::with_capacity(segments.len());
             for segment in segments.iter_mut() {
-                next_values.push(Some(T::decode(&mut segment.reader)?));
+                next_values.push(segment.decode_next()?);
+            }
+
+            let use_heap = match options.heap_iter_segment_count {
+                Some(count) => segments.len() >= count,
+                None => {
+                    let nanos = comparison_nanos(&next_values, &cmp);
+                    merge_cost = Some(nanos);
+                    prefers_heap(segments.len(), nanos)
+                }
+            };
+            if use_heap {
+                Mode::Heap(Self::heap_from_values(next_values, &mut segments, &cmp))
+            } else {
+                Mode::Peek(PeekHeads::new(next_values, &cmp))
             }
-            Mode::Peek(next_values)
-        } else {
-            Mode::Heap(BinaryHeap::new())
         };
 
+        // items that compare equal may be removed while merging
+        let count = count.filter(|_| options.duplicate_policy == DuplicatePolicy::KeepAll);
+
         Ok(SortedIterator {
-            _tempdir: tempdir,
+            _tempdir: tempdir.map(Arc::new),
+            _reservation: None,
             segments,
             mode,
             count,
+            remaining: count,
+            peeked: None,
+            duplicate: None,
+            combiner: None,
+            back_values: None,
+            exhausted: false,
+            bytes_spilled,
+            last_emitted: None,
+            merge_cost,
             cmp,
+            options,
+        })
+    }
+
+    /// Sets the number of bytes written to disk by the sorter, which may be
+    /// greater than the size of the segments if some were compacted.
+    pub(crate) fn with_bytes_spilled(mut self, bytes_spilled: u64) -> Self {
+        self.bytes_spilled = bytes_spilled;
+        self
+    }
+
+    /// Holds the bytes charged to the disk quota for the segments of the
+    /// iterator, which are released once it is dropped.
+    pub(crate) fn with_quota_reservation(mut self, reservation: Option<QuotaReservation>) -> Self {
+        self._reservation = reservation.map(Arc::new);
+        self
+    }
+
+    /// Combines the consecutive items having the same key while merging, in
+    /// which case the number of items isn't known upfront anymore.
+    pub(crate) fn with_combiner(mut self, combiner: Option<Combiner<T>>) -> Self {
+        if combiner.is_some() {
+            self.count = None;
+            self.remaining = None;
+        }
+        self.combiner = combiner;
+        self
+    }
+
+    /// Creates an iterator from parts previously returned by
+    /// [`SortedIterator::into_parts`].
+    ///
+    /// The segment files are read from their start. Segments written in blocks
+    /// (see [`ExternalSorter::with_block_size`]) are detected, and their block
+    /// index is loaded from their footer.
+    ///
+    /// [`ExternalSorter::with_block_size`]: crate::ExternalSorter::with_block_size
+    pub fn from_parts(parts: SortedIteratorParts<T, F>) -> Result<SortedIterator<T, F>, Error> {
+        let mut iter = SortedIterator::new(
+            parts.tempdir,
+            parts.buffer,
+            parts
+                .segment_files
+                .into_iter()
+                .map(SegmentFile::open)
+                .collect::<Result<_, _>>()?,
+            parts.count,
+            parts.cmp,
+            parts.options,
+        )?
+        .with_combiner(parts.combiner);
+        iter._reservation = parts.reservation;
+        Ok(iter)
+    }
+
+    /// Decomposes the iterator into its underlying parts, giving access to the
+    /// sorted segment files, the comparator and the temporary directory guard.
+    ///
+    /// The segment files are returned as is and will be read from their start
+    /// by [`SortedIterator::from_parts`]. This should therefore be called before
+    /// consuming any item from the iterator. Segments kept in memory (see
+    /// [`ExternalSorter::with_in_memory_segments`]) are written to anonymous
+    /// temporary files.
+    ///
+    /// Returns an `Unsupported` error if a segment is read from a stream (see
+    /// [`ExternalSorter::merge_readers`]) or through a segment transform (see
+    /// [`ExternalSorter::with_segment_transform`]), since streams can't be
+    /// rewound.
+    ///
+    /// [`ExternalSorter::with_segment_transform`]: crate::ExternalSorter::with_segment_transform
+    /// [`ExternalSorter::with_in_memory_segments`]: crate::ExternalSorter::with_in_memory_segments
+    /// [`ExternalSorter::merge_readers`]: crate::ExternalSorter::merge_readers
+    pub fn into_parts(self) -> Result<SortedIteratorParts<T, F>, Error> {
+        let buffer = match self.mode {
+            Mode::Passthrough(queue) => Some(queue),
+            Mode::Heap(_) | Mode::Peek(_) | Mode::Concat { .. } => None,
+        };
+
+        let segment_files = self
+            .segments
+            .into_iter()
+            .map(|segment| segment.reader.into_inner().into_file())
+            .collect::<Result<_, _>>()?;
+
+        Ok(SortedIteratorParts {
+            // the directory is only given back if no other iterator shares it
+            // (see `SortedIterator::into_segment_iters`)
+            tempdir: self
+                ._tempdir
+                .and_then(|tempdir| Arc::try_unwrap(tempdir).ok()),
+            buffer,
+            segment_files,
+            count: self.count,
+            cmp: self.cmp,
+            options: self.options,
+            combiner: self.combiner,
+            reservation: self._reservation,
         })
     }
 
+    /// Decomposes the iterator into one iterator per segment, each returning
+    /// the individually sorted items of its segment, which allows merging the
+    /// segments with a custom strategy or processing them independently.
+    ///
+    /// If all items fit in the memory buffer, the iterator is returned as is as
+    /// the only segment. As with [`SortedIterator::into_parts`], this should be
+    /// called before consuming any item from the iterator. The segment files
+    /// are deleted once all the returned iterators are dropped.
+    ///
+    /// Returns an `Unsupported` error if a segment is read from a stream (see
+    /// [`ExternalSorter::merge_readers`]) or through a segment transform (see
+    /// [`ExternalSorter::with_segment_transform`]), since streams can't be
+    /// rewound.
+    ///
+    /// [`ExternalSorter::merge_readers`]: crate::ExternalSorter::merge_readers
+    /// [`ExternalSorter::with_segment_transform`]: crate::ExternalSorter::with_segment_transform
+    pub fn into_segment_iters(self) -> Result<Vec<SortedIterator<T, F>>, Error> {
+        if let Mode::Passthrough(_) = &self.mode {
+            return Ok(vec![self]);
+        }
+        if self
+            .segments
+            .iter()
+            .any(|segment| segment.reader.is_stream())
+        {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "segments read from a stream can't be iterated independently",
+            ));
+        }
+
+        let mut iters = Vec::with_capacity(self.segments.len());
+        for segment in self.segments {
+            let format = segment.reader.format();
+            let segment_file = SegmentFile {
+                file: segment.reader.into_inner(),
+                path: segment.path,
+                scratch: segment.scratch,
+                count: segment.count,
+                size: None,
+                index: segment.index,
+                stats: segment.stats,
+                item_offsets: segment.reverse.map(ReverseCursor::into_offsets),
+                format,
+                transform: None,
+            };
+
+            let mut iter = SortedIterator::new(
+                None,
+                None,
+                vec![segment_file],
+                segment.count,
+                self.cmp.clone(),
+                self.options.clone(),
+            )?
+            .with_combiner(self.combiner.clone());
+            iter._tempdir = self._tempdir.clone();
+            iter._reservation = self._reservation.clone();
+            iters.push(iter);
+        }
+        Ok(iters)
+    }
+
     /// Returns the number of items in the sorted iterator.
+    ///
+    /// Returns 0 if the number of items isn't known upfront, which is the case
+    /// when merging pre-sorted files (see [`ExternalSorter::merge_files`]) or
+    /// when items are deduplicated or combined while being merged. Use
+    /// [`SortedIterator::known_count`] to tell these cases apart from an empty
+    /// iterator.
+    ///
+    /// [`ExternalSorter::merge_files`]: crate::ExternalSorter::merge_files
     pub fn sorted_count(&self) -> u64 {
+        self.count.unwrap_or(0)
+    }
+
+    /// Returns the number of items in the sorted iterator, or `None` if it
+    /// isn't known upfront (see [`SortedIterator::sorted_count`]).
+    pub fn known_count(&self) -> Option<u64> {
         self.count
     }
 
-    /// Returns the number of segments on disk.
+    /// Returns the number of segments on disk, excluding the segments kept in
+    /// memory or read from streams.
     ///
     /// May be 0 if the whole iterator fit in memory buffer.
     pub fn disk_segment_count(&self) -> usize {
-        self.segments.len()
+        let segments = self.segments.iter();
+        segments.filter(|segment| segment.reader.is_file()).count()
     }
 
-    /// In heap mode, fills the heap with the next values from the segments on
-    /// disk.
-    fn fill_heap(
-        heap: &mut BinaryHeap<HeapItem<T, F>>,
-        segments: &mut [Segment],
-        cmp: F,
-    ) -> std::io::Result<()> {
-        for (segment_index, segment) in segments.iter_mut().enumerate() {
-            if segment.done {
-                continue;
+    /// Returns the cumulative number of bytes written to disk by the sorter
+    /// to produce the iterator, including segments that were compacted and
+    /// deleted since.
+    ///
+    /// Returns 0 if the whole iterator fit in memory buffer, or if merging
+    /// existing files (see [`ExternalSorter::merge_files`]).
+    ///
+    /// [`ExternalSorter::merge_files`]: crate::ExternalSorter::merge_files
+    pub fn bytes_spilled(&self) -> u64 {
+        self.bytes_spilled
+    }
+
+    /// Returns a reference to the next item without consuming it.
+    ///
+    /// The item is kept by the iterator and returned by the next call to
+    /// [`Iterator::next`].
+    pub fn peek(&mut self) -> Option<&std::io::Result<T>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.next_deduplicated());
+        }
+        self.peeked.as_ref().and_then(|item| item.as_ref())
+    }
+
+    /// Consumes the iterator and collects the sorted items into a vector,
+    /// returning the first decoding error encountered, if any.
+    pub fn collect_sorted(self) -> Result<Vec<T>, Error> {
+        let mut items = Vec::with_capacity(self.remaining.unwrap_or(0) as usize);
+        for item in self {
+            items.push(item?);
+        }
+        Ok(items)
+    }
+
+    /// Consumes the iterator and writes the encoded sorted items to the given
+    /// writer, returning the number of items written.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> Result<u64, Error> {
+        let mut written = 0;
+        for item in self {
+            item?.encode(writer)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Consumes the iterator and writes the encoded sorted items to the file at
+    /// the given path, returning the number of items written.
+    ///
+    /// Items are first written to a temporary file in the same directory, which
+    /// is then atomically renamed to the given path once complete. A partially
+    /// written file is therefore never observable at the given path.
+    pub fn write_to_path<P: AsRef<Path>>(self, path: P) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let temp_file = temp_file_beside(path)?;
+        let mut buf_writer = BufWriter::new(temp_file);
+        let written = self.write_to(&mut buf_writer)?;
+
+        let temp_file = buf_writer.into_inner().map_err(|err| err.into_error())?;
+        temp_file.as_file().sync_all()?;
+        temp_file.persist(path).map_err(|err| err.error)?;
+
+        Ok(written)
+    }
+
+    /// Consumes the iterator and persists the sorted items to the file at the
+    /// given path in blocks along with their block index, returning the
+    /// persisted file which can then be queried by key (see [`SortedFile`]).
+    ///
+    /// Blocks have the configured block size (see
+    /// [`ExternalSorter::with_block_size`]), or 4KiB if segments aren't written
+    /// in blocks. As with [`SortedIterator::write_to_path`], the file is
+    /// written to a temporary file first and atomically renamed once complete.
+    ///
+    /// [`ExternalSorter::with_block_size`]: crate::ExternalSorter::with_block_size
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> Result<SortedFile<T, F>, Error> {
+        let path = path.as_ref();
+        let temp_file = temp_file_beside(path)?;
+
+        let mut options = self.options.clone();
+        if options.block_size == 0 {
+            options.block_size = PERSISTED_BLOCK_SIZE;
+        }
+        options.reverse_index = false;
+        let mut writer = SegmentWriter::create_in(temp_file.reopen()?, &options, 0)?;
+        let cmp = self.cmp.clone();
+        for item in self {
+            writer.write(item?)?;
+        }
+        writer.finish()?;
+
+        temp_file.as_file().sync_all()?;
+        temp_file.persist(path).map_err(|err| err.error)?;
+
+        SortedFile::open_by(path, cmp)
+    }
+
+    /// Splits the iterator into `n` iterators over disjoint and contiguous
+    /// ranges of the sorted items, which can then be consumed independently
+    /// (e.g. on separate threads).
+    ///
+    /// Shards are balanced by number of items, and the first shard contains
+    /// the smallest items. If the items are on disk, they are merged once and
+    /// written to one new file per shard in the sort directory.
+    ///
+    /// Returns an `InvalidInput` error if the number of items isn't known
+    /// upfront (see [`SortedIterator::known_count`]).
+    pub fn into_shards(mut self, n: usize) -> Result<Vec<SortedIterator<T, F>>, Error> {
+        let n = n.max(1);
+        let Some(count) = self.count else {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot shard an iterator with an unknown number of items",
+            ));
+        };
+
+        let shard_len = |shard: u64| (count * (shard + 1) / n as u64) - (count * shard / n as u64);
+
+        if let Mode::Passthrough(queue) = &mut self.mode {
+            let mut shards = Vec::with_capacity(n);
+            for shard in 0..n as u64 {
+                let len = shard_len(shard);
+                let shard_queue: VecDeque<T> = queue.drain(..len as usize).collect();
+                shards.push(SortedIterator::new(
+                    None,
+                    Some(shard_queue),
+                    Vec::new(),
+                    Some(len),
+                    self.cmp.clone(),
+                    self.options.clone(),
+                )?);
             }
+            return Ok(shards);
+        }
 
-            if segment.heap_count == 0 {
-                for _i in 0..20 {
-                    let value = match T::decode(&mut segment.reader) {
-                        Ok(value) => value,
-                        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-                            segment.done = true;
-                            continue;
-                        }
-                        Err(err) => return Err(err),
+        let cmp = self.cmp.clone();
+        let options = self.options.clone();
+
+        let mut shards = Vec::with_capacity(n);
+        for shard in 0..n as u64 {
+            let len = shard_len(shard);
+            let mut buf_writer = BufWriter::new(create_tempfile(&options)?);
+            for item in self.by_ref().take(len as usize) {
+                item?.encode(&mut buf_writer)?;
+            }
+
+            let file = buf_writer.into_inner().map_err(|err| err.into_error())?;
+            shards.push(SortedIterator::new(
+                None,
+                None,
+                vec![SegmentFile::new(file)],
+                Some(len),
+                cmp.clone(),
+                options.clone(),
+            )?);
+        }
+
+        Ok(shards)
+    }
+
+    /// Skips ahead to the first item greater or equal to the given key
+    /// according to the comparator.
+    ///
+    /// Segments written with an index (see
+    /// [`ExternalSorter::with_index_interval`]) are skipped ahead directly to
+    /// the closest indexed position, while other segments are decoded until
+    /// the key is reached. Seeking never goes back: seeking to a key smaller
+    /// than the last returned item has no effect.
+    ///
+    /// [`ExternalSorter::with_index_interval`]: crate::ExternalSorter::with_index_interval
+    pub fn seek_to(&mut self, key: &T) -> Result<(), Error> {
+        let cmp = &self.cmp;
+        let remaining = &mut self.remaining;
+
+        match self.peeked.take() {
+            Some(Some(Ok(value))) if cmp(&value, key) != Ordering::Less => {
+                self.peeked = Some(Some(Ok(value)));
+                return Ok(());
+            }
+            Some(Some(Ok(_))) => skip_remaining(remaining, Some(1)),
+            Some(None) => {
+                self.peeked = Some(None);
+                return Ok(());
+            }
+            Some(Some(Err(_))) | None => {}
+        }
+
+        // the item following the last removed duplicate, if any
+        match self.duplicate.take() {
+            Some(Some(Ok(value))) if cmp(&value, key) != Ordering::Less => {
+                self.duplicate = Some(Some(Ok(value)));
+                return Ok(());
+            }
+            Some(None) => {
+                self.duplicate = Some(None);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        match &mut self.mode {
+            Mode::Passthrough(queue) => {
+                let skipped = queue.partition_point(|item| cmp(item, key) == Ordering::Less);
+                queue.drain(..skipped);
+                skip_remaining(remaining, Some(skipped as u64));
+            }
+            Mode::Peek(heads) => {
+                let values = heads.values.iter_mut();
+                for (segment, next_value) in self.segments.iter_mut().zip(values) {
+                    let Some(value) = next_value else {
+                        continue;
                     };
 
-                    segment.heap_count += 1;
+                    if cmp(value, key) == Ordering::Less {
+                        skip_remaining(remaining, Some(1));
+                        *next_value = Self::seek_segment(segment, key, cmp, remaining)?;
+                    }
+                }
+                heads.reorder(cmp);
+            }
+            Mode::Concat { order, pending } => {
+                if let Some(value) = pending.as_ref() {
+                    if cmp(value, key) != Ordering::Less {
+                        return Ok(());
+                    }
+                    skip_remaining(remaining, Some(1));
+                }
+
+                *pending = None;
+                while let Some(&segment_index) = order.front() {
+                    let segment = &mut self.segments[segment_index];
+                    if let Some(value) = Self::seek_segment(segment, key, cmp, remaining)? {
+                        *pending = Some(value);
+                        break;
+                    }
+                    order.pop_front();
+                }
+            }
+            Mode::Heap(heap) => {
+                let mut kept = Vec::with_capacity(heap.len());
+                let mut behind = Vec::new();
+                for item in std::mem::take(heap).into_vec() {
+                    if cmp(&item.value, key) == Ordering::Less {
+                        skip_remaining(remaining, Some(1));
+                        behind.push(item.segment_index);
+                    } else {
+                        kept.push(item);
+                    }
+                }
 
-                    heap.push(HeapItem {
-                        segment_index,
-                        value,
-                        cmp: cmp.clone(),
-                    });
+                // only the segments whose next item was skipped need to seek
+                for segment_index in behind {
+                    let segment = &mut self.segments[segment_index];
+                    if let Some(value) = Self::seek_segment(segment, key, cmp, remaining)? {
+                        kept.push(HeapItem {
+                            segment_index,
+                            position: segment.decoded,
+                            value,
+                            cmp: cmp.clone(),
+                        });
+                    }
                 }
+                *heap = BinaryHeap::from(kept);
             }
         }
 
         Ok(())
     }
+
+    /// Consumes the iterator and returns a new iterator over the items within
+    /// the given key range according to the comparator.
+    ///
+    /// The iterator first skips ahead to the start of the range (see
+    /// [`SortedIterator::seek_to`]) and stops as soon as an item past the end of
+    /// the range is reached, without consuming the rest of the items.
+    pub fn range<R>(mut self, range: R) -> Result<SortedRange<T, F, R>, Error>
+    where
+        R: RangeBounds<T>,
+    {
+        match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => self.seek_to(start)?,
+            Bound::Unbounded => {}
+        }
+
+        Ok(SortedRange {
+            iter: self,
+            range,
+            done: false,
+        })
+    }
+
+    /// Consumes the iterator and returns a new iterator over batches of up to
+    /// `size` sorted items.
+    ///
+    /// All batches contain `size` items except for the last one, which may
+    /// contain fewer. If an item fails to be decoded, the error is returned in
+    /// place of the batch and iteration stops.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn chunks(self, size: usize) -> SortedChunks<T, F> {
+        assert!(size != 0, "chunk size must be non-zero");
+
+        SortedChunks {
+            iter: self,
+            size,
+            done: false,
+        }
+    }
+
+    /// Consumes the iterator and returns a new iterator over batches of up to
+    /// `size` sorted items, each mapped in parallel on the rayon thread pool.
+    ///
+    /// Unlike [`rayon::iter::ParallelBridge`], the order of the items is
+    /// preserved: batches are returned in sorted order and the mapped items of
+    /// a batch are in the same order as the sorted items. Batches are decoded
+    /// sequentially, so the mapping function should be expensive enough for
+    /// parallelism to have an impact. Decoding errors are handled as in
+    /// [`SortedIterator::chunks`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    #[cfg(feature = "parallel")]
+    pub fn par_map_chunks<R, M>(self, size: usize, map: M) -> SortedParMapChunks<T, F, M>
+    where
+        R: Send,
+        M: Fn(T) -> R + Send + Sync,
+    {
+        SortedParMapChunks {
+            thread_pool: self.options.thread_pool.clone(),
+            chunks: self.chunks(size),
+            map,
+        }
+    }
+
+    /// Consumes the iterator and returns a new iterator that maps each sorted
+    /// item as it gets merged, without decoding the items into another type
+    /// first.
+    ///
+    /// Decoding errors are returned as is without being mapped. The number of
+    /// remaining items is preserved, so that the length of the mapped iterator
+    /// is known if the length of the sorted iterator is.
+    pub fn map_items<R, M>(self, map: M) -> SortedMap<T, F, M>
+    where
+        M: FnMut(T) -> R,
+    {
+        SortedMap { iter: self, map }
+    }
+
+    /// Consumes the iterator and returns a new iterator implementing
+    /// [`ExactSizeIterator`], or `None` if the number of remaining items isn't
+    /// known, which is the case when merging pre-sorted files or when items
+    /// are deduplicated or combined while being merged (see
+    /// [`SortedIterator::known_count`]).
+    ///
+    /// The length only counts the items that are returned successfully, so the
+    /// iterator may end before its length reaches 0 once an error is returned.
+    pub fn into_exact(self) -> Option<SortedExact<T, F>> {
+        self.remaining?;
+        Some(SortedExact { iter: self })
+    }
+
+    /// Consumes the iterator and returns a new iterator over the sorted items
+    /// for which the predicate returns true, which is evaluated as each item
+    /// gets merged.
+    ///
+    /// Decoding errors are always returned. Since the number of items kept by
+    /// the predicate isn't known upfront, the number of remaining items is only
+    /// an upper bound.
+    pub fn filter_items<P>(self, predicate: P) -> SortedFilter<T, F, P>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        SortedFilter {
+            iter: self,
+            predicate,
+        }
+    }
+
+    /// Consumes the iterator and returns a new iterator that only keeps the
+    /// first `limit` items of each key returned by `key`, skipping the others
+    /// as the items get merged.
+    ///
+    /// Items having the same key need to be consecutive, which is the case if
+    /// the comparator orders items by key first. Ordering the items of each
+    /// key from the newest to the oldest keeps the latest `limit` items of
+    /// each key (see [`ExternalSorter::sort_tail`]). Decoding errors are
+    /// always returned.
+    ///
+    /// [`ExternalSorter::sort_tail`]: crate::ExternalSorter::sort_tail
+    pub fn tail_per_key<KF, K>(self, key: KF, limit: usize) -> SortedTail<T, F, KF, K>
+    where
+        KF: Fn(&T) -> K,
+        K: PartialEq,
+    {
+        SortedTail {
+            iter: self,
+            key,
+            limit,
+            current: None,
+        }
+    }
+
+    /// Skips ahead in a segment, whose already decoded items are all smaller
+    /// than the given key, and returns its first item greater or equal to the
+    /// key, if any.
+    ///
+    /// The skipped items are deducted from the remaining count, which becomes
+    /// unknown if the number of skipped items can't be determined.
+    fn seek_segment(
+        segment: &mut Segment,
+        key: &T,
+        cmp: &F,
+        remaining: &mut Option<u64>,
+    ) -> Result<Option<T>, Error> {
+        if let Some(stats) = &segment.stats {
+            if cmp(&stats.max()?, key) == Ordering::Less {
+                let skipped = segment.count.map(|count| count - segment.decoded);
+                skip_remaining(remaining, skipped);
+                segment.decoded = segment.count.unwrap_or(segment.decoded);
+                return Ok(None);
+            }
+        }
+
+        if let Some((offset, position)) = segment.index.seek_offset(key, cmp)? {
+            if offset > segment.reader.stream_position()? {
+                segment.reader.seek(offset)?;
+                skip_remaining(remaining, Some(position - segment.decoded));
+                segment.decoded = position;
+            }
+        }
+
+        while let Some(value) = segment.decode_next::<T>()? {
+            if cmp(&value, key) != Ordering::Less {
+                return Ok(Some(value));
+            }
+            skip_remaining(remaining, Some(1));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the order in which to read the segments if their key ranges
+    /// don't overlap, which allows concatenating them instead of merging them.
+    fn concat_order(segments: &[Segment], cmp: &F) -> Result<Option<VecDeque<usize>>, Error> {
+        if segments.len() == 1 {
+            return Ok(Some(VecDeque::from([0])));
+        }
+
+        let mut ranges = Vec::with_capacity(segments.len());
+        for (segment_index, segment) in segments.iter().enumerate() {
+            let Some(stats) = &segment.stats else {
+                return Ok(None);
+            };
+            ranges.push((segment_index, stats.min::<T>()?, stats.max::<T>()?));
+        }
+        ranges.sort_by(|a, b| cmp(&a.1, &b.1));
+
+        // segments sharing a boundary item are only concatenated in their
+        // order, so that equal items are returned in the order they were pushed
+        let overlap = ranges
+            .windows(2)
+            .any(|pair| match cmp(&pair[0].2, &pair[1].1) {
+                Ordering::Greater => true,
+                Ordering::Equal => pair[0].0 > pair[1].0,
+                Ordering::Less => false,
+            });
+        if overlap {
+            return Ok(None);
+        }
+
+        Ok(Some(ranges.into_iter().map(|range| range.0).collect()))
+    }
+
+    /// Creates the heap of the heap mode from the first item of each segment,
+    /// which is refilled once the item of a segment is popped.
+    fn heap_from_values(
+        values: Vec<Option<T>>,
+        segments: &mut [Segment],
+        cmp: &F,
+    ) -> BinaryHeap<HeapItem<T, F>> {
+        let mut heap = BinaryHeap::with_capacity(values.len());
+        for (segment_index, value) in values.into_iter().enumerate() {
+            let Some(value) = value else {
+                continue;
+            };
+
+            heap.push(HeapItem {
+                segment_index,
+                position: segments[segment_index].decoded,
+                value,
+                cmp: cmp.clone(),
+            });
+        }
+        heap
+    }
 }
 
 impl<T, F> Iterator for SortedIterator<T, F>
@@ -166,83 +983,907 @@ where
     type Item = std::io::Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &mut self.mode {
-            Mode::Passthrough(queue) => queue.pop_front().map(Ok),
-            Mode::Heap(heap) => {
-                if heap.is_empty() {
-                    if let Err(err) = Self::fill_heap(heap, &mut self.segments, self.cmp.clone()) {
-                        return Some(Err(err));
-                    }
-                }
+        let item = match self.peeked.take() {
+            Some(item) => item,
+            None => self.next_deduplicated(),
+        };
 
-                if heap.is_empty() {
-                    return None;
+        if let Some(Ok(value)) = &item {
+            skip_remaining(&mut self.remaining, Some(1));
+            self.release_if_done();
+            if self.options.verify_order {
+                if let Err(err) = self.verify_order(value) {
+                    return Some(Err(err));
                 }
+            }
+        }
+        item
+    }
 
-                let item = heap.pop().unwrap();
-                let segment = &mut self.segments[item.segment_index];
-                segment.heap_count -= 1;
-
-                if segment.heap_count == 0 {
-                    if let Err(err) = Self::fill_heap(heap, &mut self.segments, self.cmp.clone()) {
-                        return Some(Err(err));
-                    }
-                }
+    /// Returns the number of remaining items without decoding them if the
+    /// number of items is known upfront.
+    fn count(self) -> usize {
+        match self.remaining {
+            Some(remaining) if !self.exhausted || self.peeked.is_some() => remaining as usize,
+            Some(_) => 0,
+            None => self.fold(0, |count, _| count + 1),
+        }
+    }
 
-                Some(Ok(item.value))
-            }
-            Mode::Peek(next_values) => {
-                // otherwise, we iter from segments on disk
-                let mut smallest_idx: Option<usize> = None;
-                {
-                    let mut smallest: Option<&T> = None;
-                    for (idx, next_value) in next_values.iter().enumerate() {
-                        let Some(next_value) = next_value else {
-                            continue;
-                        };
-
-                        if smallest.is_none()
-                            || (self.cmp)(next_value, smallest.unwrap()) == Ordering::Less
-                        {
-                            smallest = Some(next_value);
-                            smallest_idx = Some(idx);
-                        }
-                    }
-                }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.remaining {
+            Some(remaining) => (remaining as usize, Some(remaining as usize)),
+            None => (0, None),
+        }
+    }
+}
 
-                if let Some(idx) = smallest_idx {
-                    let segment = &mut self.segments[idx];
-                    let value = next_values[idx].take().unwrap();
+/// Consuming the iterator from its end requires the segments on disk to have
+/// been written with a reverse index (see
+/// [`ExternalSorter::with_reverse_index`]). Otherwise, an `Unsupported` error
+/// is returned, unless all items fit in the memory buffer.
+///
+/// [`ExternalSorter::with_reverse_index`]: crate::ExternalSorter::with_reverse_index
+impl<T, F> DoubleEndedIterator for SortedIterator<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
 
-                    match T::decode(&mut segment.reader) {
-                        Ok(value) => {
-                            next_values[idx] = Some(value);
-                        }
-                        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-                            next_values[idx] = None;
-                        }
-                        Err(err) => {
-                            return Some(Err(err));
-                        }
-                    };
+        // if the only item left was already peeked, it's also the last one
+        let item = if self.remaining == Some(1) && matches!(self.peeked, Some(Some(Ok(_)))) {
+            self.peeked.take().flatten()
+        } else {
+            self.next_back_item()
+        };
 
-                    Some(Ok(value))
-                } else {
-                    None
-                }
-            }
+        if let Some(Ok(_)) = &item {
+            skip_remaining(&mut self.remaining, Some(1));
+            self.release_if_done();
         }
+        item
     }
 }
 
-struct HeapItem<T, F>
+impl<T, F> FusedIterator for SortedIterator<T, F>
 where
     T: Sortable,
-    F: Fn(&T, &T) -> Ordering + Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
 {
-    segment_index: usize,
-    value: T,
-    cmp: F,
+}
+
+impl<T, F> SortedIterator<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    /// Checks that the given item isn't smaller than the previously returned
+    /// one, which is kept in its encoded form since the items are moved out
+    /// of the iterator.
+    fn verify_order(&mut self, item: &T) -> Result<(), Error> {
+        if let Some(last) = &self.last_emitted {
+            let last = T::decode(&mut last.as_slice())?;
+            if (self.cmp)(&last, item) == Ordering::Greater {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "sorted item is smaller than the previous one",
+                ));
+            }
+        }
+
+        let last = self.last_emitted.get_or_insert_with(Vec::new);
+        last.clear();
+        item.encode(last)
+    }
+
+    /// Deletes the segment files once all items were returned, since the
+    /// last items are returned without reaching the end of their segment.
+    fn release_if_done(&mut self) {
+        if self.remaining == Some(0) {
+            for segment in &mut self.segments {
+                segment.release();
+            }
+        }
+    }
+
+    /// Returns the next item, removing the items that compare equal to it
+    /// according to the duplicate policy, or combining the items having the
+    /// same key into it if a combiner is set.
+    fn next_deduplicated(&mut self) -> Option<std::io::Result<T>> {
+        let item = match self.duplicate.take() {
+            Some(item) => item,
+            None => self.next_unpeeked(),
+        };
+
+        let policy = self.options.duplicate_policy;
+        if policy == DuplicatePolicy::KeepAll && self.combiner.is_none() {
+            return item;
+        }
+        let Some(Ok(mut value)) = item else {
+            return item;
+        };
+
+        loop {
+            match self.next_unpeeked() {
+                Some(Ok(next)) if self.is_duplicate(&value, &next) => {
+                    value = match &self.combiner {
+                        Some(combiner) => (combiner.reduce)(value, next),
+                        None if policy == DuplicatePolicy::KeepLast => next,
+                        None => value,
+                    };
+                }
+                next => {
+                    self.duplicate = Some(next);
+                    return Some(Ok(value));
+                }
+            }
+        }
+    }
+
+    /// Returns true if the next item gets combined with or removed in favor
+    /// of the given item.
+    fn is_duplicate(&self, value: &T, next: &T) -> bool {
+        match &self.combiner {
+            Some(combiner) => (combiner.same_key)(value, next),
+            None => (self.cmp)(next, value) == Ordering::Equal,
+        }
+    }
+
+    fn next_unpeeked(&mut self) -> Option<std::io::Result<T>> {
+        // the remaining count is checked since items may also have been
+        // consumed from the end of the iterator
+        if self.exhausted || self.remaining == Some(0) {
+            self.exhausted = true;
+            return None;
+        }
+
+        let item = self.next_item();
+        if item.is_none() {
+            self.exhausted = true;
+        }
+        item
+    }
+
+    fn next_back_item(&mut self) -> Option<std::io::Result<T>> {
+        if let Mode::Passthrough(queue) = &mut self.mode {
+            return queue.pop_back().map(Ok);
+        }
+
+        if self.remaining.is_none() || self.segments.iter().any(|s| s.reverse.is_none()) {
+            return Some(Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "iterating from the end requires segments written with a reverse index",
+            )));
+        }
+
+        let back_values = match &mut self.back_values {
+            Some(back_values) => back_values,
+            None => {
+                let mut back_values = Vec::with_capacity(self.segments.len());
+                for segment in &mut self.segments {
+                    let reverse = segment.reverse.as_mut().unwrap();
+                    match reverse.decode_prev() {
+                        Ok(value) => back_values.push(value),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+                self.back_values.insert(back_values)
+            }
+        };
+
+        let mut largest_idx: Option<usize> = None;
+        for (idx, back_value) in back_values.iter().enumerate() {
+            let Some(back_value) = back_value else {
+                continue;
+            };
+
+            let larger = largest_idx.is_none_or(|largest_idx| {
+                let largest = back_values[largest_idx].as_ref().unwrap();
+                (self.cmp)(back_value, largest) != Ordering::Less
+            });
+            if larger {
+                largest_idx = Some(idx);
+            }
+        }
+
+        let idx = largest_idx?;
+        let value = back_values[idx].take();
+        match self.segments[idx].reverse.as_mut().unwrap().decode_prev() {
+            Ok(prev_value) => back_values[idx] = prev_value,
+            Err(err) => return Some(Err(err)),
+        }
+
+        value.map(Ok)
+    }
+
+    fn next_item(&mut self) -> Option<std::io::Result<T>> {
+        match &mut self.mode {
+            Mode::Passthrough(queue) => queue.pop_front().map(Ok),
+            Mode::Concat { order, pending } => {
+                if let Some(value) = pending.take() {
+                    return Some(Ok(value));
+                }
+
+                while let Some(&segment_index) = order.front() {
+                    match self.segments[segment_index].decode_next() {
+                        Ok(Some(value)) => return Some(Ok(value)),
+                        Ok(None) => {
+                            order.pop_front();
+                        }
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+
+                None
+            }
+            Mode::Heap(heap) => {
+                // the heap holds the next item of each segment, which is
+                // replaced in place by the following item of the same segment
+                let mut top = heap.peek_mut()?;
+                let segment = &mut self.segments[top.segment_index];
+                match segment.decode_next() {
+                    Ok(Some(next_value)) => {
+                        top.position = segment.decoded;
+                        Some(Ok(std::mem::replace(&mut top.value, next_value)))
+                    }
+                    Ok(None) => {
+                        let value = PeekMut::pop(top).value;
+                        self.adapt_mode();
+                        Some(Ok(value))
+                    }
+                    Err(err) => {
+                        PeekMut::pop(top);
+                        Some(Err(err))
+                    }
+                }
+            }
+            Mode::Peek(heads) => {
+                let (idx, value) = heads.pop()?;
+                match self.segments[idx].decode_next() {
+                    Ok(Some(next_value)) => heads.insert(idx, Some(next_value), &self.cmp),
+                    Ok(None) => self.adapt_mode(),
+                    Err(err) => return Some(Err(err)),
+                }
+
+                Some(Ok(value))
+            }
+        }
+    }
+
+    /// Switches from the heap to peeking over the segments once enough of
+    /// them are exhausted for peeking to be cheaper, if the merge strategy
+    /// was picked automatically.
+    ///
+    /// Segments are only ever exhausted while merging, so a merge that
+    /// started by peeking never needs to switch to the heap.
+    fn adapt_mode(&mut self) {
+        let Some(nanos) = self.merge_cost else {
+            return;
+        };
+        let Mode::Heap(heap) = &mut self.mode else {
+            return;
+        };
+        if prefers_heap(heap.len(), nanos) {
+            return;
+        }
+
+        let mut values = (0..self.segments.len()).map(|_| None).collect::<Vec<_>>();
+        for item in std::mem::take(heap).into_vec() {
+            values[item.segment_index] = Some(item.value);
+        }
+        self.mode = Mode::Peek(PeekHeads::new(values, &self.cmp));
+    }
+
+    /// Returns true if the iterator currently merges its segments using the
+    /// binary heap.
+    #[cfg(test)]
+    pub(crate) fn uses_heap(&self) -> bool {
+        matches!(self.mode, Mode::Heap(_))
+    }
+}
+
+/// Deducts skipped items from the remaining count, which becomes unknown if the
+/// number of skipped items isn't known.
+fn skip_remaining(remaining: &mut Option<u64>, skipped: Option<u64>) {
+    *remaining = match (*remaining, skipped) {
+        (Some(remaining), Some(skipped)) => Some(remaining.saturating_sub(skipped)),
+        _ => None,
+    };
+}
+
+/// Reduces the consecutive sorted items having the same key into a single
+/// item (see [`PushExternalSorter::with_combiner`]).
+///
+/// [`PushExternalSorter::with_combiner`]: crate::PushExternalSorter::with_combiner
+pub(crate) struct Combiner<T> {
+    pub same_key: Arc<SameKey<T>>,
+    pub reduce: Arc<Reduce<T>>,
+}
+
+type SameKey<T> = dyn Fn(&T, &T) -> bool + Send + Sync;
+type Reduce<T> = dyn Fn(T, T) -> T + Send + Sync;
+
+impl<T> Combiner<T> {
+    /// Combines the consecutive items of the given sorted items having the
+    /// same key.
+    pub fn combine(&self, items: Vec<T>) -> Vec<T> {
+        let mut combined: Vec<T> = Vec::with_capacity(items.len());
+        for item in items {
+            match combined.pop() {
+                Some(last) if (self.same_key)(&last, &item) => {
+                    combined.push((self.reduce)(last, item));
+                }
+                Some(last) => {
+                    combined.push(last);
+                    combined.push(item);
+                }
+                None => combined.push(item),
+            }
+        }
+        combined
+    }
+}
+
+impl<T> Clone for Combiner<T> {
+    fn clone(&self) -> Self {
+        Combiner {
+            same_key: self.same_key.clone(),
+            reduce: self.reduce.clone(),
+        }
+    }
+}
+
+/// Iterator over the sorted items within a key range, as returned by
+/// [`SortedIterator::range`].
+pub struct SortedRange<T, F, R>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    R: RangeBounds<T>,
+{
+    iter: SortedIterator<T, F>,
+    range: R,
+    done: bool,
+}
+
+impl<T, F, R> Iterator for SortedRange<T, F, R>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    R: RangeBounds<T>,
+{
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let item = match self.iter.next()? {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let cmp = &self.iter.cmp;
+            if let Bound::Excluded(start) = self.range.start_bound() {
+                if cmp(&item, start) == Ordering::Equal {
+                    continue;
+                }
+            }
+
+            let past_end = match self.range.end_bound() {
+                Bound::Included(end) => cmp(&item, end) == Ordering::Greater,
+                Bound::Excluded(end) => cmp(&item, end) != Ordering::Less,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                self.done = true;
+                return None;
+            }
+
+            return Some(Ok(item));
+        }
+
+        None
+    }
+}
+
+impl<T, F, R> FusedIterator for SortedRange<T, F, R>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    R: RangeBounds<T>,
+{
+}
+
+/// Iterator over batches of sorted items, as returned by
+/// [`SortedIterator::chunks`].
+pub struct SortedChunks<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    iter: SortedIterator<T, F>,
+    size: usize,
+    done: bool,
+}
+
+impl<T, F> Iterator for SortedChunks<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    type Item = std::io::Result<Vec<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let capacity = match self.iter.remaining {
+            Some(remaining) => self.size.min(remaining as usize),
+            None => self.size,
+        };
+        let mut chunk = Vec::with_capacity(capacity);
+        while chunk.len() < self.size {
+            match self.iter.next() {
+                Some(Ok(item)) => chunk.push(item),
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(Ok(chunk))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            return (0, Some(0));
+        }
+
+        match self.iter.remaining {
+            Some(remaining) => {
+                let chunks = (remaining as usize).div_ceil(self.size);
+                (chunks, Some(chunks))
+            }
+            None => (0, None),
+        }
+    }
+}
+
+impl<T, F> FusedIterator for SortedChunks<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+}
+
+/// Iterator over batches of sorted items mapped in parallel, as returned by
+/// [`SortedIterator::par_map_chunks`].
+#[cfg(feature = "parallel")]
+pub struct SortedParMapChunks<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    chunks: SortedChunks<T, F>,
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+    map: M,
+}
+
+#[cfg(feature = "parallel")]
+impl<T, F, M, R> Iterator for SortedParMapChunks<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    R: Send,
+    M: Fn(T) -> R + Send + Sync,
+{
+    type Item = std::io::Result<Vec<R>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = match self.chunks.next()? {
+            Ok(chunk) => chunk,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let map = &self.map;
+        let mapped = match &self.thread_pool {
+            Some(pool) => pool.install(|| chunk.into_par_iter().map(map).collect()),
+            None => chunk.into_par_iter().map(map).collect(),
+        };
+        Some(Ok(mapped))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl<T, F, M, R> FusedIterator for SortedParMapChunks<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    R: Send,
+    M: Fn(T) -> R + Send + Sync,
+{
+}
+
+/// Iterator over the mapped sorted items, as returned by
+/// [`SortedIterator::map_items`].
+pub struct SortedMap<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    iter: SortedIterator<T, F>,
+    map: M,
+}
+
+impl<T, F, M, R> Iterator for SortedMap<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    M: FnMut(T) -> R,
+{
+    type Item = std::io::Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.iter.next()?.map(&mut self.map))
+    }
+
+    fn count(self) -> usize {
+        self.iter.count()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<T, F, M, R> DoubleEndedIterator for SortedMap<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    M: FnMut(T) -> R,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Some(self.iter.next_back()?.map(&mut self.map))
+    }
+}
+
+impl<T, F, M, R> FusedIterator for SortedMap<T, F, M>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    M: FnMut(T) -> R,
+{
+}
+
+/// Iterator over the sorted items whose number is known, as returned by
+/// [`SortedIterator::into_exact`].
+pub struct SortedExact<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    iter: SortedIterator<T, F>,
+}
+
+impl<T, F> Iterator for SortedExact<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+
+    fn count(self) -> usize {
+        self.iter.count()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<T, F> ExactSizeIterator for SortedExact<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+}
+
+impl<T, F> DoubleEndedIterator for SortedExact<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
+}
+
+impl<T, F> FusedIterator for SortedExact<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+}
+
+/// Iterator over the sorted items matching a predicate, as returned by
+/// [`SortedIterator::filter_items`].
+pub struct SortedFilter<T, F, P>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    iter: SortedIterator<T, F>,
+    predicate: P,
+}
+
+impl<T, F, P> Iterator for SortedFilter<T, F, P>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    P: FnMut(&T) -> bool,
+{
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                Ok(item) if !(self.predicate)(&item) => continue,
+                item => return Some(item),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.iter.size_hint().1)
+    }
+}
+
+impl<T, F, P> DoubleEndedIterator for SortedFilter<T, F, P>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    P: FnMut(&T) -> bool,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next_back()? {
+                Ok(item) if !(self.predicate)(&item) => continue,
+                item => return Some(item),
+            }
+        }
+    }
+}
+
+impl<T, F, P> FusedIterator for SortedFilter<T, F, P>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    P: FnMut(&T) -> bool,
+{
+}
+
+/// Iterator over the first items of each key, as returned by
+/// [`SortedIterator::tail_per_key`].
+pub struct SortedTail<T, F, KF, K>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    iter: SortedIterator<T, F>,
+    key: KF,
+    limit: usize,
+    current: Option<(K, usize)>,
+}
+
+impl<T, F, KF, K> Iterator for SortedTail<T, F, KF, K>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    KF: Fn(&T) -> K,
+    K: PartialEq,
+{
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let item = match self.iter.next()? {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let key = (self.key)(&item);
+            match &mut self.current {
+                Some((current, kept)) if *current == key => {
+                    if *kept >= self.limit {
+                        continue;
+                    }
+                    *kept += 1;
+                }
+                current => {
+                    *current = Some((key, 1));
+                    if self.limit == 0 {
+                        continue;
+                    }
+                }
+            }
+            return Some(Ok(item));
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.iter.size_hint().1)
+    }
+}
+
+impl<T, F, KF, K> FusedIterator for SortedTail<T, F, KF, K>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    KF: Fn(&T) -> K,
+    K: PartialEq,
+{
+}
+
+/// Size of the blocks of a persisted sorted file if segments aren't written in
+/// blocks.
+const PERSISTED_BLOCK_SIZE: usize = 4 * 1024;
+
+/// Creates a temporary file in the directory of the given path, which can then
+/// be atomically renamed to it.
+fn temp_file_beside(path: &Path) -> Result<tempfile::NamedTempFile, Error> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    tempfile::NamedTempFile::new_in(dir)
+}
+
+/// Number of comparisons timed to estimate the cost of the comparator when
+/// picking the merge strategy automatically.
+const TIMED_COMPARISONS: usize = 256;
+
+/// Estimated cost in nanoseconds of moving an item of the heap by one level,
+/// on top of comparing it.
+const HEAP_MOVE_NANOS: f64 = 2.0;
+
+/// Estimates the cost of the comparator in nanoseconds per comparison by
+/// timing comparisons between the given items.
+fn comparison_nanos<T, F>(values: &[Option<T>], cmp: &F) -> f64
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let values = values.iter().flatten().collect::<Vec<_>>();
+    if values.len() < 2 {
+        return 0.0;
+    }
+
+    // there is no clock on this target, assume a cheap comparator
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        return HEAP_MOVE_NANOS;
+    }
+
+    let start = std::time::Instant::now();
+    for i in 0..TIMED_COMPARISONS {
+        let a = values[i % values.len()];
+        let b = values[(i + 1) % values.len()];
+        std::hint::black_box(cmp(a, b));
+    }
+    start.elapsed().as_nanos() as f64 / TIMED_COMPARISONS as f64
+}
+
+/// Returns true if merging the given number of segments using the binary heap
+/// is expected to be faster than peeking over all segments, given the cost of
+/// a comparison in nanoseconds.
+///
+/// Peeking costs one comparison per segment for each returned item, while the
+/// heap costs about 2 comparisons and moves per level of the heap.
+pub(crate) fn prefers_heap(segment_count: usize, comparison_nanos: f64) -> bool {
+    if segment_count < 2 {
+        return false;
+    }
+
+    let levels = (segment_count as f64).log2();
+    let peek_cost = segment_count as f64 * comparison_nanos;
+    let heap_cost = 2.0 * levels * (comparison_nanos + HEAP_MOVE_NANOS);
+    heap_cost < peek_cost
+}
+
+struct HeapItem<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync,
+{
+    segment_index: usize,
+    position: u64,
+    value: T,
+    cmp: F,
+}
+
+/// Next item of each segment in peek mode, along with the indices of the
+/// segments having one ordered by their next item, so that only the segment
+/// whose item was taken needs to be compared with the others, using a binary
+/// search, instead of comparing the next item of every segment.
+struct PeekHeads<T> {
+    values: Vec<Option<T>>,
+    order: VecDeque<usize>,
+}
+
+impl<T> PeekHeads<T> {
+    fn new<F>(values: Vec<Option<T>>, cmp: &F) -> PeekHeads<T>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let mut heads = PeekHeads {
+            values,
+            order: VecDeque::new(),
+        };
+        heads.reorder(cmp);
+        heads
+    }
+
+    /// Sorts the segments by their next item again, after it changed for any
+    /// of them.
+    fn reorder<F>(&mut self, cmp: &F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let values = &self.values;
+        let mut order = (0..values.len())
+            .filter(|idx| values[*idx].is_some())
+            .collect::<Vec<_>>();
+        // the sort is stable, so that equal items are taken in the order of
+        // their segments
+        order.sort_by(|a, b| cmp(values[*a].as_ref().unwrap(), values[*b].as_ref().unwrap()));
+        self.order = VecDeque::from(order);
+    }
+
+    /// Takes the smallest next item, along with the index of its segment.
+    fn pop(&mut self) -> Option<(usize, T)> {
+        let idx = self.order.pop_front()?;
+        Some((idx, self.values[idx].take().unwrap()))
+    }
+
+    /// Sets the next item of a segment whose item was taken.
+    fn insert<F>(&mut self, idx: usize, value: Option<T>, cmp: &F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let Some(value) = value else {
+            return;
+        };
+
+        let values = &self.values;
+        let position = self.order.partition_point(|other| {
+            match cmp(values[*other].as_ref().unwrap(), &value) {
+                Ordering::Less => true,
+                Ordering::Equal => *other < idx,
+                Ordering::Greater => false,
+            }
+        });
+        self.order.insert(position, idx);
+        self.values[idx] = Some(value);
+    }
 }
 
 impl<T, F> PartialOrd for HeapItem<T, F>
@@ -261,7 +1902,12 @@ where
     F: Fn(&T, &T) -> Ordering + Send + Sync,
 {
     fn cmp(&self, other: &Self) -> Ordering {
-        (self.cmp)(&self.value, &other.value).reverse()
+        // equal items are returned in the order of their segments, and of
+        // their position within it, so that merging is deterministic
+        (self.cmp)(&self.value, &other.value)
+            .then_with(|| self.segment_index.cmp(&other.segment_index))
+            .then_with(|| self.position.cmp(&other.position))
+            .reverse()
     }
 }
 
@@ -271,7 +1917,7 @@ where
     F: Fn(&T, &T) -> Ordering + Send + Sync,
 {
     fn eq(&self, other: &Self) -> bool {
-        (self.cmp)(&self.value, &other.value) == Ordering::Equal
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -0,0 +1,45 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::Ordering;
+
+use crate::Sortable;
+
+/// Sortable item exposing a fixed-width byte key, by which it is sorted in
+/// lexicographic order (see [`ExternalSorter::sort_by_fixed_key`]).
+///
+/// Keys are compared 16 bytes at a time as big-endian `u128` integers, which
+/// is cheaper than a generic comparator for composite keys encoded in a byte
+/// array (e.g. a big-endian timestamp followed by an identifier).
+///
+/// [`ExternalSorter::sort_by_fixed_key`]: crate::ExternalSorter::sort_by_fixed_key
+pub trait FixedKeySortable<const N: usize>: Sortable {
+    /// Returns the key of the item.
+    fn fixed_key(&self) -> [u8; N];
+}
+
+/// Compares two fixed-width keys in lexicographic order, 16 bytes at a time.
+pub fn compare_fixed_keys<const N: usize>(a: &[u8; N], b: &[u8; N]) -> Ordering {
+    let (a_chunks, a_rest) = a.as_chunks::<16>();
+    let (b_chunks, b_rest) = b.as_chunks::<16>();
+
+    for (a_chunk, b_chunk) in a_chunks.iter().zip(b_chunks) {
+        match u128::from_be_bytes(*a_chunk).cmp(&u128::from_be_bytes(*b_chunk)) {
+            Ordering::Equal => {}
+            ordering => return ordering,
+        }
+    }
+
+    a_rest.cmp(b_rest)
+}
@@ -0,0 +1,238 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Error, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{framing, Sortable};
+
+/// Item made of a sortable key and an opaque value, as sorted by
+/// [`ExternalSorter::sort_kv`] and [`ExternalSorter::sort_kv_sidecar`].
+///
+/// Only the key participates in the comparison. The value is written after
+/// the key as a length-prefixed byte record, and is copied verbatim between
+/// segments and to the output without ever being decoded, which keeps the
+/// merge cheap when carrying large payloads.
+///
+/// [`ExternalSorter::sort_kv`]: crate::ExternalSorter::sort_kv
+/// [`ExternalSorter::sort_kv_sidecar`]: crate::ExternalSorter::sort_kv_sidecar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValue<K> {
+    pub key: K,
+    pub value: Vec<u8>,
+}
+
+impl<K> KeyValue<K> {
+    pub fn new<V: Into<Vec<u8>>>(key: K, value: V) -> KeyValue<K> {
+        KeyValue {
+            key,
+            value: value.into(),
+        }
+    }
+
+    /// Returns the key and the value of the item.
+    pub fn into_parts(self) -> (K, Vec<u8>) {
+        (self.key, self.value)
+    }
+}
+
+impl<K, V: Into<Vec<u8>>> From<(K, V)> for KeyValue<K> {
+    fn from((key, value): (K, V)) -> Self {
+        KeyValue::new(key, value)
+    }
+}
+
+impl<K: Sortable> Sortable for KeyValue<K> {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.key.encode(writer)?;
+        framing::write_u32_prefixed(writer, &self.value)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let key = K::decode(reader)?;
+        let value = framing::read_u32_prefixed(reader).map_err(framing::truncated)?;
+        Ok(KeyValue { key, value })
+    }
+}
+
+/// Writer of the payloads of a sidecar sort, appending them to a temporary
+/// file in which they are looked up once their keys are sorted.
+pub(crate) struct SidecarWriter {
+    writer: BufWriter<File>,
+    offset: u64,
+}
+
+impl SidecarWriter {
+    pub fn new(file: File) -> SidecarWriter {
+        SidecarWriter {
+            writer: BufWriter::new(file),
+            offset: 0,
+        }
+    }
+
+    /// Appends a payload, returning the entry to sort in place of the item.
+    pub fn append<K>(&mut self, key: K, value: &[u8]) -> Result<SidecarEntry<K>, Error> {
+        self.writer.write_all(value)?;
+        let entry = SidecarEntry {
+            key,
+            offset: self.offset,
+            len: value.len() as u64,
+        };
+        self.offset += entry.len;
+        Ok(entry)
+    }
+
+    pub fn finish(self) -> Result<File, Error> {
+        self.writer.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+/// Key of an item sorted by [`ExternalSorter::sort_kv_sidecar`], along with
+/// the location of its payload in the sidecar file.
+///
+/// [`ExternalSorter::sort_kv_sidecar`]: crate::ExternalSorter::sort_kv_sidecar
+pub(crate) struct SidecarEntry<K> {
+    pub key: K,
+    offset: u64,
+    len: u64,
+}
+
+impl<K: Sortable> Sortable for SidecarEntry<K> {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.key.encode(writer)?;
+        writer.write_all(&self.offset.to_le_bytes())?;
+        writer.write_all(&self.len.to_le_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let key = K::decode(reader)?;
+        let mut location = [0; 16];
+        reader
+            .read_exact(&mut location)
+            .map_err(framing::truncated)?;
+        let (offset, len) = location.split_at(8);
+        Ok(SidecarEntry {
+            key,
+            offset: u64::from_le_bytes(offset.try_into().unwrap()),
+            len: u64::from_le_bytes(len.try_into().unwrap()),
+        })
+    }
+}
+
+/// Iterator over the items sorted by [`ExternalSorter::sort_kv_sidecar`],
+/// reading the payload of each item back from the sidecar file as its key
+/// comes out of the sort.
+///
+/// [`ExternalSorter::sort_kv_sidecar`]: crate::ExternalSorter::sort_kv_sidecar
+pub struct SidecarIterator<K> {
+    entries: Box<dyn Iterator<Item = std::io::Result<SidecarEntry<K>>> + Send>,
+    sidecar: File,
+}
+
+impl<K> SidecarIterator<K> {
+    pub(crate) fn new<I>(entries: I, sidecar: File) -> SidecarIterator<K>
+    where
+        I: Iterator<Item = std::io::Result<SidecarEntry<K>>> + Send + 'static,
+    {
+        SidecarIterator {
+            entries: Box::new(entries),
+            sidecar,
+        }
+    }
+
+    fn read_payload(&mut self, entry: &SidecarEntry<K>) -> std::io::Result<Vec<u8>> {
+        let mut value = vec![0; entry.len as usize];
+        self.sidecar.seek(SeekFrom::Start(entry.offset))?;
+        self.sidecar.read_exact(&mut value)?;
+        Ok(value)
+    }
+}
+
+impl<K> Iterator for SidecarIterator<K> {
+    type Item = std::io::Result<KeyValue<K>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match self.entries.next()? {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(
+            self.read_payload(&entry)
+                .map(|value| KeyValue::new(entry.key, value)),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+/// Key of an item sorted by [`ExternalSorter::argsort`], along with the
+/// position of the item in the input.
+///
+/// [`ExternalSorter::argsort`]: crate::ExternalSorter::argsort
+pub(crate) struct IndexedKey<K> {
+    pub key: K,
+    pub index: u64,
+}
+
+impl<K: Sortable> Sortable for IndexedKey<K> {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.key.encode(writer)?;
+        writer.write_all(&self.index.to_le_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let key = K::decode(reader)?;
+        let mut index = [0; 8];
+        reader.read_exact(&mut index).map_err(framing::truncated)?;
+        Ok(IndexedKey {
+            key,
+            index: u64::from_le_bytes(index),
+        })
+    }
+}
+
+/// Iterator over the positions in the input of the items sorted by
+/// [`ExternalSorter::argsort`], in the sorted order of the items.
+///
+/// [`ExternalSorter::argsort`]: crate::ExternalSorter::argsort
+pub struct ArgsortIterator {
+    entries: Box<dyn Iterator<Item = std::io::Result<u64>> + Send>,
+}
+
+impl ArgsortIterator {
+    pub(crate) fn new<K, I>(entries: I) -> ArgsortIterator
+    where
+        I: Iterator<Item = std::io::Result<IndexedKey<K>>> + Send + 'static,
+    {
+        ArgsortIterator {
+            entries: Box::new(entries.map(|entry| entry.map(|entry| entry.index))),
+        }
+    }
+}
+
+impl Iterator for ArgsortIterator {
+    type Item = std::io::Result<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
@@ -22,7 +22,15 @@
 //!
 //! The sorter can optionally use [`rayon`](https://crates.io/crates/rayon) to
 //! sort the in-memory buffer. It is generally faster when the buffer size is big
-//! enough for parallelism to have an impact over its overhead.
+//! enough for parallelism to have an impact over its overhead. Rayon is only
+//! required by the `parallel` feature, enabled by default, which can be
+//! disabled for targets without threads such as `wasm32-wasip1`.
+//!
+//! Without default features, the crate also builds for `wasm32-unknown-unknown`.
+//! Since that target has no filesystem, all segments are kept in memory (see
+//! [`ExternalSorter::with_in_memory_segments`]), and operations that need
+//! files, such as persisting or merging sorted files, return an `Unsupported`
+//! error.
 //!
 //! # Examples
 //! ```rust
@@ -59,40 +67,332 @@
 
 use std::io::{Read, Write};
 
+pub mod arena;
+mod checksum;
+mod cleanup;
+pub mod compare;
+mod config;
+pub mod context;
+pub mod formats;
+pub mod framing;
 pub mod iter;
+pub mod key;
+pub mod kv;
+#[cfg(all(feature = "memfd", target_os = "linux"))]
+mod memfd;
+mod memory;
+pub mod partition;
 pub mod push;
+pub mod quota;
+mod radix;
+pub mod runs;
+mod segment;
+pub mod sorted_file;
 pub mod sorter;
+pub mod window;
 
-pub use crate::iter::SortedIterator;
-pub use crate::push::PushExternalSorter;
-pub use crate::sorter::ExternalSorter;
+pub use crate::arena::ArenaPushSorter;
+pub use crate::checksum::ChecksumError;
+pub use crate::cleanup::cleanup_stale;
+pub use crate::config::ConfigError;
+pub use crate::context::{ContextSortedIterator, ContextSorter, SortableWithContext};
+pub use crate::framing::VarintBytes;
+#[cfg(feature = "parallel")]
+pub use crate::iter::SortedParMapChunks;
+pub use crate::iter::{
+    SortedChunks, SortedExact, SortedFilter, SortedIterator, SortedIteratorParts, SortedMap,
+    SortedRange, SortedTail,
+};
+pub use crate::key::FixedKeySortable;
+pub use crate::kv::{ArgsortIterator, KeyValue, SidecarIterator};
+pub use crate::memory::MemoryPool;
+pub use crate::partition::{hash_partition, BoundarySampler};
+pub use crate::push::{PushExternalSorter, PushOutcome};
+pub use crate::quota::{DiskQuota, QuotaExceeded, QuotaPolicy};
+pub use crate::runs::RunSet;
+pub use crate::sorted_file::SortedFile;
+pub use crate::sorter::{DuplicatePolicy, ExternalSorter, GroupOrder, SortAlgorithm};
+pub use crate::window::KDisorderedIterator;
 
+/// Item that can be encoded to and decoded from the segments written to disk.
+///
+/// Errors are returned as [`std::io::Error`] so that they can be mixed with
+/// the errors of the underlying files, and are returned by the sorter without
+/// being altered. Items failing with domain-specific errors can implement
+/// [`TypedSortable`] instead.
 pub trait Sortable: Sized + Send {
     /// Encodes the item to the given writer.
     fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()>;
 
     /// Decodes the item from the given reader.
     ///
-    /// Important: the implementation relies on the `UnexpectedEof` error from
-    /// `std::io::Read` to detect the end of the stream.
+    /// Important: unless [`Sortable::try_decode`] is implemented, the sorter
+    /// relies on the `UnexpectedEof` error from `std::io::Read` to detect the
+    /// end of the stream. Other errors are returned by the sorter without
+    /// being altered.
     fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self>;
+
+    /// Decodes the next item from the given reader, or returns `None` if the
+    /// end of the stream was reached before the item.
+    ///
+    /// This is what the sorter uses to read items back, which allows codecs
+    /// that buffer internally (e.g. compression or framing libraries) to tell
+    /// the end of the stream from a failure in the middle of an item, which is
+    /// then returned as an error even if it is of kind `UnexpectedEof`.
+    ///
+    /// The default implementation calls [`Sortable::decode`], and considers an
+    /// `UnexpectedEof` error as the end of the stream.
+    fn try_decode<R: Read>(reader: &mut R) -> std::io::Result<Option<Self>> {
+        match Self::decode(reader) {
+            Ok(item) => Ok(Some(item)),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Item that can be encoded and decoded with a domain-specific error type,
+/// implementing [`Sortable`] through its conversion into a
+/// [`std::io::Error`].
+///
+/// The error is returned as is inside the errors of the sorter and its
+/// iterators if the conversion wraps it using [`std::io::Error::new`], from
+/// which it can be recovered using [`std::io::Error::get_ref`] or
+/// [`std::io::Error::downcast`]:
+///
+/// ```rust
+/// use extsort::*;
+/// use std::io::{Read, Write};
+///
+/// #[derive(Debug)]
+/// enum RecordError {
+///     BadVersion(u8),
+///     Io(std::io::Error),
+/// }
+///
+/// impl std::fmt::Display for RecordError {
+///     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+///         match self {
+///             RecordError::BadVersion(version) => write!(f, "unsupported version {}", version),
+///             RecordError::Io(err) => err.fmt(f),
+///         }
+///     }
+/// }
+///
+/// impl std::error::Error for RecordError {}
+///
+/// impl From<std::io::Error> for RecordError {
+///     fn from(err: std::io::Error) -> RecordError {
+///         RecordError::Io(err)
+///     }
+/// }
+///
+/// impl From<RecordError> for std::io::Error {
+///     fn from(err: RecordError) -> std::io::Error {
+///         match err {
+///             RecordError::Io(err) => err,
+///             err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+///         }
+///     }
+/// }
+///
+/// #[derive(PartialEq, Eq, PartialOrd, Ord)]
+/// struct Record(u8);
+///
+/// impl TypedSortable for Record {
+///     type Error = RecordError;
+///
+///     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), RecordError> {
+///         Ok(writer.write_all(&[self.0])?)
+///     }
+///
+///     fn decode<R: Read>(reader: &mut R) -> Result<Record, RecordError> {
+///         let mut version = [0];
+///         reader.read_exact(&mut version)?;
+///         match version[0] {
+///             1 => Err(RecordError::BadVersion(1)),
+///             value => Ok(Record(value)),
+///         }
+///     }
+/// }
+///
+/// let sorter = ExternalSorter::new().with_segment_size(2);
+/// let err = sorter
+///     .sort([3, 2, 1, 0].map(Record))
+///     .and_then(|sorted| sorted.collect::<std::io::Result<Vec<_>>>())
+///     .err()
+///     .unwrap();
+/// let err = err.into_inner().unwrap().downcast::<RecordError>().unwrap();
+/// assert!(matches!(*err, RecordError::BadVersion(1)));
+/// ```
+pub trait TypedSortable: Sized + Send {
+    /// Error returned when encoding or decoding the item.
+    ///
+    /// Important: as with [`Sortable::decode`], the sorter relies on the
+    /// `UnexpectedEof` error from `std::io::Read` to detect the end of the
+    /// stream, which the conversions from and into [`std::io::Error`] should
+    /// therefore preserve.
+    type Error: From<std::io::Error> + Into<std::io::Error>;
+
+    /// Encodes the item to the given writer.
+    fn encode<W: Write>(&self, writer: &mut W) -> Result<(), Self::Error>;
+
+    /// Decodes the item from the given reader.
+    fn decode<R: Read>(reader: &mut R) -> Result<Self, Self::Error>;
+}
+
+impl<T: TypedSortable> Sortable for T {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        TypedSortable::encode(self, writer).map_err(Into::into)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        <T as TypedSortable>::decode(reader).map_err(Into::into)
+    }
+}
+
+/// Byte records are encoded with their length as a little-endian `u32`
+/// followed by their bytes, and are therefore limited to `u32::MAX` bytes.
+impl Sortable for Vec<u8> {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        framing::write_u32_prefixed(writer, self)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        framing::read_u32_prefixed(reader)
+    }
+}
+
+/// Strings are encoded like byte records, and fail to decode if they aren't
+/// valid UTF-8.
+impl Sortable for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        framing::write_u32_prefixed(writer, self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        String::from_utf8(framing::read_u32_prefixed(reader)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct ExternalSorterOptions {
     pub segment_size: usize,
-    pub heap_iter_segment_count: usize,
+    pub heap_iter_segment_count: Option<usize>,
     pub sort_dir: Option<std::path::PathBuf>,
     pub parallel: bool,
+    pub index_interval: usize,
+    pub reverse_index: bool,
+    pub natural_run_min_len: usize,
+    pub compaction_fan_in: Option<usize>,
+    pub segment_bytes: u64,
+    pub max_segment_bytes: u64,
+    pub in_memory_segments: usize,
+    pub in_memory_segment_bytes: u64,
+    pub duplicate_policy: DuplicatePolicy,
+    pub memory_fraction: f64,
+    pub sort_algorithm: SortAlgorithm,
+    pub deterministic: bool,
+    pub block_size: usize,
+    pub record_checksums: bool,
+    pub prefix_compression: bool,
+    pub delta_key_bytes: usize,
+    pub verify_order: bool,
+    pub check_comparator: bool,
+    pub tempfile_builder: Option<TempfileBuilder>,
+    pub background_thread_name: String,
+    pub background_thread_start: Option<ThreadStartHook>,
+    pub segment_transform: Option<segment::SegmentTransform>,
+    pub disk_quota: Option<quota::DiskQuota>,
+    pub memory_pool: Option<memory::MemoryPool>,
+    #[cfg(feature = "parallel")]
+    pub thread_pool: Option<std::sync::Arc<rayon::ThreadPool>>,
+    #[cfg(all(feature = "memfd", target_os = "linux"))]
+    pub seal_segments: bool,
 }
 
+/// Function creating the scratch files in which segments are written (see
+/// [`ExternalSorter::with_tempfile_builder`]).
+pub(crate) type TempfileBuilder =
+    std::sync::Arc<dyn Fn() -> std::io::Result<std::fs::File> + Send + Sync>;
+
+/// Function called at the start of the background threads spawned by the
+/// sorters (see [`ExternalSorter::with_background_thread_start`]).
+pub(crate) type ThreadStartHook = std::sync::Arc<dyn Fn() + Send + Sync>;
+
 impl Default for ExternalSorterOptions {
     fn default() -> Self {
         ExternalSorterOptions {
             segment_size: 10_000,
-            heap_iter_segment_count: 20,
+            heap_iter_segment_count: None,
             sort_dir: None,
             parallel: false,
+            index_interval: 0,
+            reverse_index: false,
+            natural_run_min_len: 0,
+            compaction_fan_in: None,
+            segment_bytes: 0,
+            max_segment_bytes: 0,
+            // there is no filesystem to write segments to on this target
+            in_memory_segments: if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+                usize::MAX
+            } else {
+                0
+            },
+            in_memory_segment_bytes: 0,
+            duplicate_policy: DuplicatePolicy::default(),
+            memory_fraction: 0.0,
+            sort_algorithm: SortAlgorithm::default(),
+            deterministic: false,
+            block_size: 0,
+            record_checksums: false,
+            prefix_compression: false,
+            delta_key_bytes: 0,
+            verify_order: false,
+            check_comparator: false,
+            tempfile_builder: None,
+            background_thread_name: "extsort-compaction".to_string(),
+            background_thread_start: None,
+            segment_transform: None,
+            disk_quota: None,
+            memory_pool: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+            #[cfg(all(feature = "memfd", target_os = "linux"))]
+            seal_segments: false,
+        }
+    }
+}
+
+impl ExternalSorterOptions {
+    /// Runs the given parallel operation on the configured thread pool, or on
+    /// the current one (by default the global Rayon pool) otherwise.
+    #[cfg(feature = "parallel")]
+    pub fn install<R, OP>(&self, op: OP) -> R
+    where
+        R: Send,
+        OP: FnOnce() -> R + Send,
+    {
+        match &self.thread_pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    /// Returns the builder of the background threads spawned by the sorters,
+    /// named as configured.
+    pub fn background_thread(&self) -> std::thread::Builder {
+        std::thread::Builder::new().name(self.background_thread_name.clone())
+    }
+
+    /// Algorithm to sort the in-memory buffer with, which is always stable in
+    /// deterministic mode.
+    pub fn buffer_sort_algorithm(&self) -> SortAlgorithm {
+        if self.deterministic {
+            SortAlgorithm::Stable
+        } else {
+            self.sort_algorithm
         }
     }
 }
@@ -100,10 +400,12 @@ impl Default for ExternalSorterOptions {
 #[cfg(test)]
 pub mod test {
     use std::io::{Read, Result, Write};
+    use std::sync::{Arc, Mutex};
 
     use super::*;
 
     use byteorder::{ReadBytesExt, WriteBytesExt};
+    use rand::Rng;
 
     #[test]
     fn test_smaller_than_segment() {
@@ -133,6 +435,7 @@ pub mod test {
         assert_eq!(data, sorted_data);
     }
 
+    #[cfg(feature = "parallel")]
     #[test]
     fn test_parallel() {
         let sorter = ExternalSorter::new()
@@ -160,48 +463,3578 @@ pub mod test {
     }
 
     #[test]
-    fn test_error_propagation() {
+    fn test_sort_into_path() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sorted");
+
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let written = sorter.sort_into_path((0..1000u32).rev(), &path).unwrap();
+        assert_eq!(written, 1000);
+
+        let mut file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let mut sorted_data = Vec::new();
+        while let Ok(item) = u32::decode(&mut file) {
+            sorted_data.push(item);
+        }
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_sort_partitioned() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_sort_dir(dir.path().to_path_buf());
+
+        let partitions = sorter
+            .sort_partitioned((0..1000u32).rev(), vec![250, 500, 750])
+            .unwrap();
+        assert_eq!(partitions.len(), 4);
+
+        for (i, partition) in partitions.into_iter().enumerate() {
+            let start = i as u32 * 250;
+            let sorted_data = partition.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(sorted_data, (start..start + 250).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_boundary_sampler() {
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(1000)
+            .pushed()
+            .with_boundary_sampler(1000)
+            .with_filter(|item| item % 10 != 0);
+        sorter.push_iter((0..10_000u32).rev()).unwrap();
+        let sampler = sorter.boundary_sampler().unwrap();
+        assert_eq!(sampler.observed_count(), 9_000);
+
+        let boundaries = sampler.boundaries(4).unwrap();
+        assert_eq!(boundaries.len(), 3);
+        for (boundary, expected) in boundaries.iter().zip([2500, 5000, 7500]) {
+            assert!(
+                boundary.abs_diff(expected) < 1000,
+                "{} vs {}",
+                boundary,
+                expected
+            );
+        }
+
+        // skewed data shouldn't produce duplicate boundaries
+        let mut sampler = BoundarySampler::new(100);
+        for item in std::iter::repeat_n(1u32, 1000).chain(0..10) {
+            sampler.observe(&item).unwrap();
+        }
+        let boundaries = sampler.boundaries(10).unwrap();
+        assert!(boundaries.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_into_shards() {
+        for segment_size in [100, 10_000] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let sorted_iter = sorter.sort((0..1000u32).rev()).unwrap();
+
+            let shards = sorted_iter.into_shards(3).unwrap();
+            assert_eq!(shards.len(), 3);
+
+            let handles = shards
+                .into_iter()
+                .map(|shard| {
+                    std::thread::spawn(move || shard.collect::<Result<Vec<u32>>>().unwrap())
+                })
+                .collect::<Vec<_>>();
+            let sorted_data = handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_seek_to() {
+        for (segment_size, index_interval) in [(10_000, 0), (100, 0), (100, 10), (25, 10)] {
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_index_interval(index_interval);
+            let data = (0..1000u32).map(|i| ((i % 10) * 100 + i / 10) * 2);
+            let mut sorted_iter = sorter.sort(data).unwrap();
+
+            sorted_iter.seek_to(&501).unwrap();
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), 502);
+
+            // seeking back has no effect
+            sorted_iter.seek_to(&10).unwrap();
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), 504);
+
+            sorted_iter.seek_to(&1500).unwrap();
+            let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(
+                sorted_data,
+                (750..1000u32).map(|i| i * 2).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_block_segments() {
+        for (segment_size, block_size) in [(100, 1), (100, 64), (25, 16), (25, 10_000)] {
+            let sorter = || {
+                ExternalSorter::new()
+                    .with_segment_size(segment_size)
+                    .with_block_size(block_size)
+            };
+            let data = || (0..1000u32).map(|i| ((i % 10) * 100 + i / 10) * 2);
+
+            let sorted_data = sorter()
+                .with_heap_iter_segment_count(2)
+                .sort(data())
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).map(|i| i * 2).collect::<Vec<_>>());
+
+            let sorted_data = sorter()
+                .with_compaction(4)
+                .sort(data())
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).map(|i| i * 2).collect::<Vec<_>>());
+
+            let mut sorted_iter = sorter().sort(data()).unwrap();
+            sorted_iter.seek_to(&501).unwrap();
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), 502);
+            sorted_iter.seek_to(&1500).unwrap();
+            assert_eq!(sorted_iter.count(), 250);
+
+            // the block index is loaded from the footer of the segments
+            let parts = sorter().sort(data()).unwrap().into_parts().unwrap();
+            let mut sorted_iter = SortedIterator::from_parts(parts).unwrap();
+            sorted_iter.seek_to(&1500).unwrap();
+            let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(
+                sorted_data,
+                (750..1000u32).map(|i| i * 2).collect::<Vec<_>>()
+            );
+
+            let mut sorter = sorter().pushed_bytes();
+            sorter.push_iter(data().map(|i| i.to_be_bytes())).unwrap();
+            let sorted_data = sorter
+                .done()
+                .unwrap()
+                .map(|item| u32::from_be_bytes(item.unwrap().try_into().unwrap()))
+                .collect::<Vec<_>>();
+            assert_eq!(sorted_data, (0..1000u32).map(|i| i * 2).collect::<Vec<_>>());
+        }
+
+        // an item count not matching the items of a block is detected
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_block_size(40);
+        let mut parts = sorter
+            .sort((0..1000u32).rev())
+            .unwrap()
+            .into_parts()
+            .unwrap();
+        let file = &mut parts.segment_files[0];
+        std::io::Seek::seek(file, std::io::SeekFrom::Start(12)).unwrap();
+        file.write_all(&11u32.to_le_bytes()).unwrap();
+        let err = SortedIterator::from_parts(parts)
+            .and_then(|iter| iter.collect::<Result<Vec<u32>>>())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let err = ExternalSorter::new()
+            .with_block_size(40)
+            .with_reverse_index()
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::ReverseIndexWithBlocks);
+    }
+
+    #[test]
+    fn test_record_checksums() {
+        for (segment_size, block_size) in [(10_000, 0), (100, 0), (25, 0), (100, 64)] {
+            let sorter = || {
+                ExternalSorter::new()
+                    .with_segment_size(segment_size)
+                    .with_block_size(block_size)
+                    .with_index_interval(10)
+                    .with_record_checksums()
+            };
+            let data = || (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+
+            let sorted_data = sorter()
+                .sort(data())
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+            let mut sorted_iter = sorter().sort(data()).unwrap();
+            sorted_iter.seek_to(&750).unwrap();
+            assert_eq!(sorted_iter.count(), 250);
+
+            // the checksums are detected from the segment files
+            let parts = sorter().sort(data()).unwrap().into_parts().unwrap();
+            let sorted_data = SortedIterator::from_parts(parts)
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+            let mut sorter = sorter().pushed_bytes();
+            sorter.push_iter(data().map(|i| i.to_be_bytes())).unwrap();
+            assert_eq!(sorter.done().unwrap().count(), 1000);
+        }
+
+        let sorted_data = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_reverse_index()
+            .with_record_checksums()
+            .sort((0..1000u32).map(|i| (i % 10) * 100 + i / 10))
+            .unwrap()
+            .rev()
+            .collect::<Result<Vec<u32>>>()
+            .unwrap();
+        assert_eq!(sorted_data, (0..1000u32).rev().collect::<Vec<_>>());
+
+        // a corrupted item is detected when read back
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_block_size(40)
+            .with_record_checksums();
+        let mut parts = sorter
+            .sort((0..1000u32).rev())
+            .unwrap()
+            .into_parts()
+            .unwrap();
+        let file = &mut parts.segment_files[0];
+        std::io::Seek::seek(file, std::io::SeekFrom::Start(24)).unwrap();
+        file.write_all(&[0xFF]).unwrap();
+        let err = SortedIterator::from_parts(parts)
+            .and_then(|iter| iter.collect::<Result<Vec<u32>>>())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let checksum_err = err.get_ref().unwrap().downcast_ref::<ChecksumError>();
+        assert!(checksum_err.is_some());
+    }
+
+    #[test]
+    fn test_range() {
+        use std::ops::Bound;
+
+        for segment_size in [10_000, 100, 25] {
+            let sort = || {
+                ExternalSorter::new()
+                    .with_segment_size(segment_size)
+                    .with_index_interval(10)
+                    .sort((0..1000u32).map(|i| (i % 10) * 100 + i / 10))
+                    .unwrap()
+            };
+
+            let range = sort().range(100..200).unwrap();
+            let sorted_data = range.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(sorted_data, (100..200u32).collect::<Vec<_>>());
+
+            let range = sort().range(990..).unwrap();
+            assert_eq!(range.count(), 10);
+
+            let range = sort().range(..=9).unwrap();
+            assert_eq!(range.count(), 10);
+
+            let range = sort()
+                .range((Bound::Excluded(10), Bound::Excluded(20)))
+                .unwrap();
+            let sorted_data = range.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(sorted_data, (11..20u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_non_overlapping_segments() {
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let mut sorted_iter = sorter.sort((0..1000u32).rev()).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 10);
+
+        sorted_iter.seek_to(&450).unwrap();
+        let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+        assert_eq!(sorted_data, (450..1000u32).collect::<Vec<_>>());
+
+        // overlapping segments need to be merged
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let sorted_iter = sorter.sort(data).unwrap();
+        let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_size_hint() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_index_interval(10);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let mut sorted_iter = sorter.sort(data).unwrap();
+            assert_eq!(sorted_iter.size_hint(), (1000, Some(1000)));
+
+            sorted_iter.next().unwrap().unwrap();
+            assert_eq!(sorted_iter.size_hint(), (999, Some(999)));
+
+            sorted_iter.seek_to(&500).unwrap();
+            assert_eq!(sorted_iter.size_hint(), (500, Some(500)));
+
+            let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(sorted_data.len(), 500);
+        }
+
+        // sequential segments are skipped as a whole
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let mut sorted_iter = sorter.sort(0..1000u32).unwrap();
+        sorted_iter.seek_to(&950).unwrap();
+        assert_eq!(sorted_iter.size_hint(), (50, Some(50)));
+
+        let mut exact = sorted_iter.into_exact().unwrap();
+        assert_eq!(exact.len(), 50);
+        exact.next().unwrap().unwrap();
+        assert_eq!(exact.len(), 49);
+        assert_eq!(
+            exact.collect::<Result<Vec<u32>>>().unwrap(),
+            (951..1000).collect::<Vec<_>>()
+        );
+
+        // the number of items isn't known once duplicates are dropped
+        let sorter = ExternalSorter::new()
+            .with_segment_size(10)
+            .with_duplicate_policy(DuplicatePolicy::KeepFirst);
+        let sorted_iter = sorter.sort((0..100u32).map(|i| i % 50)).unwrap();
+        assert_eq!(sorted_iter.known_count(), None);
+        assert_eq!(sorted_iter.size_hint(), (0, None));
+        let mapped = sorted_iter.map_items(|item| item * 2);
+        assert_eq!(mapped.size_hint(), (0, None));
+        assert_eq!(mapped.count(), 50);
+
+        let sorter = ExternalSorter::new()
+            .with_segment_size(10)
+            .with_duplicate_policy(DuplicatePolicy::KeepFirst);
+        let sorted_iter = sorter.sort((0..100u32).map(|i| i % 50)).unwrap();
+        assert!(sorted_iter.into_exact().is_none());
+    }
+
+    #[test]
+    fn test_fused() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let mut sorted_iter = sorter.sort(data).unwrap();
+            assert_eq!(sorted_iter.by_ref().count(), 1000);
+            for _ in 0..3 {
+                assert!(sorted_iter.next().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static DECODED: AtomicUsize = AtomicUsize::new(0);
+
         #[derive(PartialEq, Eq, PartialOrd, Ord)]
-        struct ErrStruct(u32);
-        impl Sortable for ErrStruct {
+        struct CountedDecode(u32);
+        impl Sortable for CountedDecode {
             fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
-                writer.write_u32::<byteorder::LittleEndian>(self.0)?;
-                Ok(())
+                writer.write_u32::<byteorder::LittleEndian>(self.0)
             }
 
-            fn decode<R: Read>(reader: &mut R) -> std::io::Result<ErrStruct> {
-                let value = reader.read_u32::<byteorder::LittleEndian>()?;
-                if value == 1 {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "MyStruct::decode error",
-                    ))
-                } else {
-                    Ok(ErrStruct(value))
-                }
+            fn decode<R: Read>(reader: &mut R) -> Result<CountedDecode> {
+                DECODED.fetch_add(1, Ordering::Relaxed);
+                reader
+                    .read_u32::<byteorder::LittleEndian>()
+                    .map(CountedDecode)
             }
         }
 
-        let mut sorter = ExternalSorter::new().with_segment_size(10).pushed();
-        for item in 0..100 {
-            sorter.push(ErrStruct(item)).unwrap();
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let data = (0..1000).map(|i| CountedDecode((i % 10) * 100 + i / 10));
+        let sorted_iter = sorter.sort(data).unwrap();
+
+        let decoded_before = DECODED.load(Ordering::Relaxed);
+        assert_eq!(sorted_iter.count(), 1000);
+        assert_eq!(DECODED.load(Ordering::Relaxed), decoded_before);
+    }
+
+    #[test]
+    fn test_peek() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let mut sorted_iter = sorter.sort(data).unwrap();
+
+            assert_eq!(*sorted_iter.peek().unwrap().as_ref().unwrap(), 0);
+            assert_eq!(*sorted_iter.peek().unwrap().as_ref().unwrap(), 0);
+            assert_eq!(sorted_iter.size_hint(), (1000, Some(1000)));
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), 0);
+
+            sorted_iter.seek_to(&10).unwrap();
+            assert_eq!(*sorted_iter.peek().unwrap().as_ref().unwrap(), 10);
+            sorted_iter.seek_to(&5).unwrap();
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), 10);
+
+            let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(sorted_data, (11..1000u32).collect::<Vec<_>>());
         }
+    }
 
-        // first value is fine, but second should fail
-        let sorted_iter = sorter.done().unwrap();
-        let res = sorted_iter.take(1).next().unwrap();
-        assert!(res.is_err());
+    #[test]
+    fn test_double_ended() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_reverse_index();
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let sorted_iter = sorter.sort(data).unwrap();
+
+            let sorted_data = sorted_iter.rev().collect::<Result<Vec<u32>>>().unwrap();
+            assert_eq!(sorted_data, (0..1000u32).rev().collect::<Vec<_>>());
+        }
+
+        // consuming from both ends meets in the middle
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_reverse_index();
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let mut sorted_iter = sorter.sort(data).unwrap();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        assert_eq!(*sorted_iter.peek().unwrap().as_ref().unwrap(), 0);
+        while let Some(item) = sorted_iter.next_back() {
+            back.push(item.unwrap());
+            if let Some(item) = sorted_iter.next() {
+                front.push(item.unwrap());
+            }
+        }
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, (0..1000u32).collect::<Vec<_>>());
+
+        // disk segments without a reverse index can't be consumed from the end
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let mut sorted_iter = sorter.sort(0..1000u32).unwrap();
+        assert!(sorted_iter.next_back().unwrap().is_err());
     }
 
-    impl Sortable for u32 {
-        fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
-            writer.write_u32::<byteorder::LittleEndian>(*self)?;
-            Ok(())
+    #[test]
+    fn test_chunks() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let chunks = sorter.sort(data).unwrap().chunks(300);
+            assert_eq!(chunks.size_hint(), (4, Some(4)));
+
+            let chunks = chunks.collect::<Result<Vec<Vec<u32>>>>().unwrap();
+            assert_eq!(
+                chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(),
+                vec![300, 300, 300, 100]
+            );
+            assert_eq!(chunks.concat(), (0..1000u32).collect::<Vec<_>>());
         }
 
-        fn decode<R: Read>(reader: &mut R) -> std::io::Result<u32> {
-            reader.read_u32::<byteorder::LittleEndian>()
+        let sorter = ExternalSorter::new();
+        let sorted_iter = sorter.sort(std::iter::empty::<u32>()).unwrap();
+        assert_eq!(sorted_iter.chunks(10).count(), 0);
+    }
+
+    #[test]
+    fn test_collect_sorted() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let sorted_data = sorter.sort(data).unwrap().collect_sorted().unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_par_map_chunks() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let chunks = sorter
+                .sort(data)
+                .unwrap()
+                .par_map_chunks(64, |item| item as u64 * 2)
+                .collect::<Result<Vec<Vec<u64>>>>()
+                .unwrap();
+
+            assert_eq!(chunks.len(), 16);
+            assert_eq!(
+                chunks.concat(),
+                (0..1000u64).map(|i| i * 2).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_already_sorted() {
+        for segment_size in [10_000, 100, 25] {
+            // sorted input, with duplicates spanning segments
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| i / 3);
+            let sorted_data = sorter.sort(data).unwrap().collect_sorted().unwrap();
+            assert_eq!(sorted_data, (0..1000u32).map(|i| i / 3).collect::<Vec<_>>());
+
+            // sorted input with a single unordered item in the last segment
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..999u32).chain(std::iter::once(500));
+            let sorted_data = sorter.sort(data).unwrap().collect_sorted().unwrap();
+            let mut expected = (0..999u32).chain(std::iter::once(500)).collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(sorted_data, expected);
+        }
+    }
+
+    #[test]
+    fn test_natural_runs() {
+        // 10 ascending runs of 100 items
+        let sorter = ExternalSorter::new().with_natural_runs(50);
+        let data = (0..1000u32).map(|i| (i % 100) * 10 + i / 100);
+        let sorted_iter = sorter.sort(data).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 10);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+        // runs shorter than the minimum length are buffered and sorted
+        let sorter = ExternalSorter::new().with_natural_runs(50);
+        let shuffled = (0..100u32).map(|i| (i % 10) * 10 + i / 10 + 1000);
+        let data = (0..200u32).chain(shuffled).chain(200..400u32);
+        let sorted_iter = sorter.sort(data).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 3);
+        let mut expected = (0..400u32).chain(1000..1100u32).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sort_k_disordered() {
+        // each item is at most 3 positions away from its sorted position
+        let data = (0..1000u32).map(|i| if i % 4 == 0 { i + 3 } else { i - 1 });
+        let sorted = ExternalSorter::new()
+            .sort_k_disordered(data.clone(), 3)
+            .collect::<Vec<_>>();
+        assert_eq!(sorted, (0..1000u32).collect::<Vec<_>>());
+
+        // a window that is too small doesn't sort the output
+        let sorted = ExternalSorter::new()
+            .sort_k_disordered(data.clone(), 0)
+            .collect::<Vec<_>>();
+        assert_eq!(sorted, data.collect::<Vec<_>>());
+
+        // equal items are emitted in the order they were received
+        let data = vec![(1u32, 'a'), (0, 'b'), (1, 'c'), (0, 'd')];
+        let sorted = ExternalSorter::new()
+            .sort_k_disordered_by_key(data, 3, |item| item.0)
+            .map(|item| item.1)
+            .collect::<String>();
+        assert_eq!(sorted, "bdac");
+    }
+
+    #[test]
+    fn test_push_watermark() {
+        for segment_size in [10_000, 100, 25] {
+            let sort_dir = tempfile::TempDir::new().unwrap();
+            let mut sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_sort_dir(sort_dir.path().to_path_buf())
+                .pushed();
+
+            // blocks of 100 items in random order, where no item of a block is
+            // smaller than the middle of the previous block
+            let mut emitted = Vec::new();
+            for block in 0..10u32 {
+                let data = (0..100u32).map(|i| block * 100 + (i % 10) * 10 + i / 10);
+                sorter.push_iter(data).unwrap();
+
+                let watermark = block * 100 + 50;
+                let count = sorter
+                    .push_watermark(&watermark, |item| {
+                        emitted.push(item);
+                        Ok(())
+                    })
+                    .unwrap();
+                assert_eq!(count, if block == 0 { 50 } else { 100 });
+                assert_eq!(*emitted.last().unwrap(), watermark - 1);
+
+                // segments of emitted items are deleted
+                assert!(segment_files(sort_dir.path()).len() <= 2);
+            }
+
+            emitted.extend(sorter.done().unwrap().collect_sorted().unwrap());
+            assert_eq!(emitted, (0..1000u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_compaction() {
+        for fan_in in [2, 4, 8] {
+            let sort_dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(25)
+                .with_compaction(fan_in)
+                .with_sort_dir(sort_dir.path().to_path_buf());
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let sorted_iter = sorter.sort(data).unwrap();
+
+            // without compaction, 39 segments would be written
+            let segment_count = sorted_iter.disk_segment_count();
+            assert!(segment_count < 20, "{segment_count} segments");
+
+            // compacted segments are deleted
+            assert_eq!(segment_files(sort_dir.path()).len(), segment_count);
+
+            assert_eq!(sorted_iter.sorted_count(), 1000);
+            let sorted_data = sorted_iter.collect_sorted().unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        // segments are merged on another thread while items are pushed
+        let threads = Arc::new(Mutex::new(std::collections::HashSet::new()));
+        let cmp = {
+            let threads = threads.clone();
+            move |a: &u32, b: &u32| {
+                let thread = std::thread::current();
+                let name = thread.name().map(str::to_string);
+                threads.lock().unwrap().insert((thread.id(), name));
+                a.cmp(b)
+            }
+        };
+        let thread_names = || {
+            let threads = threads.lock().unwrap();
+            let names = threads.iter().filter_map(|(_, name)| name.clone());
+            names.collect::<std::collections::HashSet<_>>()
+        };
+        let sorted_iter = ExternalSorter::new()
+            .with_segment_size(25)
+            .with_compaction(4)
+            .sort_by((0..1000u32).map(|i| (i % 10) * 100 + i / 10), cmp.clone())
+            .unwrap();
+        assert!(sorted_iter.disk_segment_count() < 10);
+        assert_eq!(threads.lock().unwrap().len(), 2);
+        assert!(thread_names().contains("extsort-compaction"));
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+        threads.lock().unwrap().clear();
+        let started = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(25)
+            .with_compaction(4)
+            .with_background_thread_name("compaction")
+            .with_background_thread_start({
+                let started = started.clone();
+                move || {
+                    started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            })
+            .pushed_by(cmp)
+            .with_background_compaction();
+        sorter
+            .push_iter((0..1000u32).map(|i| (i % 10) * 100 + i / 10))
+            .unwrap();
+        assert!(sorter.segment_count() < 10);
+        let sorted_iter = sorter.done().unwrap();
+        assert_eq!(threads.lock().unwrap().len(), 2);
+        assert!(thread_names().contains("compaction"));
+        assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_segment_bytes() {
+        // each item is encoded in 4 bytes, so 400 bytes segments contain about
+        // 100 items once the first segment of about 10 items was written
+        let sorter = ExternalSorter::new()
+            .with_segment_size(10)
+            .with_segment_bytes(400);
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let sorted_iter = sorter.sort(data).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 11);
+
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_auto_memory_limit() {
+        let meminfo = "MemTotal:        6158152 kB\nMemAvailable:    5562056 kB\n";
+        assert_eq!(crate::memory::parse_meminfo(meminfo), Some(5562056 * 1024));
+        assert_eq!(crate::memory::parse_meminfo("MemTotal: 1 kB"), None);
+
+        assert_eq!(
+            crate::memory::parse_cgroup_memory("4294967296\n", "1073741824\n"),
+            Some(3 << 30)
+        );
+        assert_eq!(
+            crate::memory::parse_cgroup_memory("max\n", "1073741824\n"),
+            None
+        );
+        assert_eq!(crate::memory::parse_cgroup_memory("1024", "2048"), Some(0));
+
+        let files = crate::memory::cgroup_memory_files(
+            "12:cpu,cpuacct:/kubepods/pod1\n11:memory:/kubepods/pod1\n0::/kubepods/pod1\n",
+        );
+        let limits = files
+            .iter()
+            .map(|(limit, _)| limit.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            limits,
+            [
+                "/sys/fs/cgroup/memory/kubepods/pod1/memory.limit_in_bytes",
+                "/sys/fs/cgroup/memory/kubepods/memory.limit_in_bytes",
+                "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+                "/sys/fs/cgroup/kubepods/pod1/memory.max",
+                "/sys/fs/cgroup/kubepods/memory.max",
+                "/sys/fs/cgroup/memory.max",
+            ]
+        );
+        assert!(files[3].1.ends_with("pod1/memory.current"));
+
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_auto_memory_limit(0.000_001);
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let sorted_data = sorter.sort(data).unwrap().collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_spill_now() {
+        let mut sorter = ExternalSorter::new().pushed();
+        sorter.spill_now().unwrap();
+        sorter.push_iter((0..100u32).rev()).unwrap();
+        sorter.spill_now().unwrap();
+        sorter.push_iter((100..200u32).rev()).unwrap();
+
+        let sorted_iter = sorter.done().unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 2);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..200u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_buffer_watermark() {
+        let reached = Arc::new(Mutex::new(Vec::new()));
+        let mut sorter = ExternalSorter::new().pushed();
+        let reached_clone = reached.clone();
+        sorter.on_buffer_watermark(vec![80, 50], move |watermark| {
+            reached_clone.lock().unwrap().push(watermark);
+            watermark == 80
+        });
+
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        sorter.push_iter(data).unwrap();
+        let sorted_iter = sorter.done().unwrap();
+
+        // the buffer is spilled every 80 items
+        assert_eq!(sorted_iter.disk_segment_count(), 13);
+        assert_eq!(reached.lock().unwrap().len(), 24);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_sort_by_u64_key() {
+        let mut rng = rand::thread_rng();
+        let data = (0..1000u32)
+            .map(|_| rng.gen::<u32>() >> (rng.gen::<u32>() % 32))
+            .collect::<Vec<_>>();
+        let mut expected = data.clone();
+        expected.sort();
+
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let sorted_iter = sorter
+                .sort_by_u64_key(data.clone(), |item| *item as u64)
+                .unwrap();
+            assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+        }
+
+        // equal keys keep the order in which they were pushed
+        let sorter = ExternalSorter::new();
+        let data = (0..1000u32).rev();
+        let sorted_iter = sorter
+            .sort_by_u64_key(data, |item| (*item % 2) as u64)
+            .unwrap();
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        let expected = (0..1000u32)
+            .rev()
+            .filter(|i| i % 2 == 0)
+            .chain((0..1000u32).rev().filter(|i| i % 2 == 1))
+            .collect::<Vec<_>>();
+        assert_eq!(sorted_data, expected);
+    }
+
+    #[test]
+    fn test_sort_algorithm() {
+        // items are sorted by their first field only, equal items keep their
+        // push order with a stable sort
+        let data = (0..1000u32)
+            .rev()
+            .map(|i| (i % 10) * 1_000 + i)
+            .collect::<Vec<_>>();
+        let key = |item: &u32| *item / 1_000;
+
+        let sorters = [
+            ExternalSorter::new(),
+            #[cfg(feature = "parallel")]
+            ExternalSorter::new().with_parallel_sort(),
+        ];
+        for sorter in sorters {
+            let sorter = sorter.with_sort_algorithm(SortAlgorithm::Stable);
+            let sorted_iter = sorter.sort_by_key(data.clone(), key).unwrap();
+            let mut expected = data.clone();
+            expected.sort_by_key(key);
+            assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+        }
+
+        // custom buffer sort
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .pushed()
+            .with_buffer_sort(|buffer: &mut [u32]| buffer.sort());
+        sorter.push_iter(data.clone()).unwrap();
+        let mut expected = data.clone();
+        expected.sort();
+        assert_eq!(sorter.done().unwrap().collect_sorted().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sort_by_fixed_key() {
+        #[derive(Debug, PartialEq, Eq)]
+        struct Record([u8; 20]);
+
+        impl Sortable for Record {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+                writer.write_all(&self.0)
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> Result<Self> {
+                let mut key = [0; 20];
+                reader.read_exact(&mut key)?;
+                Ok(Record(key))
+            }
+        }
+
+        impl FixedKeySortable<20> for Record {
+            fn fixed_key(&self) -> [u8; 20] {
+                self.0
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        let data = (0..1000)
+            .map(|_| {
+                let mut key = [0; 20];
+                rng.fill(&mut key[..]);
+                // share the first chunk between many keys to compare the rest
+                let prefix = key[0] % 2;
+                key[..16].fill(prefix);
+                Record(key)
+            })
+            .collect::<Vec<_>>();
+        let mut expected = data.iter().map(|record| record.0).collect::<Vec<_>>();
+        expected.sort();
+
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = data.iter().map(|record| Record(record.0));
+            let sorted_data = sorter
+                .sort_by_fixed_key(data)
+                .unwrap()
+                .collect_sorted()
+                .unwrap();
+            let sorted_keys = sorted_data
+                .iter()
+                .map(|record| record.0)
+                .collect::<Vec<_>>();
+            assert_eq!(sorted_keys, expected);
+        }
+    }
+
+    #[test]
+    fn test_arena_push_sorter() {
+        let data = (0..1000u32)
+            .map(|i| format!("item-{}", (i % 10) * 100 + i / 10))
+            .collect::<Vec<_>>();
+        let mut expected = data
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        expected.sort();
+
+        for segment_size in [10_000, 100, 25] {
+            let mut sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .pushed_bytes();
+            sorter.push_iter(&data).unwrap();
+            let sorted_iter = sorter.done().unwrap();
+            assert_eq!(sorted_iter.sorted_count(), 1000);
+            assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+        }
+
+        // custom comparator, with the empty record
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(10)
+            .pushed_bytes_by(|a, b| b.cmp(a));
+        sorter.push_iter(&data).unwrap();
+        sorter.push(b"").unwrap();
+        let sorted_data = sorter.done().unwrap().collect_sorted().unwrap();
+        let mut expected = expected.clone();
+        expected.insert(0, Vec::new());
+        expected.reverse();
+        assert_eq!(sorted_data, expected);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_sort_csv() {
+        use crate::formats::csv::{ColumnType, CsvSorter};
+
+        let mut input = String::from("name,score,date\r\n");
+        for i in 0..1000u32 {
+            let value = (i % 10) * 100 + i / 10;
+            input.push_str(&format!(
+                "\"item, {}\",{},2024-{:02}-01\r\n",
+                value,
+                value % 7,
+                value % 12 + 1,
+            ));
+        }
+        input.push_str("\"multi\nline \"\"quoted\"\"\",,not a date");
+
+        for segment_size in [10_000, 100, 25] {
+            let mut output = Vec::new();
+            let count = CsvSorter::new(ExternalSorter::new().with_segment_size(segment_size))
+                .with_column_desc(1, ColumnType::Numeric)
+                .with_column(2, ColumnType::Date)
+                .with_column(0, ColumnType::String)
+                .sort(input.as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(count, 1001);
+
+            let output = String::from_utf8(output).unwrap();
+            let mut rows = output.split("\r\n");
+            assert_eq!(rows.next().unwrap(), "name,score,date");
+
+            let mut expected = (0..1000u32).collect::<Vec<_>>();
+            expected.sort_by_key(|value| {
+                (
+                    std::cmp::Reverse(value % 7),
+                    value % 12,
+                    format!("item, {}", value),
+                )
+            });
+            for value in expected {
+                assert_eq!(
+                    rows.next().unwrap(),
+                    format!(
+                        "\"item, {}\",{},2024-{:02}-01",
+                        value,
+                        value % 7,
+                        value % 12 + 1
+                    )
+                );
+            }
+
+            // the row without parsable values is written last, as is
+            assert_eq!(
+                rows.next().unwrap(),
+                "\"multi\nline \"\"quoted\"\"\",,not a date\n"
+            );
+            assert_eq!(rows.next(), None);
+        }
+
+        // more columns than fit in a byte
+        let sorter = (0..300).fold(
+            CsvSorter::new(ExternalSorter::new().with_segment_size(10)).with_headers(false),
+            |sorter, _| sorter.with_column(0, ColumnType::Numeric),
+        );
+        let input = (0..100)
+            .rev()
+            .map(|i| format!("{}\n", i))
+            .collect::<String>();
+        let mut output = Vec::new();
+        assert_eq!(sorter.sort(input.as_bytes(), &mut output).unwrap(), 100);
+        let expected = (0..100).map(|i| format!("{}\n", i)).collect::<String>();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[cfg(feature = "jsonl")]
+    #[test]
+    fn test_sort_jsonl() {
+        use crate::formats::jsonl::JsonlSorter;
+
+        let mut input = String::new();
+        for i in 0..1000u32 {
+            let value = (i % 10) * 100 + i / 10;
+            input.push_str(&format!(
+                "{{\"user\":{{\"group\":{}}},\"name\":\"item {}\"}}\n",
+                value % 7,
+                value
+            ));
+        }
+        input.push_str("\n{\"name\":\"no group\"}\r\n");
+
+        for segment_size in [10_000, 100, 25] {
+            let mut output = Vec::new();
+            let count = JsonlSorter::new(ExternalSorter::new().with_segment_size(segment_size))
+                .with_key_desc("/user/group")
+                .with_key("/name")
+                .sort(input.as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(count, 1001);
+
+            let output = String::from_utf8(output).unwrap();
+            let mut lines = output.lines();
+
+            let mut expected = (0..1000u32).collect::<Vec<_>>();
+            expected.sort_by_key(|value| (std::cmp::Reverse(value % 7), format!("item {}", value)));
+            for value in expected {
+                assert_eq!(
+                    lines.next().unwrap(),
+                    format!(
+                        "{{\"user\":{{\"group\":{}}},\"name\":\"item {}\"}}",
+                        value % 7,
+                        value
+                    )
+                );
+            }
+
+            // missing keys are sorted first, so last in descending order
+            assert_eq!(lines.next().unwrap(), "{\"name\":\"no group\"}");
+            assert_eq!(lines.next(), None);
+        }
+
+        let err = JsonlSorter::new(ExternalSorter::new())
+            .with_key("/name")
+            .sort("{}\nnot json\n".as_bytes(), &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // more keys than fit in a byte
+        let sorter = (0..300).fold(
+            JsonlSorter::new(ExternalSorter::new().with_segment_size(10)),
+            |sorter, _| sorter.with_key("/id"),
+        );
+        let input = (0..100)
+            .rev()
+            .map(|i| format!("{{\"id\":{}}}\n", i))
+            .collect::<String>();
+        let mut output = Vec::new();
+        assert_eq!(sorter.sort(input.as_bytes(), &mut output).unwrap(), 100);
+        let expected = (0..100)
+            .map(|i| format!("{{\"id\":{}}}\n", i))
+            .collect::<String>();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sort_multi_line_records() {
+        use crate::formats::records::{RecordFraming, RecordSorter};
+
+        let values = (0..1000u32)
+            .map(|i| (i % 10) * 100 + i / 10)
+            .collect::<Vec<_>>();
+        let fastq = values
+            .iter()
+            .map(|value| format!("@read{}\nACGT\n+\nIIII\n", value))
+            .collect::<String>();
+        let entries = values
+            .iter()
+            .map(|value| format!("ID {}\r\nSQ\r\n//\r\n", value))
+            .collect::<String>();
+
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let mut output = Vec::new();
+            let count = RecordSorter::new(sorter, RecordFraming::Lines(4))
+                .sort_by_key(fastq.as_bytes(), &mut output, |lines| {
+                    let id = std::str::from_utf8(&lines[0][5..]).unwrap();
+                    id.parse::<u32>().unwrap()
+                })
+                .unwrap();
+            assert_eq!(count, 1000);
+            let expected = (0..1000)
+                .map(|value| format!("@read{}\nACGT\n+\nIIII\n", value))
+                .collect::<String>();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+            // the separator is added to the last record if it is missing
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let mut output = Vec::new();
+            let input = format!("{}ID 1000\r\nSQ", entries);
+            let count = RecordSorter::new(sorter, RecordFraming::Separator("//".to_string()))
+                .sort_by_key(input.as_bytes(), &mut output, |lines| lines[0].to_vec())
+                .unwrap();
+            assert_eq!(count, 1001);
+            let mut expected = (0..1000)
+                .map(|value| format!("ID {}\r\nSQ\r\n//\r\n", value))
+                .collect::<Vec<_>>();
+            expected.push("ID 1000\r\nSQ\n//\n".to_string());
+            expected.sort_by_key(|entry| entry.lines().next().unwrap().to_string());
+            assert_eq!(String::from_utf8(output).unwrap(), expected.concat());
+        }
+
+        let err = RecordSorter::new(ExternalSorter::new(), RecordFraming::Lines(4))
+            .sort_by_key("@read\nACGT\n".as_bytes(), &mut Vec::new(), |lines| {
+                lines[0].to_vec()
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_framing() {
+        use crate::framing::*;
+
+        let mut encoded = Vec::new();
+        let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+        for value in values {
+            write_varint(&mut encoded, value).unwrap();
+        }
+        assert_eq!(encoded[..5], [0, 1, 127, 0x80, 1]);
+        let mut reader = encoded.as_slice();
+        for value in values {
+            assert_eq!(read_varint(&mut reader).unwrap(), value);
+        }
+        let err = read_varint(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let err = read_varint(&mut [0xff; 11].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // only the end of the stream between records is reported as an EOF
+        let mut encoded = Vec::new();
+        write_varint_prefixed(&mut encoded, b"hello").unwrap();
+        write_u32_prefixed(&mut encoded, b"world").unwrap();
+        for len in 0..encoded.len() {
+            let mut reader = &encoded[..len];
+            let result =
+                read_varint_prefixed(&mut reader).and_then(|_| read_u32_prefixed(&mut reader));
+            let expected = if len == 0 || len == 6 {
+                std::io::ErrorKind::UnexpectedEof
+            } else {
+                std::io::ErrorKind::InvalidData
+            };
+            assert_eq!(result.unwrap_err().kind(), expected);
+        }
+        let mut reader = encoded.as_slice();
+        assert_eq!(read_varint_prefixed(&mut reader).unwrap(), b"hello");
+        assert_eq!(read_u32_prefixed(&mut reader).unwrap(), b"world");
+
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data =
+                (0..1000u32).map(|i| VarintBytes(((i % 10) * 100 + i / 10).to_be_bytes().to_vec()));
+            let sorted = sorter.sort(data).unwrap().collect_sorted().unwrap();
+            let expected = (0..1000u32)
+                .map(|i| VarintBytes(i.to_be_bytes().to_vec()))
+                .collect::<Vec<_>>();
+            assert_eq!(sorted, expected);
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_compare() {
+        use crate::compare::{ascii_case_insensitive, unicode_case_insensitive};
+        use std::cmp::Ordering;
+
+        assert_eq!(ascii_case_insensitive("Hello", "hELLO"), Ordering::Equal);
+        assert_eq!(ascii_case_insensitive("a", "B"), Ordering::Less);
+        assert_eq!(ascii_case_insensitive("ab", "A"), Ordering::Greater);
+        assert_eq!(ascii_case_insensitive("É", "é"), Ordering::Less);
+
+        assert_eq!(unicode_case_insensitive("É", "é"), Ordering::Equal);
+        assert_eq!(
+            unicode_case_insensitive("Straße", "STRASSE"),
+            Ordering::Equal
+        );
+        assert_eq!(unicode_case_insensitive("ΣΑΣ", "σας"), Ordering::Equal);
+        assert_eq!(unicode_case_insensitive("a", "B"), Ordering::Less);
+        assert_eq!(unicode_case_insensitive("z", "É"), Ordering::Less);
+
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| {
+                let value = (i % 10) * 100 + i / 10;
+                let word = format!("word-{:03}", value / 2);
+                if value % 2 == 0 {
+                    word.to_uppercase()
+                } else {
+                    word
+                }
+            });
+            let sorted = sorter
+                .sort_by(data, |a, b| {
+                    unicode_case_insensitive(a, b).then_with(|| a.cmp(b))
+                })
+                .unwrap()
+                .collect_sorted()
+                .unwrap();
+
+            let expected = (0..500)
+                .flat_map(|value| {
+                    let word = format!("word-{:03}", value);
+                    [word.to_uppercase(), word]
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(sorted, expected);
+        }
+    }
+
+    #[test]
+    fn test_config_validation() {
+        fn config_err(sorter: ExternalSorter) -> ConfigError {
+            let err = sorter.sort(0..10u32).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<ConfigError>())
+                .unwrap()
+                .clone()
+        }
+
+        assert!(ExternalSorter::new().validate().is_ok());
+        assert_eq!(
+            config_err(ExternalSorter::new().with_segment_size(0)),
+            ConfigError::ZeroSegmentSize
+        );
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let missing = dir.path().join("missing");
+        assert_eq!(
+            config_err(ExternalSorter::new().with_sort_dir(missing.clone())),
+            ConfigError::SortDirNotFound(missing)
+        );
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(
+            config_err(ExternalSorter::new().with_sort_dir(file.clone())),
+            ConfigError::SortDirNotADirectory(file)
+        );
+        let readonly = dir.path().join("readonly");
+        std::fs::create_dir(&readonly).unwrap();
+        let mut permissions = std::fs::metadata(&readonly).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&readonly, permissions).unwrap();
+        assert_eq!(
+            config_err(ExternalSorter::new().with_sort_dir(readonly.clone())),
+            ConfigError::SortDirNotWritable(readonly)
+        );
+
+        for fan_in in [0, 1] {
+            assert_eq!(
+                config_err(ExternalSorter::new().with_compaction(fan_in)),
+                ConfigError::InvalidCompactionFanIn(fan_in)
+            );
+        }
+        assert_eq!(
+            config_err(ExternalSorter::new().with_auto_memory_limit(1.5)),
+            ConfigError::InvalidMemoryFraction(1.5)
+        );
+        assert!(matches!(
+            config_err(ExternalSorter::new().with_auto_memory_limit(f64::NAN)),
+            ConfigError::InvalidMemoryFraction(_)
+        ));
+        assert_eq!(
+            config_err(
+                ExternalSorter::new()
+                    .with_segment_size(10)
+                    .with_natural_runs(20)
+            ),
+            ConfigError::NaturalRunsLongerThanSegment {
+                min_len: 20,
+                segment_size: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_env() {
+        fn from_vars(vars: &[(&str, &str)]) -> std::result::Result<ExternalSorter, ConfigError> {
+            ExternalSorter::from_vars(|name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.into())
+            })
+        }
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let sorter = from_vars(&[
+            ("EXTSORT_SEGMENT_SIZE", "100"),
+            ("EXTSORT_SEGMENT_BYTES", " 4096 "),
+            ("EXTSORT_MEMORY_FRACTION", "0.5"),
+            ("EXTSORT_SORT_DIR", dir.path().to_str().unwrap()),
+            ("EXTSORT_PARALLEL", "1"),
+            ("EXTSORT_HEAP_ITER_SEGMENT_COUNT", "5"),
+        ])
+        .unwrap();
+        assert_eq!(sorter.options.segment_size, 100);
+        assert_eq!(sorter.options.segment_bytes, 4096);
+        assert_eq!(sorter.options.memory_fraction, 0.5);
+        assert_eq!(sorter.options.sort_dir.as_deref(), Some(dir.path()));
+        assert_eq!(sorter.options.parallel, cfg!(feature = "parallel"));
+        assert_eq!(sorter.options.heap_iter_segment_count, Some(5));
+
+        // builder methods take precedence
+        let sorter = sorter.with_segment_size(50);
+        assert_eq!(sorter.options.segment_size, 50);
+        let sorted = sorter.sort((0..1000u32).rev()).unwrap();
+        assert_eq!(
+            sorted.collect_sorted().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+
+        let sorter = from_vars(&[("EXTSORT_PARALLEL", "false")]).unwrap();
+        assert!(!sorter.options.parallel);
+        assert_eq!(sorter.options.segment_size, 10_000);
+
+        for (name, value) in [
+            ("EXTSORT_SEGMENT_SIZE", "-1"),
+            ("EXTSORT_PARALLEL", "yes"),
+            ("EXTSORT_MEMORY_FRACTION", "half"),
+        ] {
+            assert_eq!(
+                from_vars(&[(name, value)]).err(),
+                Some(ConfigError::InvalidEnvVar {
+                    name: name.to_string(),
+                    value: value.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        // items are sorted by their key only, and equal items need to keep
+        // their push order
+        let data = (0..1000u32)
+            .map(|i| ((i % 10) * 100 + i / 10) % 50 * 1_000 + i)
+            .collect::<Vec<_>>();
+        let key = |item: &u32| *item / 1_000;
+        let mut expected = data.clone();
+        expected.sort_by_key(key);
+
+        for heap_iter_segment_count in [0, 1_000] {
+            for segment_size in [10_000, 100, 25] {
+                let sorter = ExternalSorter::new()
+                    .with_deterministic()
+                    .with_sort_algorithm(SortAlgorithm::Unstable)
+                    .with_segment_size(segment_size)
+                    .with_heap_iter_segment_count(heap_iter_segment_count);
+                #[cfg(feature = "parallel")]
+                let sorter = sorter.with_parallel_sort();
+
+                let sorted_iter = sorter.sort_by_key(data.clone(), key).unwrap();
+                assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+            }
+        }
+
+        // segments are named sequentially in the sort directory
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let sorted_iter = ExternalSorter::new()
+            .with_deterministic()
+            .with_segment_size(100)
+            .with_sort_dir(sort_dir.path().to_path_buf())
+            .sort(data.clone())
+            .unwrap();
+        let mut names = segment_files(sort_dir.path())
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        names.sort_by_key(|name| name.parse::<u32>().unwrap());
+        assert_eq!(names, (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+        drop(sorted_iter);
+
+        let err = ExternalSorter::new()
+            .with_deterministic()
+            .with_auto_memory_limit(0.5)
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::NondeterministicMemoryLimit);
+
+        let err = ExternalSorter::new()
+            .with_deterministic()
+            .with_memory_pool(MemoryPool::new(1 << 20))
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::NondeterministicMemoryLimit);
+    }
+
+    #[test]
+    fn test_push_outcome() {
+        let mut sorter = ExternalSorter::new().with_segment_size(100).pushed();
+        let mut spills = Vec::new();
+        for (i, item) in (0..1000u32).rev().enumerate() {
+            let outcome = sorter.push_with_outcome(item).unwrap();
+            if outcome.spilled() {
+                assert_eq!(outcome.segments_written, 1);
+                assert_eq!(outcome.items_written, 101);
+                assert_eq!(outcome.bytes_written, 101 * 4);
+                assert_eq!(outcome.buffered, 0);
+                spills.push(i);
+            } else {
+                assert_eq!(
+                    outcome,
+                    PushOutcome {
+                        buffered: outcome.buffered,
+                        ..PushOutcome::default()
+                    }
+                );
+                assert_eq!(outcome.buffered, (i + 1) % 101);
+            }
+        }
+        assert_eq!(spills, (1..=9).map(|i| i * 101 - 1).collect::<Vec<_>>());
+
+        let sorted_iter = sorter.done().unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 10);
+        assert_eq!(
+            sorted_iter.collect_sorted().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+
+        // segments kept in memory aren't written to disk
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_in_memory_segments(3)
+            .pushed();
+        let outcomes = (0..1000u32)
+            .rev()
+            .map(|item| sorter.push_with_outcome(item).unwrap())
+            .collect::<Vec<_>>();
+        let spills = outcomes.iter().filter(|outcome| outcome.spilled()).count();
+        assert_eq!(spills, 6);
+        let items_written = outcomes
+            .iter()
+            .map(|outcome| outcome.items_written)
+            .sum::<u64>();
+        assert_eq!(items_written, 6 * 101);
+
+        let sorted_iter = sorter.done().unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 7);
+        assert_eq!(
+            sorted_iter.collect_sorted().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_push_introspection() {
+        let mut sorter = ExternalSorter::new().with_segment_size(100).pushed();
+        assert_eq!(sorter.pushed_count(), 0);
+        assert_eq!(sorter.buffered_len(), 0);
+        assert_eq!(sorter.buffered_bytes(), 0);
+        assert_eq!(sorter.segment_count(), 0);
+
+        sorter.push_iter((0..50u32).rev()).unwrap();
+        assert_eq!(sorter.pushed_count(), 50);
+        assert_eq!(sorter.buffered_len(), 50);
+        assert_eq!(sorter.buffered_bytes(), 50 * 4);
+        assert_eq!(sorter.segment_count(), 0);
+
+        sorter.push_iter((50..1000u32).rev()).unwrap();
+        assert_eq!(sorter.pushed_count(), 1000);
+        assert_eq!(sorter.buffered_len(), 1000 - 9 * 101);
+        assert_eq!(sorter.buffered_bytes(), (1000 - 9 * 101) * (4 + 4));
+        assert_eq!(sorter.segment_count(), 9);
+
+        let mut compacted = ExternalSorter::new()
+            .with_segment_size(25)
+            .with_compaction(4)
+            .pushed();
+        compacted.push_iter((0..1000u32).rev()).unwrap();
+        assert!(compacted.segment_count() < 20);
+        assert_eq!(compacted.pushed_count(), 1000);
+        assert_eq!(compacted.done().unwrap().count(), 1000);
+    }
+
+    #[test]
+    fn test_bytes_spilled() {
+        let sorter = ExternalSorter::new().sort((0..1000u32).rev()).unwrap();
+        assert_eq!(sorter.bytes_spilled(), 0);
+
+        let mut sorter = ExternalSorter::new().with_segment_size(100).pushed();
+        sorter.push_iter((0..1000u32).rev()).unwrap();
+        assert_eq!(sorter.bytes_spilled(), 9 * 101 * 4);
+        let sorted_iter = sorter.done().unwrap();
+        assert_eq!(sorted_iter.bytes_spilled(), 1000 * 4);
+
+        // compacted segments are counted even though they were deleted
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(25)
+            .with_compaction(4)
+            .pushed();
+        sorter.push_iter((0..1000u32).rev()).unwrap();
+        let sorted_iter = sorter.done().unwrap();
+        assert!(sorted_iter.bytes_spilled() > 1000 * 4);
+        assert_eq!(
+            sorted_iter.collect_sorted().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sorted");
+        ExternalSorter::new()
+            .sort_into_path(0..10u32, &path)
+            .unwrap();
+        let merged = ExternalSorter::new()
+            .merge_files::<u32, _, _>([&path])
+            .unwrap();
+        assert_eq!(merged.bytes_spilled(), 0);
+    }
+
+    #[test]
+    fn test_sort_grouped() {
+        // groups are seen in the order 3, 2, 1, 0
+        let data = (0..1000u32).rev().map(|i| (i % 10) * 100 + i / 10);
+        let group = |item: &u32| *item / 250;
+
+        for segment_size in [10_000, 100, 25] {
+            for (order, keys) in [
+                (GroupOrder::FirstSeen, vec![3, 2, 1, 0]),
+                (GroupOrder::Key, vec![0, 1, 2, 3]),
+            ] {
+                let sorter = ExternalSorter::new().with_segment_size(segment_size);
+                let groups = sorter.sort_grouped(data.clone(), group, order).unwrap();
+                assert_eq!(groups.iter().map(|(key, _)| *key).collect::<Vec<_>>(), keys);
+
+                for (key, sorted_iter) in groups {
+                    let expected = (key * 250..(key + 1) * 250).collect::<Vec<_>>();
+                    assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+                }
+            }
+
+            // descending order within groups
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let groups = sorter
+                .sort_grouped_by(
+                    data.clone(),
+                    |item| item % 2,
+                    GroupOrder::Key,
+                    |a, b| b.cmp(a),
+                )
+                .unwrap();
+            assert_eq!(groups.len(), 2);
+            for (key, sorted_iter) in groups {
+                let expected = (0..1000u32)
+                    .rev()
+                    .filter(|i| i % 2 == key)
+                    .collect::<Vec<_>>();
+                assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_spec() {
+        use crate::compare::KeySpec;
+
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let cmp = KeySpec::new()
+                .asc(|v: &u32| *v % 3)
+                .desc(|v| *v / 100)
+                .asc(|v| *v)
+                .build();
+
+            let sorted = sorter
+                .sort_by(data, cmp.clone())
+                .unwrap()
+                .collect_sorted()
+                .unwrap();
+
+            let mut expected = (0..1000u32).collect::<Vec<_>>();
+            expected.sort_by(|a, b| {
+                (a % 3)
+                    .cmp(&(b % 3))
+                    .then_with(|| (b / 100).cmp(&(a / 100)))
+                    .then_with(|| a.cmp(b))
+            });
+            assert_eq!(sorted, expected);
+            assert_eq!(cmp(&3, &6), std::cmp::Ordering::Less);
+        }
+
+        let spec = KeySpec::new()
+            .then(|a: &String, b: &String| crate::compare::ascii_case_insensitive(a, b));
+        assert_eq!(
+            spec.compare(&"ABC".to_string(), &"abc".to_string()),
+            std::cmp::Ordering::Equal
+        );
+        assert_eq!(
+            KeySpec::<u32>::new().compare(&1, &2),
+            std::cmp::Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_sort_kv() {
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let data = (0..1000u32).map(|i| {
+                let key = (i % 10) * 100 + i / 10;
+                (key, vec![key as u8; (key % 7) as usize])
+            });
+
+            let sorted = sorter
+                .sort_kv_by(data, |a, b| b.cmp(a))
+                .unwrap()
+                .collect_sorted()
+                .unwrap();
+
+            let expected = (0..1000u32)
+                .rev()
+                .map(|key| KeyValue::new(key, vec![key as u8; (key % 7) as usize]))
+                .collect::<Vec<_>>();
+            assert_eq!(sorted, expected);
+        }
+
+        let mut encoded = Vec::new();
+        KeyValue::new(1u32, "value").encode(&mut encoded).unwrap();
+        let err = KeyValue::<u32>::decode(&mut &encoded[..6]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sort_kv_sidecar() {
+        for segment_size in [10_000, 100, 25] {
+            let dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_sort_dir(dir.path().to_path_buf());
+            let data = (0..1000u32).map(|i| {
+                let key = (i % 10) * 100 + i / 10;
+                (key, vec![key as u8; (key % 7) as usize * 100])
+            });
+
+            let sorted = sorter.sort_kv_sidecar(data).unwrap();
+            assert_eq!(sorted.size_hint(), (1000, Some(1000)));
+
+            let sorted = sorted.collect::<std::io::Result<Vec<_>>>().unwrap();
+            let expected = (0..1000u32)
+                .map(|key| KeyValue::new(key, vec![key as u8; (key % 7) as usize * 100]))
+                .collect::<Vec<_>>();
+            assert_eq!(sorted, expected);
+        }
+
+        let sorted = ExternalSorter::new()
+            .with_segment_size(10)
+            .sort_kv_sidecar_by((0..100u32).map(|i| (i, i.to_string())), |a, b| b.cmp(a))
+            .unwrap()
+            .map(|item| String::from_utf8(item.unwrap().value).unwrap())
+            .collect::<Vec<_>>();
+        let expected = (0..100u32).rev().map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_codec_context() {
+        struct Dictionary(Vec<String>);
+
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Word(String);
+
+        impl SortableWithContext<Dictionary> for Word {
+            fn encode<W: Write>(&self, writer: &mut W, dict: &Dictionary) -> Result<()> {
+                let index = dict.0.iter().position(|word| *word == self.0).unwrap();
+                writer.write_u32::<byteorder::LittleEndian>(index as u32)
+            }
+
+            fn decode<R: Read>(reader: &mut R, dict: &Dictionary) -> Result<Word> {
+                let index = reader.read_u32::<byteorder::LittleEndian>()?;
+                Ok(Word(dict.0[index as usize].clone()))
+            }
+        }
+
+        let words = (0..100)
+            .map(|i| format!("word-{:02}", i))
+            .collect::<Vec<_>>();
+        for segment_size in [10_000, 100, 25] {
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_codec_context(Dictionary(words.clone()));
+            assert_eq!(sorter.context().0.len(), 100);
+
+            let data =
+                (0..1000u32).map(|i| Word(words[((i % 10) * 10 + i / 100) as usize].clone()));
+            let sorted = sorter
+                .sort_by(data, |a, b| b.cmp(a))
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+
+            let expected = (0..1000)
+                .rev()
+                .map(|i| Word(words[i / 10].clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(sorted, expected);
+        }
+    }
+
+    #[test]
+    fn test_cleanup_stale() {
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let stale = sort_dir.path().join(".extsort-stale");
+        std::fs::create_dir(&stale).unwrap();
+        std::fs::write(stale.join("0"), b"segment").unwrap();
+        let unrelated = sort_dir.path().join("unrelated");
+        std::fs::create_dir(&unrelated).unwrap();
+
+        let hour = std::time::Duration::from_secs(3600);
+        assert_eq!(cleanup_stale(sort_dir.path(), hour).unwrap(), 0);
+        assert!(stale.exists());
+
+        let zero = std::time::Duration::ZERO;
+        assert_eq!(cleanup_stale(sort_dir.path(), zero).unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(unrelated.exists());
+
+        // sorters create their temporary directory with the recognized prefix
+        let sorter = ExternalSorter::new().with_segment_size(10);
+        let parts = sorter.sort(0..100u32).unwrap().into_parts().unwrap();
+        let tempdir = parts.tempdir.unwrap();
+        let name = tempdir.path().file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".extsort-"));
+    }
+
+    #[test]
+    fn test_sort_dir_per_sorter() {
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let sorter = || {
+            ExternalSorter::new()
+                .with_segment_size(100)
+                .with_sort_dir(sort_dir.path().to_path_buf())
+        };
+
+        // concurrent sorters sharing the directory don't collide
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let first = sorter().sort(data.clone()).unwrap();
+        let second = sorter().sort(data.map(|i| i + 1000)).unwrap();
+        assert_eq!(std::fs::read_dir(sort_dir.path()).unwrap().count(), 2);
+        assert_eq!(segment_files(sort_dir.path()).len(), 20);
+
+        let sorted_data = first.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        assert_eq!(std::fs::read_dir(sort_dir.path()).unwrap().count(), 1);
+
+        let sorted_data = second.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (1000..2000u32).collect::<Vec<_>>());
+        assert_eq!(std::fs::read_dir(sort_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_tempfile_builder() {
+        let created = Arc::new(Mutex::new(0));
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let sorter = {
+            let created = created.clone();
+            ExternalSorter::new()
+                .with_segment_size(100)
+                .with_sort_dir(sort_dir.path().to_path_buf())
+                .with_tempfile_builder(move || {
+                    *created.lock().unwrap() += 1;
+                    tempfile::tempfile()
+                })
+        };
+
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let sorted_iter = sorter.sort(data).unwrap();
+        assert_eq!(*created.lock().unwrap(), 10);
+        assert_eq!(std::fs::read_dir(sort_dir.path()).unwrap().count(), 0);
+
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+        let err = ExternalSorter::new()
+            .with_reverse_index()
+            .with_tempfile_builder(tempfile::tempfile)
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::ReverseIndexWithTempfileBuilder);
+    }
+
+    #[test]
+    fn test_max_segment_bytes() {
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        for block_size in [0, 64] {
+            let sort_dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_max_segment_bytes(100)
+                .with_block_size(block_size)
+                .with_sort_dir(sort_dir.path().to_path_buf());
+            let sorted_iter = sorter.sort(data.clone()).unwrap();
+            assert!(sorted_iter.disk_segment_count() >= 40);
+            for path in segment_files(sort_dir.path()) {
+                let len = std::fs::metadata(path).unwrap().len();
+                assert!(len <= 100 || block_size > 0, "{len} bytes");
+            }
+
+            let sorted_data = sorted_iter.collect_sorted().unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        // merged segments are split as well
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_compaction(4)
+            .with_max_segment_bytes(1000);
+        let sorted_iter = sorter.sort(data.clone()).unwrap();
+        assert!(sorted_iter.disk_segment_count() >= 4);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+        // records of the arena sorter
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_max_segment_bytes(200)
+            .pushed_bytes();
+        sorter.push_iter(data.map(|i| i.to_be_bytes())).unwrap();
+        let sorted_iter = sorter.done().unwrap();
+        assert!(sorted_iter.disk_segment_count() >= 40);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        let expected = (0..1000u32).map(|i| i.to_be_bytes().to_vec());
+        assert_eq!(sorted_data, expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_auto_heap_iter_segment_count() {
+        // cheap comparators favor peeking over more segments
+        assert!(!crate::iter::prefers_heap(10, 1.0));
+        assert!(!crate::iter::prefers_heap(20, 1.0));
+        assert!(crate::iter::prefers_heap(200, 1.0));
+        assert!(!crate::iter::prefers_heap(3, 100.0));
+        assert!(crate::iter::prefers_heap(30, 100.0));
+
+        // both strategies are correct whichever is picked
+        let data = (0..1000u32).map(|i| (i % 100) * 10 + i / 100);
+        for segment_size in [100, 10, 2] {
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let sorted_iter = sorter.sort(data.clone()).unwrap();
+            assert_sorted(sorted_iter);
+
+            let sorter = ExternalSorter::new().with_segment_size(segment_size);
+            let sorted_iter = sorter
+                .sort_by(data.clone(), |a, b| {
+                    let (a, b) = (a.to_string(), b.to_string());
+                    a.parse::<u32>().unwrap().cmp(&b.parse().unwrap())
+                })
+                .unwrap();
+            assert_sorted(sorted_iter);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "memfd", target_os = "linux"))]
+    fn test_memfd_segments() {
+        for seal in [false, true] {
+            let sort_dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_sort_dir(sort_dir.path().to_path_buf())
+                .with_memfd_segments(seal);
+            let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+            let sorted_iter = sorter.sort(data).unwrap();
+            assert_eq!(sorted_iter.disk_segment_count(), 10);
+            assert_eq!(std::fs::read_dir(sort_dir.path()).unwrap().count(), 0);
+
+            let sorted_data = sorted_iter.collect_sorted().unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        // sealed files can't be written to anymore
+        let file = crate::memfd::create(true).unwrap();
+        (&file).write_all(b"segment").unwrap();
+        crate::memfd::seal(&file).unwrap();
+        assert!((&file).write_all(b"more").is_err());
+    }
+
+    #[test]
+    fn test_in_memory_segments() {
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_in_memory_segments(3)
+            .with_sort_dir(sort_dir.path().to_path_buf());
+        let sorted_iter = sorter.sort(data.clone()).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 7);
+        let files = segment_files(sort_dir.path());
+        assert_eq!(files.len(), 7);
+        let file_bytes = files
+            .iter()
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum::<u64>();
+        assert_eq!(sorted_iter.bytes_spilled(), file_bytes);
+
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+        // in-memory segments are written to files when decomposed
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_in_memory_segments(20);
+        let parts = sorter.sort(data.clone()).unwrap().into_parts().unwrap();
+        assert_eq!(parts.segment_files.len(), 10);
+        let sorted_iter = SortedIterator::from_parts(parts).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 10);
+        assert_sorted(sorted_iter);
+
+        // records of the arena sorter
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_in_memory_segments(3)
+            .pushed_bytes();
+        sorter.push_iter(data.map(|i| i.to_be_bytes())).unwrap();
+        let sorted_iter = sorter.done().unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 7);
+        let sorted_data = sorted_iter.collect_sorted().unwrap();
+        let expected = (0..1000u32).map(|i| i.to_be_bytes().to_vec());
+        assert_eq!(sorted_data, expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_in_memory_segment_bytes() {
+        // the last segment holds the 50 items left in the buffer
+        let data = (0..1050u32).map(|i| (i % 10) * 105 + i / 10);
+        for (max_bytes, disk_segments) in [(100, 11), (300, 10), (1000, 0)] {
+            let sort_dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_in_memory_segment_bytes(max_bytes)
+                .with_sort_dir(sort_dir.path().to_path_buf());
+            let sorted_iter = sorter.sort(data.clone()).unwrap();
+            assert_eq!(sorted_iter.disk_segment_count(), disk_segments);
+            assert_eq!(segment_files(sort_dir.path()).len(), disk_segments);
+
+            let sorted_data = sorted_iter.collect_sorted().unwrap();
+            assert_eq!(sorted_data, (0..1050u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        // each key is pushed 3 times, along with its version
+        let data = (0..3000u32)
+            .map(|i| ((i % 10) * 100 + (i % 1000) / 10) * 10 + i / 1000)
+            .collect::<Vec<_>>();
+        let key = |item: &u32| *item / 10;
+
+        for segment_size in [10_000, 100, 25] {
+            for (policy, expected) in [
+                (
+                    DuplicatePolicy::KeepAll,
+                    (0..3000).map(|i| i / 3 * 10 + i % 3).collect::<Vec<u32>>(),
+                ),
+                (
+                    DuplicatePolicy::KeepFirst,
+                    (0..1000).map(|k| k * 10).collect(),
+                ),
+                (
+                    DuplicatePolicy::KeepLast,
+                    (0..1000).map(|k| k * 10 + 2).collect(),
+                ),
+            ] {
+                let sorter = ExternalSorter::new()
+                    .with_deterministic()
+                    .with_segment_size(segment_size)
+                    .with_duplicate_policy(policy);
+                let sorted_iter = sorter.sort_by_key(data.clone(), key).unwrap();
+                let known_count = policy == DuplicatePolicy::KeepAll;
+                assert_eq!(sorted_iter.known_count().is_some(), known_count);
+                let sorted_data = sorted_iter.collect_sorted().unwrap();
+                assert_eq!(sorted_data, expected);
+            }
+        }
+
+        // peeking and seeking see deduplicated items
+        let sorter = ExternalSorter::new()
+            .with_deterministic()
+            .with_segment_size(100)
+            .with_duplicate_policy(DuplicatePolicy::KeepLast);
+        let mut sorted_iter = sorter.sort_by_key(data.clone(), key).unwrap();
+        assert_eq!(*sorted_iter.peek().unwrap().as_ref().unwrap(), 2);
+        assert_eq!(sorted_iter.next().unwrap().unwrap(), 2);
+        sorted_iter.seek_to(&5000).unwrap();
+        assert_eq!(sorted_iter.next().unwrap().unwrap(), 5002);
+        assert_eq!(sorted_iter.count(), 499);
+    }
+
+    #[test]
+    fn test_deterministic_across_segments() {
+        // equal keys are spread over many segments, which get compacted or
+        // concatenated, and still need to be returned in their push order
+        let key = |item: &u32| *item / 1_000;
+        let interleaved = (0..1000u32)
+            .map(|i| (i % 7) * 1_000 + i)
+            .collect::<Vec<_>>();
+        let boundaries = (0..1000u32)
+            .map(|i| (4 - i / 200 + i % 200 / 100) * 1_000 + i)
+            .collect::<Vec<_>>();
+
+        for data in [interleaved, boundaries] {
+            let mut expected = data.clone();
+            expected.sort_by_key(key);
+
+            for heap_iter_segment_count in [0, 1_000] {
+                // natural runs get written while a compaction is ongoing
+                let sorted_iter = ExternalSorter::new()
+                    .with_deterministic()
+                    .with_segment_size(50)
+                    .with_natural_runs(5)
+                    .with_compaction(3)
+                    .with_heap_iter_segment_count(heap_iter_segment_count)
+                    .sort_by_key(data.clone(), key)
+                    .unwrap();
+                assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+
+                // segments of 200 items, whose boundary keys are shared with
+                // the segments written after them
+                let sorted_iter = ExternalSorter::new()
+                    .with_deterministic()
+                    .with_segment_size(199)
+                    .with_heap_iter_segment_count(heap_iter_segment_count)
+                    .sort_by_key(data.clone(), key)
+                    .unwrap();
+                assert_eq!(sorted_iter.collect_sorted().unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_filter_items() {
+        let data = (0..1000u32).rev().collect::<Vec<_>>();
+        let sorter = || ExternalSorter::new().with_segment_size(100);
+
+        // mapped items keep the length of the sorted items
+        let mapped = sorter()
+            .sort(data.clone())
+            .unwrap()
+            .map_items(|item| item * 2);
+        assert_eq!(mapped.size_hint(), (1000, Some(1000)));
+        assert_eq!(mapped.count(), 1000);
+
+        let mapped = sorter()
+            .sort(data.clone())
+            .unwrap()
+            .map_items(|item| item.to_string())
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(mapped, (0..1000).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        let filtered = sorter()
+            .sort(data.clone())
+            .unwrap()
+            .filter_items(|item| item % 3 == 0);
+        assert_eq!(filtered.size_hint(), (0, Some(1000)));
+        let filtered = filtered.collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            filtered,
+            (0..1000).filter(|i| i % 3 == 0).collect::<Vec<_>>()
+        );
+
+        // the transforms can be applied from both ends
+        let filtered = sorter()
+            .with_reverse_index()
+            .sort(data)
+            .unwrap()
+            .filter_items(|item| item % 2 == 0)
+            .rev()
+            .take(3)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(filtered, vec![998, 996, 994]);
+
+        // decoding errors are returned as is
+        let sorter = sorter().with_block_size(40).with_record_checksums();
+        let mut parts = sorter
+            .sort((0..1000u32).rev())
+            .unwrap()
+            .into_parts()
+            .unwrap();
+        let file = &mut parts.segment_files[0];
+        std::io::Seek::seek(file, std::io::SeekFrom::Start(24)).unwrap();
+        file.write_all(&[0xFF]).unwrap();
+        let err = SortedIterator::from_parts(parts)
+            .unwrap()
+            .map_items(|item| item + 1)
+            .collect::<Result<Vec<u32>>>()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_push_filter() {
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_sort_dir(sort_dir.path().to_path_buf())
+            .pushed::<u32>()
+            .with_filter(|item| item % 4 == 0);
+
+        for item in (0..1000u32).rev() {
+            let outcome = sorter.push_with_outcome(item).unwrap();
+            assert!(outcome.buffered <= 101);
+        }
+        assert_eq!(sorter.pushed_count(), 250);
+
+        // only kept items get written to disk
+        assert_eq!(sorter.segment_count(), 2);
+        assert_eq!(sorter.bytes_spilled(), 2 * 101 * 4);
+
+        let sorted = sorter.done().unwrap();
+        assert_eq!(sorted.size_hint(), (250, Some(250)));
+        assert_eq!(
+            sorted.collect_sorted().unwrap(),
+            (0..1000).filter(|i| i % 4 == 0).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_combiner() {
+        // items are a key in thousands and a count in units
+        let key = |item: &u32| *item / 1_000;
+        let reduce = |a: u32, b: u32| a + b % 1_000;
+        let expected = (0..10).map(|i| i * 1_000 + 100).collect::<Vec<_>>();
+
+        for compaction in [0, 3] {
+            let mut sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_compaction(compaction)
+                .pushed_by_key(key)
+                .with_combiner(key, reduce);
+            sorter
+                .push_iter((0..1000u32).map(|i| (i % 10) * 1_000 + 1))
+                .unwrap();
+
+            // each segment only holds one item per key
+            if compaction == 0 {
+                assert_eq!(sorter.bytes_spilled(), 9 * 10 * 4);
+            }
+
+            let sorted = sorter.done().unwrap();
+            assert_eq!(sorted.known_count(), None);
+            assert_eq!(sorted.collect_sorted().unwrap(), expected);
+        }
+
+        // items that fit in memory are combined while iterating
+        let mut sorter = ExternalSorter::new()
+            .pushed_by_key(key)
+            .with_combiner(key, reduce);
+        sorter
+            .push_iter((0..1000u32).map(|i| (i % 10) * 1_000 + 1))
+            .unwrap();
+        assert_eq!(sorter.done().unwrap().collect_sorted().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sorted_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sorted");
+
+        for (block_size, checksums) in [(0, false), (40, false), (40, true)] {
+            let sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_block_size(block_size);
+            let sorter = if checksums {
+                sorter.with_record_checksums()
+            } else {
+                sorter
+            };
+            let sorted_file = sorter
+                .sort((0..1000u32).rev().map(|i| i * 2))
+                .unwrap()
+                .persist(&path)
+                .unwrap();
+            assert_eq!(sorted_file.len(), 1000);
+
+            // the file can be reopened, e.g. by another process
+            let sorted_file = SortedFile::<u32, _>::open(sorted_file.path()).unwrap();
+            assert_eq!(sorted_file.len(), 1000);
+            for key in [0, 1, 2, 18, 19, 20, 21, 998, 1000, 1998, 1999, 5000] {
+                let expected = (key % 2 == 0 && key < 2000).then_some(key);
+                assert_eq!(sorted_file.get(&key).unwrap(), expected, "{key}");
+                assert_eq!(sorted_file.contains(&key).unwrap(), expected.is_some());
+            }
+
+            for key in [0, 1, 2, 19, 20, 1998, 1999, 5000] {
+                assert_eq!(
+                    sorted_file.rank(&key).unwrap(),
+                    (key as u64).div_ceil(2).min(1000)
+                );
+            }
+            for position in [0, 9, 10, 11, 500, 999] {
+                let item = sorted_file.nth(position).unwrap();
+                assert_eq!(item, Some(position as u32 * 2));
+            }
+            assert_eq!(sorted_file.nth(1000).unwrap(), None);
+
+            let items = sorted_file.iter().unwrap();
+            assert_eq!(items.size_hint(), (1000, Some(1000)));
+            assert_eq!(
+                items.collect_sorted().unwrap(),
+                (0..1000).map(|i| i * 2).collect::<Vec<_>>()
+            );
+        }
+
+        // lookups use the comparator the items were sorted with
+        let sorted_file = ExternalSorter::new()
+            .sort_by((0..100u32).map(|i| i * 2), |a, b| b.cmp(a))
+            .unwrap()
+            .persist(&path)
+            .unwrap();
+        assert!(sorted_file.contains(&42).unwrap());
+        assert!(!sorted_file.contains(&43).unwrap());
+
+        let sorted_file = ExternalSorter::new()
+            .sort(Vec::<u32>::new())
+            .unwrap()
+            .persist(&path);
+        assert!(sorted_file.unwrap().is_empty());
+
+        // files written without a block index can't be queried
+        ExternalSorter::new()
+            .sort(0..100u32)
+            .unwrap()
+            .write_to_path(&path)
+            .unwrap();
+        let err = SortedFile::<u32, _>::open(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_segment_iters() {
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let data = (0..1000u32).map(|i| (i % 10) * 100 + i / 10);
+        let sorted_iter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_sort_dir(sort_dir.path().to_path_buf())
+            .sort(data)
+            .unwrap();
+        let disk_segments = sorted_iter.disk_segment_count();
+
+        let segment_iters = sorted_iter.into_segment_iters().unwrap();
+        assert_eq!(segment_iters.len(), disk_segments);
+
+        let mut items = Vec::new();
+        for segment_iter in segment_iters {
+            let len = segment_iter.size_hint().0;
+            let segment_items = segment_iter.collect_sorted().unwrap();
+            assert_eq!(segment_items.len(), len);
+            assert!(segment_items.windows(2).all(|pair| pair[0] <= pair[1]));
+            items.extend(segment_items);
+        }
+        items.sort();
+        assert_eq!(items, (0..1000).collect::<Vec<_>>());
+
+        // segment files are deleted once all iterators are dropped
+        assert!(segment_files(sort_dir.path()).is_empty());
+
+        // items that fit in memory make a single segment
+        let segment_iters = ExternalSorter::new()
+            .sort((0..100u32).rev())
+            .unwrap()
+            .into_segment_iters()
+            .unwrap();
+        assert_eq!(segment_iters.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_readers() {
+        // shards of interleaved items, as received over the network
+        let shards = (0..4u32)
+            .map(|shard| {
+                let mut encoded = Vec::new();
+                for i in (0..250u32).map(|i| i * 4 + shard) {
+                    i.encode(&mut encoded).unwrap();
+                }
+                std::io::Cursor::new(encoded)
+            })
+            .collect::<Vec<_>>();
+
+        let merged = ExternalSorter::new()
+            .merge_readers::<u32, _, _>(shards.clone())
+            .unwrap();
+        assert_eq!(merged.known_count(), None);
+        assert_eq!(merged.sorted_count(), 0);
+        assert_eq!(merged.disk_segment_count(), 0);
+        assert_eq!(
+            merged.collect_sorted().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+
+        // streams can't seek, but can still be skipped ahead
+        let mut merged = ExternalSorter::new()
+            .merge_readers_by(shards.clone(), |a: &u32, b: &u32| a.cmp(b))
+            .unwrap();
+        merged.seek_to(&500).unwrap();
+        assert_eq!(merged.next().unwrap().unwrap(), 500);
+        let err = merged.next_back().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+        // streams can't be rewound to be iterated independently
+        let merged = ExternalSorter::new()
+            .merge_readers::<u32, _, _>(shards.clone())
+            .unwrap();
+        assert!(merged.into_segment_iters().is_err());
+        let merged = ExternalSorter::new()
+            .merge_readers::<u32, _, _>(shards)
+            .unwrap();
+        let err = merged.into_parts().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_arena_write_to() {
+        let records = (0..1000u32)
+            .map(|i| format!("record-{}", (i % 10) * 100 + i / 10))
+            .collect::<Vec<_>>();
+
+        for (segment_size, block_size, checksums) in
+            [(10_000, 0, false), (100, 0, false), (100, 64, true)]
+        {
+            let sorter = || {
+                let sorter = ExternalSorter::new()
+                    .with_segment_size(segment_size)
+                    .with_block_size(block_size);
+                if checksums {
+                    sorter.with_record_checksums()
+                } else {
+                    sorter
+                }
+            };
+
+            let mut expected = Vec::new();
+            let mut arena = sorter().pushed_bytes();
+            arena.push_iter(&records).unwrap();
+            let written = arena.done().unwrap().write_to(&mut expected).unwrap();
+            assert_eq!(written, 1000);
+
+            let mut encoded = Vec::new();
+            let mut arena = sorter().pushed_bytes();
+            arena.push_iter(&records).unwrap();
+            assert_eq!(arena.write_to(&mut encoded).unwrap(), 1000);
+            assert_eq!(encoded, expected);
+        }
+
+        // equal records are removed according to the duplicate policy, the
+        // last pushed record winning in deterministic mode
+        let mut arena = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_deterministic()
+            .with_duplicate_policy(DuplicatePolicy::KeepLast)
+            .pushed_bytes_by(|a, b| a[..2].cmp(&b[..2]));
+        arena
+            .push_iter((0..1000u32).map(|i| format!("{:02}{i:03}", i % 10)))
+            .unwrap();
+        let mut encoded = Vec::new();
+        assert_eq!(arena.write_to(&mut encoded).unwrap(), 10);
+
+        let mut reader = encoded.as_slice();
+        for i in 990..1000u32 {
+            let record = Vec::<u8>::decode(&mut reader).unwrap();
+            assert_eq!(record, format!("{:02}{i:03}", i % 10).into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_run_set() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let run_set = RunSet::new(dir.path().join("runs")).unwrap();
+
+        // workers each sort a share of the items, possibly concurrently
+        std::thread::scope(|scope| {
+            for worker in 0..4u32 {
+                let run_set = &run_set;
+                scope.spawn(move || {
+                    let items = (0..250u32).rev().map(|i| i * 4 + worker);
+                    let sorted = ExternalSorter::new()
+                        .with_segment_size(100)
+                        .sort(items)
+                        .unwrap();
+                    let written = run_set.write(&format!("worker-{worker}"), sorted).unwrap();
+                    assert_eq!(written, 250);
+                });
+            }
+        });
+
+        // incomplete runs of crashed workers are ignored
+        std::fs::write(run_set.dir().join(".tmp1234"), b"partial").unwrap();
+        assert_eq!(run_set.runs().unwrap().len(), 4);
+
+        let merged = run_set.open_merge::<u32>().unwrap();
+        assert_eq!(merged.sorted_count(), 1000);
+        assert_eq!(
+            merged.collect_sorted().unwrap(),
+            (0..1000).collect::<Vec<_>>()
+        );
+
+        let sorted = ExternalSorter::new().sort(0..10u32).unwrap();
+        let err = run_set.write("worker-0", sorted).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        for name in ["", ".hidden", "../escape"] {
+            let sorted = ExternalSorter::new().sort(0..10u32).unwrap();
+            let err = run_set.write(name, sorted).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_run_set_append() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let run_set = RunSet::new(dir.path()).unwrap();
+        assert_eq!(run_set.compact::<u32>().unwrap(), 0);
+
+        // the initial dataset and each batch are appended as runs
+        let sort = |items: Vec<u32>| ExternalSorter::new().sort(items).unwrap();
+        run_set
+            .append(sort((0..1000).map(|i| i * 2).collect()))
+            .unwrap();
+        run_set.append(sort(vec![3, 1, 5])).unwrap();
+        run_set.append(sort(vec![1999, 7])).unwrap();
+        assert_eq!(run_set.runs().unwrap().len(), 3);
+
+        let mut expected = (0..1000).map(|i| i * 2).collect::<Vec<_>>();
+        expected.extend([1, 3, 5, 7, 1999]);
+        expected.sort();
+        let merged = run_set.open_merge::<u32>().unwrap();
+        assert_eq!(merged.collect_sorted().unwrap(), expected);
+
+        // compaction rewrites the appended runs into one
+        let compacted = run_set.runs().unwrap();
+        assert_eq!(run_set.compact::<u32>().unwrap(), 1005);
+        let runs = run_set.runs().unwrap();
+        assert_eq!(runs.len(), 1);
+        let name = runs[0].file_name().unwrap().to_str().unwrap();
+        assert_eq!(name, "00000000000000000000-00000000000000000002.run");
+        let merged = run_set.open_merge::<u32>().unwrap();
+        assert_eq!(merged.sorted_count(), 1005);
+        assert_eq!(merged.collect_sorted().unwrap(), expected);
+
+        // runs left by an interrupted compaction are ignored, then deleted
+        std::fs::copy(&runs[0], &compacted[1]).unwrap();
+        assert_eq!(run_set.runs().unwrap(), runs);
+
+        // appends continue after the compacted runs
+        run_set.append(sort(vec![9])).unwrap();
+        let runs = run_set.runs().unwrap();
+        assert_eq!(runs.len(), 2);
+        assert!(runs[1].ends_with("00000000000000000003.run"));
+        assert_eq!(run_set.compact::<u32>().unwrap(), 1006);
+        let runs = run_set.runs().unwrap();
+        assert!(runs[0].ends_with("00000000000000000000-00000000000000000003.run"));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_compact_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sort_dir = dir.path().to_path_buf();
+
+        // each file holds a version of the items, encoded as `key * 4 + version`
+        let key = |item: &u32| *item / 4;
+        let persist = |name: &str, version: u32, keys: std::ops::Range<u32>| {
+            let items = keys.rev().map(|key| key * 4 + version);
+            ExternalSorter::new()
+                .with_segment_size(100)
+                .sort_by_key(items, key)
+                .unwrap()
+                .persist(sort_dir.join(name))
+                .unwrap();
+            sort_dir.join(name)
+        };
+        let paths = [
+            persist("v0", 0, 0..500),
+            persist("v1", 1, 250..750),
+            persist("v2", 2, 400..450),
+        ];
+
+        let compact = |policy: DuplicatePolicy, output: &str| {
+            ExternalSorter::new()
+                .with_duplicate_policy(policy)
+                .compact_files_by(&paths, dir.path().join(output), |a, b| key(a).cmp(&key(b)))
+                .unwrap()
+        };
+
+        let compacted = compact(DuplicatePolicy::KeepLast, "latest");
+        assert_eq!(compacted.len(), 750);
+        let items = compacted.iter().unwrap().collect_sorted().unwrap();
+        let versions = items.iter().map(|item| item % 4).collect::<Vec<_>>();
+        let mut expected = vec![0; 250];
+        expected.extend([1; 150]);
+        expected.extend([2; 50]);
+        expected.extend([1; 300]);
+        assert_eq!(versions, expected);
+        assert!(items.iter().map(key).eq(0..750));
+
+        let compacted = compact(DuplicatePolicy::KeepFirst, "oldest");
+        let items = compacted.iter().unwrap().collect_sorted().unwrap();
+        assert!(items
+            .iter()
+            .all(|item| item % 4 == (key(item) >= 500) as u32));
+
+        let compacted = compact(DuplicatePolicy::KeepAll, "all");
+        assert_eq!(compacted.len(), 1050);
+        assert_eq!(compacted.get(&(420 * 4)).unwrap(), Some(420 * 4));
+
+        // files are left as is
+        assert!(paths.iter().all(|path| path.exists()));
+    }
+
+    #[test]
+    fn test_sort_hash_partitioned() {
+        let key = |item: &u32| *item % 100;
+        let partitions = ExternalSorter::new()
+            .with_segment_size(100)
+            .sort_hash_partitioned((0..1000u32).rev(), 4, key)
+            .unwrap();
+        assert_eq!(partitions.len(), 4);
+
+        let mut all_items = Vec::new();
+        for (partition, sorted_iter) in partitions {
+            let items = sorted_iter.collect_sorted().unwrap();
+            assert!(items.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert!(items
+                .iter()
+                .all(|item| hash_partition(&key(item), 4) == partition));
+            all_items.extend(items);
+        }
+        all_items.sort();
+        assert_eq!(all_items, (0..1000).collect::<Vec<_>>());
+
+        // partitions are stable, and spread the keys between partitions
+        assert_eq!(hash_partition(&42u32, 1), 0);
+        assert_eq!(hash_partition("key", 0), 0);
+        let mut counts = [0; 4];
+        for key in 0..1000u32 {
+            counts[hash_partition(&key, 4)] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 150), "{counts:?}");
+    }
+
+    #[test]
+    fn test_disk_quota() {
+        // 2 sorters sharing a quota that only fits 3 segments of 101 items
+        let quota = DiskQuota::new(3 * 101 * 4, QuotaPolicy::Error);
+        let sorter = || {
+            PushExternalSorter::new(
+                ExternalSorter::new()
+                    .with_segment_size(100)
+                    .with_disk_quota(quota.clone())
+                    .options,
+                |a: &u32, b: &u32| a.cmp(b),
+            )
+        };
+
+        let mut first = sorter();
+        for item in (0..300u32).rev() {
+            first.push(item).unwrap();
+        }
+        assert_eq!(quota.used_bytes(), 2 * 101 * 4);
+
+        let mut second = sorter();
+        let err = (0..300u32)
+            .try_for_each(|item| second.push(item).map(|_| ()))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        let quota_err = err.get_ref().unwrap().downcast_ref::<QuotaExceeded>();
+        assert_eq!(quota_err.unwrap().max_bytes, 3 * 101 * 4);
+        drop(second);
+        assert_eq!(quota.used_bytes(), 2 * 101 * 4);
+
+        // bytes are held by the sorted iterator, and released once dropped
+        let sorted_iter = first.done().unwrap();
+        assert_eq!(quota.used_bytes(), 300 * 4);
+        assert_eq!(quota.available_bytes(), 3 * 4);
+        drop(sorted_iter);
+        assert_eq!(quota.used_bytes(), 0);
+
+        // a blocked sorter resumes once another one releases its bytes
+        let quota = DiskQuota::new(3 * 101 * 4, QuotaPolicy::Block);
+        let first = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_disk_quota(quota.clone())
+            .sort((0..300u32).rev())
+            .unwrap();
+        let second = {
+            let quota = quota.clone();
+            std::thread::spawn(move || {
+                ExternalSorter::new()
+                    .with_segment_size(100)
+                    .with_disk_quota(quota)
+                    .sort((0..300u32).rev())
+                    .unwrap()
+                    .collect_sorted()
+                    .unwrap()
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!second.is_finished());
+        assert_eq!(quota.used_bytes(), 300 * 4);
+        assert_eq!(
+            first.collect_sorted().unwrap(),
+            (0..300).collect::<Vec<_>>()
+        );
+        assert_eq!(second.join().unwrap(), (0..300).collect::<Vec<_>>());
+        assert_eq!(quota.used_bytes(), 0);
+
+        // compacted segments are released once merged
+        let quota = DiskQuota::new(u64::MAX, QuotaPolicy::Error);
+        let sorted_iter = ExternalSorter::new()
+            .with_segment_size(9)
+            .with_compaction(3)
+            .with_disk_quota(quota.clone())
+            .sort((0..1000u32).rev())
+            .unwrap();
+        assert!(quota.used_bytes() < sorted_iter.bytes_spilled());
+        assert_eq!(quota.used_bytes(), 1000 * 4);
+    }
+
+    #[test]
+    fn test_memory_pool() {
+        let pool = MemoryPool::new(8192);
+        let sorter = || {
+            PushExternalSorter::new(
+                ExternalSorter::new().with_memory_pool(pool.clone()).options,
+                |a: &u32, b: &u32| a.cmp(b),
+            )
+        };
+
+        // the first sorter draws a single step for its buffer
+        let mut first = sorter();
+        first.push_iter((0..600u32).rev()).unwrap();
+        assert_eq!(first.segment_count(), 0);
+        assert_eq!(pool.used_bytes(), 4096);
+
+        // the second one spills early since it can't draw more than the rest
+        let mut second = sorter();
+        for item in (0..3000u32).rev() {
+            second.push(item).unwrap();
+            assert!(pool.used_bytes() <= pool.max_bytes());
+        }
+        assert!(second.segment_count() >= 3);
+        assert!(second.buffered_bytes() <= 4096);
+
+        // memory is given back once sorters are done
+        let used_by_second = pool.used_bytes() - 4096;
+        let first_iter = first.done().unwrap();
+        assert_eq!(pool.used_bytes(), used_by_second);
+        assert_eq!(
+            first_iter.collect_sorted().unwrap(),
+            (0..600).collect::<Vec<_>>()
+        );
+
+        let second_iter = second.done().unwrap();
+        assert_eq!(pool.used_bytes(), 0);
+        assert_eq!(
+            second_iter.collect_sorted().unwrap(),
+            (0..3000).collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_thread_pool() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|index| format!("extsort-sort-{index}"))
+            .build()
+            .unwrap();
+        let pool = std::sync::Arc::new(pool);
+
+        let thread_names = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sorter = ExternalSorter::new()
+            .with_segment_size(10_000)
+            .with_thread_pool(pool.clone());
+        let sorted_iter = sorter.sort((0..50_000u32).rev()).unwrap();
+
+        let names = thread_names.clone();
+        let sorted = sorted_iter
+            .par_map_chunks(1000, move |item| {
+                let name = std::thread::current().name().map(str::to_string);
+                names.lock().unwrap().push(name);
+                item
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap()
+            .concat();
+        assert_eq!(sorted, (0..50_000).collect::<Vec<_>>());
+
+        let thread_names = thread_names.lock().unwrap();
+        assert_eq!(thread_names.len(), 50_000);
+        assert!(thread_names.iter().all(|name| name
+            .as_deref()
+            .is_some_and(|name| name.starts_with("extsort-sort-"))));
+    }
+
+    #[test]
+    fn test_push_snapshot() {
+        for compaction in [false, true] {
+            let mut sorter = ExternalSorter::new().with_segment_size(100);
+            if compaction {
+                sorter = sorter.with_compaction(3);
+            }
+            let mut sorter = sorter.pushed();
+
+            // snapshot of the buffer only
+            sorter.push_iter((0..50u32).rev()).unwrap();
+            let snapshot = sorter.snapshot().unwrap();
+            assert_eq!(
+                snapshot.collect_sorted().unwrap(),
+                (0..50).collect::<Vec<_>>()
+            );
+
+            // snapshot of the segments along with the buffer
+            sorter.push_iter((50..1050u32).rev()).unwrap();
+            assert!(sorter.segment_count() > 0);
+            let snapshot = sorter.snapshot().unwrap();
+            assert_eq!(snapshot.sorted_count(), 1050);
+
+            // items pushed after the snapshot aren't part of it
+            sorter.push_iter((1050..2000u32).rev()).unwrap();
+            assert_eq!(
+                snapshot.collect_sorted().unwrap(),
+                (0..1050).collect::<Vec<_>>()
+            );
+
+            let sorted_iter = sorter.done().unwrap();
+            assert_eq!(
+                sorted_iter.collect_sorted().unwrap(),
+                (0..2000).collect::<Vec<_>>()
+            );
+        }
+
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(10)
+            .with_tempfile_builder(tempfile::tempfile)
+            .pushed();
+        sorter.push_iter((0..100u32).rev()).unwrap();
+        let err = sorter.snapshot().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_sort_tail() {
+        // items encoded as `key * 100 + timestamp`, pushed in time order
+        let key = |item: &u32| *item / 100;
+        let timestamp = |item: &u32| *item % 100;
+        let items =
+            (0..50u32).flat_map(|timestamp| (0..20u32).map(move |key| key * 100 + timestamp));
+
+        let tail = ExternalSorter::new()
+            .with_segment_size(100)
+            .sort_tail(items.clone(), key, 3, move |a, b| {
+                timestamp(a).cmp(&timestamp(b))
+            })
+            .unwrap()
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        let expected = (0..20u32)
+            .flat_map(|key| [49, 48, 47].map(|timestamp| key * 100 + timestamp))
+            .collect::<Vec<_>>();
+        assert_eq!(tail, expected);
+
+        let tail = ExternalSorter::new()
+            .sort_tail(items, key, 0, move |a, b| timestamp(a).cmp(&timestamp(b)))
+            .unwrap();
+        assert_eq!(tail.count(), 0);
+
+        // keys with fewer items than the limit are kept whole
+        let tail = ExternalSorter::new()
+            .sort(vec![5u32, 1, 1, 3, 1, 5])
+            .unwrap()
+            .tail_per_key(|item| *item, 2)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(tail, vec![1, 1, 3, 5, 5]);
+    }
+
+    #[test]
+    fn test_adaptive_merge_mode() {
+        // 198 overlapping segments of small items, and 2 of large items that
+        // are the only ones left once the small items are merged
+        let small = (0..198u32).flat_map(|i| (0..10).map(move |j| i + 198 * j));
+        let large = (0..2u32).flat_map(|i| (0..10).map(move |j| 10_000 + 2 * j + i));
+
+        let sorter = ExternalSorter::new().with_segment_size(10);
+        let mut sorted_iter = sorter.sort(small.chain(large)).unwrap();
+        assert!(sorted_iter.uses_heap());
+
+        let merged = sorted_iter
+            .by_ref()
+            .take(1980)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(merged, (0..1980).collect::<Vec<_>>());
+        assert!(!sorted_iter.uses_heap());
+
+        let merged = sorted_iter.collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(merged, (10_000..10_020).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_prefix_compression() {
+        // paths encoded before a separator, so that their encoding starts
+        // with their bytes
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Path(Vec<u8>);
+
+        impl Sortable for Path {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+                writer.write_all(&self.0)?;
+                writer.write_all(b"\n")
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> Result<Self> {
+                let mut bytes = Vec::new();
+                loop {
+                    match reader.read_u8()? {
+                        b'\n' => return Ok(Path(bytes)),
+                        byte => bytes.push(byte),
+                    }
+                }
+            }
+        }
+
+        let path = |i: u32| Path(format!("https://example.com/items/{i:06}").into_bytes());
+        let data = (0..5000)
+            .map(|i| path((i * 7919) % 5000))
+            .collect::<Vec<_>>();
+        let expected = (0..5000).map(path).collect::<Vec<_>>();
+
+        let sorter = ExternalSorter::new()
+            .with_segment_size(1000)
+            .with_block_size(512);
+        let uncompressed = sorter.sort(data.clone()).unwrap().bytes_spilled();
+
+        for checksums in [false, true] {
+            let sorter = || {
+                let sorter = ExternalSorter::new()
+                    .with_segment_size(1000)
+                    .with_block_size(512)
+                    .with_prefix_compression();
+                if checksums {
+                    sorter.with_record_checksums()
+                } else {
+                    sorter
+                }
+            };
+
+            let sorted_iter = sorter().sort(data.clone()).unwrap();
+            assert!(sorted_iter.bytes_spilled() < uncompressed / 2);
+            let sorted = sorted_iter.collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(sorted, expected);
+
+            // seeking restarts the decompression at the start of a block
+            let mut sorted_iter = sorter().sort(data.clone()).unwrap();
+            sorted_iter.seek_to(&path(2500)).unwrap();
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), path(2500));
+
+            let dir = tempfile::TempDir::new().unwrap();
+            let file_path = dir.path().join("sorted");
+            let sorted_file = sorter()
+                .sort(data.clone())
+                .unwrap()
+                .persist(&file_path)
+                .unwrap();
+            assert_eq!(sorted_file.get(&path(1234)).unwrap(), Some(path(1234)));
+            assert_eq!(sorted_file.nth(4321).unwrap(), Some(path(4321)));
+
+            // byte records are compressed in their encoded form
+            let mut arena = sorter().pushed_bytes();
+            arena.push_iter(data.iter().map(|path| &path.0)).unwrap();
+            let sorted = arena.done().unwrap().collect_sorted().unwrap();
+            assert_eq!(
+                sorted,
+                expected
+                    .iter()
+                    .map(|path| path.0.clone())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        let err = ExternalSorter::new()
+            .with_prefix_compression()
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::PrefixCompressionWithoutBlocks);
+    }
+
+    #[test]
+    fn test_delta_encoding() {
+        // events encoded as their big-endian timestamp followed by their id
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Event {
+            timestamp: u64,
+            id: u32,
+        }
+
+        impl Sortable for Event {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+                writer.write_u64::<byteorder::BigEndian>(self.timestamp)?;
+                writer.write_u32::<byteorder::LittleEndian>(self.id)
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> Result<Self> {
+                Ok(Event {
+                    timestamp: reader.read_u64::<byteorder::BigEndian>()?,
+                    id: reader.read_u32::<byteorder::LittleEndian>()?,
+                })
+            }
+        }
+
+        let event = |i: u32| Event {
+            timestamp: 1_700_000_000_000 + i as u64 * 3,
+            id: i,
+        };
+        let data = (0..5000)
+            .map(|i| event((i * 7919) % 5000))
+            .collect::<Vec<_>>();
+        let expected = (0..5000).map(event).collect::<Vec<_>>();
+
+        for checksums in [false, true] {
+            let sorter_with = |key_bytes| {
+                let sorter = ExternalSorter::new()
+                    .with_segment_size(1000)
+                    .with_block_size(512)
+                    .with_delta_encoding(key_bytes);
+                if checksums {
+                    sorter.with_record_checksums()
+                } else {
+                    sorter
+                }
+            };
+            let sorter = || sorter_with(8);
+
+            let plain = sorter_with(0).sort(data.clone()).unwrap().bytes_spilled();
+            let sorted_iter = sorter().sort(data.clone()).unwrap();
+            assert!(sorted_iter.bytes_spilled() < plain * 3 / 4);
+            let sorted = sorted_iter.collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(sorted, expected);
+
+            // keys decreasing within segments are delta encoded as well
+            let sorted = sorter()
+                .sort_by(data.clone(), |a, b| b.cmp(a))
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(sorted, expected.iter().rev().cloned().collect::<Vec<_>>());
+
+            let mut sorted_iter = sorter().sort(data.clone()).unwrap();
+            sorted_iter.seek_to(&event(2500)).unwrap();
+            assert_eq!(sorted_iter.next().unwrap().unwrap(), event(2500));
+
+            let dir = tempfile::TempDir::new().unwrap();
+            let file_path = dir.path().join("sorted");
+            let sorted_file = sorter()
+                .sort(data.clone())
+                .unwrap()
+                .persist(&file_path)
+                .unwrap();
+            assert_eq!(sorted_file.get(&event(1234)).unwrap(), Some(event(1234)));
+            assert_eq!(sorted_file.nth(4321).unwrap(), Some(event(4321)));
+        }
+
+        // items shorter than the key can't be written
+        let err = ExternalSorter::new()
+            .with_segment_size(10)
+            .with_block_size(512)
+            .with_delta_encoding(8)
+            .sort(0..100u32)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let err = ExternalSorter::new()
+            .with_block_size(512)
+            .with_delta_encoding(9)
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::InvalidDeltaKeyBytes(9));
+
+        let err = ExternalSorter::new()
+            .with_delta_encoding(8)
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::DeltaEncodingWithoutBlocks);
+
+        let err = ExternalSorter::new()
+            .with_block_size(512)
+            .with_prefix_compression()
+            .with_delta_encoding(8)
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::PrefixCompressionWithDeltaEncoding);
+    }
+
+    #[test]
+    fn test_segment_transform() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        // scrambles the bytes of the segments, counting the bytes written to
+        // and read from the files
+        struct XorWriter(Box<dyn Write + Send>, Arc<AtomicU64>);
+
+        impl Write for XorWriter {
+            fn write(&mut self, buf: &[u8]) -> Result<usize> {
+                let scrambled = buf.iter().map(|byte| byte ^ 0x5a).collect::<Vec<_>>();
+                self.0.write_all(&scrambled)?;
+                self.1.fetch_add(buf.len() as u64, Ordering::Relaxed);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> Result<()> {
+                self.0.flush()
+            }
+        }
+
+        struct XorReader(Box<dyn Read + Send>, Arc<AtomicU64>);
+
+        impl Read for XorReader {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                let read = self.0.read(buf)?;
+                buf[..read].iter_mut().for_each(|byte| *byte ^= 0x5a);
+                self.1.fetch_add(read as u64, Ordering::Relaxed);
+                Ok(read)
+            }
+        }
+
+        for (block_size, compaction) in [(0, None), (64, None), (0, Some(4))] {
+            let written = Arc::new(AtomicU64::new(0));
+            let read = Arc::new(AtomicU64::new(0));
+            let (write_count, read_count) = (written.clone(), read.clone());
+            let mut sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_block_size(block_size)
+                .with_segment_transform(
+                    move |writer| Box::new(XorWriter(writer, write_count.clone())),
+                    move |reader| Box::new(XorReader(reader, read_count.clone())),
+                );
+            if let Some(fan_in) = compaction {
+                sorter = sorter.with_compaction(fan_in);
+            }
+
+            let mut sorted_iter = sorter.sort((0..1000u32).rev()).unwrap();
+            assert!(sorted_iter.disk_segment_count() > 1);
+            sorted_iter.seek_to(&10).unwrap();
+            let sorted = sorted_iter.collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(sorted, (10..1000).collect::<Vec<_>>());
+
+            assert!(written.load(Ordering::Relaxed) >= 4000);
+            assert_eq!(
+                read.load(Ordering::Relaxed),
+                written.load(Ordering::Relaxed)
+            );
+        }
+
+        // transformed segments can't be rewound to be decomposed
+        let sorter = || {
+            ExternalSorter::new()
+                .with_segment_size(100)
+                .with_segment_transform(|writer| writer, |reader| reader)
+        };
+        let sorted_iter = sorter().sort((0..1000u32).rev()).unwrap();
+        let err = sorted_iter.into_parts().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        let sorted_iter = sorter().sort((0..1000u32).rev()).unwrap();
+        let err = sorted_iter.into_segment_iters().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+        let err = ExternalSorter::new()
+            .with_reverse_index()
+            .with_segment_transform(|writer| writer, |reader| reader)
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::ReverseIndexWithSegmentTransform);
+    }
+
+    #[test]
+    fn test_argsort() {
+        for segment_size in [10_000, 100, 25] {
+            let dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_sort_dir(dir.path().to_path_buf());
+            let data = (0..1000u32)
+                .map(|i| (i % 10) * 100 + i / 10)
+                .collect::<Vec<_>>();
+
+            let indices = sorter.argsort(data.clone()).unwrap();
+            assert_eq!(indices.size_hint(), (1000, Some(1000)));
+
+            let indices = indices.collect::<std::io::Result<Vec<_>>>().unwrap();
+            let sorted = indices
+                .iter()
+                .map(|&i| data[i as usize])
+                .collect::<Vec<_>>();
+            assert_eq!(sorted, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        // equal items are returned by ascending position
+        let indices = ExternalSorter::new()
+            .with_segment_size(10)
+            .argsort_by((0..100u32).map(|i| i % 4), |a, b| b.cmp(a))
+            .unwrap()
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        let expected = (0..4u64)
+            .rev()
+            .flat_map(|key| (0..25u64).map(move |i| i * 4 + key))
+            .collect::<Vec<_>>();
+        assert_eq!(indices, expected);
+    }
+
+    #[test]
+    fn test_sort_encoded_stream() {
+        let mut encoded = Vec::new();
+        for i in (0..1000u32).rev() {
+            i.encode(&mut encoded).unwrap();
+        }
+
+        for segment_size in [10_000, 100, 25] {
+            let dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_sort_dir(dir.path().to_path_buf());
+            let sorted = sorter
+                .sort_encoded_stream::<u32, _>(std::io::Cursor::new(encoded.clone()))
+                .unwrap()
+                .collect_sorted()
+                .unwrap();
+            assert_eq!(sorted, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sorted");
+        ExternalSorter::new()
+            .with_segment_size(100)
+            .sort_encoded_stream_by::<u32, _, _>(std::io::Cursor::new(encoded.clone()), |a, b| {
+                b.cmp(a)
+            })
+            .unwrap()
+            .write_to_path(&path)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_merge_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut paths = Vec::new();
+        for i in 0..3u32 {
+            let path = dir.path().join(format!("run{}", i));
+            let run = (0..100u32).map(|j| j * 3 + i);
+            ExternalSorter::new().sort_into_path(run, &path).unwrap();
+            paths.push(path);
+        }
+
+        // an empty run should simply be skipped over
+        let empty_path = dir.path().join("empty");
+        std::fs::File::create(&empty_path).unwrap();
+        paths.push(empty_path);
+
+        let sorted_iter = ExternalSorter::new()
+            .merge_files::<u32, _, _>(&paths)
+            .unwrap();
+        assert_eq!(sorted_iter.known_count(), None);
+        assert_eq!(sorted_iter.disk_segment_count(), 4);
+
+        let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+        assert_eq!(sorted_data, (0..300u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_verify_order() {
+        for segment_size in [10_000, 100, 25] {
+            let sorted_data = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_verify_order()
+                .sort((0..1000u32).map(|i| (i % 10) * 100 + i / 10))
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        // an unsorted file makes the merged output unsorted
+        let dir = tempfile::TempDir::new().unwrap();
+        let sorted_path = dir.path().join("sorted");
+        ExternalSorter::new()
+            .sort_into_path(0..100u32, &sorted_path)
+            .unwrap();
+        let unsorted_path = dir.path().join("unsorted");
+        let mut file = std::fs::File::create(&unsorted_path).unwrap();
+        for item in [50u32, 10, 90] {
+            item.encode(&mut file).unwrap();
+        }
+
+        let merge = |sorter: ExternalSorter| {
+            sorter
+                .merge_files::<u32, _, _>([&sorted_path, &unsorted_path])
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+        };
+        assert!(merge(ExternalSorter::new()).is_ok());
+        let err = merge(ExternalSorter::new().with_verify_order()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_comparator_checks() {
+        use std::cmp::Ordering;
+
+        for segment_size in [10_000, 100, 25] {
+            let sorted_data = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .with_comparator_checks()
+                .sort((0..1000u32).map(|i| (i % 10) * 100 + i / 10))
+                .unwrap()
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+        }
+
+        let sort = |cmp: fn(&u32, &u32) -> Ordering| {
+            ExternalSorter::new()
+                .with_segment_size(100)
+                .with_comparator_checks()
+                .sort_by((0..1000u32).rev(), cmp)
+                .err()
+                .map(|err| err.kind())
+        };
+        assert_eq!(
+            sort(|_, _| Ordering::Less),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+        assert_eq!(
+            sort(|a, b| a.cmp(b).then(Ordering::Less)),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+        // rock-paper-scissors on the remainder by 3 isn't transitive
+        assert_eq!(
+            sort(|a, b| match (a % 3 + 3 - b % 3) % 3 {
+                0 => Ordering::Equal,
+                1 => Ordering::Greater,
+                _ => Ordering::Less,
+            }),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+        assert_eq!(sort(|a, b| a.cmp(b)), None);
+
+        // a buffer sort disagreeing with the comparator
+        let mut sorter = ExternalSorter::new()
+            .with_comparator_checks()
+            .pushed::<u32>()
+            .with_buffer_sort(|items| items.sort_by(|a, b| b.cmp(a)));
+        sorter.push_iter(0..100u32).unwrap();
+        sorter.push(0).unwrap();
+        let err = sorter.done().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_truncated_segment() {
+        let sort_dir = tempfile::TempDir::new().unwrap();
+        let sorter = ExternalSorter::new()
+            .with_segment_size(10_000)
+            .with_sort_dir(sort_dir.path().to_path_buf());
+        let sorted_iter = sorter.sort((0..30_000u32).rev()).unwrap();
+        assert_eq!(sorted_iter.disk_segment_count(), 3);
+
+        // cut the last items of a segment, past what was already buffered
+        let path = segment_files(sort_dir.path()).remove(0);
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(30_000).unwrap();
+
+        let err = sorted_iter.collect::<Result<Vec<u32>>>().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_try_decode() {
+        // implements `try_decode`, failing on partial items
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Framed(u32);
+        impl Sortable for Framed {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+                writer.write_u32::<byteorder::LittleEndian>(self.0)
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> Result<Framed> {
+                Framed::try_decode(reader)?.ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "end of stream")
+                })
+            }
+
+            fn try_decode<R: Read>(reader: &mut R) -> Result<Option<Framed>> {
+                let mut bytes = [0; 4];
+                let mut read = 0;
+                while read < 4 {
+                    match reader.read(&mut bytes[read..])? {
+                        0 if read == 0 => return Ok(None),
+                        0 => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "partial item",
+                            ))
+                        }
+                        count => read += count,
+                    }
+                }
+                Ok(Some(Framed(u32::from_le_bytes(bytes))))
+            }
+        }
+
+        for segment_size in [10_000, 100, 25] {
+            let sorted_data = ExternalSorter::new()
+                .with_segment_size(segment_size)
+                .sort((0..1000u32).map(|i| Framed((i % 10) * 100 + i / 10)))
+                .unwrap()
+                .collect::<Result<Vec<Framed>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..1000u32).map(Framed).collect::<Vec<_>>());
+        }
+
+        let mut encoded = Vec::new();
+        Framed(42).encode(&mut encoded).unwrap();
+        assert_eq!(Framed::decode(&mut encoded.as_slice()).unwrap(), Framed(42));
+        let err = Framed::decode(&mut &encoded[..0]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // a partial item at the end of a merged file is an error
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("partial");
+        encoded.extend_from_slice(&[1, 2]);
+        std::fs::write(&path, &encoded).unwrap();
+        let merged = ExternalSorter::new()
+            .merge_files::<Framed, _, _>([&path])
+            .and_then(|iter| iter.collect::<Result<Vec<_>>>());
+        assert!(merged.is_err());
+    }
+
+    #[test]
+    fn test_exhausted_segments_deleted() {
+        let file_count = |dir: &std::path::Path| segment_files(dir).len();
+
+        // segments holding consecutive items are exhausted one after the other
+        for (data, files_left) in [
+            ((0..1000u32).rev().collect::<Vec<_>>(), 5),
+            ((0..1000u32).map(|i| (i % 10) * 100 + i / 10).collect(), 10),
+        ] {
+            let sort_dir = tempfile::TempDir::new().unwrap();
+            let sorter = ExternalSorter::new()
+                .with_segment_size(100)
+                .with_sort_dir(sort_dir.path().to_path_buf());
+            let mut sorted_iter = sorter.sort(data).unwrap();
+            assert_eq!(file_count(sort_dir.path()), 10);
+
+            let sorted_data = sorted_iter
+                .by_ref()
+                .take(550)
+                .collect::<Result<Vec<u32>>>()
+                .unwrap();
+            assert_eq!(sorted_data, (0..550u32).collect::<Vec<_>>());
+            assert_eq!(file_count(sort_dir.path()), files_left);
+
+            assert_eq!(sorted_iter.by_ref().count(), 450);
+            assert_eq!(file_count(sort_dir.path()), 0);
+        }
+
+        // segments read from their end are kept readable
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_reverse_index();
+        let mut sorted_iter = sorter.sort((0..1000u32).rev()).unwrap();
+        let mut both_ends = Vec::new();
+        while let Some(item) = sorted_iter.next() {
+            both_ends.push(item.unwrap());
+            if let Some(item) = sorted_iter.next_back() {
+                both_ends.push(item.unwrap());
+            }
+        }
+        assert_eq!(both_ends.len(), 1000);
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let sorter = ExternalSorter::new().with_segment_size(100);
+        let sorted_iter = sorter.sort((0..1000u32).rev()).unwrap();
+
+        let parts = sorted_iter.into_parts().unwrap();
+        assert!(parts.tempdir.is_some());
+        assert!(parts.buffer.is_none());
+        assert_eq!(parts.segment_files.len(), 10);
+        assert_eq!(parts.count, Some(1000));
+
+        let sorted_iter = SortedIterator::from_parts(parts).unwrap();
+        let sorted_data = sorted_iter.collect::<Result<Vec<u32>>>().unwrap();
+        assert_eq!(sorted_data, (0..1000u32).collect::<Vec<_>>());
+
+        // the options of the sorter are carried along
+        let sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .with_duplicate_policy(DuplicatePolicy::KeepFirst);
+        let parts = sorter
+            .sort((0..1000u32).map(|i| i % 100))
+            .unwrap()
+            .into_parts()
+            .unwrap();
+        let sorted_data = SortedIterator::from_parts(parts)
+            .unwrap()
+            .collect::<Result<Vec<u32>>>()
+            .unwrap();
+        assert_eq!(sorted_data, (0..100u32).collect::<Vec<_>>());
+
+        let mut sorter = ExternalSorter::new()
+            .with_segment_size(100)
+            .pushed_by_key(|i: &u32| i / 10)
+            .with_combiner(|i: &u32| i / 10, |a: u32, b: u32| a.min(b));
+        sorter
+            .push_iter((0..1000u32).map(|i| (i % 100) * 10 + i / 100))
+            .unwrap();
+        let parts = sorter.done().unwrap().into_parts().unwrap();
+        let sorted_data = SortedIterator::from_parts(parts)
+            .unwrap()
+            .collect::<Result<Vec<u32>>>()
+            .unwrap();
+        assert_eq!(sorted_data, (0..100u32).map(|i| i * 10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        struct ErrStruct(u32);
+        impl Sortable for ErrStruct {
+            fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+                writer.write_u32::<byteorder::LittleEndian>(self.0)?;
+                Ok(())
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> std::io::Result<ErrStruct> {
+                let value = reader.read_u32::<byteorder::LittleEndian>()?;
+                if value == 1 {
+                    Err(std::io::Error::other("MyStruct::decode error"))
+                } else {
+                    Ok(ErrStruct(value))
+                }
+            }
+        }
+
+        let mut sorter = ExternalSorter::new().with_segment_size(10).pushed();
+        for item in 0..100 {
+            sorter.push(ErrStruct(item)).unwrap();
+        }
+
+        // first value is fine, but second should fail
+        let mut sorted_iter = sorter.done().unwrap();
+        assert!(sorted_iter.next().unwrap().is_ok());
+        assert!(sorted_iter.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_typed_sortable_error() {
+        #[derive(Debug)]
+        enum TypedError {
+            Odd(u32),
+            Io(std::io::Error),
+        }
+
+        impl std::fmt::Display for TypedError {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{:?}", self)
+            }
+        }
+
+        impl std::error::Error for TypedError {}
+
+        impl From<std::io::Error> for TypedError {
+            fn from(err: std::io::Error) -> TypedError {
+                TypedError::Io(err)
+            }
+        }
+
+        impl From<TypedError> for std::io::Error {
+            fn from(err: TypedError) -> std::io::Error {
+                match err {
+                    TypedError::Io(err) => err,
+                    err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+                }
+            }
+        }
+
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Even(u32);
+        impl TypedSortable for Even {
+            type Error = TypedError;
+
+            fn encode<W: Write>(&self, writer: &mut W) -> std::result::Result<(), TypedError> {
+                Ok(writer.write_u32::<byteorder::LittleEndian>(self.0)?)
+            }
+
+            fn decode<R: Read>(reader: &mut R) -> std::result::Result<Even, TypedError> {
+                match reader.read_u32::<byteorder::LittleEndian>()? {
+                    value if value % 2 == 1 => Err(TypedError::Odd(value)),
+                    value => Ok(Even(value)),
+                }
+            }
+        }
+
+        let sorter = || ExternalSorter::new().with_segment_size(10);
+
+        // end of the segments is still detected through the conversions
+        let sorted = sorter()
+            .sort((0..100).rev().map(|i| Even(i * 2)))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(sorted, (0..100).map(|i| Even(i * 2)).collect::<Vec<_>>());
+
+        let err = sorter()
+            .sort((0..100).rev().map(Even))
+            .and_then(|sorted| sorted.collect::<Result<Vec<_>>>())
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<TypedError>().unwrap();
+        assert!(matches!(*err, TypedError::Odd(value) if value % 2 == 1));
+    }
+
+    impl Sortable for u32 {
+        fn encode<W: Write>(&self, writer: &mut W) -> Result<()> {
+            writer.write_u32::<byteorder::LittleEndian>(*self)?;
+            Ok(())
+        }
+
+        fn decode<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+            reader.read_u32::<byteorder::LittleEndian>()
+        }
+    }
+
+    /// Returns the segment files written by sorters in the given sort directory.
+    fn segment_files(sort_dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(sort_dir).unwrap() {
+            let path = entry.unwrap().path();
+            for segment in std::fs::read_dir(path).unwrap() {
+                files.push(segment.unwrap().path());
+            }
         }
+        files
     }
 
     fn assert_sorted(iter: impl Iterator<Item = std::io::Result<u32>>) {
@@ -0,0 +1,53 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::Error,
+    os::fd::{AsRawFd, FromRawFd},
+};
+
+const SEGMENT_NAME: &CStr = c"extsort-segment";
+
+/// Creates an anonymous memory-backed file, which allows adding seals to it
+/// if `sealing` is true.
+pub(crate) fn create(sealing: bool) -> Result<File, Error> {
+    let mut flags = libc::MFD_CLOEXEC;
+    if sealing {
+        flags |= libc::MFD_ALLOW_SEALING;
+    }
+
+    // SAFETY: the name is a nul-terminated string literal
+    let fd = unsafe { libc::memfd_create(SEGMENT_NAME.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // SAFETY: the file descriptor was just created and isn't owned elsewhere
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Seals a file created with sealing allowed, preventing it from being written
+/// to or grown. Shrinking is still allowed so that its memory can be freed
+/// once the segment was read.
+pub(crate) fn seal(file: &File) -> Result<(), Error> {
+    let seals = libc::F_SEAL_WRITE | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+
+    // SAFETY: the file descriptor is valid for the lifetime of the file
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,241 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Minimum number of bytes drawn from a memory pool at once, so that sorters
+/// don't contend on the pool for every pushed item.
+const POOL_STEP_BYTES: u64 = 4096;
+
+/// Memory budget shared between sorters, from which they draw the memory of
+/// their in-memory buffer (see [`ExternalSorter::with_memory_pool`]).
+///
+/// The pool is a handle that can be cloned and given to every sorter running
+/// concurrently, so that their combined buffers stay within the budget
+/// instead of each being sized for the whole machine. A sorter whose buffer
+/// grows while the pool is exhausted writes its buffer to disk earlier than
+/// its segment size (see [`ExternalSorter::with_segment_size`]), which gives
+/// its memory back to the pool. Memory is drawn in steps of a few kilobytes
+/// using the estimate of [`PushExternalSorter::buffered_bytes`], so the
+/// budget needs to be large enough for each sorter to buffer a reasonable
+/// number of items.
+///
+/// [`ExternalSorter::with_memory_pool`]: crate::ExternalSorter::with_memory_pool
+/// [`ExternalSorter::with_segment_size`]: crate::ExternalSorter::with_segment_size
+/// [`PushExternalSorter::buffered_bytes`]: crate::PushExternalSorter::buffered_bytes
+#[derive(Clone, Debug)]
+pub struct MemoryPool {
+    inner: Arc<PoolState>,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    max_bytes: u64,
+    used: AtomicU64,
+}
+
+impl MemoryPool {
+    /// Creates a pool of the given number of bytes.
+    pub fn new(max_bytes: u64) -> MemoryPool {
+        MemoryPool {
+            inner: Arc::new(PoolState {
+                max_bytes,
+                used: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the maximum number of bytes of the pool.
+    pub fn max_bytes(&self) -> u64 {
+        self.inner.max_bytes
+    }
+
+    /// Returns the number of bytes currently drawn from the pool by all the
+    /// sorters sharing it.
+    pub fn used_bytes(&self) -> u64 {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes that can still be drawn from the pool.
+    pub fn available_bytes(&self) -> u64 {
+        self.max_bytes().saturating_sub(self.used_bytes())
+    }
+
+    /// Draws the given number of bytes from the pool, returning false if
+    /// they don't fit.
+    fn try_draw(&self, bytes: u64) -> bool {
+        self.inner
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes)
+                    .filter(|used| *used <= self.inner.max_bytes)
+            })
+            .is_ok()
+    }
+
+    /// Gives bytes previously drawn back to the pool.
+    fn give_back(&self, bytes: u64) {
+        self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Bytes drawn from a [`MemoryPool`] by a sorter for its buffer, which are
+/// given back once dropped.
+pub(crate) struct PoolReservation {
+    pool: MemoryPool,
+    bytes: u64,
+}
+
+impl PoolReservation {
+    pub fn new(pool: MemoryPool) -> PoolReservation {
+        PoolReservation { pool, bytes: 0 }
+    }
+
+    /// Grows the reservation to at least the given number of bytes, drawing
+    /// a step ahead if possible. Returns false if the pool doesn't have
+    /// enough bytes left.
+    pub fn reserve(&mut self, bytes: u64) -> bool {
+        let Some(missing) = bytes.checked_sub(self.bytes).filter(|missing| *missing > 0) else {
+            return true;
+        };
+
+        for draw in [missing.max(POOL_STEP_BYTES), missing] {
+            if self.pool.try_draw(draw) {
+                self.bytes += draw;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Shrinks the reservation down to the given number of bytes, giving the
+    /// rest back to the pool.
+    pub fn shrink_to(&mut self, bytes: u64) {
+        if bytes < self.bytes {
+            self.pool.give_back(self.bytes - bytes);
+            self.bytes = bytes;
+        }
+    }
+}
+
+impl Drop for PoolReservation {
+    fn drop(&mut self) {
+        self.shrink_to(0);
+    }
+}
+
+/// Mount point of the cgroup v2 hierarchy, and of the memory controller of
+/// the cgroup v1 hierarchies.
+const CGROUP_V2_MOUNT: &str = "/sys/fs/cgroup";
+const CGROUP_V1_MEMORY_MOUNT: &str = "/sys/fs/cgroup/memory";
+
+/// Returns the memory available to the process in bytes, if it can be
+/// determined on this platform.
+///
+/// The memory available on the host is capped by the memory left within the
+/// limits of the cgroups of the process, if any, so that a process running in
+/// a container doesn't size itself against the memory of the whole host.
+pub(crate) fn available_memory() -> Option<u64> {
+    let host = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| parse_meminfo(&meminfo));
+    match (host, cgroup_available_memory()) {
+        (Some(host), Some(cgroup)) => Some(host.min(cgroup)),
+        (host, cgroup) => host.or(cgroup),
+    }
+}
+
+/// Returns the memory left within the most restrictive memory limit of the
+/// cgroup of the process and its ancestors, if any is set.
+fn cgroup_available_memory() -> Option<u64> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    cgroup_memory_files(&cgroups)
+        .into_iter()
+        .filter_map(|(limit, usage)| {
+            let limit = std::fs::read_to_string(limit).ok()?;
+            let usage = std::fs::read_to_string(usage).unwrap_or_default();
+            parse_cgroup_memory(&limit, &usage)
+        })
+        .min()
+}
+
+/// Returns the limit and usage files of the memory controller for the
+/// cgroups listed in the content of `/proc/self/cgroup` and their ancestors,
+/// for both cgroup v2 (`memory.max`) and v1 (`memory.limit_in_bytes`).
+///
+/// Ancestors up to the root of the hierarchy are included since a limit can
+/// be set on a parent cgroup, and since the cgroup path isn't mounted as is in
+/// containers whose cgroup namespace is rooted at their own cgroup.
+pub(crate) fn cgroup_memory_files(cgroups: &str) -> Vec<(PathBuf, PathBuf)> {
+    let mut files = Vec::new();
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        let (mount, limit, usage) = if controllers.is_empty() {
+            (CGROUP_V2_MOUNT, "memory.max", "memory.current")
+        } else if controllers.split(',').any(|name| name == "memory") {
+            (
+                CGROUP_V1_MEMORY_MOUNT,
+                "memory.limit_in_bytes",
+                "memory.usage_in_bytes",
+            )
+        } else {
+            continue;
+        };
+
+        let mut cgroup = Some(Path::new(path.trim_start_matches('/')));
+        while let Some(dir) = cgroup {
+            let dir = Path::new(mount).join(dir);
+            files.push((dir.join(limit), dir.join(usage)));
+            cgroup = cgroup.and_then(Path::parent);
+        }
+    }
+    files
+}
+
+/// Parses the memory left within a cgroup from the content of its limit and
+/// usage files, or `None` if it has no limit.
+pub(crate) fn parse_cgroup_memory(limit: &str, usage: &str) -> Option<u64> {
+    // unlimited v1 cgroups report a limit close to `i64::MAX`, which is
+    // larger than the memory of the host anyway
+    let limit = limit.trim().parse::<u64>().ok()?;
+    let usage = usage.trim().parse::<u64>().unwrap_or(0);
+    Some(limit.saturating_sub(usage))
+}
+
+/// Parses the available memory in bytes from the content of `/proc/meminfo`.
+pub(crate) fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes = line
+        .trim_start_matches("MemAvailable:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
@@ -0,0 +1,186 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+    io::Error,
+    marker::PhantomData,
+};
+
+use crate::{segment::encode_item, Sortable};
+
+/// Returns the partition of the given key among `partitions` partitions, as
+/// used by [`ExternalSorter::sort_hash_partitioned`].
+///
+/// Keys are hashed with FNV-1a rather than the standard library's hasher,
+/// whose output may change between Rust releases, so that separate machines
+/// assign keys to the same partitions. The partition count is at least 1.
+///
+/// [`ExternalSorter::sort_hash_partitioned`]: crate::ExternalSorter::sort_hash_partitioned
+pub fn hash_partition<K: Hash + ?Sized>(key: &K, partitions: usize) -> usize {
+    let mut hasher = FnvHasher(FNV_OFFSET_BASIS);
+    key.hash(&mut hasher);
+    (hasher.finish() % partitions.max(1) as u64) as usize
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a hasher, whose output is stable across platforms of the same
+/// endianness and Rust releases.
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Computes balanced partition boundaries from a uniform sample of items.
+///
+/// Items are observed as they are pushed and a fixed size reservoir sample is
+/// kept in their encoded form. Once all items have been observed, balanced
+/// boundaries can be computed from the sample, for example to be used with
+/// [`ExternalSorter::sort_partitioned`].
+///
+/// A sampler can be fed by a push sorter (see
+/// [`PushExternalSorter::with_boundary_sampler`]), or separately by calling
+/// [`BoundarySampler::observe`] alongside each push.
+///
+/// [`ExternalSorter::sort_partitioned`]: crate::ExternalSorter::sort_partitioned
+/// [`PushExternalSorter::with_boundary_sampler`]: crate::PushExternalSorter::with_boundary_sampler
+pub struct BoundarySampler<T>
+where
+    T: Sortable,
+{
+    sample_size: usize,
+    observed: u64,
+    samples: Vec<Vec<u8>>,
+    rng_state: u64,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> BoundarySampler<T>
+where
+    T: Sortable,
+{
+    /// Creates a sampler keeping at most `sample_size` items.
+    ///
+    /// A larger sample yields more balanced boundaries at the cost of memory.
+    pub fn new(sample_size: usize) -> BoundarySampler<T> {
+        BoundarySampler {
+            sample_size,
+            observed: 0,
+            samples: Vec::with_capacity(sample_size),
+            rng_state: 0x2545_f491_4f6c_dd1d,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Observes an item, possibly keeping it in the sample.
+    ///
+    /// Items are only encoded when they get selected in the sample.
+    pub fn observe(&mut self, item: &T) -> Result<(), Error> {
+        self.observed += 1;
+
+        if self.samples.len() < self.sample_size {
+            self.samples.push(encode_item(item)?);
+            return Ok(());
+        }
+
+        let index = self.next_random() % self.observed;
+        if (index as usize) < self.sample_size {
+            self.samples[index as usize] = encode_item(item)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of items observed so far.
+    pub fn observed_count(&self) -> u64 {
+        self.observed
+    }
+
+    /// Computes boundaries splitting the observed items into `partitions`
+    /// balanced partitions using the default comparator.
+    ///
+    /// See [`BoundarySampler::boundaries_by`].
+    pub fn boundaries(&self, partitions: usize) -> Result<Vec<T>, Error>
+    where
+        T: Ord,
+    {
+        self.boundaries_by(partitions, |a, b| a.cmp(b))
+    }
+
+    /// Computes boundaries splitting the observed items into `partitions`
+    /// balanced partitions using the given comparator.
+    ///
+    /// At most `partitions - 1` sorted boundaries are returned. Fewer
+    /// boundaries may be returned if the sample is too small or if it contains
+    /// many equal items, since duplicate boundaries would produce empty
+    /// partitions.
+    pub fn boundaries_by<F>(&self, partitions: usize, cmp: F) -> Result<Vec<T>, Error>
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let mut samples = self
+            .samples
+            .iter()
+            .map(|encoded| T::decode(&mut encoded.as_slice()))
+            .collect::<Result<Vec<T>, Error>>()?;
+        samples.sort_unstable_by(&cmp);
+
+        if partitions <= 1 || samples.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let positions = (1..partitions)
+            .map(|i| i * samples.len() / partitions)
+            .collect::<Vec<_>>();
+
+        let mut boundaries: Vec<T> = Vec::with_capacity(partitions - 1);
+        for (index, sample) in samples.into_iter().enumerate() {
+            if positions.binary_search(&index).is_err() {
+                continue;
+            }
+
+            let duplicate = boundaries
+                .last()
+                .is_some_and(|last| cmp(last, &sample) == Ordering::Equal);
+            if !duplicate {
+                boundaries.push(sample);
+            }
+        }
+
+        Ok(boundaries)
+    }
+
+    /// Xorshift64* pseudo-random generator. Sampling doesn't need a high
+    /// quality generator, and using a fixed seed keeps boundaries reproducible
+    /// for identical inputs.
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
@@ -15,14 +15,27 @@
 use std::{
     cmp::Ordering,
     collections::VecDeque,
-    fs::{File, OpenOptions},
-    io::{BufWriter, Error},
+    io::Error,
     path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TryRecvError},
+        Arc,
+    },
 };
 
+#[cfg(feature = "parallel")]
 use rayon::slice::ParallelSliceMut;
 
-use crate::{ExternalSorterOptions, Sortable, SortedIterator};
+use crate::{
+    cleanup::create_temp_dir,
+    compare::check_total_order,
+    iter::Combiner,
+    memory::PoolReservation,
+    partition::BoundarySampler,
+    quota::QuotaReservation,
+    segment::{remove_segment, SegmentFile, SegmentWriter},
+    ExternalSorterOptions, SortAlgorithm, Sortable, SortedIterator,
+};
 
 /// External sorter that uses a "push" pattern instead of consuming an iterator.
 ///
@@ -36,11 +49,37 @@ where
     options: ExternalSorterOptions,
     tempdir: Option<tempfile::TempDir>,
     count: u64,
-    segment_files: Vec<File>,
+    segment_files: Vec<SegmentFile>,
+    next_segment_id: usize,
+    memory_segments: usize,
+    segment_size: usize,
+    written_segments: usize,
+    written_items: u64,
+    written_bytes: u64,
+    disk_segments_written: usize,
+    disk_items_written: u64,
+    disk_bytes_written: u64,
+    bytes_spilled: u64,
     buffer: Vec<T>,
+    buffer_sorted: bool,
+    run_start: usize,
+    compaction: Option<Compaction<T, F>>,
+    compactor: Option<Compactor<T>>,
+    buffer_watermarks: Vec<usize>,
+    buffer_watermark_callback: Option<BufferWatermarkCallback>,
+    buffer_sort: Option<BufferSort<T>>,
+    filter: Option<PushFilter<T>>,
+    combiner: Option<Combiner<T>>,
+    sampler: Option<BoundarySampler<T>>,
+    reservation: Option<QuotaReservation>,
+    pool_reservation: Option<PoolReservation>,
     cmp: F,
 }
 
+type BufferWatermarkCallback = Box<dyn FnMut(usize) -> bool + Send>;
+type BufferSort<T> = Box<dyn Fn(&mut [T]) + Send + Sync>;
+type PushFilter<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
 impl<T, F> PushExternalSorter<T, F>
 where
     T: Sortable,
@@ -48,15 +87,147 @@ where
 {
     pub(crate) fn new(options: crate::ExternalSorterOptions, cmp: F) -> PushExternalSorter<T, F> {
         PushExternalSorter {
+            segment_size: options.segment_size,
+            reservation: options.disk_quota.clone().map(QuotaReservation::new),
+            pool_reservation: options.memory_pool.clone().map(PoolReservation::new),
             options,
             tempdir: None,
             count: 0,
             segment_files: Vec::new(),
+            next_segment_id: 0,
+            memory_segments: 0,
+            written_segments: 0,
+            written_items: 0,
+            written_bytes: 0,
+            disk_segments_written: 0,
+            disk_items_written: 0,
+            disk_bytes_written: 0,
+            bytes_spilled: 0,
             buffer: Vec::new(),
+            buffer_sorted: true,
+            run_start: 0,
+            compaction: None,
+            compactor: None,
+            buffer_watermarks: Vec::new(),
+            buffer_watermark_callback: None,
+            buffer_sort: None,
+            filter: None,
+            combiner: None,
+            sampler: None,
             cmp,
         }
     }
 
+    /// Sorts the in-memory buffer using the given function instead of the
+    /// configured sort algorithm (see [`ExternalSorter::with_sort_algorithm`]).
+    ///
+    /// The function needs to sort the items in the same order as the
+    /// comparator, which is still used to merge the segments on disk.
+    ///
+    /// [`ExternalSorter::with_sort_algorithm`]: crate::ExternalSorter::with_sort_algorithm
+    pub fn with_buffer_sort<S>(mut self, sort: S) -> Self
+    where
+        S: Fn(&mut [T]) + Send + Sync + 'static,
+    {
+        self.buffer_sort = Some(Box::new(sort));
+        self
+    }
+
+    /// Drops the pushed items for which the predicate returns false before
+    /// they get buffered, so that they take neither memory nor disk space.
+    ///
+    /// Dropped items aren't counted as pushed (see
+    /// [`PushExternalSorter::pushed_count`]).
+    pub fn with_filter<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Box::new(predicate));
+        self
+    }
+
+    /// Reduces the items having the same key into a single item, both in the
+    /// buffer before its items get written to disk and while merging the
+    /// sorted segments, which saves disk space when many items share a key.
+    ///
+    /// Items having the same key need to compare equal, so that they are
+    /// consecutive once sorted. The reducer may be applied in any order
+    /// between the items of a key, unless the sorter is deterministic (see
+    /// [`ExternalSorter::with_deterministic`]), and on already reduced items.
+    /// The duplicate policy is ignored (see
+    /// [`ExternalSorter::with_duplicate_policy`]). Since the number of keys
+    /// isn't known upfront, the sorted iterator doesn't know its number of
+    /// items (see [`SortedIterator::known_count`]).
+    ///
+    /// [`ExternalSorter::with_deterministic`]: crate::ExternalSorter::with_deterministic
+    /// [`ExternalSorter::with_duplicate_policy`]: crate::ExternalSorter::with_duplicate_policy
+    pub fn with_combiner<K, KF, R>(mut self, key: KF, reduce: R) -> Self
+    where
+        K: PartialEq,
+        KF: Fn(&T) -> K + Send + Sync + 'static,
+        R: Fn(T, T) -> T + Send + Sync + 'static,
+    {
+        self.combiner = Some(Combiner {
+            same_key: Arc::new(move |a, b| key(a) == key(b)),
+            reduce: Arc::new(reduce),
+        });
+        self
+    }
+
+    /// Samples the pushed items using a [`BoundarySampler`] keeping at most
+    /// `sample_size` items, from which balanced partition boundaries can be
+    /// computed once all items were pushed (see
+    /// [`PushExternalSorter::boundary_sampler`]).
+    ///
+    /// Items dropped by the filter aren't sampled (see
+    /// [`PushExternalSorter::with_filter`]).
+    pub fn with_boundary_sampler(mut self, sample_size: usize) -> Self {
+        self.sampler = Some(BoundarySampler::new(sample_size));
+        self
+    }
+
+    /// Merges groups of segments on a background thread while items are
+    /// being pushed, instead of incrementally on each push (see
+    /// [`ExternalSorter::with_compaction`]).
+    ///
+    /// The thread is spawned right away if compaction is enabled, and exits
+    /// once the sorter is done or dropped. Items and comparators need to be
+    /// `'static` to be moved to the thread, which isn't required when sorting
+    /// an iterator since its segments are always merged on a background
+    /// thread scoped to the sort. If the thread can't be spawned, which is the
+    /// case on targets without threads, groups are merged incrementally on
+    /// each push instead.
+    ///
+    /// [`ExternalSorter::with_compaction`]: crate::ExternalSorter::with_compaction
+    pub fn with_background_compaction(self) -> Self
+    where
+        T: 'static,
+        F: 'static,
+    {
+        match compaction_worker(&self.options, &self.cmp) {
+            Some((handle, worker)) => {
+                let spawned = self.options.background_thread().spawn(worker).is_ok();
+                self.with_compaction_handle(spawned.then_some(handle))
+            }
+            None => self,
+        }
+    }
+
+    /// Merges groups of segments on the background thread of the given
+    /// worker (see [`compaction_worker`]).
+    pub(crate) fn with_compaction_handle(mut self, handle: Option<CompactionHandle<T>>) -> Self {
+        self.compactor = handle.map(|handle| {
+            let (results_sender, results) = sync_channel(1);
+            Compactor {
+                jobs: handle.jobs,
+                results_sender,
+                results,
+                pending: None,
+            }
+        });
+        self
+    }
+
     /// Pushes all items from an iterator into the sorter.
     ///
     /// This can be called multiple times to push more items into the sorter.
@@ -72,80 +243,907 @@ where
 
     /// Pushes a single item into the sorter.
     pub fn push(&mut self, item: T) -> Result<(), Error> {
+        self.push_with_outcome(item)?;
+        Ok(())
+    }
+
+    /// Pushes a single item into the sorter, returning whether buffered items
+    /// were written to disk as a result.
+    ///
+    /// This allows producers to apply backpressure or log when the sorter
+    /// writes to disk. See [`PushOutcome`].
+    pub fn push_with_outcome(&mut self, item: T) -> Result<PushOutcome, Error> {
+        if let Some(filter) = &self.filter {
+            if !filter(&item) {
+                return Ok(PushOutcome {
+                    buffered: self.buffer.len(),
+                    ..PushOutcome::default()
+                });
+            }
+        }
+
+        if let Some(sampler) = &mut self.sampler {
+            sampler.observe(&item)?;
+        }
+
+        let written_segments = self.disk_segments_written;
+        let written_items = self.disk_items_written;
+        let written_bytes = self.disk_bytes_written;
+
+        // keep track of whether items were pushed in order, in which case the
+        // buffer doesn't need to be sorted
+        let in_order = self
+            .buffer
+            .last()
+            .is_none_or(|last| (self.cmp)(last, &item) != Ordering::Greater);
+        if !in_order {
+            let min_run_len = self.options.natural_run_min_len;
+            if min_run_len > 0 && self.buffer.len() - self.run_start >= min_run_len {
+                self.write_natural_run()?;
+            } else {
+                self.buffer_sorted = false;
+            }
+            self.run_start = self.buffer.len();
+        }
+
         self.buffer.push(item);
         self.count += 1;
 
-        if self.buffer.len() > self.options.segment_size {
+        if let Some(callback) = &mut self.buffer_watermark_callback {
+            let len = self.buffer.len();
+            if self.buffer_watermarks.binary_search(&len).is_ok() && callback(len) {
+                self.spill_now()?;
+            }
+        }
+
+        if self.buffer.len() > self.segment_size || !self.reserve_buffer_memory() {
             self.sort_and_write_segment()?;
         }
 
+        self.compact()?;
+
+        Ok(PushOutcome {
+            segments_written: self.disk_segments_written - written_segments,
+            items_written: self.disk_items_written - written_items,
+            bytes_written: self.disk_bytes_written - written_bytes,
+            buffered: self.buffer.len(),
+        })
+    }
+
+    /// Sorts and writes the items buffered in memory to disk right away, for
+    /// example to release memory when the application is under memory
+    /// pressure.
+    ///
+    /// Does nothing if no items are buffered.
+    pub fn spill_now(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            self.sort_and_write_segment()?;
+        }
         Ok(())
     }
 
+    /// Sets a callback called each time the number of items buffered in memory
+    /// reaches one of the given watermarks, with the reached watermark.
+    ///
+    /// If the callback returns true, the buffered items are written to disk
+    /// as with [`PushExternalSorter::spill_now`].
+    pub fn on_buffer_watermark<C>(&mut self, mut watermarks: Vec<usize>, callback: C)
+    where
+        C: FnMut(usize) -> bool + Send + 'static,
+    {
+        watermarks.sort_unstable();
+        self.buffer_watermarks = watermarks;
+        self.buffer_watermark_callback = Some(Box::new(callback));
+    }
+
+    /// Signals that no item smaller than the given watermark will be pushed
+    /// anymore, and passes all items pushed so far that are smaller than the
+    /// watermark to `emit` in sorted order.
+    ///
+    /// This allows consuming time-ordered data while it is still being
+    /// pushed instead of waiting for [`PushExternalSorter::done`]. The items
+    /// greater or equal to the watermark are merged back into the sorter,
+    /// which rewrites the ones that were on disk. Items smaller than the
+    /// watermark pushed afterward are emitted by later calls, and the overall
+    /// output won't be sorted.
+    ///
+    /// Returns the number of emitted items.
+    pub fn push_watermark<E>(&mut self, watermark: &T, mut emit: E) -> Result<u64, Error>
+    where
+        E: FnMut(T) -> Result<(), Error>,
+    {
+        let pass_through_queue = self.flush_buffer()?;
+        let previous_paths = self
+            .segment_files
+            .iter()
+            .filter_map(|segment_file| segment_file.path.clone())
+            .collect::<Vec<_>>();
+
+        let mut emitted = 0;
+        for item in self.take_sorted(None, pass_through_queue)? {
+            let item = item?;
+            if (self.cmp)(&item, watermark) == Ordering::Less {
+                emit(item)?;
+                emitted += 1;
+            } else {
+                self.push(item)?;
+            }
+        }
+
+        for path in previous_paths {
+            remove_segment(&path)?;
+        }
+
+        Ok(emitted)
+    }
+
+    /// Returns the number of items pushed so far, excluding the ones emitted
+    /// by [`PushExternalSorter::push_watermark`].
+    pub fn pushed_count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sampler of the pushed items, if enabled using
+    /// [`PushExternalSorter::with_boundary_sampler`].
+    pub fn boundary_sampler(&self) -> Option<&BoundarySampler<T>> {
+        self.sampler.as_ref()
+    }
+
+    /// Returns the number of items buffered in memory, which haven't been
+    /// written to disk yet.
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns an estimate of the memory used by the items buffered in memory,
+    /// in bytes.
+    ///
+    /// Each item is estimated to use its in-memory size plus the average
+    /// encoded size of the items written to disk so far, as an approximation
+    /// of the memory it owns on the heap. Before any item is written to disk,
+    /// only the in-memory size is counted.
+    pub fn buffered_bytes(&self) -> u64 {
+        let heap_bytes = self
+            .written_bytes
+            .checked_div(self.written_items)
+            .unwrap_or(0);
+        self.buffer.len() as u64 * (std::mem::size_of::<T>() as u64 + heap_bytes)
+    }
+
+    /// Returns the cumulative number of bytes written to disk so far,
+    /// including segments that were compacted and deleted since.
+    pub fn bytes_spilled(&self) -> u64 {
+        self.bytes_spilled
+    }
+
+    /// Returns the number of segments currently on disk, including the ones
+    /// being merged by an ongoing compaction.
+    pub fn segment_count(&self) -> usize {
+        let compacting = self
+            .compaction
+            .as_ref()
+            .map_or(0, |compaction| compaction.inputs.len());
+        let compacting_in_background = self
+            .compactor
+            .as_ref()
+            .and_then(|compactor| compactor.pending.as_ref())
+            .map_or(0, |pending| pending.segments);
+        self.segment_files.len() + compacting + compacting_in_background
+    }
+
     pub fn done(mut self) -> Result<SortedIterator<T, F>, Error> {
-        // Write any items left in the buffer, but only if we had at least 1 segment
-        // written. Otherwise, we use the buffer itself to iterate from memory.
-        let pass_through_queue = if !self.buffer.is_empty() && !self.segment_files.is_empty() {
-            self.sort_and_write_segment()?;
-            None
+        let pass_through_queue = self.flush_buffer()?;
+        let tempdir = self.tempdir.take();
+        self.take_sorted(tempdir, pass_through_queue)
+    }
+
+    /// Returns a sorted iterator over a snapshot of the items pushed so far,
+    /// while the sorter keeps accepting new items.
+    ///
+    /// The snapshot merges the segments written so far, which are reopened
+    /// independently from the sorter, with a sorted copy of the buffered
+    /// items, which is kept in memory. An ongoing compaction is finished
+    /// first (see [`ExternalSorter::with_compaction`]). Items pushed
+    /// afterward aren't part of the snapshot, which allows taking periodic
+    /// sorted checkpoints of a long-running ingestion.
+    ///
+    /// Segments are opened when the snapshot is taken, so that it stays
+    /// readable once they are deleted by later compactions on platforms
+    /// allowing open files to be deleted, such as Unix. Returns an
+    /// `Unsupported` error if segments are written to anonymous files (see
+    /// [`ExternalSorter::with_tempfile_builder`]).
+    ///
+    /// [`ExternalSorter::with_compaction`]: crate::ExternalSorter::with_compaction
+    /// [`ExternalSorter::with_tempfile_builder`]: crate::ExternalSorter::with_tempfile_builder
+    pub fn snapshot(&mut self) -> Result<SortedIterator<T, F>, Error>
+    where
+        T: Clone,
+    {
+        if let Some(compaction) = self.compaction.take() {
+            self.finish_compaction(compaction, usize::MAX)?;
+        }
+        self.wait_background_compaction()?;
+
+        let mut buffer = self.buffer.clone();
+        if !self.buffer_sorted {
+            buffer.sort_by(|a, b| (self.cmp)(a, b));
+        }
+
+        let mut segment_files = self
+            .segment_files
+            .iter()
+            .map(SegmentFile::reopen)
+            .collect::<Result<Vec<_>, Error>>()?;
+        let pass_through_queue = if segment_files.is_empty() {
+            Some(VecDeque::from(buffer))
         } else {
-            let cmp = self.cmp.clone();
-            self.buffer.sort_unstable_by(cmp);
-            Some(VecDeque::from(self.buffer))
+            if !buffer.is_empty() {
+                let mut writer = SegmentWriter::create_in_memory(&self.options, buffer.len())?;
+                for item in buffer {
+                    writer.write(item)?;
+                }
+                segment_files.push(writer.finish()?);
+            }
+            None
         };
 
         SortedIterator::new(
-            self.tempdir,
+            None,
             pass_through_queue,
-            self.segment_files,
-            self.count,
-            self.cmp,
+            segment_files,
+            Some(self.count),
+            self.cmp.clone(),
             self.options.clone(),
         )
+        .map(|iter| iter.with_combiner(self.combiner.clone()))
+    }
+
+    /// Writes any items left in the buffer, but only if we had at least 1
+    /// segment written. Otherwise, the sorted buffer itself is returned to
+    /// iterate from memory.
+    fn flush_buffer(&mut self) -> Result<Option<VecDeque<T>>, Error> {
+        if let Some(compaction) = self.compaction.take() {
+            self.finish_compaction(compaction, usize::MAX)?;
+        }
+        self.wait_background_compaction()?;
+
+        if self.segment_files.is_empty() {
+            if !self.buffer_sorted {
+                self.sort_items(..);
+            }
+            self.check_sorted(self.buffer.len())?;
+            self.buffer_sorted = true;
+            self.run_start = 0;
+            return Ok(Some(VecDeque::from(std::mem::take(&mut self.buffer))));
+        }
+
+        if !self.buffer.is_empty() {
+            let min_run_len = self.options.natural_run_min_len;
+            if min_run_len > 0 && self.buffer.len() - self.run_start >= min_run_len {
+                self.write_natural_run()?;
+            } else {
+                self.sort_and_write_segment()?;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Takes all items pushed so far as a sorted iterator, leaving the sorter
+    /// empty.
+    fn take_sorted(
+        &mut self,
+        tempdir: Option<tempfile::TempDir>,
+        pass_through_queue: Option<VecDeque<T>>,
+    ) -> Result<SortedIterator<T, F>, Error> {
+        SortedIterator::new(
+            tempdir,
+            pass_through_queue,
+            std::mem::take(&mut self.segment_files),
+            Some(std::mem::take(&mut self.count)),
+            self.cmp.clone(),
+            self.options.clone(),
+        )
+        .map(|iter| {
+            iter.with_bytes_spilled(self.bytes_spilled)
+                .with_combiner(self.combiner.clone())
+                .with_quota_reservation(self.reservation.as_mut().map(QuotaReservation::take))
+        })
+    }
+
+    /// Writes the ascending run at the end of the buffer as its own segment,
+    /// after writing the unordered items preceding it, if any.
+    fn write_natural_run(&mut self) -> Result<(), Error> {
+        let run_start = self.run_start;
+        if run_start > 0 {
+            if !self.buffer_sorted {
+                self.sort_items(..run_start);
+            }
+            self.write_segment(run_start)?;
+        }
+
+        // the rest of the buffer is the run, which is already sorted
+        self.buffer_sorted = true;
+        self.sort_and_write_segment()
     }
 
     fn sort_and_write_segment(&mut self) -> Result<(), Error> {
+        if !self.buffer_sorted {
+            self.sort_items(..);
+        }
+        self.buffer_sorted = true;
+        self.run_start = 0;
+
+        self.write_segment(self.buffer.len())
+    }
+
+    /// Sorts the given range of the buffer.
+    fn sort_items<R>(&mut self, range: R)
+    where
+        R: std::slice::SliceIndex<[T], Output = [T]>,
+    {
         let cmp = self.cmp.clone();
-        if self.options.parallel {
-            self.buffer.par_sort_unstable_by(|a, b| cmp(a, b));
+        let items = &mut self.buffer[range];
+        if let Some(buffer_sort) = &self.buffer_sort {
+            buffer_sort(items);
+            return;
+        }
+
+        match (self.options.buffer_sort_algorithm(), self.options.parallel) {
+            #[cfg(feature = "parallel")]
+            (SortAlgorithm::Unstable, true) => self
+                .options
+                .install(|| items.par_sort_unstable_by(|a, b| cmp(a, b))),
+            #[cfg(feature = "parallel")]
+            (SortAlgorithm::Stable, true) => {
+                self.options.install(|| items.par_sort_by(|a, b| cmp(a, b)))
+            }
+            (SortAlgorithm::Unstable, _) => items.sort_unstable_by(|a, b| cmp(a, b)),
+            (SortAlgorithm::Stable, _) => items.sort_by(|a, b| cmp(a, b)),
+        }
+    }
+
+    /// Checks the comparator against the first `len` items of the buffer,
+    /// which need to be sorted, if enabled.
+    fn check_sorted(&self, len: usize) -> Result<(), Error> {
+        if self.options.check_comparator {
+            check_total_order(&self.buffer[..len], &self.cmp)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the first `len` items of the buffer, which need to be sorted, to
+    /// a new segment on disk. The buffer keeps its capacity for the next items.
+    fn write_segment(&mut self, len: usize) -> Result<(), Error> {
+        self.check_sorted(len)?;
+        let len = match &self.combiner {
+            Some(combiner) => {
+                let rest = self.buffer.split_off(len);
+                self.buffer = combiner.combine(std::mem::take(&mut self.buffer));
+                let len = self.buffer.len();
+                self.buffer.extend(rest);
+                len
+            }
+            None => len,
+        };
+        let mut writer = self.create_segment_writer(len)?;
+        let mut buffer = std::mem::take(&mut self.buffer);
+        for item in buffer.drain(..len) {
+            if writer.is_full(self.options.max_segment_bytes) {
+                let next_writer = self.create_segment_writer(len)?;
+                let full_writer = std::mem::replace(&mut writer, next_writer);
+                self.add_segment_file(full_writer.finish()?)?;
+            }
+            writer.write(item)?;
+        }
+        self.buffer = buffer;
+        self.add_segment_file(writer.finish()?)?;
+        self.adjust_segment_size();
+
+        let buffered_bytes = self.buffered_bytes();
+        if let Some(reservation) = &mut self.pool_reservation {
+            reservation.shrink_to(buffered_bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Draws the memory used by the buffer from the memory pool, if any,
+    /// returning false if the pool ran out of memory.
+    fn reserve_buffer_memory(&mut self) -> bool {
+        let bytes = self.buffered_bytes();
+        match &mut self.pool_reservation {
+            Some(reservation) => reservation.reserve(bytes),
+            None => true,
+        }
+    }
+
+    /// Adds a segment written from the buffer to the segments of the sorter,
+    /// which are only counted as written to disk if they aren't kept in
+    /// memory.
+    fn add_segment_file(&mut self, segment_file: SegmentFile) -> Result<(), Error> {
+        if segment_file.file.is_file() {
+            self.charge_quota(segment_file.size.unwrap_or(0))?;
+            self.bytes_spilled += segment_file.size.unwrap_or(0);
+            self.disk_segments_written += 1;
+            self.disk_items_written += segment_file.count.unwrap_or(0);
+            self.disk_bytes_written += segment_file.size.unwrap_or(0);
+        }
+        self.written_segments += 1;
+        self.written_items += segment_file.count.unwrap_or(0);
+        self.written_bytes += segment_file.size.unwrap_or(0);
+        self.segment_files.push(segment_file);
+        Ok(())
+    }
+
+    /// Charges the given number of bytes written to disk to the disk quota,
+    /// if any.
+    fn charge_quota(&mut self, bytes: u64) -> Result<(), Error> {
+        match &mut self.reservation {
+            Some(reservation) => reservation.grow(bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates the writer of a segment written from the buffer, which is kept
+    /// in memory for the first segments or while it is small if enabled.
+    fn create_segment_writer(&mut self, capacity: usize) -> Result<SegmentWriter<T>, Error> {
+        if self.memory_segments < self.options.in_memory_segments {
+            self.memory_segments += 1;
+            return SegmentWriter::create_in_memory(&self.options, capacity);
+        }
+
+        let segment_path = self.next_segment_path()?;
+        match self.options.in_memory_segment_bytes {
+            0 => SegmentWriter::create(segment_path, &self.options, capacity),
+            max_bytes => {
+                SegmentWriter::create_spilling(segment_path, max_bytes, &self.options, capacity)
+            }
+        }
+    }
+
+    /// Advances the ongoing compaction, if any, or starts a new one if enough
+    /// segments were written.
+    fn compact(&mut self) -> Result<(), Error> {
+        if self.compactor.is_some() {
+            return self.compact_in_background();
+        }
+
+        let fan_in = self.options.compaction_fan_in.unwrap_or(0);
+        if self.compaction.is_none() && fan_in > 1 && self.segment_files.len() >= fan_in {
+            let segment_files = self.segment_files.drain(..fan_in).collect::<Vec<_>>();
+            let count = segment_files
+                .iter()
+                .map(|segment_file| segment_file.count.unwrap_or(0))
+                .sum::<u64>();
+            let inputs = segment_files
+                .iter()
+                .filter_map(|segment_file| segment_file.path.clone())
+                .collect();
+            let input_bytes = segment_files
+                .iter()
+                .filter(|segment_file| segment_file.file.is_file())
+                .map(|segment_file| segment_file.size.unwrap_or(0))
+                .sum();
+
+            let path = self.next_segment_path()?;
+            let writer = SegmentWriter::create(path, &self.options, count as usize)?;
+            let iter = SortedIterator::new(
+                None,
+                None,
+                segment_files,
+                Some(count),
+                self.cmp.clone(),
+                self.options.clone(),
+            )?
+            .with_combiner(self.combiner.clone());
+
+            // merge enough items on each push for the compaction to be done
+            // by the time the next segment is written
+            let step = count.div_ceil(self.segment_size.max(1) as u64) as usize;
+            self.compaction = Some(Compaction {
+                iter,
+                writer,
+                inputs,
+                input_bytes,
+                step,
+                outputs: 0,
+            });
+        }
+
+        if let Some(compaction) = self.compaction.take() {
+            let step = compaction.step;
+            self.finish_compaction(compaction, step)?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges up to `max_items` items of the compaction, and adds the merged
+    /// segment to the segments once all items were merged.
+    fn finish_compaction(
+        &mut self,
+        mut compaction: Compaction<T, F>,
+        max_items: usize,
+    ) -> Result<(), Error> {
+        for _ in 0..max_items {
+            let Some(item) = compaction.iter.next() else {
+                let Compaction {
+                    iter,
+                    writer,
+                    inputs,
+                    input_bytes,
+                    outputs,
+                    ..
+                } = compaction;
+                drop(iter);
+
+                let segment_file = writer.finish()?;
+                self.charge_quota(segment_file.size.unwrap_or(0))?;
+                self.bytes_spilled += segment_file.size.unwrap_or(0);
+                self.segment_files.insert(outputs, segment_file);
+                for path in inputs {
+                    remove_segment(&path)?;
+                }
+                if let Some(reservation) = &mut self.reservation {
+                    reservation.shrink(input_bytes);
+                }
+                return Ok(());
+            };
+
+            if compaction.writer.is_full(self.options.max_segment_bytes) {
+                let path = self.next_segment_path()?;
+                let writer = SegmentWriter::create(path, &self.options, 0)?;
+                let full_writer = std::mem::replace(&mut compaction.writer, writer);
+                let segment_file = full_writer.finish()?;
+                self.charge_quota(segment_file.size.unwrap_or(0))?;
+                self.bytes_spilled += segment_file.size.unwrap_or(0);
+                self.segment_files.insert(compaction.outputs, segment_file);
+                compaction.outputs += 1;
+            }
+            compaction.writer.write(item?)?;
+        }
+
+        self.compaction = Some(compaction);
+        Ok(())
+    }
+
+    /// Adds the segments merged by the background compaction once it is done,
+    /// and hands the next group of segments over to the background thread if
+    /// enough segments were written.
+    ///
+    /// If the previous group isn't merged yet by then, this waits for it so
+    /// that the number of segments stays bounded when items are pushed faster
+    /// than they are merged.
+    fn compact_in_background(&mut self) -> Result<(), Error> {
+        let Some(mut compactor) = self.compactor.take() else {
+            return Ok(());
+        };
+
+        let fan_in = self.options.compaction_fan_in.unwrap_or(0);
+        let due = fan_in > 1 && self.segment_files.len() >= fan_in;
+        let result = if compactor.pending.is_some() {
+            compactor.try_finish(due)
         } else {
-            self.buffer.sort_unstable_by(|a, b| cmp(a, b));
+            Ok(None)
+        };
+        let result = result.and_then(|merged| match merged {
+            Some((pending, segment_files)) => self.add_compacted_segments(pending, segment_files),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            self.compactor = Some(compactor);
+            return Err(err);
         }
 
-        let sort_dir = self.get_sort_dir()?;
-        let segment_path = sort_dir.join(format!("{}", self.segment_files.len()));
-        let segment_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .read(true)
-            .write(true)
-            .open(segment_path)?;
-        let mut buf_writer = BufWriter::new(segment_file);
+        if compactor.pending.is_none() && due {
+            let segment_files = self.segment_files.drain(..fan_in).collect::<Vec<_>>();
+            let pending = PendingCompaction {
+                segments: segment_files.len(),
+                inputs: segment_files
+                    .iter()
+                    .filter_map(|segment_file| segment_file.path.clone())
+                    .collect(),
+                input_bytes: segment_files
+                    .iter()
+                    .filter(|segment_file| segment_file.file.is_file())
+                    .map(|segment_file| segment_file.size.unwrap_or(0))
+                    .sum(),
+            };
+            let job = CompactionJob {
+                segment_files,
+                path: self.next_segment_path()?,
+                combiner: self.combiner.clone(),
+                results: compactor.results_sender.clone(),
+            };
+            if compactor.jobs.send(job).is_err() {
+                self.compactor = Some(compactor);
+                return Err(compaction_thread_error());
+            }
+            compactor.pending = Some(pending);
+        }
 
-        for item in self.buffer.drain(0..) {
-            item.encode(&mut buf_writer)?;
+        self.compactor = Some(compactor);
+        Ok(())
+    }
+
+    /// Waits for the background compaction, if any, and adds its merged
+    /// segments to the segments.
+    fn wait_background_compaction(&mut self) -> Result<(), Error> {
+        let Some(compactor) = &mut self.compactor else {
+            return Ok(());
+        };
+        if compactor.pending.is_none() {
+            return Ok(());
         }
 
-        let file = buf_writer.into_inner()?;
-        self.segment_files.push(file);
+        if let Some((pending, segment_files)) = compactor.try_finish(true)? {
+            self.add_compacted_segments(pending, segment_files)?;
+        }
+        Ok(())
+    }
 
+    /// Adds the segments merged by a background compaction before the
+    /// segments written since it started, and deletes the merged segments.
+    fn add_compacted_segments(
+        &mut self,
+        pending: PendingCompaction,
+        segment_files: Vec<SegmentFile>,
+    ) -> Result<(), Error> {
+        for (position, segment_file) in segment_files.into_iter().enumerate() {
+            self.charge_quota(segment_file.size.unwrap_or(0))?;
+            self.bytes_spilled += segment_file.size.unwrap_or(0);
+            self.segment_files.insert(position, segment_file);
+        }
+        for path in pending.inputs {
+            remove_segment(&path)?;
+        }
+        if let Some(reservation) = &mut self.reservation {
+            reservation.shrink(pending.input_bytes);
+        }
         Ok(())
     }
 
+    /// Adjusts the maximum number of items of the next segments from the
+    /// sizes of the items written so far.
+    fn adjust_segment_size(&mut self) {
+        if self.written_items == 0 || self.written_bytes == 0 {
+            return;
+        }
+
+        let mut segment_size: Option<u64> = None;
+        if self.options.segment_bytes > 0 {
+            segment_size =
+                Some(self.options.segment_bytes * self.written_items / self.written_bytes);
+        }
+
+        if self.options.memory_fraction > 0.0 {
+            if let Some(available) = crate::memory::available_memory() {
+                let limit = (available as f64 * self.options.memory_fraction) as u64;
+                let item_bytes = std::mem::size_of::<T>() as u64
+                    + self.written_bytes.div_ceil(self.written_items);
+                let memory_size = limit / item_bytes.max(1);
+                segment_size = Some(segment_size.map_or(memory_size, |size| size.min(memory_size)));
+            }
+        }
+
+        if let Some(segment_size) = segment_size {
+            self.segment_size = segment_size.max(1) as usize;
+        }
+    }
+
+    /// Returns the path of the next segment, or `None` if segments are written
+    /// in scratch files created by the tempfile builder.
+    fn next_segment_path(&mut self) -> Result<Option<PathBuf>, Error> {
+        if self.options.tempfile_builder.is_some() {
+            return Ok(None);
+        }
+
+        let sort_dir = self.get_sort_dir()?;
+        let segment_path = sort_dir.join(format!("{}", self.next_segment_id));
+        self.next_segment_id += 1;
+        Ok(Some(segment_path))
+    }
+
     /// We only want to create a directory if it's needed (i.e., if the dataset
     /// doesn't fit in memory) to prevent filesystem latency.
     fn get_sort_dir(&mut self) -> Result<PathBuf, Error> {
-        if let Some(sort_dir) = self.options.sort_dir.as_ref() {
-            return Ok(sort_dir.clone());
+        if self.tempdir.is_none() {
+            self.tempdir = Some(create_temp_dir(self.options.sort_dir.as_deref())?);
+        }
+
+        Ok(self.tempdir.as_ref().unwrap().path().to_path_buf())
+    }
+}
+
+/// Outcome of pushing an item into a sorter (see
+/// [`PushExternalSorter::push_with_outcome`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PushOutcome {
+    /// Number of segments written to disk from the buffer, which is usually 0,
+    /// or 1 when the buffer was full. Natural runs may write 2 segments, and
+    /// more segments are written when split at the maximum segment size (see
+    /// [`ExternalSorter::with_max_segment_bytes`]).
+    ///
+    /// Segments kept in memory aren't counted (see
+    /// [`ExternalSorter::with_in_memory_segments`]).
+    ///
+    /// [`ExternalSorter::with_max_segment_bytes`]: crate::ExternalSorter::with_max_segment_bytes
+    /// [`ExternalSorter::with_in_memory_segments`]: crate::ExternalSorter::with_in_memory_segments
+    pub segments_written: usize,
+
+    /// Number of buffered items written to disk, excluding the items of the
+    /// segments kept in memory.
+    pub items_written: u64,
+
+    /// Number of bytes written to disk for the buffered items, excluding the
+    /// segments kept in memory.
+    pub bytes_written: u64,
+
+    /// Number of items buffered in memory after the push.
+    pub buffered: usize,
+}
+
+impl PushOutcome {
+    /// Returns true if buffered items were written to disk.
+    pub fn spilled(&self) -> bool {
+        self.segments_written > 0
+    }
+}
+
+/// Handle on a worker merging groups of segments on a background thread,
+/// which can be shared by several sorters (see [`compaction_worker`]).
+pub(crate) struct CompactionHandle<T> {
+    jobs: SyncSender<CompactionJob<T>>,
+}
+
+impl<T> Clone for CompactionHandle<T> {
+    fn clone(&self) -> Self {
+        CompactionHandle {
+            jobs: self.jobs.clone(),
         }
+    }
+}
 
-        self.options.sort_dir = if let Some(ref sort_dir) = self.options.sort_dir {
-            Some(sort_dir.to_path_buf())
+/// Returns the handle on a worker merging groups of segments on a background
+/// thread along with the function running it, or `None` if compaction isn't
+/// enabled (see [`ExternalSorter::with_compaction`]).
+///
+/// The function needs to be run on a thread, and returns once all the
+/// handles and the sorters using them are dropped.
+///
+/// [`ExternalSorter::with_compaction`]: crate::ExternalSorter::with_compaction
+pub(crate) fn compaction_worker<T, F>(
+    options: &ExternalSorterOptions,
+    cmp: &F,
+) -> Option<(CompactionHandle<T>, impl FnOnce() + Send)>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    options.compaction_fan_in.filter(|fan_in| *fan_in > 1)?;
+
+    let (jobs_sender, jobs) = sync_channel::<CompactionJob<T>>(1);
+    let options = options.clone();
+    let cmp = cmp.clone();
+    let worker = move || {
+        if let Some(start) = &options.background_thread_start {
+            start();
+        }
+        for job in jobs {
+            let results = job.results.clone();
+            // the sorter may have been dropped in the meantime
+            let _ = results.send(job.merge(&cmp, &options));
+        }
+    };
+    Some((CompactionHandle { jobs: jobs_sender }, worker))
+}
+
+fn compaction_thread_error() -> Error {
+    Error::other("compaction thread exited unexpectedly")
+}
+
+/// Sorter side of a background compaction worker.
+struct Compactor<T> {
+    jobs: SyncSender<CompactionJob<T>>,
+    results_sender: SyncSender<Result<Vec<SegmentFile>, Error>>,
+    results: Receiver<Result<Vec<SegmentFile>, Error>>,
+    pending: Option<PendingCompaction>,
+}
+
+impl<T> Compactor<T> {
+    /// Returns the segments merged by the pending compaction if it is done,
+    /// waiting for it if `wait` is true.
+    fn try_finish(
+        &mut self,
+        wait: bool,
+    ) -> Result<Option<(PendingCompaction, Vec<SegmentFile>)>, Error> {
+        let merged = if wait {
+            self.results.recv().map_err(|_| compaction_thread_error())?
         } else {
-            self.tempdir = Some(tempfile::TempDir::new()?);
-            Some(self.tempdir.as_ref().unwrap().path().to_path_buf())
+            match self.results.try_recv() {
+                Ok(merged) => merged,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Err(compaction_thread_error()),
+            }
         };
 
-        Ok(self.options.sort_dir.as_ref().unwrap().clone())
+        let pending = self.pending.take().expect("no pending compaction");
+        Ok(Some((pending, merged?)))
     }
 }
+
+/// Group of segments being merged by a background compaction.
+struct PendingCompaction {
+    segments: usize,
+    inputs: Vec<PathBuf>,
+    input_bytes: u64,
+}
+
+/// Group of segments handed over to a background compaction worker.
+struct CompactionJob<T> {
+    segment_files: Vec<SegmentFile>,
+    path: Option<PathBuf>,
+    combiner: Option<Combiner<T>>,
+    results: SyncSender<Result<Vec<SegmentFile>, Error>>,
+}
+
+impl<T: Sortable> CompactionJob<T> {
+    /// Merges the segments of the job into larger segments, which are split at
+    /// the maximum segment size (see [`ExternalSorter::with_max_segment_bytes`]).
+    ///
+    /// [`ExternalSorter::with_max_segment_bytes`]: crate::ExternalSorter::with_max_segment_bytes
+    fn merge<F>(self, cmp: &F, options: &ExternalSorterOptions) -> Result<Vec<SegmentFile>, Error>
+    where
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        let count = self
+            .segment_files
+            .iter()
+            .map(|segment_file| segment_file.count.unwrap_or(0))
+            .sum::<u64>();
+        let iter = SortedIterator::new(
+            None,
+            None,
+            self.segment_files,
+            Some(count),
+            cmp.clone(),
+            options.clone(),
+        )?
+        .with_combiner(self.combiner);
+
+        let output_path = |output: usize| match (&self.path, output) {
+            (Some(path), 0) => Some(path.clone()),
+            (Some(path), output) => Some(path.with_extension(output.to_string())),
+            (None, _) => None,
+        };
+        let mut outputs = Vec::new();
+        let mut writer = SegmentWriter::create(output_path(0), options, count as usize)?;
+        for item in iter {
+            if writer.is_full(options.max_segment_bytes) {
+                let next = SegmentWriter::create(output_path(outputs.len() + 1), options, 0)?;
+                outputs.push(std::mem::replace(&mut writer, next).finish()?);
+            }
+            writer.write(item?)?;
+        }
+        outputs.push(writer.finish()?);
+        Ok(outputs)
+    }
+}
+
+/// Ongoing merge of a group of segments into a single larger segment.
+struct Compaction<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    iter: SortedIterator<T, F>,
+    writer: SegmentWriter<T>,
+    inputs: Vec<PathBuf>,
+    input_bytes: u64,
+    step: usize,
+
+    /// Number of merged segments written so far, which are placed before the
+    /// segments written since the compaction started.
+    outputs: usize,
+}
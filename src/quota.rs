@@ -0,0 +1,225 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Disk space shared between sorters, capping the combined number of bytes
+/// of the segments they have on disk (see [`ExternalSorter::with_disk_quota`]).
+///
+/// The quota is a handle that can be cloned and given to every sorter of a
+/// job, so that the job as a whole never spills more than the given number of
+/// bytes, which per-sorter limits can't guarantee. Bytes are charged once a
+/// segment is written to disk, and released once the sorted iterator owning
+/// the segment is dropped, or once its segment is merged by a compaction (see
+/// [`ExternalSorter::with_compaction`]). Segments kept in memory aren't
+/// charged.
+///
+/// Since a segment's size is only known once written, each sorter may exceed
+/// the quota by the segment it is writing until it is charged.
+///
+/// [`ExternalSorter::with_disk_quota`]: crate::ExternalSorter::with_disk_quota
+/// [`ExternalSorter::with_compaction`]: crate::ExternalSorter::with_compaction
+#[derive(Clone)]
+pub struct DiskQuota {
+    inner: Arc<QuotaState>,
+}
+
+struct QuotaState {
+    max_bytes: u64,
+    policy: QuotaPolicy,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+impl DiskQuota {
+    /// Creates a quota of the given number of bytes, with the given behavior
+    /// once exhausted.
+    pub fn new(max_bytes: u64, policy: QuotaPolicy) -> DiskQuota {
+        DiskQuota {
+            inner: Arc::new(QuotaState {
+                max_bytes,
+                policy,
+                used: Mutex::new(0),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Returns the maximum number of bytes of the quota.
+    pub fn max_bytes(&self) -> u64 {
+        self.inner.max_bytes
+    }
+
+    /// Returns the number of bytes currently charged to the quota by all the
+    /// sorters sharing it.
+    pub fn used_bytes(&self) -> u64 {
+        *self.lock_used()
+    }
+
+    /// Returns the number of bytes that can still be charged to the quota.
+    pub fn available_bytes(&self) -> u64 {
+        self.max_bytes().saturating_sub(self.used_bytes())
+    }
+
+    /// Charges the given number of bytes to the quota, waiting for other
+    /// sorters to release bytes or failing if it is exhausted, depending on
+    /// its policy.
+    fn charge(&self, bytes: u64) -> Result<(), Error> {
+        let exceeded = || {
+            Error::new(
+                ErrorKind::StorageFull,
+                QuotaExceeded {
+                    max_bytes: self.inner.max_bytes,
+                    requested_bytes: bytes,
+                },
+            )
+        };
+
+        // waiting wouldn't help if the bytes can't fit in the whole quota
+        if bytes > self.inner.max_bytes {
+            return Err(exceeded());
+        }
+
+        let mut used = self.lock_used();
+        while *used + bytes > self.inner.max_bytes {
+            match self.inner.policy {
+                QuotaPolicy::Error => return Err(exceeded()),
+                QuotaPolicy::Block => {
+                    used = self
+                        .inner
+                        .released
+                        .wait(used)
+                        .unwrap_or_else(|err| err.into_inner());
+                }
+            }
+        }
+        *used += bytes;
+        Ok(())
+    }
+
+    /// Releases bytes previously charged to the quota, waking up the sorters
+    /// waiting for them.
+    fn release(&self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+
+        let mut used = self.lock_used();
+        *used = used.saturating_sub(bytes);
+        self.inner.released.notify_all();
+    }
+
+    fn lock_used(&self) -> std::sync::MutexGuard<'_, u64> {
+        // the count stays consistent even if a holder panicked
+        self.inner
+            .used
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl fmt::Debug for DiskQuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskQuota")
+            .field("max_bytes", &self.inner.max_bytes)
+            .field("used_bytes", &self.used_bytes())
+            .field("policy", &self.inner.policy)
+            .finish()
+    }
+}
+
+/// Behavior of a sorter writing a segment that doesn't fit in its
+/// [`DiskQuota`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// The sorter fails with a [`QuotaExceeded`] error of kind
+    /// `StorageFull`.
+    #[default]
+    Error,
+
+    /// The sorter waits until other sorters sharing the quota release enough
+    /// bytes. A segment larger than the whole quota still fails, but a sorter
+    /// waiting on bytes that only the sorters of the same thread can release
+    /// waits forever.
+    Block,
+}
+
+/// Error returned when a segment doesn't fit in the [`DiskQuota`] of its
+/// sorter, wrapped in an `std::io::Error` of kind `StorageFull`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    /// Maximum number of bytes of the quota.
+    pub max_bytes: u64,
+
+    /// Number of bytes of the segment that didn't fit.
+    pub requested_bytes: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "disk quota of {} bytes exceeded by a segment of {} bytes",
+            self.max_bytes, self.requested_bytes
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Bytes charged to a [`DiskQuota`] by a sorter or its sorted iterator, which
+/// are released once dropped.
+pub(crate) struct QuotaReservation {
+    quota: DiskQuota,
+    bytes: u64,
+}
+
+impl QuotaReservation {
+    pub fn new(quota: DiskQuota) -> QuotaReservation {
+        QuotaReservation { quota, bytes: 0 }
+    }
+
+    /// Charges the given number of bytes to the quota.
+    pub fn grow(&mut self, bytes: u64) -> Result<(), Error> {
+        self.quota.charge(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Releases up to the given number of bytes of the reservation.
+    pub fn shrink(&mut self, bytes: u64) {
+        let bytes = bytes.min(self.bytes);
+        self.quota.release(bytes);
+        self.bytes -= bytes;
+    }
+
+    /// Takes the bytes charged so far as a new reservation, leaving this one
+    /// empty.
+    pub fn take(&mut self) -> QuotaReservation {
+        QuotaReservation {
+            quota: self.quota.clone(),
+            bytes: std::mem::take(&mut self.bytes),
+        }
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        self.quota.release(self.bytes);
+    }
+}
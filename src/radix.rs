@@ -0,0 +1,77 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Sorts the items by their `u64` key using a stable LSD radix sort, one byte
+/// at a time.
+///
+/// Keys are extracted once and sorted along with the position of their item,
+/// after which the items are swapped in place to their sorted position. Passes
+/// over bytes that are identical for all keys are skipped, which makes small
+/// keys cheaper to sort.
+pub(crate) fn radix_sort_by_key<T, K>(items: &mut [T], key: K)
+where
+    K: Fn(&T) -> u64,
+{
+    if items.len() <= 1 {
+        return;
+    }
+
+    let mut keys = items
+        .iter()
+        .enumerate()
+        .map(|(position, item)| (key(item), position))
+        .collect::<Vec<_>>();
+    let mut scratch = keys.clone();
+
+    for byte in 0..8 {
+        let shift = byte * 8;
+        let mut counts = [0usize; 256];
+        for (key, _) in &keys {
+            counts[((key >> shift) & 0xff) as usize] += 1;
+        }
+        if counts.contains(&keys.len()) {
+            continue;
+        }
+
+        let mut offsets = [0usize; 256];
+        let mut offset = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            offsets[bucket] = offset;
+            offset += count;
+        }
+
+        for entry in &keys {
+            let bucket = ((entry.0 >> shift) & 0xff) as usize;
+            scratch[offsets[bucket]] = *entry;
+            offsets[bucket] += 1;
+        }
+        std::mem::swap(&mut keys, &mut scratch);
+    }
+
+    // follow each cycle of the permutation, where the item at `keys[i].1`
+    // needs to be moved to `i`
+    let mut placed = vec![false; items.len()];
+    for start in 0..items.len() {
+        let mut current = start;
+        while !placed[current] {
+            placed[current] = true;
+            let source = keys[current].1;
+            if source == start {
+                break;
+            }
+            items.swap(current, source);
+            current = source;
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use crate::{segment::SegmentFile, ExternalSorterOptions, Sortable, SortedIterator};
+
+/// Extension of the run files of a run set.
+const RUN_EXTENSION: &str = "run";
+
+/// Number of digits of the sequence numbers naming appended runs, so that
+/// their names sort in the order in which they were appended.
+const SEQUENCE_DIGITS: usize = 20;
+
+/// Directory of sorted runs produced by separate worker processes, which a
+/// coordinator process then merges into a single sorted iterator.
+///
+/// Each worker sorts its share of the items and writes them as a run (see
+/// [`RunSet::write`]), identified by a name unique within the run set such as
+/// the index of the worker. Runs are written to a temporary file and
+/// atomically renamed to `<name>.run` once complete, so the run files of the
+/// directory are its manifest: a run is either fully visible or not at all,
+/// and the temporary files left by crashed workers are ignored. Run files
+/// are written in blocks (see [`SortedIterator::persist`]) and record their
+/// number of items, so the coordinator knows the total number of items
+/// without reading them.
+///
+/// The coordinator merges the runs once all workers are done (see
+/// [`RunSet::open_merge`]). Runs are merged in the order of their names,
+/// which breaks the ties between equal items of different runs.
+///
+/// A run set can also hold a sorted dataset that is updated incrementally,
+/// in the manner of an LSM tree: each batch of new items is sorted and
+/// appended as a new run (see [`RunSet::append`]) instead of re-sorting the
+/// whole dataset, the merged view covers all the runs, and the appended runs
+/// are periodically rewritten into one (see [`RunSet::compact`]).
+pub struct RunSet {
+    dir: PathBuf,
+}
+
+impl RunSet {
+    /// Creates a handle on the run set in the given directory, which is
+    /// created if it doesn't exist yet.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<RunSet, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        Ok(RunSet { dir })
+    }
+
+    /// Returns the directory of the run set.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Consumes the sorted iterator and writes its items as the run with the
+    /// given name, returning the number of items written.
+    ///
+    /// Returns an `InvalidInput` error if the name is empty, starts with a dot
+    /// or contains a path separator, and an `AlreadyExists` error if the run
+    /// set already holds a run with that name.
+    pub fn write<T, F>(&self, name: &str, sorted_iter: SortedIterator<T, F>) -> Result<u64, Error>
+    where
+        T: Sortable,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid run name {name:?}"),
+            ));
+        }
+
+        let path = self.dir.join(format!("{name}.{RUN_EXTENSION}"));
+        if path.exists() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("run {name:?} already exists"),
+            ));
+        }
+
+        Ok(sorted_iter.persist(path)?.len())
+    }
+
+    /// Consumes the sorted iterator and appends its items as a new run named
+    /// after the next sequence number of the run set, returning the number of
+    /// items written.
+    ///
+    /// Appended runs are named after increasing sequence numbers, so that
+    /// they are merged after the runs appended before them. Runs should be
+    /// appended by a single process at a time, since concurrent appends may
+    /// pick the same sequence number, in which case one of them fails with an
+    /// `AlreadyExists` error.
+    pub fn append<T, F>(&self, sorted_iter: SortedIterator<T, F>) -> Result<u64, Error>
+    where
+        T: Sortable,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        let sequence = self
+            .run_files()?
+            .iter()
+            .filter_map(|(name, _)| parse_sequences(name))
+            .map(|(_, last)| last + 1)
+            .max()
+            .unwrap_or(0);
+        self.write(&format!("{sequence:0SEQUENCE_DIGITS$}"), sorted_iter)
+    }
+
+    /// Returns the paths of the complete runs of the run set, in the order of
+    /// their names.
+    ///
+    /// Appended runs that were already merged by a compaction interrupted
+    /// before deleting them are ignored (see [`RunSet::compact`]).
+    pub fn runs(&self) -> Result<Vec<PathBuf>, Error> {
+        let run_files = self.run_files()?;
+        let sequences = run_files
+            .iter()
+            .filter_map(|(name, _)| parse_sequences(name))
+            .collect::<Vec<_>>();
+        Ok(run_files
+            .into_iter()
+            .filter(|(name, _)| !is_compacted(name, &sequences))
+            .map(|(_, path)| path)
+            .collect())
+    }
+
+    /// Rewrites the appended runs whose items are sorted in their natural
+    /// order into a single run, returning the number of items of the
+    /// compacted run.
+    ///
+    /// See [`RunSet::compact_by`].
+    pub fn compact<T>(&self) -> Result<u64, Error>
+    where
+        T: Sortable + Ord,
+    {
+        self.compact_by::<T, _>(|a, b| a.cmp(b))
+    }
+
+    /// Rewrites the appended runs whose items are sorted according to the
+    /// given comparator into a single run, returning the number of items of
+    /// the compacted run.
+    ///
+    /// The compacted run is named after the range of sequence numbers of the
+    /// runs it merges, which are deleted once it is complete. If the
+    /// compaction is interrupted before all of them are deleted, the
+    /// remaining ones are ignored (see [`RunSet::runs`]) and deleted by the
+    /// next compaction, so that the merged view never holds an item twice.
+    /// Runs written with [`RunSet::write`] are left as is.
+    pub fn compact_by<T, F>(&self, cmp: F) -> Result<u64, Error>
+    where
+        T: Sortable,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        let run_files = self.run_files()?;
+        let sequences = run_files
+            .iter()
+            .filter_map(|(name, _)| parse_sequences(name))
+            .collect::<Vec<_>>();
+
+        let mut inputs = Vec::new();
+        for (name, path) in run_files {
+            if is_compacted(&name, &sequences) {
+                std::fs::remove_file(path)?;
+            } else if let Some(range) = parse_sequences(&name) {
+                inputs.push((range, path));
+            }
+        }
+
+        match inputs.as_slice() {
+            [] => return Ok(0),
+            // a single run doesn't need to be rewritten
+            [(_, path)] => return SegmentFile::open(File::open(path)?)?.blocked_len(),
+            _ => {}
+        }
+
+        let first = inputs[0].0 .0;
+        let last = inputs[inputs.len() - 1].0 .1;
+        let path = self.dir.join(format!(
+            "{first:0SEQUENCE_DIGITS$}-{last:0SEQUENCE_DIGITS$}.{RUN_EXTENSION}"
+        ));
+        let paths = inputs.into_iter().map(|(_, path)| path).collect::<Vec<_>>();
+        let len = self.merge_runs(&paths, cmp)?.persist(path)?.len();
+        for path in paths {
+            std::fs::remove_file(path)?;
+        }
+        Ok(len)
+    }
+
+    /// Returns the names and paths of the run files of the run set, in the
+    /// order of their names.
+    fn run_files(&self) -> Result<Vec<(String, PathBuf)>, Error> {
+        let mut runs = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == RUN_EXTENSION) && path.is_file() {
+                let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                runs.push((name.to_string(), path));
+            }
+        }
+        runs.sort();
+        Ok(runs)
+    }
+
+    /// Merges the runs of the run set whose items are sorted in their natural
+    /// order, returning a new iterator with the merged sorted items.
+    pub fn open_merge<T>(
+        &self,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable + Ord,
+    {
+        self.open_merge_by::<T, _>(|a, b| a.cmp(b))
+    }
+
+    /// Merges the runs of the run set whose items are sorted according to the
+    /// given comparator, returning a new iterator with the merged sorted
+    /// items.
+    ///
+    /// Run files are left as is, and can be deleted along with the directory
+    /// once the merged iterator has been consumed.
+    pub fn open_merge_by<T, F>(&self, cmp: F) -> Result<SortedIterator<T, F>, Error>
+    where
+        T: Sortable,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.merge_runs(&self.runs()?, cmp)
+    }
+
+    /// Merges the given run files of the run set.
+    fn merge_runs<T, F>(&self, paths: &[PathBuf], cmp: F) -> Result<SortedIterator<T, F>, Error>
+    where
+        T: Sortable,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        let mut count = 0;
+        let mut segment_files = Vec::new();
+        for path in paths {
+            let mut segment_file = SegmentFile::open(File::open(path)?)?;
+            if !segment_file.format.blocked {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("run {} isn't written in blocks", path.display()),
+                ));
+            }
+            count += segment_file.blocked_len()?;
+            segment_files.push(segment_file);
+        }
+
+        SortedIterator::new(
+            None,
+            None,
+            segment_files,
+            Some(count),
+            cmp,
+            ExternalSorterOptions::default(),
+        )
+    }
+}
+
+/// Parses the range of sequence numbers of the appended runs merged in the
+/// run of the given name, which is a single sequence number for a run that
+/// wasn't compacted.
+fn parse_sequences(name: &str) -> Option<(u64, u64)> {
+    let parse = |sequence: &str| {
+        (sequence.len() == SEQUENCE_DIGITS && sequence.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| sequence.parse().ok())
+            .flatten()
+    };
+    match name.split_once('-') {
+        Some((first, last)) => Some((parse(first)?, parse(last)?)),
+        None => parse(name).map(|sequence| (sequence, sequence)),
+    }
+}
+
+/// Returns true if the run of the given name is an appended run whose items
+/// were already merged in another run by a compaction.
+fn is_compacted(name: &str, sequences: &[(u64, u64)]) -> bool {
+    let Some((first, last)) = parse_sequences(name) else {
+        return false;
+    };
+    sequences.iter().any(|&(other_first, other_last)| {
+        other_first <= first && last <= other_last && (other_first, other_last) != (first, last)
+    })
+}
@@ -0,0 +1,1543 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Ordering,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    checksum::{ChecksumError, ChecksumReader, ChecksumWriter, Crc32},
+    cleanup::create_anonymous_tempfile,
+    framing::{read_varint, write_varint},
+    ExternalSorterOptions, Sortable,
+};
+
+/// Magic bytes at the start of segments written in blocks.
+const BLOCK_MAGIC: &[u8; 8] = b"EXTSBLK1";
+
+/// Magic bytes at the start of segments written in blocks with record
+/// checksums.
+const BLOCK_CHECKSUMS_MAGIC: &[u8; 8] = b"EXTSBLKC";
+
+/// Magic bytes at the start of segments written in prefix compressed blocks.
+const BLOCK_PREFIX_MAGIC: &[u8; 8] = b"EXTSBLP1";
+
+/// Magic bytes at the start of segments written in prefix compressed blocks
+/// with record checksums.
+const BLOCK_PREFIX_CHECKSUMS_MAGIC: &[u8; 8] = b"EXTSBLPC";
+
+/// Magic bytes at the start of segments that aren't written in blocks but
+/// with record checksums, which would otherwise be read as plain items.
+const CHECKSUMS_MAGIC: &[u8; 8] = b"EXTSRCK1";
+
+/// Start of the magic bytes of segments written in delta encoded blocks,
+/// followed by `K`, or by `C` with record checksums, and by the number of
+/// bytes of the keys as a digit.
+const BLOCK_DELTA_MAGIC: &[u8; 6] = b"EXTSBD";
+
+/// Layout of the items in a segment file.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct SegmentFormat {
+    /// Items are written in blocks (see [`SegmentReader`]).
+    pub blocked: bool,
+
+    /// Each item is followed by the checksum of its encoded bytes.
+    pub checksums: bool,
+
+    /// Encoding of the items within blocks.
+    pub codec: BlockCodec,
+}
+
+/// Encoding of the items within the blocks of a segment, relative to the
+/// previous item of their block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum BlockCodec {
+    /// Items are written as encoded.
+    #[default]
+    Plain,
+
+    /// Each item is written as the length of the prefix its encoding shares
+    /// with the previous item, followed by the rest of its encoding.
+    Prefix,
+
+    /// The encoding of each item starts with a big-endian unsigned integer
+    /// key of the given number of bytes, which is written as its zigzag
+    /// varint difference with the key of the previous item, followed by the
+    /// rest of its encoding.
+    Delta(u8),
+}
+
+impl SegmentFormat {
+    pub fn from_options(options: &ExternalSorterOptions) -> SegmentFormat {
+        let codec = if options.block_size == 0 {
+            BlockCodec::Plain
+        } else if options.prefix_compression {
+            BlockCodec::Prefix
+        } else if options.delta_key_bytes > 0 {
+            BlockCodec::Delta(options.delta_key_bytes as u8)
+        } else {
+            BlockCodec::Plain
+        };
+
+        SegmentFormat {
+            blocked: options.block_size > 0,
+            checksums: options.record_checksums,
+            codec,
+        }
+    }
+
+    /// Returns the magic bytes at the start of segments of this format, or
+    /// `None` if their items start right away.
+    fn magic(&self) -> Option<[u8; 8]> {
+        match (self.blocked, self.checksums) {
+            (true, _) => Some(self.block_magic()),
+            (false, true) => Some(*CHECKSUMS_MAGIC),
+            (false, false) => None,
+        }
+    }
+
+    fn block_magic(&self) -> [u8; 8] {
+        match (self.codec, self.checksums) {
+            (BlockCodec::Plain, false) => *BLOCK_MAGIC,
+            (BlockCodec::Plain, true) => *BLOCK_CHECKSUMS_MAGIC,
+            (BlockCodec::Prefix, false) => *BLOCK_PREFIX_MAGIC,
+            (BlockCodec::Prefix, true) => *BLOCK_PREFIX_CHECKSUMS_MAGIC,
+            (BlockCodec::Delta(key_bytes), checksums) => {
+                let mut magic = [0; 8];
+                magic[..6].copy_from_slice(BLOCK_DELTA_MAGIC);
+                magic[6] = if checksums { b'C' } else { b'K' };
+                magic[7] = b'0' + key_bytes;
+                magic
+            }
+        }
+    }
+
+    /// Returns the format of a segment from its magic bytes, or `None` if it
+    /// doesn't start with magic bytes.
+    fn from_magic(magic: &[u8; 8]) -> Option<SegmentFormat> {
+        if magic == CHECKSUMS_MAGIC {
+            return Some(SegmentFormat {
+                blocked: false,
+                checksums: true,
+                codec: BlockCodec::Plain,
+            });
+        }
+
+        let (checksums, codec) = match magic {
+            BLOCK_MAGIC => (false, BlockCodec::Plain),
+            BLOCK_CHECKSUMS_MAGIC => (true, BlockCodec::Plain),
+            BLOCK_PREFIX_MAGIC => (false, BlockCodec::Prefix),
+            BLOCK_PREFIX_CHECKSUMS_MAGIC => (true, BlockCodec::Prefix),
+            [b'E', b'X', b'T', b'S', b'B', b'D', flag @ (b'K' | b'C'), key_bytes @ b'1'..=b'8'] => {
+                (*flag == b'C', BlockCodec::Delta(key_bytes - b'0'))
+            }
+            _ => return None,
+        };
+
+        Some(SegmentFormat {
+            blocked: true,
+            checksums,
+            codec,
+        })
+    }
+}
+
+/// Item count of the block header announcing the footer of a segment written
+/// in blocks, which holds its block index.
+const FOOTER_MARKER: u32 = u32::MAX;
+
+/// Size of the buffer in which segments are encoded before being written to
+/// their file, so that a segment is written in few large writes instead of
+/// many small ones, which is much faster on network and object-store backed
+/// file systems.
+const WRITE_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// Storage of a segment, which is a file unless the segment is kept in memory
+/// (see [`ExternalSorter::with_in_memory_segments`]) or read from a stream
+/// (see [`ExternalSorter::merge_readers`]).
+///
+/// [`ExternalSorter::with_in_memory_segments`]: crate::ExternalSorter::with_in_memory_segments
+/// [`ExternalSorter::merge_readers`]: crate::ExternalSorter::merge_readers
+pub(crate) enum SegmentData {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+    Stream(StreamSegment),
+}
+
+/// Stream of a segment that can't seek, along with the number of bytes read
+/// from it so far. Segment files written through a segment transform are
+/// read back as streams, which are still on disk.
+pub(crate) struct StreamSegment {
+    reader: Box<dyn Read + Send>,
+    position: u64,
+    on_disk: bool,
+}
+
+impl SegmentData {
+    pub fn stream<R: Read + Send + 'static>(reader: R) -> SegmentData {
+        SegmentData::Stream(StreamSegment {
+            reader: Box::new(reader),
+            position: 0,
+            on_disk: false,
+        })
+    }
+
+    pub fn is_file(&self) -> bool {
+        match self {
+            SegmentData::File(_) => true,
+            SegmentData::Memory(_) => false,
+            SegmentData::Stream(stream) => stream.on_disk,
+        }
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, SegmentData::Stream(_))
+    }
+
+    /// Frees the content of the segment, which won't be read anymore.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        match self {
+            SegmentData::File(file) => file.set_len(0),
+            SegmentData::Memory(cursor) => {
+                *cursor = Cursor::new(Vec::new());
+                Ok(())
+            }
+            SegmentData::Stream(stream) => {
+                stream.reader = Box::new(std::io::empty());
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the segment as a file, writing it to an anonymous temporary
+    /// file if it is kept in memory. Segments read from a stream can't be
+    /// rewound, and therefore can't be returned as a file.
+    pub fn into_file(self) -> Result<File, Error> {
+        match self {
+            SegmentData::File(file) => Ok(file),
+            SegmentData::Memory(cursor) => {
+                let mut file = create_anonymous_tempfile()?;
+                file.write_all(cursor.get_ref())?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(file)
+            }
+            SegmentData::Stream(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "segments read from a stream can't be returned as files",
+            )),
+        }
+    }
+}
+
+impl Read for SegmentData {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            SegmentData::File(file) => file.read(buf),
+            SegmentData::Memory(cursor) => cursor.read(buf),
+            SegmentData::Stream(stream) => {
+                let read = stream.reader.read(buf)?;
+                stream.position += read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+/// Streams can only "seek" to their current position, which is enough for
+/// the initial rewind of the segments and for reporting their position.
+impl Seek for SegmentData {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            SegmentData::File(file) => file.seek(pos),
+            SegmentData::Memory(cursor) => cursor.seek(pos),
+            SegmentData::Stream(stream) => match pos {
+                SeekFrom::Start(offset) if offset == stream.position => Ok(offset),
+                SeekFrom::Current(0) => Ok(stream.position),
+                _ => Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "segments read from a stream can't seek",
+                )),
+            },
+        }
+    }
+}
+
+/// Destination of a segment being written, which is buffered when writing to
+/// a file, along with the path of the file if it has one.
+///
+/// Files written through a segment transform keep a second handle on the
+/// file, from which the segment is read back once the transformed stream is
+/// dropped.
+enum SegmentSink {
+    File(BufWriter<File>, Option<PathBuf>),
+    Transformed {
+        writer: BufWriter<Box<dyn Write + Send>>,
+        file: File,
+        path: Option<PathBuf>,
+        transform: SegmentTransform,
+    },
+    Memory(Vec<u8>, Option<Box<Spill>>),
+}
+
+/// Functions wrapping the streams of the segment files written by the sorter,
+/// when writing and when reading them back (see
+/// [`ExternalSorter::with_segment_transform`]).
+///
+/// [`ExternalSorter::with_segment_transform`]: crate::ExternalSorter::with_segment_transform
+#[derive(Clone)]
+pub(crate) struct SegmentTransform {
+    pub write: Arc<WriteWrap>,
+    pub read: Arc<ReadWrap>,
+}
+
+pub(crate) type WriteWrap = dyn Fn(Box<dyn Write + Send>) -> Box<dyn Write + Send> + Send + Sync;
+pub(crate) type ReadWrap = dyn Fn(Box<dyn Read + Send>) -> Box<dyn Read + Send> + Send + Sync;
+
+/// Threshold above which a segment being written in memory is moved to a
+/// file (see [`ExternalSorter::with_in_memory_segment_bytes`]).
+///
+/// [`ExternalSorter::with_in_memory_segment_bytes`]: crate::ExternalSorter::with_in_memory_segment_bytes
+struct Spill {
+    max_bytes: u64,
+    path: Option<PathBuf>,
+    options: ExternalSorterOptions,
+}
+
+impl SegmentSink {
+    /// Moves the segment written in memory to its file.
+    fn spill(&mut self) -> Result<(), Error> {
+        let SegmentSink::Memory(buffer, spill) = self else {
+            return Ok(());
+        };
+        let Some(spill) = spill.take() else {
+            return Ok(());
+        };
+
+        let buffer = std::mem::take(buffer);
+        let file = open_segment_file(spill.path.as_deref(), &spill.options)?;
+        *self = SegmentSink::file(file, spill.path, &spill.options)?;
+        self.write_all(&buffer)
+    }
+
+    /// Creates the destination of a segment written to the given file,
+    /// through the segment transform if one is configured.
+    fn file(
+        file: File,
+        path: Option<PathBuf>,
+        options: &ExternalSorterOptions,
+    ) -> Result<SegmentSink, Error> {
+        let Some(transform) = options.segment_transform.clone() else {
+            return Ok(SegmentSink::File(
+                BufWriter::with_capacity(WRITE_BUFFER_BYTES, file),
+                path,
+            ));
+        };
+
+        let stream = (transform.write)(Box::new(file.try_clone()?));
+        Ok(SegmentSink::Transformed {
+            writer: BufWriter::with_capacity(WRITE_BUFFER_BYTES, stream),
+            file,
+            path,
+            transform,
+        })
+    }
+
+    /// Returns the data of the written segment, along with its path and the
+    /// transform through which it needs to be read back, if any.
+    #[allow(clippy::type_complexity)]
+    fn into_data(self) -> Result<(SegmentData, Option<PathBuf>, Option<SegmentTransform>), Error> {
+        match self {
+            SegmentSink::File(writer, path) => {
+                Ok((SegmentData::File(writer.into_inner()?), path, None))
+            }
+            SegmentSink::Transformed {
+                writer,
+                file,
+                path,
+                transform,
+            } => {
+                // the transformed stream is dropped so that it writes its
+                // remaining bytes, if any, before the segment is read back
+                let mut stream = writer.into_inner().map_err(|err| err.into_error())?;
+                stream.flush()?;
+                drop(stream);
+                Ok((SegmentData::File(file), path, Some(transform)))
+            }
+            SegmentSink::Memory(buffer, _) => {
+                Ok((SegmentData::Memory(Cursor::new(buffer)), None, None))
+            }
+        }
+    }
+}
+
+impl Write for SegmentSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let SegmentSink::Memory(buffer, Some(spill)) = self {
+            if (buffer.len() + buf.len()) as u64 > spill.max_bytes {
+                self.spill()?;
+            }
+        }
+
+        match self {
+            SegmentSink::File(writer, _) => writer.write(buf),
+            SegmentSink::Transformed { writer, .. } => writer.write(buf),
+            SegmentSink::Memory(buffer, _) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SegmentSink::File(writer, _) => writer.flush(),
+            SegmentSink::Transformed { writer, .. } => writer.flush(),
+            SegmentSink::Memory(..) => Ok(()),
+        }
+    }
+}
+
+/// Sorted segment file written by the push sorter, along with the metadata
+/// collected while writing it.
+pub(crate) struct SegmentFile {
+    pub file: SegmentData,
+    pub path: Option<PathBuf>,
+    pub scratch: bool,
+    pub count: Option<u64>,
+    pub size: Option<u64>,
+    pub index: SparseIndex,
+    pub stats: Option<SegmentStats>,
+    pub item_offsets: Option<Vec<u64>>,
+    pub format: SegmentFormat,
+    pub transform: Option<SegmentTransform>,
+}
+
+impl SegmentFile {
+    pub fn new(file: File) -> SegmentFile {
+        SegmentFile::from_data(SegmentData::File(file))
+    }
+
+    pub fn from_data(file: SegmentData) -> SegmentFile {
+        SegmentFile {
+            file,
+            path: None,
+            scratch: false,
+            count: None,
+            size: None,
+            index: SparseIndex::default(),
+            stats: None,
+            item_offsets: None,
+            format: SegmentFormat::default(),
+            transform: None,
+        }
+    }
+
+    /// Wraps the file of a segment written through a segment transform in the
+    /// stream reading it back, from its current position. Since the stream
+    /// can't seek, the index of the segment is dropped.
+    pub fn apply_read_transform(&mut self) {
+        let Some(transform) = self.transform.take() else {
+            return;
+        };
+
+        let data = std::mem::replace(&mut self.file, SegmentData::Memory(Cursor::default()));
+        self.file = SegmentData::Stream(StreamSegment {
+            reader: (transform.read)(Box::new(data)),
+            position: 0,
+            on_disk: true,
+        });
+        self.index = SparseIndex::default();
+    }
+
+    /// Opens a segment file of unknown format, loading the block index from
+    /// its footer if it was written in blocks. Segments that don't start with
+    /// magic bytes are assumed to be written without record checksums.
+    pub fn open(mut file: File) -> Result<SegmentFile, Error> {
+        let mut magic = [0; 8];
+        file.seek(SeekFrom::Start(0))?;
+        let format = match file.read_exact(&mut magic) {
+            Ok(()) => match SegmentFormat::from_magic(&magic) {
+                Some(format) => format,
+                None => return Ok(SegmentFile::new(file)),
+            },
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Ok(SegmentFile::new(file))
+            }
+            Err(err) => return Err(err),
+        };
+        if !format.blocked {
+            let mut segment_file = SegmentFile::new(file);
+            segment_file.format = format;
+            return Ok(segment_file);
+        }
+
+        let mut footer_offset = [0; 8];
+        file.seek(SeekFrom::End(-8))?;
+        file.read_exact(&mut footer_offset)
+            .map_err(|_| corrupted("truncated block segment"))?;
+        file.seek(SeekFrom::Start(u64::from_le_bytes(footer_offset)))?;
+
+        let (len, marker) = read_block_header(&mut file)?;
+        if marker != FOOTER_MARKER {
+            return Err(corrupted("invalid block segment footer"));
+        }
+        let mut footer = Vec::new();
+        (&mut file).take(len as u64).read_to_end(&mut footer)?;
+
+        let mut segment_file = SegmentFile::new(file);
+        segment_file.index = SparseIndex::decode_footer(&footer)?;
+        segment_file.format = format;
+        Ok(segment_file)
+    }
+
+    /// Opens an independent handle on the segment that reads its data from
+    /// its start without sharing the read position of this one, by reopening
+    /// its file or by copying it if it is kept in memory.
+    ///
+    /// The new handle isn't a scratch segment, so reading it doesn't release
+    /// the segment. Returns an `Unsupported` error if the segment is an
+    /// anonymous file or a stream, which can't be reopened.
+    pub fn reopen(&self) -> Result<SegmentFile, Error> {
+        let file = match (&self.file, &self.path) {
+            (SegmentData::Memory(cursor), _) => {
+                SegmentData::Memory(Cursor::new(cursor.get_ref().clone()))
+            }
+            (SegmentData::File(_), Some(path)) => SegmentData::File(File::open(path)?),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "segment without a path can't be reopened",
+                ))
+            }
+        };
+
+        Ok(SegmentFile {
+            file,
+            path: None,
+            scratch: false,
+            count: self.count,
+            size: self.size,
+            index: self.index.clone(),
+            stats: self.stats.clone(),
+            item_offsets: None,
+            format: self.format,
+            transform: self.transform.clone(),
+        })
+    }
+
+    /// Returns the number of items of a segment written in blocks, from the
+    /// position and item count of its last block.
+    pub fn blocked_len(&mut self) -> Result<u64, Error> {
+        let Some((offset, position)) = self.index.last() else {
+            return Ok(0);
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        let (_, count) = read_block_header(&mut self.file)?;
+        if count == FOOTER_MARKER {
+            return Err(corrupted("invalid block segment index"));
+        }
+        Ok(position + count as u64)
+    }
+}
+
+/// Writer of a segment file, collecting its metadata while sorted items are
+/// written to it.
+pub(crate) struct SegmentWriter<T: Sortable> {
+    writer: CountingWriter<SegmentSink>,
+    count: u64,
+    index_interval: usize,
+    index: SparseIndex,
+    item_offsets: Option<Vec<u64>>,
+    min: Option<Vec<u8>>,
+    max: Option<T>,
+    max_encoded: Option<Vec<u8>>,
+    block: Option<BlockBuffer>,
+    checksums: bool,
+    codec: BlockCodec,
+    #[cfg(all(feature = "memfd", target_os = "linux"))]
+    seal: bool,
+}
+
+/// Items of the block being written by a segment writer.
+struct BlockBuffer {
+    size: usize,
+    buffer: Vec<u8>,
+    count: u32,
+    position: u64,
+    first: Vec<u8>,
+    last: Vec<u8>,
+}
+
+impl<T: Sortable> SegmentWriter<T> {
+    /// Creates a writer for a new segment at the given path, or in an
+    /// anonymous scratch file if no path is given (see [`create_tempfile`]).
+    pub fn create(
+        path: Option<PathBuf>,
+        options: &ExternalSorterOptions,
+        capacity: usize,
+    ) -> Result<SegmentWriter<T>, Error> {
+        let file = open_segment_file(path.as_deref(), options)?;
+        let sink = SegmentSink::file(file, path, options)?;
+        SegmentWriter::with_sink(sink, options, capacity)
+    }
+
+    /// Creates a writer for a new segment in the given empty file.
+    pub fn create_in(
+        file: File,
+        options: &ExternalSorterOptions,
+        capacity: usize,
+    ) -> Result<SegmentWriter<T>, Error> {
+        let sink = SegmentSink::File(BufWriter::with_capacity(WRITE_BUFFER_BYTES, file), None);
+        SegmentWriter::with_sink(sink, options, capacity)
+    }
+
+    /// Creates a writer for a new segment kept in memory.
+    pub fn create_in_memory(
+        options: &ExternalSorterOptions,
+        capacity: usize,
+    ) -> Result<SegmentWriter<T>, Error> {
+        let sink = SegmentSink::Memory(Vec::new(), None);
+        SegmentWriter::with_sink(sink, options, capacity)
+    }
+
+    /// Creates a writer for a new segment kept in memory until its size
+    /// exceeds `max_bytes`, at which point it is moved to the given path, or to
+    /// an anonymous scratch file if no path is given.
+    pub fn create_spilling(
+        path: Option<PathBuf>,
+        max_bytes: u64,
+        options: &ExternalSorterOptions,
+        capacity: usize,
+    ) -> Result<SegmentWriter<T>, Error> {
+        let spill = Spill {
+            max_bytes,
+            path,
+            options: options.clone(),
+        };
+        let sink = SegmentSink::Memory(Vec::new(), Some(Box::new(spill)));
+        SegmentWriter::with_sink(sink, options, capacity)
+    }
+
+    fn with_sink(
+        sink: SegmentSink,
+        options: &ExternalSorterOptions,
+        capacity: usize,
+    ) -> Result<SegmentWriter<T>, Error> {
+        let mut writer = CountingWriter::new(sink);
+        let block = (options.block_size > 0).then(|| BlockBuffer {
+            size: options.block_size,
+            buffer: Vec::with_capacity(options.block_size),
+            count: 0,
+            position: 0,
+            first: Vec::new(),
+            last: Vec::new(),
+        });
+        let format = SegmentFormat::from_options(options);
+        if let Some(magic) = format.magic() {
+            writer.write_all(&magic)?;
+        }
+
+        Ok(SegmentWriter {
+            writer,
+            count: 0,
+            index_interval: options.index_interval,
+            index: SparseIndex::default(),
+            item_offsets: options
+                .reverse_index
+                .then(|| Vec::with_capacity(capacity + 1)),
+            min: None,
+            max: None,
+            max_encoded: None,
+            block,
+            checksums: format.checksums,
+            codec: format.codec,
+            #[cfg(all(feature = "memfd", target_os = "linux"))]
+            seal: options.seal_segments,
+        })
+    }
+
+    /// Writes the next item of the segment, which needs to be greater or equal
+    /// to the previously written items.
+    pub fn write(&mut self, item: T) -> Result<(), Error> {
+        if self.block.is_some() {
+            return self.write_to_block(item);
+        }
+
+        let position = self.count;
+        if self.index_interval > 0 && position.is_multiple_of(self.index_interval as u64) {
+            self.index.push(self.writer.written(), position, &item)?;
+        }
+        if let Some(item_offsets) = &mut self.item_offsets {
+            item_offsets.push(self.writer.written());
+        }
+        if self.min.is_none() {
+            self.min = Some(encode_item(&item)?);
+        }
+
+        encode_record(&item, &mut self.writer, self.checksums)?;
+        self.count += 1;
+        self.max = Some(item);
+
+        Ok(())
+    }
+
+    /// Writes the next item of the segment in its encoded form, which needs to
+    /// be greater or equal to the previously written items.
+    pub fn write_encoded(&mut self, encoded: &[u8]) -> Result<(), Error> {
+        if self.min.is_none() {
+            self.min = Some(encoded.to_vec());
+        }
+
+        if let Some(block) = &mut self.block {
+            block.start_item(self.count);
+            block.push_encoded(encoded, self.codec)?;
+            if self.checksums {
+                let checksum = Crc32::checksum(encoded);
+                block.buffer.extend_from_slice(&checksum.to_le_bytes());
+            }
+            self.finish_block_item()?;
+        } else {
+            let position = self.count;
+            if self.index_interval > 0 && position.is_multiple_of(self.index_interval as u64) {
+                self.index
+                    .push_encoded(self.writer.written(), position, encoded.to_vec());
+            }
+            if let Some(item_offsets) = &mut self.item_offsets {
+                item_offsets.push(self.writer.written());
+            }
+
+            self.writer.write_all(encoded)?;
+            if self.checksums {
+                let checksum = Crc32::checksum(encoded);
+                self.writer.write_all(&checksum.to_le_bytes())?;
+            }
+            self.count += 1;
+        }
+
+        let max_encoded = self.max_encoded.get_or_insert_with(Vec::new);
+        max_encoded.clear();
+        max_encoded.extend_from_slice(encoded);
+
+        Ok(())
+    }
+
+    /// Writes the next item of the segment to the current block, which is
+    /// written to disk once it reaches the block size.
+    fn write_to_block(&mut self, item: T) -> Result<(), Error> {
+        if self.min.is_none() {
+            self.min = Some(encode_item(&item)?);
+        }
+
+        let block = self
+            .block
+            .as_mut()
+            .expect("segment isn't written in blocks");
+        block.start_item(self.count);
+        if self.codec != BlockCodec::Plain {
+            let encoded = encode_item(&item)?;
+            block.push_encoded(&encoded, self.codec)?;
+            if self.checksums {
+                let checksum = Crc32::checksum(&encoded);
+                block.buffer.extend_from_slice(&checksum.to_le_bytes());
+            }
+        } else {
+            encode_record(&item, &mut block.buffer, self.checksums)?;
+        }
+        self.finish_block_item()?;
+        self.max = Some(item);
+
+        Ok(())
+    }
+
+    fn finish_block_item(&mut self) -> Result<(), Error> {
+        let block = self
+            .block
+            .as_mut()
+            .expect("segment isn't written in blocks");
+        if block.count == 0 {
+            // the index holds the first item without its checksum
+            block.first = if self.codec != BlockCodec::Plain {
+                block.last.clone()
+            } else {
+                let len = block.buffer.len() - if self.checksums { 4 } else { 0 };
+                block.buffer[..len].to_vec()
+            };
+        }
+        block.count += 1;
+        self.count += 1;
+
+        if block.buffer.len() >= block.size {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the current block, if any, along with its header, and records
+    /// its first item in the index.
+    fn flush_block(&mut self) -> Result<(), Error> {
+        let Some(block) = &mut self.block else {
+            return Ok(());
+        };
+        if block.count == 0 {
+            return Ok(());
+        }
+
+        let len = u32::try_from(block.buffer.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "block exceeds 4GiB"))?;
+        self.index.push_encoded(
+            self.writer.written(),
+            block.position,
+            std::mem::take(&mut block.first),
+        );
+        write_block_header(&mut self.writer, len, block.count)?;
+        self.writer.write_all(&block.buffer)?;
+
+        block.buffer.clear();
+        block.count = 0;
+        Ok(())
+    }
+
+    /// Writes the footer of a segment written in blocks, holding its block
+    /// index followed by the offset of the footer itself.
+    fn write_footer(&mut self) -> Result<(), Error> {
+        let footer_offset = self.writer.written();
+        let footer = self.index.encode_footer();
+        let len = u32::try_from(footer.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "block index exceeds 4GiB"))?;
+        write_block_header(&mut self.writer, len, FOOTER_MARKER)?;
+        self.writer.write_all(&footer)?;
+        self.writer.write_all(&footer_offset.to_le_bytes())
+    }
+
+    /// Returns true if the segment holds at least one item and reached the
+    /// given maximum size in bytes, ignoring its block index footer. A
+    /// maximum of 0 means that the segment size is unbounded.
+    pub fn is_full(&self, max_bytes: u64) -> bool {
+        let buffered = self.block.as_ref().map_or(0, |block| block.buffer.len());
+        max_bytes > 0 && self.count > 0 && self.writer.written() + buffered as u64 >= max_bytes
+    }
+
+    pub fn finish(mut self) -> Result<SegmentFile, Error> {
+        if let Some(item_offsets) = &mut self.item_offsets {
+            item_offsets.push(self.writer.written());
+        }
+        let blocked = self.block.is_some();
+        if blocked {
+            self.flush_block()?;
+            self.write_footer()?;
+        }
+
+        let max = match &self.max {
+            Some(max) => Some(encode_item(max)?),
+            None => self.max_encoded.take(),
+        };
+        let stats = match (self.min, max) {
+            (Some(min), Some(max)) => Some(SegmentStats { min, max }),
+            _ => None,
+        };
+
+        let size = self.writer.written();
+        let (file, path, transform) = self.writer.into_inner().into_data()?;
+        #[cfg(all(feature = "memfd", target_os = "linux"))]
+        if let (true, SegmentData::File(file)) = (self.seal, &file) {
+            crate::memfd::seal(file)?;
+        }
+
+        Ok(SegmentFile {
+            file,
+            path,
+            scratch: true,
+            count: Some(self.count),
+            size: Some(size),
+            index: self.index,
+            stats,
+            item_offsets: self.item_offsets,
+            format: SegmentFormat {
+                blocked,
+                checksums: self.checksums,
+                codec: self.codec,
+            },
+            transform,
+        })
+    }
+}
+
+impl BlockBuffer {
+    fn start_item(&mut self, position: u64) {
+        if self.count == 0 {
+            self.position = position;
+        }
+    }
+
+    /// Writes an encoded item relative to the previous item of the block
+    /// according to the codec, followed by the length and bytes of the rest
+    /// of its encoding. The first item of a block is written relative to an
+    /// empty item, so that blocks can be decoded on their own.
+    fn push_encoded(&mut self, encoded: &[u8], codec: BlockCodec) -> Result<(), Error> {
+        if self.count == 0 {
+            self.last.clear();
+        }
+
+        let start = match codec {
+            BlockCodec::Plain => {
+                self.buffer.extend_from_slice(encoded);
+                return Ok(());
+            }
+            BlockCodec::Prefix => {
+                let shared = self
+                    .last
+                    .iter()
+                    .zip(encoded)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_varint(&mut self.buffer, shared as u64)?;
+                shared
+            }
+            BlockCodec::Delta(key_bytes) => {
+                let key = delta_key(encoded, key_bytes).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "item is shorter than its delta encoded key",
+                    )
+                })?;
+                let previous = delta_key(&self.last, key_bytes).unwrap_or(0);
+                let delta = key.wrapping_sub(previous) as i64;
+                write_varint(&mut self.buffer, ((delta << 1) ^ (delta >> 63)) as u64)?;
+                key_bytes as usize
+            }
+        };
+        write_varint(&mut self.buffer, (encoded.len() - start) as u64)?;
+        self.buffer.extend_from_slice(&encoded[start..]);
+
+        self.last.clear();
+        self.last.extend_from_slice(encoded);
+        Ok(())
+    }
+}
+
+/// Encodes an item, followed by its checksum if enabled.
+fn encode_record<T: Sortable, W: Write>(
+    item: &T,
+    writer: &mut W,
+    checksums: bool,
+) -> Result<(), Error> {
+    if !checksums {
+        return item.encode(writer);
+    }
+
+    let mut checksum_writer = ChecksumWriter::new(writer);
+    item.encode(&mut checksum_writer)?;
+    let checksum = checksum_writer.checksum();
+    writer.write_all(&checksum.to_le_bytes())
+}
+
+/// Verifies the checksum following an item decoded from the given reader.
+fn verify_checksum<R: Read>(reader: &mut R, actual: u32) -> Result<(), Error> {
+    let mut expected = [0; 4];
+    reader.read_exact(&mut expected).map_err(|err| {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            corrupted("truncated record checksum")
+        } else {
+            err
+        }
+    })?;
+
+    let expected = u32::from_le_bytes(expected);
+    if expected != actual {
+        return Err(ChecksumError { expected, actual }.into());
+    }
+    Ok(())
+}
+
+fn write_block_header<W: Write>(writer: &mut W, len: u32, count: u32) -> Result<(), Error> {
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&count.to_le_bytes())
+}
+
+fn read_block_header<R: Read>(reader: &mut R) -> Result<(u32, u32), Error> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).map_err(|err| {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            corrupted("truncated block segment")
+        } else {
+            err
+        }
+    })?;
+    let (len, count) = header.split_at(4);
+    Ok((
+        u32::from_le_bytes(len.try_into().unwrap()),
+        u32::from_le_bytes(count.try_into().unwrap()),
+    ))
+}
+
+/// Returns the big-endian unsigned integer key at the start of an encoded
+/// item, if it is long enough.
+fn delta_key(encoded: &[u8], key_bytes: u8) -> Option<u64> {
+    let key = encoded.get(..key_bytes as usize)?;
+    Some(key.iter().fold(0, |key, byte| (key << 8) | *byte as u64))
+}
+
+fn corrupted(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Reader of a segment file, which is either a flat stream of encoded items
+/// or a sequence of blocks.
+///
+/// Blocks start with a header holding their length and item count, and are
+/// read one at a time so that a corrupted item can't be decoded past the end
+/// of its block. Items of blocks that aren't written as encoded (see
+/// [`BlockCodec`]) are rebuilt from the previous item of their block before
+/// being decoded.
+pub(crate) struct SegmentReader {
+    inner: BufReader<SegmentData>,
+    block: Option<BlockCursor>,
+    checksums: bool,
+    codec: BlockCodec,
+    last: Vec<u8>,
+}
+
+#[derive(Default)]
+struct BlockCursor {
+    bytes_left: u64,
+    items_left: u32,
+    done: bool,
+}
+
+impl SegmentReader {
+    /// Creates a reader of the segment from the current position of the file,
+    /// which needs to be at the start of the segment.
+    pub fn new(file: SegmentData, format: SegmentFormat) -> Result<SegmentReader, Error> {
+        let mut inner = BufReader::new(file);
+        if let Some(expected) = format.magic() {
+            let mut magic = [0; 8];
+            inner.read_exact(&mut magic)?;
+            if magic != expected {
+                return Err(corrupted("invalid segment header"));
+            }
+        }
+        let block = format.blocked.then(BlockCursor::default);
+        Ok(SegmentReader {
+            inner,
+            block,
+            checksums: format.checksums,
+            codec: if format.blocked {
+                format.codec
+            } else {
+                BlockCodec::Plain
+            },
+            last: Vec::new(),
+        })
+    }
+
+    /// Decodes the next item of the segment, or returns `None` if the end of
+    /// the segment has been reached.
+    pub fn decode_next<T: Sortable>(&mut self) -> Result<Option<T>, Error> {
+        if !self.next_item()? {
+            return Ok(None);
+        }
+
+        if self.codec != BlockCodec::Plain {
+            self.read_coded()?;
+            return T::decode(&mut self.last.as_slice())
+                .map(Some)
+                .map_err(|err| {
+                    if err.kind() == ErrorKind::UnexpectedEof {
+                        corrupted("item overflows its record")
+                    } else {
+                        err
+                    }
+                });
+        }
+
+        let decoded = if self.checksums {
+            let mut reader = ChecksumReader::new(self);
+            T::try_decode(&mut reader).map(|item| item.map(|item| (item, Some(reader.checksum()))))
+        } else {
+            T::try_decode(self).map(|item| item.map(|item| (item, None)))
+        };
+
+        match decoded {
+            Ok(Some((item, checksum))) => {
+                if let Some(checksum) = checksum {
+                    verify_checksum(self, checksum)?;
+                }
+                if let Some(block) = &mut self.block {
+                    block.items_left -= 1;
+                }
+                Ok(Some(item))
+            }
+            Ok(None) if self.block.is_none() => Ok(None),
+            Ok(None) => Err(corrupted("item overflows its block")),
+            Err(err) if self.block.is_some() && err.kind() == ErrorKind::UnexpectedEof => {
+                Err(corrupted("item overflows its block"))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the next item of a segment of byte records in its encoded form,
+    /// which is its length as a little-endian `u32` followed by its bytes,
+    /// without decoding it. Returns false if the end of the segment has been
+    /// reached.
+    pub fn read_encoded_record(&mut self, buf: &mut Vec<u8>) -> Result<bool, Error> {
+        if !self.next_item()? {
+            return Ok(false);
+        }
+
+        if self.codec != BlockCodec::Plain {
+            self.read_coded()?;
+            buf.clear();
+            buf.extend_from_slice(&self.last);
+            return Ok(true);
+        }
+
+        let mut len = [0; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match self.read(&mut len[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        match read {
+            0 if self.block.is_none() => return Ok(false),
+            4 => {}
+            _ if self.block.is_some() => return Err(corrupted("item overflows its block")),
+            _ => return Err(corrupted("truncated byte record")),
+        }
+
+        buf.clear();
+        buf.extend_from_slice(&len);
+        buf.resize(4 + u32::from_le_bytes(len) as usize, 0);
+        self.read_exact(&mut buf[4..])
+            .map_err(|err| match (err.kind(), self.block.is_some()) {
+                (ErrorKind::UnexpectedEof, true) => corrupted("item overflows its block"),
+                (ErrorKind::UnexpectedEof, false) => corrupted("truncated byte record"),
+                _ => err,
+            })?;
+
+        if self.checksums {
+            verify_checksum(self, Crc32::checksum(buf))?;
+        }
+        if let Some(block) = &mut self.block {
+            block.items_left -= 1;
+        }
+        Ok(true)
+    }
+
+    /// Reads the next item of a block that isn't written as encoded, and
+    /// rebuilds its encoded form from the previous item of the block.
+    fn read_coded(&mut self) -> Result<(), Error> {
+        let overflow = |err: Error| match err.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::InvalidData => {
+                corrupted("item overflows its block")
+            }
+            _ => err,
+        };
+
+        let mut last = std::mem::take(&mut self.last);
+        match self.codec {
+            BlockCodec::Plain => unreachable!("plain items are decoded directly"),
+            BlockCodec::Prefix => {
+                let shared = read_varint(self).map_err(overflow)?;
+                if shared > last.len() as u64 {
+                    return Err(corrupted("invalid shared prefix length"));
+                }
+                last.truncate(shared as usize);
+            }
+            BlockCodec::Delta(key_bytes) => {
+                let zigzag = read_varint(self).map_err(overflow)?;
+                let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+                let key = delta_key(&last, key_bytes)
+                    .unwrap_or(0)
+                    .wrapping_add(delta as u64);
+                if key_bytes < 8 && key >> (8 * key_bytes) != 0 {
+                    return Err(corrupted("invalid key delta"));
+                }
+                last.clear();
+                last.extend_from_slice(&key.to_be_bytes()[8 - key_bytes as usize..]);
+            }
+        }
+        let suffix_len = read_varint(self).map_err(overflow)?;
+
+        // the rest of the item is read incrementally so that a corrupted
+        // length doesn't allocate more than what the block contains
+        let read = self.take(suffix_len).read_to_end(&mut last)?;
+        if (read as u64) < suffix_len {
+            return Err(corrupted("item overflows its block"));
+        }
+        if self.checksums {
+            verify_checksum(self, Crc32::checksum(&last))?;
+        }
+        self.last = last;
+
+        if let Some(block) = &mut self.block {
+            block.items_left -= 1;
+        }
+        Ok(())
+    }
+
+    /// Prepares the reader for the next item, returning false if the end of a
+    /// segment written in blocks has been reached. Segments that aren't
+    /// written in blocks always return true, their end being detected when
+    /// decoding the next item.
+    fn next_item(&mut self) -> Result<bool, Error> {
+        let Some(block) = &mut self.block else {
+            return Ok(true);
+        };
+        if block.items_left > 0 {
+            return Ok(true);
+        }
+        if block.bytes_left > 0 {
+            return Err(corrupted("block has more bytes than items"));
+        }
+        if block.done {
+            return Ok(false);
+        }
+
+        let (len, count) = read_block_header(&mut self.inner)?;
+        if count == FOOTER_MARKER {
+            block.done = true;
+            return Ok(false);
+        }
+        if count == 0 {
+            return Err(corrupted("empty block"));
+        }
+        block.bytes_left = len as u64;
+        block.items_left = count;
+        self.last.clear();
+        Ok(true)
+    }
+
+    /// Moves the reader to the given offset, which needs to be the start of an
+    /// item, or the start of a block for segments written in blocks.
+    pub fn seek(&mut self, offset: u64) -> Result<(), Error> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        if let Some(block) = &mut self.block {
+            *block = BlockCursor::default();
+        }
+        Ok(())
+    }
+
+    /// Truncates the segment, which won't be read anymore.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.inner.get_mut().truncate()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.inner.get_ref().is_file()
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.inner.get_ref().is_stream()
+    }
+
+    pub fn format(&self) -> SegmentFormat {
+        SegmentFormat {
+            blocked: self.block.is_some(),
+            checksums: self.checksums,
+            codec: self.codec,
+        }
+    }
+
+    pub fn stream_position(&mut self) -> Result<u64, Error> {
+        self.inner.stream_position()
+    }
+
+    pub fn into_inner(self) -> SegmentData {
+        self.inner.into_inner()
+    }
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(block) = &mut self.block else {
+            return self.inner.read(buf);
+        };
+
+        let max = buf
+            .len()
+            .min(block.bytes_left.min(usize::MAX as u64) as usize);
+        if max == 0 {
+            return Ok(0);
+        }
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(corrupted("truncated block"));
+        }
+        block.bytes_left -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Cursor reading a segment from its end, using the recorded offsets of all
+/// of its items. The segment file is opened a second time so that reading
+/// backward doesn't interfere with the forward reader.
+pub(crate) struct ReverseCursor {
+    file: File,
+    offsets: Vec<u64>,
+    position: usize,
+    buffer: Vec<u8>,
+    checksums: bool,
+}
+
+impl ReverseCursor {
+    /// Opens a cursor over the segment, where `offsets` contains the start
+    /// offset of each item followed by the end offset of the last item.
+    pub fn open(
+        path: &PathBuf,
+        offsets: Vec<u64>,
+        format: SegmentFormat,
+    ) -> Result<ReverseCursor, Error> {
+        Ok(ReverseCursor {
+            file: File::open(path)?,
+            position: offsets.len().saturating_sub(1),
+            offsets,
+            buffer: Vec::new(),
+            checksums: format.checksums,
+        })
+    }
+
+    /// Returns the recorded offsets of the items of the segment.
+    pub fn into_offsets(self) -> Vec<u64> {
+        self.offsets
+    }
+
+    /// Decodes the previous item of the segment, or returns `None` if the
+    /// start of the segment has been reached.
+    pub fn decode_prev<T: Sortable>(&mut self) -> Result<Option<T>, Error> {
+        if self.position == 0 {
+            return Ok(None);
+        }
+
+        self.position -= 1;
+        let start = self.offsets[self.position];
+        let end = self.offsets[self.position + 1];
+
+        self.buffer.resize((end - start) as usize, 0);
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut self.buffer)?;
+
+        let mut reader = self.buffer.as_slice();
+        if !self.checksums {
+            return T::decode(&mut reader).map(Some);
+        }
+
+        let mut checksum_reader = ChecksumReader::new(&mut reader);
+        let item = T::decode(&mut checksum_reader)?;
+        let checksum = checksum_reader.checksum();
+        verify_checksum(&mut reader, checksum)?;
+        Ok(Some(item))
+    }
+}
+
+/// Smallest and largest items of a segment, in their encoded form.
+#[derive(Clone)]
+pub(crate) struct SegmentStats {
+    min: Vec<u8>,
+    max: Vec<u8>,
+}
+
+impl SegmentStats {
+    pub fn min<T: Sortable>(&self) -> Result<T, Error> {
+        T::decode(&mut self.min.as_slice())
+    }
+
+    pub fn max<T: Sortable>(&self) -> Result<T, Error> {
+        T::decode(&mut self.max.as_slice())
+    }
+}
+
+/// Sparse index of a segment, recording the byte offset and position of every
+/// nth item along with its encoded value.
+#[derive(Clone, Default)]
+pub(crate) struct SparseIndex {
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Clone)]
+struct IndexEntry {
+    offset: u64,
+    position: u64,
+    item: Vec<u8>,
+}
+
+impl SparseIndex {
+    pub fn push<T: Sortable>(&mut self, offset: u64, position: u64, item: &T) -> Result<(), Error> {
+        self.push_encoded(offset, position, encode_item(item)?);
+        Ok(())
+    }
+
+    pub fn push_encoded(&mut self, offset: u64, position: u64, item: Vec<u8>) {
+        self.entries.push(IndexEntry {
+            offset,
+            position,
+            item,
+        });
+    }
+
+    /// Returns the offset and position of the last indexed item at or before
+    /// the given position, from which a reader can start decoding to find the
+    /// item at that position.
+    pub fn seek_position(&self, position: u64) -> Option<(u64, u64)> {
+        let after = self
+            .entries
+            .partition_point(|entry| entry.position <= position);
+        after
+            .checked_sub(1)
+            .map(|i| (self.entries[i].offset, self.entries[i].position))
+    }
+
+    /// Returns the offset and position of the last indexed item, if any.
+    pub fn last(&self) -> Option<(u64, u64)> {
+        self.entries
+            .last()
+            .map(|entry| (entry.offset, entry.position))
+    }
+
+    /// Encodes the index to be written in the footer of a segment written in
+    /// blocks.
+    fn encode_footer(&self) -> Vec<u8> {
+        let mut footer = Vec::new();
+        for entry in &self.entries {
+            footer.extend_from_slice(&entry.offset.to_le_bytes());
+            footer.extend_from_slice(&entry.position.to_le_bytes());
+            footer.extend_from_slice(&(entry.item.len() as u64).to_le_bytes());
+            footer.extend_from_slice(&entry.item);
+        }
+        footer
+    }
+
+    fn decode_footer(mut footer: &[u8]) -> Result<SparseIndex, Error> {
+        fn read_u64(footer: &mut &[u8]) -> Result<u64, Error> {
+            let mut value = [0; 8];
+            footer
+                .read_exact(&mut value)
+                .map_err(|_| corrupted("truncated block index"))?;
+            Ok(u64::from_le_bytes(value))
+        }
+
+        let mut index = SparseIndex::default();
+        while !footer.is_empty() {
+            let offset = read_u64(&mut footer)?;
+            let position = read_u64(&mut footer)?;
+            let len = read_u64(&mut footer)?;
+            if len > footer.len() as u64 {
+                return Err(corrupted("truncated block index"));
+            }
+            let (item, rest) = footer.split_at(len as usize);
+            index.push_encoded(offset, position, item.to_vec());
+            footer = rest;
+        }
+        Ok(index)
+    }
+
+    /// Returns the offset and position of the last indexed item smaller than
+    /// the given key, from which a reader can start decoding to find the first
+    /// item greater or equal to the key.
+    pub fn seek_offset<T, F>(&self, key: &T, cmp: &F) -> Result<Option<(u64, u64)>, Error>
+    where
+        T: Sortable,
+        F: Fn(&T, &T) -> Ordering,
+    {
+        // binary search for the number of indexed items smaller than the key
+        let (mut low, mut high) = (0, self.entries.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let item = T::decode(&mut self.entries[mid].item.as_slice())?;
+            if cmp(&item, key) == Ordering::Less {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low
+            .checked_sub(1)
+            .map(|i| (self.entries[i].offset, self.entries[i].position)))
+    }
+}
+
+/// Creates the file of a segment at the given path, or an anonymous scratch
+/// file if no path is given.
+fn open_segment_file(path: Option<&Path>, options: &ExternalSorterOptions) -> Result<File, Error> {
+    match path {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path),
+        None => create_tempfile(options),
+    }
+}
+
+/// Creates an anonymous scratch file, using the configured tempfile builder if
+/// any, or in the sort directory otherwise.
+pub(crate) fn create_tempfile(options: &ExternalSorterOptions) -> Result<File, Error> {
+    match (&options.tempfile_builder, &options.sort_dir) {
+        (Some(builder), _) => builder(),
+        (None, Some(sort_dir)) => tempfile::tempfile_in(sort_dir),
+        (None, None) => create_anonymous_tempfile(),
+    }
+}
+
+/// Deletes a segment file written by the sorter, which may already have been
+/// deleted once its items were all read.
+pub(crate) fn remove_segment(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn encode_item<T: Sortable>(item: &T) -> Result<Vec<u8>, Error> {
+    let mut encoded = Vec::new();
+    item.encode(&mut encoded)?;
+    Ok(encoded)
+}
+
+/// Writer wrapper counting the number of bytes written through it.
+pub(crate) struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, written: 0 }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
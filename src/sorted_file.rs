@@ -0,0 +1,191 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{Error, ErrorKind},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    segment::{SegmentData, SegmentFile, SegmentFormat, SegmentReader, SparseIndex},
+    ExternalSorterOptions, Sortable, SortedIterator,
+};
+
+/// Sorted items persisted to a file in blocks along with their block index,
+/// as written by [`SortedIterator::persist`].
+///
+/// Only the block index is loaded in memory, so that items can be looked up
+/// by key with a binary search over the index followed by the decoding of a
+/// single block (see [`SortedFile::get`]), or by position since the index
+/// also records the position of each block (see [`SortedFile::nth`]). The
+/// file is reopened for each lookup, and can therefore be shared between
+/// threads or opened by other processes.
+pub struct SortedFile<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    path: PathBuf,
+    index: SparseIndex,
+    format: SegmentFormat,
+    len: u64,
+    cmp: F,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> SortedFile<T, fn(&T, &T) -> Ordering>
+where
+    T: Sortable + Ord,
+{
+    /// Opens a sorted file whose items are sorted in their natural order.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        SortedFile::open_by(path, Ord::cmp)
+    }
+}
+
+impl<T, F> SortedFile<T, F>
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    /// Opens a sorted file whose items are sorted according to the given
+    /// comparator, which needs to be the one used to sort them.
+    ///
+    /// Returns an `InvalidData` error if the file wasn't written in blocks.
+    pub fn open_by<P: AsRef<Path>>(path: P, cmp: F) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut segment_file = SegmentFile::open(File::open(&path)?)?;
+        if !segment_file.format.blocked {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "sorted file isn't written in blocks",
+            ));
+        }
+        let len = segment_file.blocked_len()?;
+
+        Ok(SortedFile {
+            path,
+            index: segment_file.index,
+            format: segment_file.format,
+            len,
+            cmp,
+            _item: PhantomData,
+        })
+    }
+
+    /// Returns the path of the sorted file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of items in the sorted file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the sorted file holds no items.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the first item equal to the given key according to the
+    /// comparator, if any.
+    pub fn get(&self, key: &T) -> Result<Option<T>, Error> {
+        let offset = self.index.seek_offset(key, &self.cmp)?;
+        let mut reader = self.reader_at(offset.map(|(offset, _)| offset))?;
+        while let Some(item) = reader.decode_next::<T>()? {
+            match (self.cmp)(&item, key) {
+                Ordering::Less => {}
+                Ordering::Equal => return Ok(Some(item)),
+                Ordering::Greater => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns true if an item is equal to the given key according to the
+    /// comparator.
+    pub fn contains(&self, key: &T) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Returns the number of items smaller than the given key according to the
+    /// comparator, which is the position of the first item equal to the key if
+    /// any.
+    pub fn rank(&self, key: &T) -> Result<u64, Error> {
+        let (offset, mut rank) = match self.index.seek_offset(key, &self.cmp)? {
+            Some((offset, position)) => (Some(offset), position),
+            None => (None, 0),
+        };
+        let mut reader = self.reader_at(offset)?;
+        while let Some(item) = reader.decode_next::<T>()? {
+            if (self.cmp)(&item, key) != Ordering::Less {
+                break;
+            }
+            rank += 1;
+        }
+        Ok(rank)
+    }
+
+    /// Returns the item at the given position in sorted order, if any.
+    ///
+    /// The block holding the item is found using the positions recorded in the
+    /// block index, and is the only one decoded. This allows percentile
+    /// lookups or pagination without reading the file from its start.
+    pub fn nth(&self, position: u64) -> Result<Option<T>, Error> {
+        if position >= self.len {
+            return Ok(None);
+        }
+
+        let (offset, mut current) = match self.index.seek_position(position) {
+            Some((offset, block_position)) => (Some(offset), block_position),
+            None => (None, 0),
+        };
+        let mut reader = self.reader_at(offset)?;
+        while let Some(item) = reader.decode_next::<T>()? {
+            if current == position {
+                return Ok(Some(item));
+            }
+            current += 1;
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over all the items of the sorted file.
+    pub fn iter(&self) -> Result<SortedIterator<T, F>, Error> {
+        let segment_file = SegmentFile::open(File::open(&self.path)?)?;
+        SortedIterator::new(
+            None,
+            None,
+            vec![segment_file],
+            Some(self.len),
+            self.cmp.clone(),
+            ExternalSorterOptions::default(),
+        )
+    }
+
+    /// Opens a reader of the sorted file, positioned at the given block
+    /// offset or at its first block.
+    fn reader_at(&self, offset: Option<u64>) -> Result<SegmentReader, Error> {
+        let file = File::open(&self.path)?;
+        let mut reader = SegmentReader::new(SegmentData::File(file), self.format)?;
+        if let Some(offset) = offset {
+            reader.seek(offset)?;
+        }
+        Ok(reader)
+    }
+}
@@ -12,9 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{cmp::Ordering, io::Error, path::PathBuf};
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{Error, Read, Write},
+    path::{Path, PathBuf},
+};
 
-use crate::{iter::SortedIterator, push::PushExternalSorter, ExternalSorterOptions, Sortable};
+use crate::{
+    arena::ArenaPushSorter,
+    context::ContextSorter,
+    iter::{SortedIterator, SortedTail},
+    key::{compare_fixed_keys, FixedKeySortable},
+    kv::{ArgsortIterator, IndexedKey, KeyValue, SidecarEntry, SidecarIterator, SidecarWriter},
+    partition::hash_partition,
+    push::{compaction_worker, CompactionHandle, PushExternalSorter},
+    radix::radix_sort_by_key,
+    segment::{
+        create_tempfile, SegmentData, SegmentFile, SegmentFormat, SegmentReader, SegmentTransform,
+    },
+    sorted_file::SortedFile,
+    window::KDisorderedIterator,
+    ConfigError, ExternalSorterOptions, Sortable,
+};
 
 /// Exposes external sorting (i.e. on-disk sorting) capability on arbitrarily
 /// sized iterators, even if the generated content of the iterator doesn't fit in
@@ -25,7 +45,7 @@ use crate::{iter::SortedIterator, push::PushExternalSorter, ExternalSorterOption
 /// to remain efficient for all implementations, the crate doesn't handle
 /// serialization, but leaves that to the user.
 pub struct ExternalSorter {
-    options: ExternalSorterOptions,
+    pub(crate) options: ExternalSorterOptions,
 }
 
 impl ExternalSorter {
@@ -35,6 +55,71 @@ impl ExternalSorter {
         }
     }
 
+    /// Creates a sorter whose default options are overridden by the following
+    /// environment variables, if they are set:
+    ///
+    /// * `EXTSORT_SEGMENT_SIZE`: see [`ExternalSorter::with_segment_size`]
+    /// * `EXTSORT_SEGMENT_BYTES`: see [`ExternalSorter::with_segment_bytes`]
+    /// * `EXTSORT_MEMORY_FRACTION`: see [`ExternalSorter::with_auto_memory_limit`]
+    /// * `EXTSORT_SORT_DIR`: see [`ExternalSorter::with_sort_dir`]
+    /// * `EXTSORT_PARALLEL`: `true` or `1` to sort in parallel, `false` or `0`
+    ///   otherwise. Parallel sorting requires the `parallel` feature and the
+    ///   variable is ignored without it.
+    /// * `EXTSORT_HEAP_ITER_SEGMENT_COUNT`: see
+    ///   [`ExternalSorter::with_heap_iter_segment_count`]
+    ///
+    /// Options can still be set using the builder methods afterward, which
+    /// take precedence over the environment.
+    pub fn from_env() -> Result<ExternalSorter, ConfigError> {
+        Self::from_vars(|name| std::env::var_os(name))
+    }
+
+    pub(crate) fn from_vars<V>(var: V) -> Result<ExternalSorter, ConfigError>
+    where
+        V: Fn(&str) -> Option<std::ffi::OsString>,
+    {
+        fn parse<T: std::str::FromStr>(
+            name: &str,
+            value: std::ffi::OsString,
+        ) -> Result<T, ConfigError> {
+            value
+                .to_str()
+                .and_then(|str_value| str_value.trim().parse().ok())
+                .ok_or_else(|| ConfigError::InvalidEnvVar {
+                    name: name.to_string(),
+                    value: value.to_string_lossy().into_owned(),
+                })
+        }
+
+        let mut options = ExternalSorterOptions::default();
+        if let Some(value) = var("EXTSORT_SEGMENT_SIZE") {
+            options.segment_size = parse("EXTSORT_SEGMENT_SIZE", value)?;
+        }
+        if let Some(value) = var("EXTSORT_SEGMENT_BYTES") {
+            options.segment_bytes = parse("EXTSORT_SEGMENT_BYTES", value)?;
+        }
+        if let Some(value) = var("EXTSORT_MEMORY_FRACTION") {
+            options.memory_fraction = parse("EXTSORT_MEMORY_FRACTION", value)?;
+        }
+        if let Some(value) = var("EXTSORT_SORT_DIR") {
+            options.sort_dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("EXTSORT_PARALLEL") {
+            let parallel = match value.to_str().map(str::trim) {
+                Some("1") => true,
+                Some("0") => false,
+                _ => parse("EXTSORT_PARALLEL", value)?,
+            };
+            options.parallel = parallel && cfg!(feature = "parallel");
+        }
+        if let Some(value) = var("EXTSORT_HEAP_ITER_SEGMENT_COUNT") {
+            options.heap_iter_segment_count =
+                Some(parse("EXTSORT_HEAP_ITER_SEGMENT_COUNT", value)?);
+        }
+
+        Ok(ExternalSorter { options })
+    }
+
     /// Sets the maximum size of each segment in number of sorted items.
     ///
     /// This number of items needs to fit in memory. While sorting, an
@@ -50,38 +135,551 @@ impl ExternalSorter {
         self
     }
 
+    /// Sets the target size of each segment on disk in bytes, adapting the
+    /// maximum number of items per segment to the observed encoded size of
+    /// the items.
+    ///
+    /// The first segment is written once the segment size in number of items
+    /// is reached (see [`ExternalSorter::with_segment_size`]). The maximum
+    /// number of items of the following segments is then adjusted from the
+    /// average encoded size of the items written so far. Note that the memory
+    /// used by the in-memory buffer depends on the in-memory size of the items,
+    /// which may differ from their encoded size.
+    ///
+    /// Default is 0 (disabled)
+    pub fn with_segment_bytes(mut self, bytes: u64) -> Self {
+        self.options.segment_bytes = bytes;
+        self
+    }
+
+    /// Sets the maximum size in bytes of each segment file, splitting the
+    /// sorted buffer into multiple segments if its encoded size exceeds it.
+    ///
+    /// A segment is closed as soon as it reaches the maximum size, which means
+    /// that it can exceed it by the size of one item (or of one block along
+    /// with the block index, see [`ExternalSorter::with_block_size`]). Merged
+    /// segments written by compaction are split in the same way (see
+    /// [`ExternalSorter::with_compaction`]).
+    ///
+    /// Default is 0 (unbounded)
+    pub fn with_max_segment_bytes(mut self, bytes: u64) -> Self {
+        self.options.max_segment_bytes = bytes;
+        self
+    }
+
+    /// Sizes the in-memory buffer to use at most the given fraction of the
+    /// memory available on the system, re-checking the available memory each
+    /// time a segment is written.
+    ///
+    /// On Linux, the available memory is capped by the memory left within the
+    /// limits of the cgroups of the process (both v1 and v2), so that sorts
+    /// running in containers size their buffer against the container's limit
+    /// rather than the memory of the host.
+    ///
+    /// The first segment is written once the segment size in number of items
+    /// is reached (see [`ExternalSorter::with_segment_size`]), which is used to
+    /// estimate the memory used by each item from its in-memory and average
+    /// encoded sizes. If the available memory can't be determined on the
+    /// platform, the segment size is used as is.
+    ///
+    /// If a target segment size in bytes is also set (see
+    /// [`ExternalSorter::with_segment_bytes`]), the smallest resulting segment
+    /// size is used.
+    ///
+    /// Default is 0.0 (disabled)
+    pub fn with_auto_memory_limit(mut self, fraction: f64) -> Self {
+        self.options.memory_fraction = fraction;
+        self
+    }
+
+    /// Draws the memory of the in-memory buffer from the given pool, which
+    /// can be shared by cloning it and giving it to other sorters to cap
+    /// their combined memory usage.
+    ///
+    /// The buffer is written to disk once it reaches the segment size, or
+    /// earlier if the pool runs out of memory (see [`MemoryPool`]). Memory is
+    /// given back to the pool once the buffer is written, and once the sorter
+    /// is done. This can't be combined with deterministic mode (see
+    /// [`ExternalSorter::with_deterministic`]).
+    ///
+    /// Default is to not share memory between sorters
+    ///
+    /// [`MemoryPool`]: crate::MemoryPool
+    pub fn with_memory_pool(mut self, pool: crate::MemoryPool) -> Self {
+        self.options.memory_pool = Some(pool);
+        self
+    }
+
+    /// Sets the algorithm used to sort the in-memory buffer before writing it
+    /// to disk.
+    ///
+    /// A stable sort keeps items that compare equal in the order they were
+    /// pushed within each segment, but the segments on disk may still be
+    /// merged in any order. For sorting with a radix sort, see
+    /// [`ExternalSorter::sort_by_u64_key`], and for a custom algorithm, see
+    /// [`PushExternalSorter::with_buffer_sort`].
+    ///
+    /// Default is [`SortAlgorithm::Unstable`]
+    pub fn with_sort_algorithm(mut self, algorithm: SortAlgorithm) -> Self {
+        self.options.sort_algorithm = algorithm;
+        self
+    }
+
+    /// Makes sorting reproducible: identical inputs sorted with identical
+    /// options produce identical outputs, including the order of items that
+    /// compare equal, and identical segments on disk.
+    ///
+    /// The in-memory buffer is sorted with a stable sort, even when sorting in
+    /// parallel, so that equal items are returned in the order they were
+    /// pushed, including across segments since segments are merged in the
+    /// order their items were pushed. Sizing the buffer from the
+    /// available memory (see [`ExternalSorter::with_auto_memory_limit`]) is
+    /// rejected, since segment boundaries would depend on the system.
+    /// Segment files are named sequentially within the sort directory, which
+    /// should be set (see [`ExternalSorter::with_sort_dir`]) for their paths
+    /// to also be identical. A custom buffer sort (see
+    /// [`PushExternalSorter::with_buffer_sort`]) needs to be deterministic
+    /// itself.
+    ///
+    /// Default is false
+    pub fn with_deterministic(mut self) -> Self {
+        self.options.deterministic = true;
+        self
+    }
+
+    /// Writes segments in blocks of about the given number of bytes instead of
+    /// a flat stream of items.
+    ///
+    /// Each block starts with a header holding its length and number of items,
+    /// and the first item of each block is recorded in a block index, which
+    /// is also written in the footer of the segment. Seeking in the sorted
+    /// iterator (see [`SortedIterator::seek_to`]) skips whole blocks, and a
+    /// corrupted item is detected as soon as it doesn't match its block
+    /// instead of silently shifting the decoding of the following items.
+    ///
+    /// Blocks replace the index configured with
+    /// [`ExternalSorter::with_index_interval`], and can't be used with a
+    /// reverse index. Blocks are closed at item boundaries, so a block may
+    /// exceed the given size by one item.
+    ///
+    /// Default is 0 (segments aren't written in blocks)
+    pub fn with_block_size(mut self, bytes: usize) -> Self {
+        self.options.block_size = bytes;
+        self
+    }
+
+    /// Appends a CRC-32 checksum to each item written to disk, which is
+    /// verified when the item is read back.
+    ///
+    /// An item whose checksum doesn't match is returned as an error of kind
+    /// `InvalidData` wrapping a [`ChecksumError`](crate::ChecksumError),
+    /// instead of silently returning a corrupted item. Each item takes 4 more
+    /// bytes on disk.
+    ///
+    /// Only segments written by the sorter are checked, which start with magic
+    /// bytes recording their checksums so that they are also checked when
+    /// given back to [`SortedIterator::from_parts`]. Files of encoded items
+    /// given to [`ExternalSorter::merge_files`] are read without checksums.
+    ///
+    /// Default is false
+    pub fn with_record_checksums(mut self) -> Self {
+        self.options.record_checksums = true;
+        self
+    }
+
+    /// Writes each item of a block as the length of the prefix its encoding
+    /// shares with the previous item of the block, followed by the rest of its
+    /// encoding.
+    ///
+    /// Since segments are sorted, consecutive items sharing long key prefixes
+    /// (e.g. URLs or paths) take much less space on disk. Prefixes are shared
+    /// between the encoded items, so this only pays off for items whose
+    /// encoding starts with their key, which mustn't be preceded by its length
+    /// as it would differ between keys of different lengths.
+    ///
+    /// The compression restarts at each block, so that blocks can be decoded
+    /// on their own when seeking. Segments therefore need to be written in
+    /// blocks (see [`ExternalSorter::with_block_size`]), or sorting fails with
+    /// [`ConfigError::PrefixCompressionWithoutBlocks`].
+    ///
+    /// Default is false
+    pub fn with_prefix_compression(mut self) -> Self {
+        self.options.prefix_compression = true;
+        self
+    }
+
+    /// Writes the key at the start of the encoding of each item of a block as
+    /// its difference with the key of the previous item of the block, followed
+    /// by the rest of its encoding as is.
+    ///
+    /// The key is a big-endian unsigned integer of the given number of bytes,
+    /// such as a timestamp or an identifier. Since segments are sorted, keys
+    /// are monotonic within a block and their differences are small, so that
+    /// they take 1 or 2 bytes as varints instead of their full width. Items
+    /// don't need to be sorted by their key, but larger differences take more
+    /// space. Writing an item shorter than the key fails with an
+    /// `InvalidInput` error.
+    ///
+    /// As with [`ExternalSorter::with_prefix_compression`], the encoding
+    /// restarts at each block, so segments need to be written in blocks (see
+    /// [`ExternalSorter::with_block_size`]). Returns
+    /// [`ConfigError::InvalidDeltaKeyBytes`] for keys wider than 8 bytes, and
+    /// delta encoding can't be combined with prefix compression.
+    ///
+    /// Default is 0 (keys aren't delta encoded)
+    pub fn with_delta_encoding(mut self, key_bytes: usize) -> Self {
+        self.options.delta_key_bytes = key_bytes;
+        self
+    }
+
+    /// Verifies that each item returned by the sorted iterator is greater or
+    /// equal to the previous one according to the comparator, returning an
+    /// error of kind `InvalidData` otherwise.
+    ///
+    /// This catches inconsistent comparators, as well as corrupted segments or
+    /// unsorted files given to [`ExternalSorter::merge_files`], before the
+    /// wrongly sorted output is consumed. The previous item is kept encoded
+    /// and decoded again for each comparison, which makes iteration slower.
+    /// Items returned from the end of the iterator aren't verified.
+    ///
+    /// Default is false
+    pub fn with_verify_order(mut self) -> Self {
+        self.options.verify_order = true;
+        self
+    }
+
+    /// Spot-checks that the comparator is a total order each time sorted
+    /// items of the in-memory buffer are written to disk or returned from
+    /// memory, returning an error of kind `InvalidInput` otherwise.
+    ///
+    /// The sorted buffer is checked to be in order according to the
+    /// comparator, which catches custom buffer sorts (see
+    /// [`PushExternalSorter::with_buffer_sort`]) disagreeing with the
+    /// comparator used to merge segments. Reflexivity, antisymmetry and
+    /// transitivity are then checked on a sample of triples of buffered items.
+    /// This is meant for debugging and tests, since it adds a few comparisons
+    /// per buffered item.
+    ///
+    /// Default is false
+    pub fn with_comparator_checks(mut self) -> Self {
+        self.options.check_comparator = true;
+        self
+    }
+
+    /// Creates the scratch files in which segments are written using the given
+    /// function instead of creating them in the sort directory.
+    ///
+    /// The returned file must be opened for both reading and writing, and is
+    /// expected to be empty. Segments are never reopened by path, so the file
+    /// can be anonymous (e.g. unlinked, or a `memfd`), and it is closed once
+    /// the segment isn't needed anymore. This can't be combined with
+    /// [`ExternalSorter::with_reverse_index`].
+    ///
+    /// Default is to create files in the sort directory (see
+    /// [`ExternalSorter::with_sort_dir`]).
+    pub fn with_tempfile_builder<B>(mut self, builder: B) -> Self
+    where
+        B: Fn() -> std::io::Result<File> + Send + Sync + 'static,
+    {
+        self.options.tempfile_builder = Some(std::sync::Arc::new(builder));
+        self
+    }
+
+    /// Writes the segment files through the stream returned by `write_wrap`
+    /// for each of them, and reads them back through the stream returned by
+    /// `read_wrap`, which allows plugging custom codecs such as compression
+    /// or encryption.
+    ///
+    /// `write_wrap` is given the stream writing to the file of a new segment,
+    /// and the stream it returns is flushed and dropped once the segment is
+    /// complete, so it should write any trailing bytes when dropped. The
+    /// stream returned by `read_wrap` is given to read the file from its
+    /// start, and needs to return the bytes given to the write stream.
+    ///
+    /// Transformed segments are read sequentially, so seeking in the sorted
+    /// iterator (see [`SortedIterator::seek_to`]) decodes the items instead of
+    /// skipping the blocks of the segments, and sizes such as
+    /// [`SortedIterator::bytes_spilled`] count the bytes before the
+    /// transform. Segments kept in memory and files written by
+    /// [`SortedIterator::persist`] aren't transformed, and this can't be
+    /// combined with [`ExternalSorter::with_reverse_index`]. Since the
+    /// transformed segments can't be rewound, the sorted iterator can neither
+    /// be decomposed into its parts nor into one iterator per segment:
+    /// [`SortedIterator::into_parts`] and
+    /// [`SortedIterator::into_segment_iters`] return an `Unsupported` error.
+    ///
+    /// Default is to write segments as is.
+    pub fn with_segment_transform<W, R>(mut self, write_wrap: W, read_wrap: R) -> Self
+    where
+        W: Fn(Box<dyn Write + Send>) -> Box<dyn Write + Send> + Send + Sync + 'static,
+        R: Fn(Box<dyn Read + Send>) -> Box<dyn Read + Send> + Send + Sync + 'static,
+    {
+        self.options.segment_transform = Some(SegmentTransform {
+            write: std::sync::Arc::new(write_wrap),
+            read: std::sync::Arc::new(read_wrap),
+        });
+        self
+    }
+
+    /// Writes segments in anonymous memory-backed files created with
+    /// `memfd_create` instead of files in the sort directory, so that they
+    /// live in memory (or swap) and never touch a filesystem.
+    ///
+    /// If `seal` is true, segments are sealed against writes once written
+    /// (see `F_SEAL_WRITE`). The memory of a segment is freed once all of its
+    /// items were read. This replaces any tempfile builder (see
+    /// [`ExternalSorter::with_tempfile_builder`]), and requires the `memfd`
+    /// feature.
+    ///
+    /// Default is to write segments in the sort directory
+    #[cfg(all(feature = "memfd", target_os = "linux"))]
+    pub fn with_memfd_segments(mut self, seal: bool) -> Self {
+        self.options.seal_segments = seal;
+        self.with_tempfile_builder(move || crate::memfd::create(seal))
+    }
+
+    /// Keeps the first `count` segments in memory as encoded bytes instead of
+    /// writing them to disk, merging them along with the segments on disk.
+    ///
+    /// This avoids writing and reading back everything when the items barely
+    /// exceed the in-memory buffer, at the cost of holding the encoded items
+    /// of these segments in memory on top of the buffer. This can't be
+    /// combined with [`ExternalSorter::with_reverse_index`].
+    ///
+    /// Default is 0, or unlimited on `wasm32-unknown-unknown` which has no
+    /// filesystem to write segments to
+    pub fn with_in_memory_segments(mut self, count: usize) -> Self {
+        self.options.in_memory_segments = count;
+        self
+    }
+
+    /// Keeps segments whose encoded size is at most the given number of bytes
+    /// in memory instead of writing them to disk.
+    ///
+    /// Segments are written in memory first, and moved to disk once they
+    /// exceed the given size. This avoids the overhead of creating files for
+    /// small segments, such as the one written from the last items of the
+    /// buffer. This can't be combined with
+    /// [`ExternalSorter::with_reverse_index`].
+    ///
+    /// Default is 0 (disabled)
+    pub fn with_in_memory_segment_bytes(mut self, bytes: u64) -> Self {
+        self.options.in_memory_segment_bytes = bytes;
+        self
+    }
+
+    /// Sets which items are kept among the items that compare equal, which are
+    /// removed while merging the sorted segments.
+    ///
+    /// Items that compare equal are returned in the order they were pushed in
+    /// deterministic mode (see [`ExternalSorter::with_deterministic`]), in
+    /// which case [`DuplicatePolicy::KeepLast`] keeps the last pushed item
+    /// (i.e. last write wins). Otherwise, the kept item is arbitrary among the
+    /// items that compare equal. Since the number of distinct items isn't
+    /// known upfront, the sorted iterator doesn't know its number of items
+    /// (see [`SortedIterator::known_count`]) unless all items are kept.
+    ///
+    /// Default is [`DuplicatePolicy::KeepAll`]
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.options.duplicate_policy = policy;
+        self
+    }
+
     /// Sets the directory in which sorted segments will be written (if they don't
     /// fit in memory).
     ///
+    /// Each sorter writes its segments in its own uniquely named subdirectory,
+    /// which is removed once the sorted iterator is dropped, so that
+    /// concurrent sorters can safely share the same directory.
+    ///
     /// Default is to use the system's temporary directory.
     pub fn with_sort_dir(mut self, path: PathBuf) -> Self {
         self.options.sort_dir = Some(path);
         self
     }
 
+    /// Charges the segments written to disk to the given quota, which can be
+    /// shared by cloning it and giving it to other sorters to cap their
+    /// combined disk usage.
+    ///
+    /// Once the quota is exhausted, writing a segment either fails or waits
+    /// for other sorters to release bytes, depending on the quota's policy
+    /// (see [`QuotaPolicy`]). Bytes are released once the sorted iterator is
+    /// dropped.
+    ///
+    /// Default is to not limit disk usage
+    ///
+    /// [`QuotaPolicy`]: crate::QuotaPolicy
+    pub fn with_disk_quota(mut self, quota: crate::DiskQuota) -> Self {
+        self.options.disk_quota = Some(quota);
+        self
+    }
+
     /// Uses Rayon to sort the in-memory buffer.
     ///
     /// This may not be needed if the buffer isn't big enough for parallelism to
     /// be beneficial over the overhead of multithreading.
     ///
+    /// Requires the `parallel` feature, enabled by default.
+    ///
     /// Default is false
+    #[cfg(feature = "parallel")]
     pub fn with_parallel_sort(mut self) -> Self {
         self.options.parallel = true;
         self
     }
 
+    /// Sorts the in-memory buffer in parallel on the given Rayon thread pool
+    /// instead of the global one, which also applies to
+    /// [`SortedIterator::par_map_chunks`].
+    ///
+    /// The pool's threads can be named or have their priority lowered using
+    /// `rayon::ThreadPoolBuilder::thread_name` and
+    /// `rayon::ThreadPoolBuilder::start_handler`, while the threads merging
+    /// segments during compaction are configured using
+    /// [`ExternalSorter::with_background_thread_name`] and
+    /// [`ExternalSorter::with_background_thread_start`]. This enables parallel
+    /// sorting (see [`ExternalSorter::with_parallel_sort`]).
+    ///
+    /// Requires the `parallel` feature, enabled by default.
+    ///
+    /// Default is to use the current Rayon thread pool
+    #[cfg(feature = "parallel")]
+    pub fn with_thread_pool(mut self, pool: std::sync::Arc<rayon::ThreadPool>) -> Self {
+        self.options.parallel = true;
+        self.options.thread_pool = Some(pool);
+        self
+    }
+
+    /// Names the background threads spawned by the sorter to merge segments
+    /// while items are being pushed (see [`ExternalSorter::with_compaction`]).
+    ///
+    /// Default is `extsort-compaction`
+    pub fn with_background_thread_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.options.background_thread_name = name.into();
+        self
+    }
+
+    /// Calls the given function at the start of each background thread
+    /// spawned by the sorter (see [`ExternalSorter::with_compaction`]), for
+    /// example to lower its priority or to register it with a profiler.
+    ///
+    /// Default is to not call any function
+    pub fn with_background_thread_start<H>(mut self, hook: H) -> Self
+    where
+        H: Fn() + Send + Sync + 'static,
+    {
+        self.options.background_thread_start = Some(std::sync::Arc::new(hook));
+        self
+    }
+
     /// From how many segments on disk should the iterator switch to using a
     /// binary heap to keep track of the smallest item from each segment.
     ///
     /// For a small amount of segments, it is faster to peek over all segments
     /// at each iteration than to maintain a binary heap.
     ///
-    /// Default is 20
+    /// Default is to pick the merge strategy automatically once sorting is
+    /// done, from the number of segments and the measured cost of the
+    /// comparator: an expensive comparator favors the binary heap, which
+    /// needs fewer comparisons per item. The strategy is re-evaluated as
+    /// segments are exhausted while merging, so that a merge of many
+    /// segments stops maintaining the heap once only a few are left.
     pub fn with_heap_iter_segment_count(mut self, count: usize) -> Self {
-        self.options.heap_iter_segment_count = count;
+        self.options.heap_iter_segment_count = Some(count);
+        self
+    }
+
+    /// Records the position of every `interval`th item of each segment while
+    /// writing it to disk, allowing [`SortedIterator::seek_to`] to skip ahead
+    /// in segments without decoding all items before the sought key.
+    ///
+    /// A smaller interval makes seeking faster at the cost of memory, since
+    /// the indexed items are kept in memory in their encoded form.
+    ///
+    /// Default is 0 (disabled)
+    pub fn with_index_interval(mut self, interval: usize) -> Self {
+        self.options.index_interval = interval;
+        self
+    }
+
+    /// Records the offset of every item of each segment while writing it to
+    /// disk, allowing the sorted iterator to also be consumed from its end
+    /// (i.e. from the largest item) as a [`DoubleEndedIterator`].
+    ///
+    /// This costs 8 bytes of memory per item written to disk, and isn't needed
+    /// if all items fit in the memory buffer.
+    ///
+    /// Default is false
+    pub fn with_reverse_index(mut self) -> Self {
+        self.options.reverse_index = true;
+        self
+    }
+
+    /// Writes ascending runs of at least `min_len` items found in the input
+    /// directly as segments on disk, without sorting them.
+    ///
+    /// Items of shorter runs are buffered and sorted as usual. This reduces
+    /// the sorting cost of mostly sorted input, at the cost of potentially
+    /// writing more segments to disk.
+    ///
+    /// Default is 0 (disabled)
+    pub fn with_natural_runs(mut self, min_len: usize) -> Self {
+        self.options.natural_run_min_len = min_len;
+        self
+    }
+
+    /// Merges groups of `fan_in` segments on disk into larger segments while
+    /// items are being pushed, once at least `fan_in` segments were written.
+    ///
+    /// This bounds the number of segments merged by the sorted iterator and
+    /// spreads the merging I/O over the pushing phase. When sorting an
+    /// iterator, groups are merged on a background thread while items keep
+    /// being pushed, one group at a time. If the next group is due before the
+    /// previous one is merged, pushing waits for it so that the number of
+    /// segments stays bounded. Pushed sorters (see [`ExternalSorter::pushed`])
+    /// merge on a background thread once enabled with
+    /// [`PushExternalSorter::with_background_compaction`], and incrementally
+    /// on each push otherwise, so that a group is merged by the time the next
+    /// segment is written.
+    ///
+    /// Background threads are spawned with the standard library rather than
+    /// rayon, so they are used even without the `parallel` feature. If the
+    /// thread can't be spawned, which is the case on targets without threads
+    /// such as `wasm32-wasip1`, groups are merged incrementally on each push
+    /// instead of returning an error.
+    ///
+    /// The fan-in needs to be at least 2.
+    ///
+    /// Default is disabled
+    pub fn with_compaction(mut self, fan_in: usize) -> Self {
+        self.options.compaction_fan_in = Some(fan_in);
         self
     }
 
+    /// Sets a context shared by the items when they are encoded and decoded,
+    /// returning a sorter of [`SortableWithContext`](crate::SortableWithContext)
+    /// items.
+    ///
+    /// See the [`context`](crate::context) module.
+    pub fn with_codec_context<C>(self, context: C) -> ContextSorter<C>
+    where
+        C: Send + Sync + 'static,
+    {
+        ContextSorter::new(self, context)
+    }
+
+    /// Validates the configured options, which is done by all sorting and
+    /// merging methods before consuming any item.
+    ///
+    /// Pushed sorters (see [`ExternalSorter::pushed`]) don't validate their
+    /// options, which should be validated using this method beforehand.
+    ///
+    /// See [`ConfigError`] for the rejected configurations.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.options.validate()
+    }
+
     /// Sorts a given iterator, returning a new iterator with the sorted items.
     pub fn sort<T, I>(
         self,
@@ -109,6 +707,226 @@ impl ExternalSorter {
         self.sort_by(iterator, move |a, b| f(a).cmp(&f(b)))
     }
 
+    /// Sorts a given iterator by the key returned by `key`, returning a new
+    /// iterator with only the latest `limit` items of each key.
+    ///
+    /// The `recency` comparator orders the items of a key by how recent they
+    /// are, with newer items comparing greater (e.g. by timestamp). Items are
+    /// returned by ascending key, and from the newest to the oldest within
+    /// each key. Older items are skipped while merging the sorted segments
+    /// (see [`SortedIterator::tail_per_key`]), which avoids a second pass over
+    /// the sorted items for log retention or deduplication keeping a history.
+    #[allow(clippy::type_complexity)]
+    pub fn sort_tail<T, I, KF, K, R>(
+        self,
+        iterator: I,
+        key: KF,
+        limit: usize,
+        recency: R,
+    ) -> Result<SortedTail<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone, KF, K>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = T>,
+        KF: Fn(&T) -> K + Send + Sync + Clone,
+        K: Ord,
+        R: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        let sort_key = key.clone();
+        let sorted_iter = self.sort_by(iterator, move |a, b| {
+            sort_key(a).cmp(&sort_key(b)).then_with(|| recency(b, a))
+        })?;
+        Ok(sorted_iter.tail_per_key(key, limit))
+    }
+
+    /// Sorts a given iterator by a `u64` key, returning a new iterator with the
+    /// sorted items.
+    ///
+    /// The in-memory buffer is sorted using a radix sort on the key, which is
+    /// generally faster than a comparison sort for large buffers, while the
+    /// segments on disk are merged by comparing keys. Unlike
+    /// [`ExternalSorter::sort_by_key`], items with equal keys are returned in
+    /// the order they were pushed if they fit in the memory buffer. The parallel
+    /// sort option is ignored.
+    pub fn sort_by_u64_key<T, I, F>(
+        self,
+        iterator: I,
+        f: F,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = T>,
+        F: Fn(&T) -> u64 + Send + Sync + Clone + 'static,
+    {
+        self.validate()?;
+        let key = f.clone();
+        let cmp = move |a: &T, b: &T| f(a).cmp(&f(b));
+        with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut sorter = PushExternalSorter::new(self.options.clone(), cmp.clone())
+                .with_buffer_sort(move |buffer| radix_sort_by_key(buffer, &key))
+                .with_compaction_handle(handle);
+            sorter.push_iter(iterator)?;
+            sorter.done()
+        })
+    }
+
+    /// Sorts a given iterator by the fixed-width byte key of its items,
+    /// returning a new iterator with the sorted items.
+    ///
+    /// See [`FixedKeySortable`].
+    pub fn sort_by_fixed_key<T, I, const N: usize>(
+        self,
+        iterator: I,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: FixedKeySortable<N>,
+        I: IntoIterator<Item = T>,
+    {
+        self.sort_by(iterator, |a, b| {
+            compare_fixed_keys(&a.fixed_key(), &b.fixed_key())
+        })
+    }
+
+    /// Sorts a given iterator of keys and opaque values by their keys,
+    /// returning a new iterator with the sorted items.
+    ///
+    /// Values are carried as bytes and never decoded during the sort (see
+    /// [`KeyValue`]).
+    #[allow(clippy::type_complexity)]
+    pub fn sort_kv<K, V, I>(
+        self,
+        iterator: I,
+    ) -> Result<
+        SortedIterator<
+            KeyValue<K>,
+            impl Fn(&KeyValue<K>, &KeyValue<K>) -> Ordering + Send + Sync + Clone,
+        >,
+        Error,
+    >
+    where
+        K: Sortable + Ord,
+        V: Into<Vec<u8>>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.sort_kv_by(iterator, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator of keys and opaque values with a comparator
+    /// function on their keys, returning a new iterator with the sorted items.
+    ///
+    /// See [`ExternalSorter::sort_kv`].
+    #[allow(clippy::type_complexity)]
+    pub fn sort_kv_by<K, V, I, F>(
+        self,
+        iterator: I,
+        cmp: F,
+    ) -> Result<
+        SortedIterator<
+            KeyValue<K>,
+            impl Fn(&KeyValue<K>, &KeyValue<K>) -> Ordering + Send + Sync + Clone,
+        >,
+        Error,
+    >
+    where
+        K: Sortable,
+        V: Into<Vec<u8>>,
+        I: IntoIterator<Item = (K, V)>,
+        F: Fn(&K, &K) -> Ordering + Send + Sync + Clone,
+    {
+        self.sort_by(iterator.into_iter().map(KeyValue::from), move |a, b| {
+            cmp(&a.key, &b.key)
+        })
+    }
+
+    /// Sorts a given iterator of keys and large opaque values by their keys,
+    /// returning a new iterator with the sorted items.
+    ///
+    /// Unlike [`ExternalSorter::sort_kv`], values are written only once to a
+    /// sidecar file, and only the keys along with the location of their value
+    /// are sorted. Values are read back from the sidecar file when their item
+    /// is returned by the iterator, which requires a random read per item but
+    /// avoids rewriting every value in each segment and merge. This is
+    /// preferable for small keys carrying values of many kilobytes or more.
+    ///
+    /// The sidecar file is created in the sort directory if one is set, or in
+    /// the system's temporary directory otherwise, and is deleted once the
+    /// iterator is dropped.
+    pub fn sort_kv_sidecar<K, V, I>(self, iterator: I) -> Result<SidecarIterator<K>, Error>
+    where
+        K: Sortable + Ord + 'static,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.sort_kv_sidecar_by(iterator, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator of keys and large opaque values with a
+    /// comparator function on their keys, returning a new iterator with the
+    /// sorted items.
+    ///
+    /// See [`ExternalSorter::sort_kv_sidecar`].
+    pub fn sort_kv_sidecar_by<K, V, I, F>(
+        self,
+        iterator: I,
+        cmp: F,
+    ) -> Result<SidecarIterator<K>, Error>
+    where
+        K: Sortable + 'static,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+        F: Fn(&K, &K) -> Ordering + Send + Sync + Clone + 'static,
+    {
+        self.validate()?;
+        let file = create_tempfile(&self.options)?;
+        let mut sidecar = SidecarWriter::new(file);
+        let cmp = move |a: &SidecarEntry<K>, b: &SidecarEntry<K>| cmp(&a.key, &b.key);
+        let entries = with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut sorter = PushExternalSorter::new(self.options.clone(), cmp.clone())
+                .with_compaction_handle(handle);
+            for (key, value) in iterator {
+                sorter.push(sidecar.append(key, value.as_ref())?)?;
+            }
+            sorter.done()
+        })?;
+        Ok(SidecarIterator::new(entries, sidecar.finish()?))
+    }
+
+    /// Sorts a given iterator, returning an iterator over the positions of the
+    /// items in the input in their sorted order instead of the items
+    /// themselves.
+    ///
+    /// Each item is sorted along with its position, and equal items are
+    /// returned by ascending position. This allows reordering data kept
+    /// aside, such as the other columns of a table, without carrying it
+    /// through the sort.
+    pub fn argsort<T, I>(self, iterator: I) -> Result<ArgsortIterator, Error>
+    where
+        T: Sortable + Ord + 'static,
+        I: IntoIterator<Item = T>,
+    {
+        self.argsort_by(iterator, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator with a comparator function, returning an
+    /// iterator over the positions of the items in the input in their sorted
+    /// order.
+    ///
+    /// See [`ExternalSorter::argsort`].
+    pub fn argsort_by<T, I, F>(self, iterator: I, cmp: F) -> Result<ArgsortIterator, Error>
+    where
+        T: Sortable + 'static,
+        I: IntoIterator<Item = T>,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone + 'static,
+    {
+        let entries = iterator
+            .into_iter()
+            .zip(0..)
+            .map(|(key, index)| IndexedKey { key, index });
+        let sorted_iter = self.sort_by(entries, move |a: &IndexedKey<T>, b: &IndexedKey<T>| {
+            cmp(&a.key, &b.key).then(a.index.cmp(&b.index))
+        })?;
+        Ok(ArgsortIterator::new(sorted_iter))
+    }
+
     /// Sorts a given iterator with a comparator function, returning a new iterator with the sorted items.
     pub fn sort_by<T, I, F>(self, iterator: I, cmp: F) -> Result<SortedIterator<T, F>, Error>
     where
@@ -116,9 +934,531 @@ impl ExternalSorter {
         I: IntoIterator<Item = T>,
         F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
     {
-        let mut sorter = PushExternalSorter::new(self.options, cmp);
-        sorter.push_iter(iterator)?;
-        sorter.done()
+        self.validate()?;
+        with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut sorter = PushExternalSorter::new(self.options.clone(), cmp.clone())
+                .with_compaction_handle(handle);
+            sorter.push_iter(iterator)?;
+            sorter.done()
+        })
+    }
+
+    /// Sorts a stream of consecutive encoded items, such as a file written by
+    /// [`SortedIterator::write_to`], returning a new iterator with the sorted
+    /// items.
+    ///
+    /// Items are decoded straight from the stream into the sorter's buffer,
+    /// which avoids decoding them beforehand only to collect them into an
+    /// iterator. The reader is read through a buffer, so it doesn't need to be
+    /// buffered itself. The sorted items can be written back in the same
+    /// encoding with [`SortedIterator::write_to_path`]. The end of the stream
+    /// is detected using [`Sortable::try_decode`].
+    pub fn sort_encoded_stream<T, R>(
+        self,
+        reader: R,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable + Ord,
+        R: Read + Send + 'static,
+    {
+        self.sort_encoded_stream_by(reader, |a: &T, b: &T| a.cmp(b))
+    }
+
+    /// Sorts a stream of consecutive encoded items with a comparator function,
+    /// returning a new iterator with the sorted items.
+    ///
+    /// See [`ExternalSorter::sort_encoded_stream`].
+    pub fn sort_encoded_stream_by<T, R, F>(
+        self,
+        reader: R,
+        cmp: F,
+    ) -> Result<SortedIterator<T, F>, Error>
+    where
+        T: Sortable,
+        R: Read + Send + 'static,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+        let mut reader = SegmentReader::new(SegmentData::stream(reader), SegmentFormat::default())?;
+        with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut sorter = PushExternalSorter::new(self.options.clone(), cmp.clone())
+                .with_compaction_handle(handle);
+            while let Some(item) = reader.decode_next::<T>()? {
+                sorter.push(item)?;
+            }
+            sorter.done()
+        })
+    }
+
+    /// Sorts a given iterator whose items are at most `k` positions away from
+    /// their sorted position, returning a streaming iterator over the sorted
+    /// items.
+    ///
+    /// Only `k + 1` items are kept in memory and nothing is written to disk,
+    /// making it suitable for unbounded streams with bounded disorder (e.g.
+    /// timestamped events with bounded jitter). If an item is further away
+    /// from its sorted position, the output won't be sorted.
+    ///
+    /// See [`KDisorderedIterator`].
+    pub fn sort_k_disordered<T, I>(
+        self,
+        iterator: I,
+        k: usize,
+    ) -> KDisorderedIterator<I::IntoIter, T, impl Fn(&T, &T) -> Ordering + Clone>
+    where
+        T: Ord,
+        I: IntoIterator<Item = T>,
+    {
+        self.sort_k_disordered_by(iterator, k, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator whose items are at most `k` positions away from
+    /// their sorted position with a key extraction function.
+    ///
+    /// See [`ExternalSorter::sort_k_disordered`].
+    pub fn sort_k_disordered_by_key<T, I, F, K>(
+        self,
+        iterator: I,
+        k: usize,
+        f: F,
+    ) -> KDisorderedIterator<I::IntoIter, T, impl Fn(&T, &T) -> Ordering + Clone>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(&T) -> K + Clone,
+        K: Ord,
+    {
+        self.sort_k_disordered_by(iterator, k, move |a, b| f(a).cmp(&f(b)))
+    }
+
+    /// Sorts a given iterator whose items are at most `k` positions away from
+    /// their sorted position with a comparator function.
+    ///
+    /// See [`ExternalSorter::sort_k_disordered`].
+    pub fn sort_k_disordered_by<T, I, F>(
+        self,
+        iterator: I,
+        k: usize,
+        cmp: F,
+    ) -> KDisorderedIterator<I::IntoIter, T, F>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(&T, &T) -> Ordering + Clone,
+    {
+        KDisorderedIterator::new(iterator.into_iter(), k, cmp)
+    }
+
+    /// Sorts a given iterator and writes the encoded sorted items to the file at
+    /// the given path, returning the number of items written.
+    ///
+    /// The file is written atomically: items are first written to a temporary
+    /// file in the same directory, which is then renamed to the given path.
+    /// See [`SortedIterator::write_to_path`].
+    pub fn sort_into_path<T, I, P>(self, iterator: I, path: P) -> Result<u64, Error>
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = T>,
+        P: AsRef<Path>,
+    {
+        self.sort(iterator)?.write_to_path(path)
+    }
+
+    /// Sorts a given iterator into partitions delimited by the given sorted
+    /// boundaries, returning one sorted iterator per partition.
+    ///
+    /// `n` boundaries produce `n + 1` partitions, where partition `i` contains
+    /// the items greater or equal to `boundaries[i - 1]` and smaller than
+    /// `boundaries[i]`. Each partition uses its own in-memory buffer, which
+    /// should be taken into account when choosing the segment size.
+    #[allow(clippy::type_complexity)]
+    pub fn sort_partitioned<T, I>(
+        self,
+        iterator: I,
+        boundaries: Vec<T>,
+    ) -> Result<Vec<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>>, Error>
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = T>,
+    {
+        self.sort_partitioned_by(iterator, boundaries, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator into partitions delimited by the given sorted
+    /// boundaries using a key extraction function, returning one sorted
+    /// iterator per partition.
+    ///
+    /// See [`ExternalSorter::sort_partitioned`].
+    #[allow(clippy::type_complexity)]
+    pub fn sort_partitioned_by_key<T, I, F, K>(
+        self,
+        iterator: I,
+        boundaries: Vec<T>,
+        f: F,
+    ) -> Result<Vec<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = T>,
+        F: Fn(&T) -> K + Send + Sync + Clone,
+        K: Ord,
+    {
+        self.sort_partitioned_by(iterator, boundaries, move |a, b| f(a).cmp(&f(b)))
+    }
+
+    /// Sorts a given iterator into partitions delimited by the given sorted
+    /// boundaries using a comparator function, returning one sorted iterator
+    /// per partition.
+    ///
+    /// See [`ExternalSorter::sort_partitioned`].
+    pub fn sort_partitioned_by<T, I, F>(
+        self,
+        iterator: I,
+        boundaries: Vec<T>,
+        cmp: F,
+    ) -> Result<Vec<SortedIterator<T, F>>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = T>,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+        with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut sorters = (0..=boundaries.len())
+                .map(|_| {
+                    PushExternalSorter::new(self.options.clone(), cmp.clone())
+                        .with_compaction_handle(handle.clone())
+                })
+                .collect::<Vec<_>>();
+
+            for item in iterator {
+                let partition = boundaries.partition_point(|b| cmp(b, &item) != Ordering::Greater);
+                sorters[partition].push(item)?;
+            }
+
+            sorters.into_iter().map(|sorter| sorter.done()).collect()
+        })
+    }
+
+    /// Sorts a given iterator into `partitions` partitions by the hash of the
+    /// key returned by `key`, returning one sorted iterator per partition
+    /// along with its partition id.
+    ///
+    /// Items are assigned to partitions using [`hash_partition`], which is
+    /// stable across machines, so that a sort can be distributed by having
+    /// each machine pre-sort its items and send each partition to the
+    /// machine merging it. Unlike [`ExternalSorter::sort_partitioned`], items
+    /// of a partition aren't contiguous in the overall sorted order, but no
+    /// boundaries need to be known upfront. Each partition uses its own
+    /// in-memory buffer, which should be taken into account when choosing the
+    /// segment size.
+    ///
+    /// [`hash_partition`]: crate::hash_partition
+    #[allow(clippy::type_complexity)]
+    pub fn sort_hash_partitioned<T, I, H, K>(
+        self,
+        iterator: I,
+        partitions: usize,
+        key: H,
+    ) -> Result<
+        Vec<(
+            usize,
+            SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>,
+        )>,
+        Error,
+    >
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = T>,
+        H: Fn(&T) -> K,
+        K: std::hash::Hash,
+    {
+        self.sort_hash_partitioned_by(iterator, partitions, key, |a, b| a.cmp(b))
+    }
+
+    /// Sorts a given iterator into `partitions` partitions by the hash of the
+    /// key returned by `key` using a comparator function, returning one sorted
+    /// iterator per partition along with its partition id.
+    ///
+    /// See [`ExternalSorter::sort_hash_partitioned`].
+    #[allow(clippy::type_complexity)]
+    pub fn sort_hash_partitioned_by<T, I, H, K, F>(
+        self,
+        iterator: I,
+        partitions: usize,
+        key: H,
+        cmp: F,
+    ) -> Result<Vec<(usize, SortedIterator<T, F>)>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = T>,
+        H: Fn(&T) -> K,
+        K: std::hash::Hash,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+        let partitions = partitions.max(1);
+        with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut sorters = (0..partitions)
+                .map(|_| {
+                    PushExternalSorter::new(self.options.clone(), cmp.clone())
+                        .with_compaction_handle(handle.clone())
+                })
+                .collect::<Vec<_>>();
+
+            for item in iterator {
+                let partition = hash_partition(&key(&item), partitions);
+                sorters[partition].push(item)?;
+            }
+
+            sorters
+                .into_iter()
+                .enumerate()
+                .map(|(partition, sorter)| Ok((partition, sorter.done()?)))
+                .collect()
+        })
+    }
+
+    /// Groups the items of a given iterator by the key returned by `group`,
+    /// and sorts the items of each group, returning one sorted iterator per
+    /// group along with its key.
+    ///
+    /// Unlike sorting by a composite key, items are only compared within their
+    /// group, which saves work when there are few groups. Each group uses its
+    /// own in-memory buffer, which should be taken into account when choosing
+    /// the segment size.
+    #[allow(clippy::type_complexity)]
+    pub fn sort_grouped<T, I, G, K>(
+        self,
+        iterator: I,
+        group: G,
+        order: GroupOrder,
+    ) -> Result<
+        Vec<(
+            K,
+            SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>,
+        )>,
+        Error,
+    >
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = T>,
+        G: Fn(&T) -> K,
+        K: Ord + std::hash::Hash + Clone,
+    {
+        self.sort_grouped_by(iterator, group, order, |a, b| a.cmp(b))
+    }
+
+    /// Groups the items of a given iterator by the key returned by `group`,
+    /// and sorts the items of each group using a comparator function.
+    ///
+    /// See [`ExternalSorter::sort_grouped`].
+    #[allow(clippy::type_complexity)]
+    pub fn sort_grouped_by<T, I, G, K, F>(
+        self,
+        iterator: I,
+        group: G,
+        order: GroupOrder,
+        cmp: F,
+    ) -> Result<Vec<(K, SortedIterator<T, F>)>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = T>,
+        G: Fn(&T) -> K,
+        K: Ord + std::hash::Hash + Clone,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+
+        with_compaction_worker(&self.options, &cmp, |handle| {
+            let mut indices = std::collections::HashMap::new();
+            let mut groups: Vec<(K, PushExternalSorter<T, F>)> = Vec::new();
+            for item in iterator {
+                let key = group(&item);
+                let index = match indices.get(&key) {
+                    Some(index) => *index,
+                    None => {
+                        indices.insert(key.clone(), groups.len());
+                        let sorter = PushExternalSorter::new(self.options.clone(), cmp.clone())
+                            .with_compaction_handle(handle.clone());
+                        groups.push((key, sorter));
+                        groups.len() - 1
+                    }
+                };
+
+                groups[index].1.push(item)?;
+            }
+
+            if order == GroupOrder::Key {
+                groups.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+
+            groups
+                .into_iter()
+                .map(|(key, sorter)| Ok((key, sorter.done()?)))
+                .collect()
+        })
+    }
+
+    /// Merges existing files of encoded and individually sorted items,
+    /// returning a new iterator with the merged sorted items.
+    ///
+    /// Each file is treated as a sorted segment, as if it had been written by
+    /// the sorter itself, and is expected to contain items sorted according to
+    /// the default comparator. Since the files are consumed as is, the number
+    /// of items isn't known upfront and [`SortedIterator::known_count`] returns
+    /// `None`.
+    pub fn merge_files<T, I, P>(
+        self,
+        paths: I,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.merge_files_by::<T, _, _, _>(paths, |a, b| a.cmp(b))
+    }
+
+    /// Merges existing files of encoded and individually sorted items using a
+    /// key extraction function, returning a new iterator with the merged sorted
+    /// items.
+    pub fn merge_files_by_key<T, I, P, F, K>(
+        self,
+        paths: I,
+        f: F,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+        F: Fn(&T) -> K + Send + Sync + Clone,
+        K: Ord,
+    {
+        self.merge_files_by(paths, move |a, b| f(a).cmp(&f(b)))
+    }
+
+    /// Merges existing files of encoded and individually sorted items using a
+    /// comparator function, returning a new iterator with the merged sorted
+    /// items.
+    pub fn merge_files_by<T, I, P, F>(self, paths: I, cmp: F) -> Result<SortedIterator<T, F>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+        let segment_files = paths
+            .into_iter()
+            .map(|path| File::open(path).map(SegmentFile::new))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        SortedIterator::new(None, None, segment_files, None, cmp, self.options)
+    }
+
+    /// Merges persisted files of sorted items, such as the outputs of prior
+    /// sorts (see [`SortedIterator::persist`]) or the runs of a
+    /// [`RunSet`](crate::RunSet), into a single sorted file at the given path,
+    /// returning the persisted file.
+    ///
+    /// Files are merged as with [`ExternalSorter::merge_files`], and the
+    /// merged file is persisted as with [`SortedIterator::persist`]. Equal
+    /// items of different files are merged in the order of the given paths,
+    /// so that the duplicate policy (see
+    /// [`ExternalSorter::with_duplicate_policy`]) keeps the item of the first
+    /// or last file among them: given files from the oldest to the newest,
+    /// [`DuplicatePolicy::KeepLast`] keeps the latest version of each item.
+    /// Given files are left as is.
+    pub fn compact_files<T, I, P, Q>(
+        self,
+        paths: I,
+        output: Q,
+    ) -> Result<SortedFile<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.compact_files_by::<T, _, _, _, _>(paths, output, |a, b| a.cmp(b))
+    }
+
+    /// Merges persisted files of items sorted according to the given
+    /// comparator into a single sorted file at the given path, returning the
+    /// persisted file.
+    ///
+    /// See [`ExternalSorter::compact_files`].
+    pub fn compact_files_by<T, I, P, Q, F>(
+        self,
+        paths: I,
+        output: Q,
+        cmp: F,
+    ) -> Result<SortedFile<T, F>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+        let mut count = Some(0);
+        let mut segment_files = Vec::new();
+        for path in paths {
+            let mut segment_file = SegmentFile::open(File::open(path)?)?;
+            count = match (count, segment_file.format.blocked) {
+                (Some(count), true) => Some(count + segment_file.blocked_len()?),
+                _ => None,
+            };
+            segment_files.push(segment_file);
+        }
+
+        SortedIterator::new(None, None, segment_files, count, cmp, self.options)?.persist(output)
+    }
+
+    /// Merges streams of encoded and individually sorted items, such as
+    /// sorted shards received over the network, returning a new iterator
+    /// with the merged sorted items.
+    ///
+    /// Each reader is treated as a sorted segment like the files given to
+    /// [`ExternalSorter::merge_files`], but is consumed as a stream without
+    /// being written to disk first. Readers are read through a buffer, so
+    /// they don't need to be buffered themselves. Since streams can't seek,
+    /// skipping ahead in the merged iterator (see
+    /// [`SortedIterator::seek_to`]) decodes the skipped items, and the
+    /// iterator can neither be consumed from its end nor be decomposed (see
+    /// [`SortedIterator::into_parts`]).
+    pub fn merge_readers<T, I, R>(
+        self,
+        readers: I,
+    ) -> Result<SortedIterator<T, impl Fn(&T, &T) -> Ordering + Send + Sync + Clone>, Error>
+    where
+        T: Sortable + Ord,
+        I: IntoIterator<Item = R>,
+        R: Read + Send + 'static,
+    {
+        self.merge_readers_by::<T, _, _, _>(readers, |a, b| a.cmp(b))
+    }
+
+    /// Merges streams of encoded and individually sorted items using a
+    /// comparator function, returning a new iterator with the merged sorted
+    /// items.
+    pub fn merge_readers_by<T, I, R, F>(
+        self,
+        readers: I,
+        cmp: F,
+    ) -> Result<SortedIterator<T, F>, Error>
+    where
+        T: Sortable,
+        I: IntoIterator<Item = R>,
+        R: Read + Send + 'static,
+        F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+    {
+        self.validate()?;
+        let segment_files = readers
+            .into_iter()
+            .map(|reader| SegmentFile::from_data(SegmentData::stream(reader)))
+            .collect::<Vec<_>>();
+
+        SortedIterator::new(None, None, segment_files, None, cmp, self.options)
     }
 
     /// Creates a pushed external sorter, which will consume items in a push
@@ -157,6 +1497,92 @@ impl ExternalSorter {
     }
 }
 
+/// Algorithm used to sort the in-memory buffer of a sorter (see
+/// [`ExternalSorter::with_sort_algorithm`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortAlgorithm {
+    /// Unstable sort, which is generally faster and doesn't allocate.
+    #[default]
+    Unstable,
+
+    /// Stable sort, which keeps items that compare equal in the order they
+    /// were pushed.
+    Stable,
+}
+
+/// Items kept among the items that compare equal (see
+/// [`ExternalSorter::with_duplicate_policy`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// All items are kept.
+    #[default]
+    KeepAll,
+
+    /// Only the first of the items that compare equal is kept.
+    KeepFirst,
+
+    /// Only the last of the items that compare equal is kept.
+    KeepLast,
+}
+
+/// Order of the groups returned by [`ExternalSorter::sort_grouped`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GroupOrder {
+    /// Groups are returned in the order in which their first item was seen.
+    #[default]
+    FirstSeen,
+
+    /// Groups are returned in the order of their key.
+    Key,
+}
+
+impl ExternalSorter {
+    /// Creates a pushed external sorter of byte records, which copies records
+    /// into a contiguous arena and sorts them in lexicographic order.
+    ///
+    /// See [`ArenaPushSorter`].
+    pub fn pushed_bytes(
+        self,
+    ) -> ArenaPushSorter<impl Fn(&[u8], &[u8]) -> Ordering + Send + Sync + Clone> {
+        self.pushed_bytes_by(|a, b| a.cmp(b))
+    }
+
+    /// Creates a pushed external sorter of byte records, which copies records
+    /// into a contiguous arena and compares them using the given comparator
+    /// function.
+    ///
+    /// See [`ArenaPushSorter`].
+    pub fn pushed_bytes_by<F>(self, cmp: F) -> ArenaPushSorter<F>
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync + Clone,
+    {
+        ArenaPushSorter::new(self.options, cmp)
+    }
+}
+
+/// Runs a sort with the handle of a worker merging the segments of its
+/// sorters on a background thread scoped to the sort, if compaction is
+/// enabled (see [`ExternalSorter::with_compaction`]).
+fn with_compaction_worker<T, F, R>(
+    options: &ExternalSorterOptions,
+    cmp: &F,
+    sort: impl FnOnce(Option<CompactionHandle<T>>) -> R,
+) -> R
+where
+    T: Sortable,
+    F: Fn(&T, &T) -> Ordering + Send + Sync + Clone,
+{
+    std::thread::scope(|scope| {
+        // groups are merged incrementally on each push if the thread can't be
+        // spawned, such as on targets without threads
+        let handle = compaction_worker(options, cmp).and_then(|(handle, worker)| {
+            let spawned = options.background_thread().spawn_scoped(scope, worker);
+            spawned.is_ok().then_some(handle)
+        });
+        sort(handle)
+    })
+}
+
 impl Default for ExternalSorter {
     fn default() -> Self {
         ExternalSorter::new()
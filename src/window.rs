@@ -0,0 +1,136 @@
+// Copyright 2018 Andre-Philippe Paquet
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp::Ordering, collections::BinaryHeap, iter::FusedIterator};
+
+/// Streaming iterator sorting items that are at most `k` positions away from
+/// their sorted position, as returned by
+/// [`ExternalSorter::sort_k_disordered`].
+///
+/// Items are kept in a heap of `k + 1` items and emitted as soon as the heap
+/// is full, without writing anything to disk. Equal items are emitted in the
+/// order they were received.
+///
+/// If an item is more than `k` positions away from its sorted position, it is
+/// emitted late and the output isn't sorted.
+///
+/// [`ExternalSorter::sort_k_disordered`]: crate::ExternalSorter::sort_k_disordered
+pub struct KDisorderedIterator<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T, &T) -> Ordering + Clone,
+{
+    iter: std::iter::Fuse<I>,
+    k: usize,
+    heap: BinaryHeap<WindowItem<T, F>>,
+    sequence: u64,
+    cmp: F,
+}
+
+impl<I, T, F> KDisorderedIterator<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T, &T) -> Ordering + Clone,
+{
+    pub(crate) fn new(iter: I, k: usize, cmp: F) -> KDisorderedIterator<I, T, F> {
+        KDisorderedIterator {
+            iter: iter.fuse(),
+            k,
+            heap: BinaryHeap::with_capacity(k.saturating_add(1).min(1 << 16)),
+            sequence: 0,
+            cmp,
+        }
+    }
+}
+
+impl<I, T, F> Iterator for KDisorderedIterator<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T, &T) -> Ordering + Clone,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.heap.len() <= self.k {
+            let Some(value) = self.iter.next() else {
+                break;
+            };
+
+            self.heap.push(WindowItem {
+                value,
+                sequence: self.sequence,
+                cmp: self.cmp.clone(),
+            });
+            self.sequence += 1;
+        }
+
+        self.heap.pop().map(|item| item.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.iter.size_hint();
+        let buffered = self.heap.len();
+        (
+            lower.saturating_add(buffered),
+            upper.and_then(|upper| upper.checked_add(buffered)),
+        )
+    }
+}
+
+impl<I, T, F> FusedIterator for KDisorderedIterator<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T, &T) -> Ordering + Clone,
+{
+}
+
+struct WindowItem<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    value: T,
+    sequence: u64,
+    cmp: F,
+}
+
+impl<T, F> PartialOrd for WindowItem<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, F> Ord for WindowItem<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.cmp)(&self.value, &other.value)
+            .then(self.sequence.cmp(&other.sequence))
+            .reverse()
+    }
+}
+
+impl<T, F> PartialEq for WindowItem<T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, F> Eq for WindowItem<T, F> where F: Fn(&T, &T) -> Ordering {}